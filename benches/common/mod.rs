@@ -0,0 +1,32 @@
+//! Synthetic multiplexer signals shared by the benchmarks
+
+use esquaredg_picoscope_monitering::ConstConfig;
+use pico_sdk::prelude::PicoChannel;
+
+/// Per channel capture rates to benchmark, the range the device is used at
+pub const SAMPLE_RATES: [u64; 4] = [1_000_000, 5_000_000, 10_000_000, 20_000_000];
+pub const CHANNELS: [PicoChannel; 4] = [
+    PicoChannel::A,
+    PicoChannel::B,
+    PicoChannel::C,
+    PicoChannel::D,
+];
+
+/// A clean multiplexer signal as the scope sees it: a sync pulse slot followed by one
+/// slot per virtual channel, repeated
+pub fn mux_signal(sample_rate: u64, len: usize) -> Vec<f64> {
+    let config = ConstConfig::get_config();
+    let slot_width = sample_rate as f64 / config.arduino_hz as f64;
+    let slots = config.virt_channel_count + 1;
+
+    (0..len)
+        .map(|i| match (i as f64 / slot_width) as usize % slots {
+            0 => 4.5,
+            slot => slot as f64 * 0.2,
+        })
+        .collect()
+}
+
+pub fn rate_label(sample_rate: u64) -> String {
+    format!("{}MS/s", sample_rate / 1_000_000)
+}
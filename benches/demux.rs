@@ -0,0 +1,30 @@
+//! Demultiplexer throughput at each capture rate, on 100 ms blocks of 4 channels
+
+mod common;
+
+use common::{mux_signal, rate_label, CHANNELS, SAMPLE_RATES};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use esquaredg_picoscope_monitering::virt_channels::split_into_virt_channels;
+
+use std::collections::HashMap;
+
+fn demux(c: &mut Criterion) {
+    let mut group = c.benchmark_group("demux");
+    group.sample_size(10);
+
+    for rate in SAMPLE_RATES.iter().copied() {
+        let signal = mux_signal(rate, rate as usize / 10);
+        let block: HashMap<_, _> = CHANNELS.iter().map(|ch| (*ch, signal.clone())).collect();
+
+        group.throughput(Throughput::Elements((signal.len() * CHANNELS.len()) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(rate_label(rate)),
+            &block,
+            |b, block| b.iter(|| split_into_virt_channels(black_box(block), rate).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, demux);
+criterion_main!(benches);
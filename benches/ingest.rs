@@ -0,0 +1,83 @@
+//! Cost of the streaming callback while recording, at each capture rate with 4
+//! channels. It only scales the samples and queues them for the pipeline, and anything
+//! it takes past the time between events shows up as dropouts
+
+mod common;
+
+use common::{mux_signal, rate_label, CHANNELS, SAMPLE_RATES};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use esquaredg_picoscope_monitering::{
+    app::state::{AppState, DeviceInfo},
+    pico::CaptureStats,
+};
+
+use actix_web::web;
+use parking_lot::Mutex;
+use pico_sdk::prelude::*;
+
+/// Events the driver delivers per second, which sets how many samples each one carries
+const EVENTS_PER_SECOND: usize = 10;
+/// Volts per count on the ±5 V range
+const MULTIPLIER: f64 = 5.0 / 32767.0;
+
+fn event(sample_rate: u64) -> StreamingEvent {
+    let length = sample_rate as usize / EVENTS_PER_SECOND;
+    let samples: Vec<i16> = mux_signal(sample_rate, length)
+        .iter()
+        .map(|v| (v / MULTIPLIER).round() as i16)
+        .collect();
+
+    StreamingEvent {
+        length,
+        samples_per_second: sample_rate as u32,
+        channels: CHANNELS
+            .iter()
+            .map(|ch| {
+                (
+                    *ch,
+                    RawChannelDataBlock {
+                        multiplier: MULTIPLIER,
+                        samples: samples.clone(),
+                    },
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Recording, but without a session, so the pipeline cuts blocks but writes nothing
+fn recording_state(sample_rate: u64) -> web::Data<Mutex<AppState>> {
+    let mut state = AppState::new(DeviceInfo {
+        pico_scope_type: "bench".to_string(),
+        channel_info: vec![],
+        refresh_rate: sample_rate as u32,
+    });
+    state.recording = true;
+    web::Data::new(Mutex::new(state))
+}
+
+fn ingest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ingest");
+    group.sample_size(10);
+
+    for rate in SAMPLE_RATES.iter().copied() {
+        let event = event(rate);
+        let state = recording_state(rate);
+        let capture_stats = CaptureStats::new(state.clone());
+
+        group.throughput(Throughput::Elements((event.length * CHANNELS.len()) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(rate_label(rate)),
+            &event,
+            |b, event| {
+                // Events come in far faster than real time here, so the pipeline falls
+                // behind and drops some, which costs the callback no more than queueing
+                b.iter(|| capture_stats.handle_event(event))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ingest);
+criterion_main!(benches);
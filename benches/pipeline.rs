@@ -0,0 +1,99 @@
+//! Processing while recording, end to end, at each capture rate with 4 channels. Each
+//! iteration streams a second of signal through the buffer, demux and sink stages into
+//! a session on disk, and waits for the session to be saved, so the throughput is what
+//! the pipeline sustains. It keeps up without dropouts only if that's above the capture
+//! rate and no queue dropped anything, so the queues' counters are printed after each rate
+
+mod common;
+
+use common::{mux_signal, rate_label, CHANNELS, SAMPLE_RATES};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use esquaredg_picoscope_monitering::{
+    app::state::{AppState, DeviceInfo},
+    electrodes::ChannelMap,
+    pipeline::{self, Item, RawEvent},
+};
+
+use actix_web::web;
+use parking_lot::Mutex;
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+/// Events the driver delivers per second, which sets how many samples each one carries
+const EVENTS_PER_SECOND: u64 = 10;
+
+/// Every enabled channel mapped, with the pipeline started on it
+fn processing_state(sample_rate: u64) -> web::Data<Mutex<AppState>> {
+    let mut state = AppState::new(DeviceInfo {
+        pico_scope_type: "bench".to_string(),
+        channel_info: vec![],
+        refresh_rate: sample_rate as u32,
+    });
+    let enabled: Vec<String> = CHANNELS.iter().map(|ch| ch.to_string()).collect();
+    state.set_channel_map(ChannelMap::new(&enabled));
+
+    let state = web::Data::new(Mutex::new(state));
+    pipeline::start(&state);
+    state
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+
+    for rate in SAMPLE_RATES.iter().copied() {
+        let length = rate / EVENTS_PER_SECOND;
+        let samples = mux_signal(rate, length as usize);
+        let state = processing_state(rate);
+        let ingest = state.lock().ingest.clone().unwrap();
+        // Carried across sessions, as it is while streaming
+        let mut streamed = 0;
+        let mut sessions = 0;
+
+        group.throughput(Throughput::Elements(rate * CHANNELS.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(rate_label(rate)), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    let name = format!("pipeline-bench-{}-{}", rate, sessions);
+                    sessions += 1;
+                    AppState::start_recording(&state, name).unwrap();
+
+                    let started = Instant::now();
+                    for _ in 0..EVENTS_PER_SECOND {
+                        // Waits rather than drops here, so every event reaches the buffer
+                        // stage and only the queues after it can drop
+                        ingest.send_wait(Item::Data(RawEvent {
+                            start: streamed,
+                            samples_per_second: rate as u32,
+                            streaming_speed: rate,
+                            channels: CHANNELS.iter().map(|ch| (*ch, samples.clone())).collect(),
+                        }));
+                        streamed += length;
+                    }
+                    let stopped = state.lock().stop_recording().unwrap().recv().unwrap();
+                    elapsed += started.elapsed();
+
+                    // Hashed in the background, and not part of keeping up with the stream
+                    stopped.manifest.join().unwrap();
+                    fs::remove_dir_all(stopped.session.directory()).unwrap();
+                }
+                elapsed
+            })
+        });
+
+        for queue in state.lock().live.pipeline_report() {
+            println!(
+                "  {} queue: {} sent, {} dropped, {} of {} deep at most",
+                queue.name, queue.sent, queue.dropped, queue.high_water, queue.capacity
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json},
+    HttpResponse,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::state::AppState;
+use crate::calibration::{measure, Calibration};
+
+#[derive(Deserialize)]
+pub struct NewReading {
+    /// Volts applied to every input while the quality window filled
+    reference: f64,
+}
+
+#[derive(Serialize)]
+struct PendingReadings {
+    /// References read so far, in order
+    references: Vec<f64>,
+}
+
+fn pending(state: &AppState) -> PendingReadings {
+    PendingReadings {
+        references: state.calibration_readings.iter().map(|r| r.0).collect(),
+    }
+}
+
+// Mounts to /api/calibration, the calibration in effect
+#[get("/calibration")]
+pub fn current_calibration(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let live = state.lock().live.clone();
+    let current = live.corrections.lock().calibration.clone();
+    match current {
+        Some(current) => HttpResponse::Ok().json(&*current),
+        None => HttpResponse::NotFound().body("Nothing has been calibrated"),
+    }
+}
+
+// Mounts to /api/calibration/readings, reads every virtual channel at a reference. Apply
+// it to every input for a whole quality window first
+#[post("/calibration/readings")]
+pub fn add_reading(state: Data<Mutex<AppState>>, reading: Json<NewReading>) -> HttpResponse {
+    let locked_state = state.lock();
+    if locked_state.recording {
+        return HttpResponse::Conflict().body("Stop recording before calibrating");
+    }
+    let live = locked_state.live.clone();
+    drop(locked_state);
+
+    // Straight from the raw samples, so whatever calibration is in effect doesn't matter
+    let (raw, sample_rate) = live.quality.lock().raw_samples();
+    let readings = match measure(&raw, sample_rate) {
+        Ok(readings) => readings,
+        Err(err) => return HttpResponse::BadRequest().body(format!("{}", err)),
+    };
+
+    let mut locked_state = state.lock();
+    locked_state
+        .calibration_readings
+        .push((reading.reference, readings));
+    HttpResponse::Ok().json(pending(&locked_state))
+}
+
+// Mounts to /api/calibration/readings, to start over
+#[delete("/calibration/readings")]
+pub fn clear_readings(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let mut locked_state = state.lock();
+    locked_state.calibration_readings.clear();
+    HttpResponse::Ok().json(pending(&locked_state))
+}
+
+// Mounts to /api/calibration, fits the readings taken, then saves and applies the
+// calibration
+#[post("/calibration")]
+pub fn fit_calibration(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let mut locked_state = state.lock();
+    let (references, readings): (Vec<f64>, Vec<_>) =
+        locked_state.calibration_readings.iter().cloned().unzip();
+    let fitted = match Calibration::fit(&references, &readings) {
+        Ok(fitted) => fitted,
+        Err(err) => return HttpResponse::BadRequest().body(format!("{}", err)),
+    };
+
+    if let Err(err) = locked_state.apply_calibration(fitted.clone()) {
+        return if locked_state.recording {
+            HttpResponse::Conflict().body(format!("{}", err))
+        } else {
+            HttpResponse::InternalServerError().body(format!("{}", err))
+        };
+    }
+    locked_state.calibration_readings.clear();
+    HttpResponse::Ok().json(fitted)
+}
@@ -0,0 +1,170 @@
+use actix_web::{
+    get, post,
+    web::{Data, Json, Path, Query},
+    HttpResponse,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::state::AppState;
+use crate::catalogue::Catalogue;
+
+/// Events returned when no limit is given
+const DEFAULT_EVENT_LIMIT: u32 = 200;
+
+#[derive(Deserialize)]
+pub struct EventQuery {
+    session: Option<String>,
+    kind: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct NewSubject {
+    name: String,
+    #[serde(default)]
+    notes: String,
+}
+
+#[derive(Deserialize)]
+pub struct SessionSubject {
+    subject: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewMarker {
+    label: String,
+    /// Seconds into the session, defaults to now for the session being recorded
+    time: Option<f64>,
+}
+
+/// Takes the catalogue out of the app state so queries don't hold its lock
+fn shared_catalogue(state: &Data<Mutex<AppState>>) -> Option<Arc<Catalogue>> {
+    state.lock().catalogue.clone()
+}
+
+fn unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("Catalogue is unavailable")
+}
+
+// Mounts to /api/events
+#[get("/events")]
+pub fn events(state: Data<Mutex<AppState>>, query: Query<EventQuery>) -> HttpResponse {
+    let catalogue = match shared_catalogue(&state) {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    match catalogue.events(
+        query.session.as_deref(),
+        query.kind.as_deref(),
+        query.limit.unwrap_or(DEFAULT_EVENT_LIMIT),
+    ) {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/subjects
+#[get("/subjects")]
+pub fn subjects(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let catalogue = match shared_catalogue(&state) {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    match catalogue.subjects() {
+        Ok(subjects) => HttpResponse::Ok().json(subjects),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/subjects
+#[post("/subjects")]
+pub fn add_subject(state: Data<Mutex<AppState>>, subject: Json<NewSubject>) -> HttpResponse {
+    let catalogue = match shared_catalogue(&state) {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    match catalogue.add_subject(&subject.name, &subject.notes) {
+        Ok(id) => HttpResponse::Created().json(id),
+        Err(err) => HttpResponse::Conflict().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/sessions/{name}/subject
+#[post("/sessions/{name}/subject")]
+pub fn set_session_subject(
+    state: Data<Mutex<AppState>>,
+    name: Path<String>,
+    body: Json<SessionSubject>,
+) -> HttpResponse {
+    let catalogue = match shared_catalogue(&state) {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    match catalogue.set_session_subject(&name, &body.subject) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Unknown session or subject"),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/sessions/{name}/markers
+#[get("/sessions/{name}/markers")]
+pub fn markers(state: Data<Mutex<AppState>>, name: Path<String>) -> HttpResponse {
+    let catalogue = match shared_catalogue(&state) {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    match catalogue.markers(&name) {
+        Ok(markers) => HttpResponse::Ok().json(markers),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/sessions/{name}/markers
+#[post("/sessions/{name}/markers")]
+pub fn add_marker(
+    state: Data<Mutex<AppState>>,
+    name: Path<String>,
+    marker: Json<NewMarker>,
+) -> HttpResponse {
+    let (catalogue, started) = {
+        let locked_state = state.lock();
+        let started = locked_state
+            .session
+            .as_ref()
+            .filter(|s| s.name == *name)
+            .map(|s| s.started);
+        (locked_state.catalogue.clone(), started)
+    };
+    let catalogue = match catalogue {
+        Some(catalogue) => catalogue,
+        None => return unavailable(),
+    };
+
+    let time = match (marker.time, started) {
+        (Some(time), _) => time,
+        (None, Some(started)) => {
+            (chrono::Local::now() - started).num_milliseconds() as f64 / 1000.0
+        }
+        (None, None) => {
+            return HttpResponse::BadRequest()
+                .body("A time is needed for sessions that aren't being recorded")
+        }
+    };
+
+    match catalogue
+        .add_marker(&name, time, &marker.label)
+        .map(|id| id.map(|_| time))
+    {
+        Ok(Some(time)) => HttpResponse::Created().json(time),
+        Ok(None) => HttpResponse::NotFound().body("Unknown session"),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
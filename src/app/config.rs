@@ -0,0 +1,33 @@
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use super::state::AppState;
+use crate::{
+    validation::{validate, ConfigProblem},
+    ConstConfig,
+};
+
+#[derive(Serialize)]
+struct ConfigReport {
+    config: ConstConfig,
+    problems: Vec<ConfigProblem>,
+}
+
+// Mounts to /api/config
+#[get("/config")]
+pub fn config(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let config = ConstConfig::get_config();
+    let device_info = state.lock().device_info.clone();
+    // Before the device is configured there's nothing to check the config against
+    let device = if device_info.refresh_rate > 0 {
+        Some(&device_info)
+    } else {
+        None
+    };
+
+    HttpResponse::Ok().json(ConfigReport {
+        problems: validate(&config, device),
+        config,
+    })
+}
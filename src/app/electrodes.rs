@@ -0,0 +1,10 @@
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+
+use super::state::AppState;
+
+// Mounts to /api/electrodes
+#[get("/electrodes")]
+pub fn electrodes(state: Data<Mutex<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(&state.lock().channel_map.channels)
+}
@@ -0,0 +1,130 @@
+use actix_web::{
+    get, put,
+    web::{Data, Json, Path, Query},
+    HttpResponse,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::state::AppState;
+use crate::{
+    catalogue::EventKind,
+    electrodes::{by_name, ChannelMap},
+    filters::{ChannelFilters, FilterKind, FilteredFrame},
+};
+
+use std::collections::BTreeMap;
+
+#[derive(Deserialize)]
+pub struct FramesQuery {
+    /// Only frames after this many seconds into the session
+    since: Option<f64>,
+    /// `raw` or `filtered`, both when left out
+    stream: Option<String>,
+}
+
+#[derive(Serialize)]
+struct NamedFilters {
+    name: String,
+    #[serde(flatten)]
+    filters: ChannelFilters,
+}
+
+#[derive(Serialize)]
+struct NamedFrame<'a> {
+    time: f64,
+    raw: BTreeMap<String, &'a f64>,
+    filtered: BTreeMap<String, &'a f64>,
+}
+
+fn named_selection(map: &ChannelMap, selection: Vec<ChannelFilters>) -> Vec<NamedFilters> {
+    selection
+        .into_iter()
+        .map(|chain| NamedFilters {
+            name: map.name(chain.channel),
+            filters: chain,
+        })
+        .collect()
+}
+
+// Mounts to /api/filters
+#[get("/filters")]
+pub fn filters(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    let selection = live.filters.lock().selection();
+    HttpResponse::Ok().json(named_selection(&map, selection))
+}
+
+// Mounts to /api/filters/{channel}, by electrode or montage name, or key
+#[put("/filters/{channel}")]
+pub fn set_filters(
+    state: Data<Mutex<AppState>>,
+    channel: Path<String>,
+    selection: Json<Vec<FilterKind>>,
+) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    let channel = match map.lookup(&channel) {
+        Some(channel) => channel,
+        None => return HttpResponse::NotFound().body(format!("Unknown channel {}", channel)),
+    };
+
+    let mut bank = live.filters.lock();
+    if let Err(err) = bank.set(channel, selection.into_inner()) {
+        return HttpResponse::BadRequest().body(format!("{}", err));
+    }
+    let selection = bank.selection();
+    drop(bank);
+
+    state.lock().log_event(
+        EventKind::FiltersChanged,
+        &format!(
+            "Filters of {} set to {:?}",
+            map.name(channel),
+            selection
+                .iter()
+                .find(|c| c.channel == channel)
+                .map(|c| &c.filters)
+        ),
+    );
+    HttpResponse::Ok().json(named_selection(&map, selection))
+}
+
+// Mounts to /api/frames, samples keyed by electrode or montage name
+#[get("/frames")]
+pub fn recent_frames(state: Data<Mutex<AppState>>, query: Query<FramesQuery>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    let mapped = &map.channels;
+    let since = query.since.unwrap_or(f64::NEG_INFINITY);
+    // Copied out, so the sinks aren't held up while they're serialised
+    let recent_frames: Vec<FilteredFrame> = live
+        .recent_frames
+        .lock()
+        .iter()
+        .filter(|f| f.time > since)
+        .cloned()
+        .collect();
+    let frames = recent_frames.iter();
+
+    match query.stream.as_deref() {
+        None => HttpResponse::Ok().json(
+            frames
+                .map(|f: &FilteredFrame| NamedFrame {
+                    time: f.time,
+                    raw: by_name(mapped, f.raw.iter()),
+                    filtered: by_name(mapped, f.filtered.iter()),
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Some("raw") => HttpResponse::Ok().json(
+            frames
+                .map(|f| (f.time, by_name(mapped, f.raw.iter())))
+                .collect::<Vec<_>>(),
+        ),
+        Some("filtered") => HttpResponse::Ok().json(
+            frames
+                .map(|f| (f.time, by_name(mapped, f.filtered.iter())))
+                .collect::<Vec<_>>(),
+        ),
+        Some(stream) => HttpResponse::BadRequest().body(format!("Unknown stream {}", stream)),
+    }
+}
@@ -0,0 +1,77 @@
+use actix_web::{
+    get,
+    web::{Path, Query},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    electrodes::by_name,
+    pyramid::{DecimationMode, Pyramid},
+    session::{session_directory, SessionMetadata},
+};
+
+use std::collections::BTreeMap;
+
+/// Most values per channel a single request can ask for
+const MAX_POINTS: usize = 10_000;
+
+#[derive(Deserialize)]
+pub struct RangeQueryParams {
+    /// Seconds since the session started, defaults to the start of the recording
+    start: Option<f64>,
+    /// Defaults to the end of the recording
+    end: Option<f64>,
+    /// Pixel budget, the number of values to return per channel
+    points: Option<usize>,
+    mode: Option<DecimationMode>,
+}
+
+/// A range with its channels by electrode or montage name
+#[derive(Serialize)]
+struct NamedRange<'a> {
+    start: f64,
+    end: f64,
+    level: usize,
+    mode: DecimationMode,
+    channels: BTreeMap<String, &'a Vec<Vec<f64>>>,
+}
+
+// Mounts to /api/sessions/{name}/range
+#[get("/sessions/{name}/range")]
+pub fn session_range(name: Path<String>, query: Query<RangeQueryParams>) -> HttpResponse {
+    let directory = match session_directory(&name) {
+        Ok(directory) => directory,
+        Err(err) => return HttpResponse::NotFound().body(format!("{}", err)),
+    };
+    let pyramid = match Pyramid::open(&directory) {
+        Ok(pyramid) => pyramid,
+        Err(err) => return HttpResponse::NotFound().body(format!("{}", err)),
+    };
+    // Sessions from before electrodes were mapped are named by key
+    let mapped = SessionMetadata::load(&directory)
+        .map(|m| m.electrodes)
+        .unwrap_or_default();
+
+    let (first, last) = match pyramid.time_span() {
+        Ok(Some(span)) => span,
+        Ok(None) => (0.0, 0.0),
+        Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+    };
+
+    match pyramid.query(
+        query.start.unwrap_or(first),
+        query.end.unwrap_or(last),
+        query.points.unwrap_or(1000).min(MAX_POINTS),
+        query.mode.unwrap_or(DecimationMode::MinMax),
+    ) {
+        Ok(range) => HttpResponse::Ok().json(NamedRange {
+            start: range.start,
+            end: range.end,
+            level: range.level,
+            mode: range.mode,
+            channels: by_name(&mapped, range.channels.iter()),
+        }),
+        Err(err) => HttpResponse::BadRequest().body(format!("{}", err)),
+    }
+}
@@ -0,0 +1,11 @@
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+
+use super::state::AppState;
+
+// Mounts to /api/memory
+#[get("/memory")]
+pub fn memory(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let live = state.lock().live.clone();
+    HttpResponse::Ok().json(live.memory_report())
+}
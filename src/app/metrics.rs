@@ -0,0 +1,30 @@
+use crate::metrics::{render, Gauges};
+
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+
+use super::state::AppState;
+
+use std::sync::atomic::Ordering;
+
+// Mounts to /metrics, where Prometheus looks by default
+#[get("/metrics")]
+pub fn metrics(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let locked_state = state.lock();
+    let live = locked_state.live.clone();
+    let recording = locked_state.recording;
+    drop(locked_state);
+
+    let memory = live.memory_report();
+    let gauges = Gauges {
+        streaming_speed: live.streaming_speed.load(Ordering::Relaxed),
+        recording,
+        queues: live.pipeline_report(),
+        process: memory.process,
+        buffered_bytes: memory.buffered_bytes,
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&gauges))
+}
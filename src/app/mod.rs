@@ -1,15 +1,21 @@
+pub mod calibration;
+pub mod catalogue;
+pub mod config;
+pub mod electrodes;
+pub mod filters;
+pub mod history;
+pub mod memory;
+pub mod metrics;
+pub mod pipeline;
+pub mod quality;
+pub mod sessions;
+pub mod spectrum;
 pub mod state;
 
 use actix_web::{get, web::Data, HttpResponse};
 use parking_lot::Mutex;
 
-use std::time::Instant;
-
-use crate::pico::clear_and_get_memory;
-
-use super::state::AppState;
-
-use serde_json;
+use state::AppState;
 
 // /
 #[get("/")]
@@ -0,0 +1,10 @@
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+
+use super::state::AppState;
+
+// Mounts to /api/pipeline
+#[get("/pipeline")]
+pub fn pipeline(state: Data<Mutex<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.lock().live.pipeline_report())
+}
@@ -0,0 +1,25 @@
+use actix_web::{get, web::Data, HttpResponse};
+use parking_lot::Mutex;
+
+use super::state::AppState;
+
+// Mounts to /api/quality
+#[get("/quality")]
+pub fn quality(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    HttpResponse::Ok().json(live.quality_report(&map))
+}
+
+// Mounts to /api/sync
+#[get("/sync")]
+pub fn sync(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let live = state.lock().live.clone();
+    HttpResponse::Ok().json(live.sync_view())
+}
+
+// Mounts to /api/preflight
+#[get("/preflight")]
+pub fn preflight(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    HttpResponse::Ok().json(live.preflight(&map))
+}
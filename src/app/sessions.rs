@@ -0,0 +1,159 @@
+use actix_files::NamedFile;
+use actix_web::{
+    delete, get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Data, Path, Query},
+    HttpResponse,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use std::{
+    fs::{self, File},
+    io::{Seek, SeekFrom},
+};
+
+use super::state::AppState;
+use crate::{
+    catalogue::EventKind,
+    export::OutputFormat,
+    session::{archive::write_zip, list_sessions, session_directory, SessionMetadata, SessionSummary},
+};
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    /// `zip` for the session as recorded, or `csv`, `npz` or `mat` to convert the frames
+    format: Option<String>,
+}
+
+/// Name of the session currently being recorded, if any
+fn active_session(state: &Data<Mutex<AppState>>) -> Option<String> {
+    state.lock().session.as_ref().map(|s| s.name.clone())
+}
+
+/// Why a session's files can't be read or removed yet, if they're still being written.
+/// The manifest comes last, a little after the recording stops
+fn still_writing(state: &Data<Mutex<AppState>>, name: &str) -> Option<&'static str> {
+    let state = state.lock();
+    if state.session.as_ref().map(|s| s.name.as_str()) == Some(name) {
+        Some("it's being recorded")
+    } else if state.live.pending_manifests.lock().contains(name) {
+        Some("its manifest is still being written")
+    } else {
+        None
+    }
+}
+
+// Mounts to /api/sessions
+#[get("/sessions")]
+pub fn sessions(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let active = active_session(&state);
+
+    match list_sessions() {
+        Ok(sessions) => HttpResponse::Ok().json(
+            sessions
+                .into_iter()
+                .map(|s| {
+                    let recording = Some(&s.name) == active.as_ref();
+                    SessionSummary::new(s, recording)
+                })
+                .collect::<Vec<SessionSummary>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/sessions/{name}
+#[get("/sessions/{name}")]
+pub fn session_info(state: Data<Mutex<AppState>>, name: Path<String>) -> HttpResponse {
+    let directory = match session_directory(&name) {
+        Ok(directory) => directory,
+        Err(err) => return HttpResponse::NotFound().body(format!("{}", err)),
+    };
+
+    match SessionMetadata::load(&directory) {
+        Ok(metadata) => {
+            let recording = Some(&metadata.name) == active_session(&state).as_ref();
+            HttpResponse::Ok().json(SessionSummary::new(metadata, recording))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
+
+// Mounts to /api/sessions/{name}/download
+#[get("/sessions/{name}/download")]
+pub async fn download_session(
+    state: Data<Mutex<AppState>>,
+    name: Path<String>,
+    query: Query<DownloadQuery>,
+) -> actix_web::Result<NamedFile> {
+    let name = name.into_inner();
+    if let Some(reason) = still_writing(&state, &name) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Can't download the session, {}",
+            reason
+        )));
+    }
+    let directory = session_directory(&name).map_err(actix_web::error::ErrorNotFound)?;
+
+    let format = match query.format.as_deref() {
+        None | Some("zip") => None,
+        Some(format) => Some(OutputFormat::from_extension(format).ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(format!("unknown format {}", format))
+        })?),
+    };
+    let file_name = format!(
+        "{}{}.zip",
+        name,
+        format.map(|f| format!("_{}", f.extension())).unwrap_or_default()
+    );
+
+    // Zipping a long session takes a while, so keep it off the server's threads. The
+    // file has no name, so downloads never share one, and the OS deletes it once it's
+    // been sent and closed
+    let zip = web::block(move || -> anyhow::Result<File> {
+        let mut zip = tempfile::tempfile()?;
+        write_zip(&directory, format, &mut zip)?;
+        zip.seek(SeekFrom::Start(0))?;
+        Ok(zip)
+    })
+    .await
+    .map_err(|e| actix_web::error::ErrorInternalServerError(format!("{:?}", e)))?;
+
+    Ok(
+        NamedFile::from_file(zip, &file_name)?.set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        }),
+    )
+}
+
+// Mounts to /api/sessions/{name}
+#[delete("/sessions/{name}")]
+pub fn delete_session(state: Data<Mutex<AppState>>, name: Path<String>) -> HttpResponse {
+    if let Some(reason) = still_writing(&state, &name) {
+        return HttpResponse::Conflict().body(format!("Can't delete the session, {}", reason));
+    }
+
+    let directory = match session_directory(&name) {
+        Ok(directory) => directory,
+        Err(err) => return HttpResponse::NotFound().body(format!("{}", err)),
+    };
+
+    match fs::remove_dir_all(directory) {
+        Ok(()) => {
+            let catalogue = state.lock().catalogue.clone();
+            if let Some(catalogue) = catalogue {
+                if let Err(err) = catalogue.delete_session(&name) {
+                    tracing::error!(?err, "could not remove session from the catalogue");
+                }
+            }
+            state.lock().log_event(
+                EventKind::SessionDeleted,
+                &format!("Deleted session {}", name),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+    }
+}
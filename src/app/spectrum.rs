@@ -0,0 +1,77 @@
+use actix_web::{
+    get,
+    web::{Data, Query},
+    HttpResponse,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::state::AppState;
+use crate::{electrodes::by_name, spectrum::ChannelSpectrum};
+
+use std::collections::BTreeMap;
+
+#[derive(Deserialize)]
+pub struct SpectrogramQuery {
+    /// Only tiles after this many seconds into the session
+    since: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct NamedSpectrum {
+    name: String,
+    #[serde(flatten)]
+    spectrum: ChannelSpectrum,
+}
+
+/// A spectrogram tile with its channels by name
+#[derive(Serialize)]
+struct NamedTile<'a> {
+    time: f64,
+    power: BTreeMap<String, &'a Vec<f64>>,
+}
+
+#[derive(Serialize)]
+struct Spectrogram<'a> {
+    /// Hz of each row of a tile
+    frequencies: Vec<f64>,
+    tiles: Vec<NamedTile<'a>>,
+}
+
+// Mounts to /api/spectrum
+#[get("/spectrum")]
+pub fn spectrum(state: Data<Mutex<AppState>>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    // Welch runs on a copy, so the sinks can keep adding frames meanwhile
+    let analyser = live.spectrum.lock().clone();
+    HttpResponse::Ok().json(
+        analyser
+            .spectra()
+            .into_iter()
+            .map(|spectrum| NamedSpectrum {
+                name: map.name(spectrum.channel),
+                spectrum,
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+// Mounts to /api/spectrogram
+#[get("/spectrogram")]
+pub fn spectrogram(state: Data<Mutex<AppState>>, query: Query<SpectrogramQuery>) -> HttpResponse {
+    let (live, map) = state.lock().live_and_map();
+    let analyser = live.spectrum.lock().clone();
+    let mapped = &map.channels;
+
+    HttpResponse::Ok().json(Spectrogram {
+        frequencies: analyser.frequencies(),
+        tiles: analyser
+            .tiles_since(query.since.unwrap_or(f64::NEG_INFINITY))
+            .into_iter()
+            .map(|tile| NamedTile {
+                time: tile.time,
+                power: by_name(mapped, tile.power.iter()),
+            })
+            .collect(),
+    })
+}
@@ -1,15 +1,45 @@
+use crate::{
+    calibration::{Calibration, Readings},
+    catalogue::{Catalogue, EventKind, EventLog},
+    electrodes::ChannelMap,
+    filters::{FilterBank, FilteredFrame},
+    memory::{self, BufferStats, MemoryReport, RingBuffer},
+    metrics::COUNTERS,
+    pipeline::{
+        queue::{QueueSnapshot, QueueStats, Sender},
+        Corrections, Item, Marker, RawEvent, Recording, Stop, Stopped,
+    },
+    preflight::{self, CheckStatus, PreflightReport},
+    pyramid::PyramidWriter,
+    quality::{
+        sync::{SyncLog, SyncMonitor, SyncView},
+        ChannelQuality, QualityMonitor,
+    },
+    recorder::{ChunkRecorder, RecordingIndex},
+    session::SessionMetadata,
+    spectrum::SpectralAnalyser,
+    virt_channels::MuxLayout,
+    ConstConfig,
+};
+
+use parking_lot::Mutex;
 use pico_sdk::common::PicoChannel;
 use serde::Serialize;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    path::Path,
+    sync::{atomic::AtomicU64, mpsc, Arc},
     time::Instant,
 };
 
 #[derive(Clone, Serialize)]
 pub struct ChannelInfo {
     pub channel: String,
+    /// Virtual channels in each frame, from its layout
     pub virt_channels: u32,
+    pub layout: MuxLayout,
     pub voltage_range: f32,
 }
 
@@ -26,26 +56,310 @@ impl ToString for DeviceInfo {
     }
 }
 
+/// Frames kept for the live view, about 3 seconds at the default multiplexer speed
+pub const RECENT_FRAME_LIMIT: usize = 10_000;
+
+/// Shared by the app and the processing stages, each part behind a lock of its own so
+/// a request only ever waits on what it reads. Cheap to clone, so requests can take it
+/// and let go of the app state first
 #[derive(Clone)]
+pub struct LiveState {
+    /// Latest raw samples, for clients to collect
+    pub voltage_queue: Arc<Mutex<HashMap<PicoChannel, RingBuffer>>>,
+    /// Fill of the buffer stage's stream buffers, as of the latest event
+    pub stream_buffers: Arc<Mutex<Vec<BufferStats>>>,
+    pub streaming_speed: Arc<AtomicU64>,
+    pub filters: Arc<Mutex<FilterBank>>,
+    /// Latest frames, raw and filtered, for the live view
+    pub recent_frames: Arc<Mutex<VecDeque<FilteredFrame>>>,
+    pub spectrum: Arc<Mutex<SpectralAnalyser>>,
+    pub quality: Arc<Mutex<QualityMonitor>>,
+    pub sync: Arc<Mutex<SyncMonitor>>,
+    /// Applied to every block demultiplexed from when they're set
+    pub corrections: Arc<Mutex<Corrections>>,
+    /// Queues between the processing stages, once they're running
+    pub pipeline: Vec<Arc<QueueStats>>,
+    /// Stopped sessions whose manifest is still being written, by name
+    pub pending_manifests: Arc<Mutex<HashSet<String>>>,
+}
+
+impl LiveState {
+    fn new() -> Self {
+        LiveState {
+            voltage_queue: Default::default(),
+            stream_buffers: Default::default(),
+            streaming_speed: Default::default(),
+            filters: Arc::new(Mutex::new(FilterBank::new(BTreeMap::new()))),
+            recent_frames: Default::default(),
+            spectrum: Arc::new(Mutex::new(SpectralAnalyser::new())),
+            quality: Arc::new(Mutex::new(QualityMonitor::new())),
+            sync: Arc::new(Mutex::new(SyncMonitor::new())),
+            corrections: Default::default(),
+            pipeline: vec![],
+            pending_manifests: Default::default(),
+        }
+    }
+
+    /// Sets each channel's frame rate for filtering and quality checks. Clears any
+    /// filters, which were designed for the old rates
+    fn set_frame_rates(&self, map: &ChannelMap) {
+        *self.filters.lock() = FilterBank::new(map.frame_rates());
+        self.quality.lock().set_frame_rates(map.frame_rates());
+    }
+
+    /// Latest blocks, and the totals of the session being recorded if any
+    pub fn sync_view(&self) -> SyncView {
+        self.sync.lock().view()
+    }
+
+    /// Quality of every channel, measured on a copy so the stages aren't held up
+    pub fn quality_report(&self, map: &ChannelMap) -> Vec<ChannelQuality> {
+        let quality = self.quality.lock().clone();
+        quality.report(map)
+    }
+
+    /// Checks the signal streamed so far is fit to record
+    pub fn preflight(&self, map: &ChannelMap) -> PreflightReport {
+        let quality = self.quality.lock().clone();
+        let (raw, sample_rate) = quality.raw_samples();
+        preflight::run(&raw, sample_rate, &quality.report(map))
+    }
+
+    /// Fill and overflow of every sample buffer, with the process's memory use
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut channels: Vec<BufferStats> = self
+            .voltage_queue
+            .lock()
+            .iter()
+            .map(|(channel, buffer)| buffer.stats("memory queue", channel.to_string()))
+            .collect();
+        channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+        channels.extend(self.stream_buffers.lock().iter().cloned());
+        memory::report(channels)
+    }
+
+    /// Depth and drops of every queue between the processing stages
+    pub fn pipeline_report(&self) -> Vec<QueueSnapshot> {
+        self.pipeline.iter().map(|q| q.snapshot()).collect()
+    }
+}
 
 pub struct AppState {
-    pub voltage_stream: HashMap<PicoChannel, Vec<f64>>,
-    pub voltage_queue: HashMap<PicoChannel, VecDeque<f64>>,
     pub device_info: DeviceInfo,
-    pub streaming_speed: u64,
     pub start_time: Instant,
     pub recording: bool,
+    /// The session being recorded, as it was started. The stages keep its running totals
+    pub session: Option<SessionMetadata>,
+    pub catalogue: Option<Arc<Catalogue>>,
+    pub events: EventLog,
+    /// Into the first processing stage, for markers that have to keep their place among
+    /// the streamed events
+    pub ingest: Option<Sender<Item<RawEvent>>>,
+    pub live: LiveState,
+    /// Electrode and montage of every key in the frames, set once channels are configured
+    pub channel_map: Arc<ChannelMap>,
+    /// Readings taken so far at each reference voltage, until they're fitted
+    pub calibration_readings: Vec<(f64, Readings)>,
 }
 
 impl AppState {
     pub fn new(device_info: DeviceInfo) -> Self {
         AppState {
-            voltage_stream: HashMap::new(),
-            voltage_queue: HashMap::new(),
             device_info,
-            streaming_speed: 0u64,
             start_time: Instant::now(),
             recording: false,
+            session: None,
+            catalogue: None,
+            events: EventLog::default(),
+            ingest: None,
+            live: LiveState::new(),
+            channel_map: Arc::new(ChannelMap::default()),
+            calibration_readings: vec![],
         }
     }
+
+    /// Starts a new recording session, and creates its output directory. Recording
+    /// starts from the next streamed event. Returns the preflight checks of the signal
+    /// it starts with, which are logged against the session unless they all pass. The
+    /// state isn't locked while the start marker waits for room in the queue, so nothing
+    /// else is held up behind it
+    pub fn start_recording(
+        state: &Mutex<AppState>,
+        name: String,
+    ) -> anyhow::Result<PreflightReport> {
+        let (ingest, recording, report) = state.lock().prepare_recording(name)?;
+        let session = recording.session.clone();
+
+        if !ingest.send_wait(Item::Marker(Marker::Start(Box::new(recording)))) {
+            state.lock().abandon_session(&session);
+            return Err(anyhow::anyhow!("processing has stopped"));
+        }
+
+        let mut state = state.lock();
+        state.session = Some(session);
+        state.recording = true;
+
+        if report.status != CheckStatus::Pass {
+            let problems: Vec<String> = report
+                .checks
+                .iter()
+                .filter(|c| c.status != CheckStatus::Pass)
+                .map(|c| format!("{} ({:?}): {}", c.name, c.status, c.detail))
+                .collect();
+            state.log_event(
+                EventKind::PreflightProblem,
+                &format!(
+                    "Started with {:?} preflight: {}",
+                    report.status,
+                    problems.join("; ")
+                ),
+            );
+        }
+        Ok(report)
+    }
+
+    /// Writes out and catalogues a new session, ready for the stages to record
+    fn prepare_recording(
+        &self,
+        name: String,
+    ) -> anyhow::Result<(Sender<Item<RawEvent>>, Recording, PreflightReport)> {
+        let ingest = self
+            .ingest
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("processing hasn't started"))?;
+        let report = self.live.preflight(&self.channel_map);
+        let corrections = self.live.corrections.lock().clone();
+
+        let mut session = SessionMetadata::new(name, &self.device_info);
+        session.filters = self.live.filters.lock().selection();
+        session.calibration = corrections
+            .calibration
+            .as_ref()
+            .map(|c| c.provenance(Path::new(ConstConfig::get_config().calibration_file)));
+        session.crosstalk = corrections
+            .crosstalk
+            .as_ref()
+            .map(|c| c.provenance(Path::new(ConstConfig::get_config().crosstalk_file)));
+        session.electrodes = self.channel_map.channels.clone();
+        session.save()?;
+
+        let files = || -> anyhow::Result<Recording> {
+            Ok(Recording {
+                session: session.clone(),
+                recorder: Some(ChunkRecorder::create(&session.directory())?),
+                pyramid: PyramidWriter::create(
+                    &session.directory(),
+                    self.channel_map.channels.iter().map(|c| c.key).collect(),
+                )?,
+                sync_log: SyncLog::create(&session.directory())?,
+            })
+        };
+        let recording = match files() {
+            Ok(recording) => recording,
+            Err(err) => {
+                self.abandon_session(&session);
+                return Err(err);
+            }
+        };
+        if let Some(catalogue) = self.catalogue.as_ref() {
+            if let Err(err) = catalogue.start_session(&session) {
+                tracing::error!(?err, "could not catalogue session");
+            }
+        }
+        Ok((ingest, recording, report))
+    }
+
+    /// Removes what there is of a session that never started recording
+    fn abandon_session(&self, session: &SessionMetadata) {
+        if let Err(err) = fs::remove_dir_all(session.directory()) {
+            tracing::error!(?err, "could not remove abandoned session");
+        }
+        if let Some(catalogue) = self.catalogue.as_ref() {
+            if let Err(err) = catalogue.delete_session(&session.name) {
+                tracing::error!(
+                    ?err,
+                    "could not remove abandoned session from the catalogue"
+                );
+            }
+        }
+    }
+
+    /// Stops the current session. The stages finish it once they've written everything
+    /// streamed before now, sending its final metadata back on what's returned. Its
+    /// manifest is pending until the sinks have written that too
+    pub fn stop_recording(&mut self) -> Option<mpsc::Receiver<Stopped>> {
+        self.recording = false;
+        let session = self.session.take()?;
+        let ingest = self.ingest.as_ref()?;
+        self.live.pending_manifests.lock().insert(session.name);
+
+        let (reply, stopped) = mpsc::channel();
+        let stop = Stop {
+            stopped: chrono::Local::now(),
+            index: RecordingIndex::default(),
+            blocks: 0,
+            samples: 0,
+            gaps: vec![],
+            reply,
+        };
+        ingest.send_wait(Item::Marker(Marker::Stop(stop)));
+        Some(stopped)
+    }
+
+    /// Maps the enabled Pico channels, once they're configured
+    pub fn set_channel_map(&mut self, map: ChannelMap) {
+        self.live.set_frame_rates(&map);
+        // Every frame of each Pico channel is counted, whatever its slots
+        let frame_rates: BTreeMap<&String, f64> = map
+            .channels
+            .iter()
+            .filter_map(|c| Some((c.channel.as_ref()?, c.frame_rate)))
+            .collect();
+        COUNTERS.set_frame_rate(frame_rates.values().sum());
+        self.channel_map = Arc::new(map);
+    }
+
+    /// Shared parts of the state with the channel map, for requests to read without
+    /// holding the app state
+    pub fn live_and_map(&self) -> (LiveState, Arc<ChannelMap>) {
+        (self.live.clone(), self.channel_map.clone())
+    }
+
+    /// Logs an event against the current session, in the catalogue if it's available
+    pub fn log_event(&self, kind: EventKind, detail: &str) {
+        let session = self.session.as_ref().map(|s| s.name.as_str());
+        self.events.log(session, kind, detail);
+    }
+
+    /// Saves a calibration and applies it to every block cut from now on. Sessions are
+    /// calibrated all the same way, as their provenance says, so not while recording
+    pub fn apply_calibration(&self, calibration: Calibration) -> anyhow::Result<()> {
+        if self.recording {
+            return Err(anyhow::anyhow!("stop recording before calibrating"));
+        }
+        let file = ConstConfig::get_config().calibration_file;
+        calibration.save(Path::new(file))?;
+        self.live.corrections.lock().calibration = Some(Arc::new(calibration));
+        self.log_event(
+            EventKind::CalibrationChanged,
+            &format!("Calibration saved to {} and applied", file),
+        );
+        Ok(())
+    }
+
+    /// Marks the current point of the recording
+    pub fn add_marker(&self, label: &str) -> anyhow::Result<f64> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("not recording"))?;
+        let catalogue = self
+            .catalogue
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("catalogue is unavailable"))?;
+
+        let time = (chrono::Local::now() - session.started).num_milliseconds() as f64 / 1000.0;
+        catalogue.add_marker(&session.name, time, label)?;
+        Ok(time)
+    }
 }
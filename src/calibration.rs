@@ -0,0 +1,260 @@
+//! Gain and offset of every multiplexer slot and analog path, fitted from known
+//! reference voltages and applied to each virtual channel as it's demultiplexed
+
+use crate::{
+    virt_channels::{demultiplex_channel, VirtChannel, VirtFrame},
+    ConstConfig,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use pico_sdk::common::PicoChannel;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::Path,
+};
+
+/// Mean of every virtual channel of every Pico channel, with one reference applied
+pub type Readings = BTreeMap<(PicoChannel, VirtChannel), f64>;
+
+/// Maps what one virtual channel reads to the voltage actually applied, as
+/// `gain * reading + offset`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelFit {
+    pub channel: String,
+    pub virt_channel: VirtChannel,
+    pub gain: f64,
+    pub offset: f64,
+    /// RMS difference between the corrected readings and the references
+    pub residual: f64,
+    /// Uncalibrated reading at each reference
+    pub readings: Vec<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    pub created: DateTime<Local>,
+    /// Volts applied to every input, in the order they were recorded
+    pub references: Vec<f64>,
+    pub fits: Vec<ChannelFit>,
+}
+
+/// Where the calibration applied to a session came from, kept in its metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CalibrationProvenance {
+    pub file: String,
+    pub created: DateTime<Local>,
+    pub references: Vec<f64>,
+}
+
+impl Calibration {
+    /// Fits every virtual channel seen at each reference by least squares. Needs at
+    /// least two different references
+    pub fn fit(references: &[f64], readings: &[Readings]) -> Result<Self> {
+        if references.len() != readings.len() {
+            return Err(anyhow!(
+                "{} references but {} sets of readings",
+                references.len(),
+                readings.len()
+            ));
+        }
+        let lowest = references.iter().copied().fold(f64::INFINITY, f64::min);
+        let highest = references.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if references.len() < 2 || lowest == highest {
+            return Err(anyhow!(
+                "at least two different reference voltages are needed"
+            ));
+        }
+
+        let mut fits = vec![];
+        for key in readings[0].keys() {
+            let measured = readings
+                .iter()
+                .map(|r| r.get(key).copied())
+                .collect::<Option<Vec<f64>>>()
+                .ok_or_else(|| {
+                    anyhow!("virtual channel {} of {} missed a reference", key.1, key.0)
+                })?;
+            fits.push(fit_channel(key.0, key.1, references, measured)?);
+        }
+        if fits.is_empty() {
+            return Err(anyhow!("no virtual channels were read"));
+        }
+
+        Ok(Calibration {
+            created: Local::now(),
+            references: references.to_vec(),
+            fits,
+        })
+    }
+
+    /// Corrects the frames of one Pico channel in place. Virtual channels without a fit
+    /// are left as they are
+    pub fn apply(&self, channel: PicoChannel, frames: &mut [VirtFrame]) {
+        let name = channel.to_string();
+        let fits: HashMap<VirtChannel, &ChannelFit> = self
+            .fits
+            .iter()
+            .filter(|f| f.channel == name)
+            .map(|f| (f.virt_channel, f))
+            .collect();
+        if fits.is_empty() {
+            return;
+        }
+
+        for frame in frames.iter_mut() {
+            for (virt_channel, sample) in frame.samples.iter_mut() {
+                if let Some(fit) = fits.get(virt_channel) {
+                    *sample = fit.gain * *sample + fit.offset;
+                }
+            }
+        }
+    }
+
+    /// Checks every fit is for a slot of the enabled Pico channels' current layouts, and
+    /// every slot has a fit. A calibration from other layouts would correct the wrong
+    /// inputs
+    pub fn check(&self, pico_channels: &[String]) -> Result<()> {
+        let const_config = ConstConfig::get_config();
+        let mut problems = vec![];
+
+        for fit in self.fits.iter() {
+            if !pico_channels.contains(&fit.channel) {
+                problems.push(format!("{} isn't enabled", fit.channel));
+                continue;
+            }
+            let layout = const_config.mux_layout(&fit.channel);
+            if fit.virt_channel >= layout.virt_channels() {
+                problems.push(format!(
+                    "{} has no virtual channel {} with {} layout",
+                    fit.channel, fit.virt_channel, layout
+                ));
+            }
+        }
+        for channel in pico_channels.iter() {
+            for virt_channel in 0..const_config.mux_layout(channel).virt_channels() {
+                if !self
+                    .fits
+                    .iter()
+                    .any(|f| &f.channel == channel && f.virt_channel == virt_channel)
+                {
+                    problems.push(format!(
+                        "virtual channel {} of {} isn't calibrated",
+                        virt_channel, channel
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "calibration doesn't match the channel layouts: {}",
+                problems.join(", ")
+            ))
+        }
+    }
+
+    pub fn provenance(&self, file: &Path) -> CalibrationProvenance {
+        CalibrationProvenance {
+            file: file.display().to_string(),
+            created: self.created,
+            references: self.references.clone(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Reads the calibration file, or `None` if nothing has been calibrated yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(File::open(path)?)?))
+    }
+}
+
+fn fit_channel(
+    channel: PicoChannel,
+    virt_channel: VirtChannel,
+    references: &[f64],
+    measured: Vec<f64>,
+) -> Result<ChannelFit> {
+    let n = references.len() as f64;
+    let mean_measured = measured.iter().sum::<f64>() / n;
+    let mean_reference = references.iter().sum::<f64>() / n;
+    let covariance: f64 = measured
+        .iter()
+        .zip(references)
+        .map(|(m, r)| (m - mean_measured) * (r - mean_reference))
+        .sum();
+    let variance: f64 = measured.iter().map(|m| (m - mean_measured).powi(2)).sum();
+    if variance <= f64::EPSILON {
+        return Err(anyhow!(
+            "virtual channel {} of {} read {:.4} whatever the reference, check it's connected",
+            virt_channel,
+            channel,
+            mean_measured
+        ));
+    }
+
+    let gain = covariance / variance;
+    let offset = mean_reference - gain * mean_measured;
+    let residual = (measured
+        .iter()
+        .zip(references)
+        .map(|(m, r)| (gain * m + offset - r).powi(2))
+        .sum::<f64>()
+        / n)
+        .sqrt();
+
+    Ok(ChannelFit {
+        channel: channel.to_string(),
+        virt_channel,
+        gain,
+        offset,
+        residual,
+        readings: measured,
+    })
+}
+
+/// Demultiplexes the latest raw samples of every Pico channel, averaging each virtual
+/// channel. Nothing is calibrated yet, so these are the readings to fit against
+pub fn measure(raw: &HashMap<PicoChannel, Vec<f64>>, sample_rate: f64) -> Result<Readings> {
+    let const_config = ConstConfig::get_config();
+    let mut readings = Readings::new();
+
+    for (channel, samples) in raw.iter() {
+        let frames = demultiplex_channel(*channel, samples, sample_rate as u64).frames?;
+        let layout = const_config.mux_layout(&channel.to_string());
+        for virt_channel in 0..layout.virt_channels() {
+            let values: Vec<f64> = frames
+                .iter()
+                .filter_map(|f| f.samples.get(&virt_channel).copied())
+                .filter(|v| v.is_finite())
+                .collect();
+            if values.is_empty() {
+                return Err(anyhow!(
+                    "no readings of virtual channel {} on {}",
+                    virt_channel,
+                    channel
+                ));
+            }
+            readings.insert(
+                (*channel, virt_channel),
+                values.iter().sum::<f64>() / values.len() as f64,
+            );
+        }
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests;
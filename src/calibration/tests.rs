@@ -0,0 +1,228 @@
+//! Fits against readings of known gain and offset
+
+use super::*;
+use crate::virt_channels::testing::{frame, slots};
+
+const REFERENCES: [f64; 3] = [-1.0, 0.0, 2.5];
+
+/// What a slot needing this gain and offset to correct it reads at `reference`
+fn reading(reference: f64, gain: f64, offset: f64) -> f64 {
+    (reference - offset) / gain
+}
+
+/// Each slot of A and B with its own gain and offset
+fn true_fit(channel: PicoChannel, virt_channel: VirtChannel) -> (f64, f64) {
+    let spread = virt_channel as f64
+        + if channel == PicoChannel::A {
+            0.0
+        } else {
+            slots(PicoChannel::A) as f64
+        };
+    (1.0 + 0.05 * spread, 0.01 * spread - 0.02)
+}
+
+fn readings_at(reference: f64) -> Readings {
+    let mut readings = Readings::new();
+    for channel in [PicoChannel::A, PicoChannel::B].iter() {
+        for virt_channel in 0..slots(*channel) {
+            let (gain, offset) = true_fit(*channel, virt_channel);
+            readings.insert((*channel, virt_channel), reading(reference, gain, offset));
+        }
+    }
+    readings
+}
+
+fn calibration() -> Calibration {
+    let readings: Vec<Readings> = REFERENCES.iter().map(|r| readings_at(*r)).collect();
+    Calibration::fit(&REFERENCES, &readings).unwrap()
+}
+
+fn enabled() -> Vec<String> {
+    vec![PicoChannel::A.to_string(), PicoChannel::B.to_string()]
+}
+
+#[test]
+fn fits_every_slot() {
+    let calibration = calibration();
+    assert_eq!(
+        calibration.fits.len(),
+        slots(PicoChannel::A) + slots(PicoChannel::B)
+    );
+    assert_eq!(calibration.references, REFERENCES);
+
+    for fit in calibration.fits.iter() {
+        let channel = if fit.channel == PicoChannel::A.to_string() {
+            PicoChannel::A
+        } else {
+            PicoChannel::B
+        };
+        let (gain, offset) = true_fit(channel, fit.virt_channel);
+        let context = format!("{} slot {}", fit.channel, fit.virt_channel);
+        assert!((fit.gain - gain).abs() < 1e-12, "{} gain", context);
+        assert!((fit.offset - offset).abs() < 1e-12, "{} offset", context);
+        assert!(fit.residual < 1e-12, "{} residual", context);
+        assert_eq!(fit.readings.len(), REFERENCES.len(), "{}", context);
+    }
+}
+
+#[test]
+fn residual_is_the_rms_error() {
+    // Off the line by +e, -2e and +e. The errors don't change the mean or covariance,
+    // only the variance of the readings, by 6e²
+    let e = 0.01;
+    let references = [0.0, 1.0, 2.0];
+    let mut readings: Vec<Readings> = references.iter().map(|r| readings_at(*r)).collect();
+    let key = (PicoChannel::A, 0);
+    let errors = [e, -2.0 * e, e];
+    for ((readings, reference), error) in readings
+        .iter_mut()
+        .zip(references.iter())
+        .zip(errors.iter())
+    {
+        readings.insert(key, reference + error);
+    }
+
+    let fit = Calibration::fit(&references, &readings).unwrap();
+    let fit = fit
+        .fits
+        .iter()
+        .find(|f| f.channel == key.0.to_string() && f.virt_channel == key.1)
+        .unwrap();
+    let gain = 2.0 / (2.0 + 6.0 * e * e);
+    let offset = 1.0 - gain;
+    assert!((fit.gain - gain).abs() < 1e-12);
+    assert!((fit.offset - offset).abs() < 1e-12);
+
+    let squared_errors: f64 = references
+        .iter()
+        .zip(errors.iter())
+        .map(|(r, error)| (gain * (r + error) + offset - r).powi(2))
+        .sum();
+    assert!((fit.residual - (squared_errors / 3.0).sqrt()).abs() < 1e-12);
+    assert!(fit.residual > 0.9 * e * 2f64.sqrt());
+}
+
+#[test]
+fn fitting_needs_two_references_read_by_every_slot() {
+    let error = |references: &[f64], readings: Vec<Readings>| {
+        Calibration::fit(references, &readings)
+            .unwrap_err()
+            .to_string()
+    };
+
+    assert!(error(&[1.0], vec![readings_at(1.0)]).contains("at least two"));
+    assert!(error(&[1.0, 1.0], vec![readings_at(1.0), readings_at(1.0)]).contains("at least two"));
+    assert!(error(&[0.0, 1.0], vec![readings_at(0.0)]).contains("2 references but 1"));
+    assert!(error(&[0.0, 1.0], vec![Readings::new(), Readings::new()]).contains("no virtual"));
+
+    let mut missed = readings_at(1.0);
+    missed.remove(&(PicoChannel::B, 2));
+    assert!(error(&[0.0, 1.0], vec![readings_at(0.0), missed]).contains("missed a reference"));
+
+    // A disconnected input reads the same whatever is applied
+    let mut flat = readings_at(1.0);
+    flat.insert((PicoChannel::A, 3), readings_at(0.0)[&(PicoChannel::A, 3)]);
+    assert!(error(&[0.0, 1.0], vec![readings_at(0.0), flat]).contains("whatever the reference"));
+}
+
+#[test]
+fn corrects_only_its_own_channel() {
+    let calibration = calibration();
+    // One slot more than there are fits for
+    let uncorrected = |channel| {
+        frame(
+            channel,
+            (0..=slots(channel)).map(|v| {
+                (
+                    v,
+                    reading(1.0, 1.0 + 0.05 * v as f64, 0.01 * v as f64 - 0.02),
+                )
+            }),
+        )
+    };
+
+    let mut frames = vec![uncorrected(PicoChannel::A)];
+    calibration.apply(PicoChannel::A, &mut frames);
+    let extra = slots(PicoChannel::A);
+    for virt_channel in 0..extra {
+        assert!((frames[0].samples[&virt_channel] - 1.0).abs() < 1e-12);
+    }
+    // No fit for the extra slot, so it's left alone
+    assert_eq!(
+        frames[0].samples[&extra],
+        uncorrected(PicoChannel::A).samples[&extra]
+    );
+
+    let mut others = vec![uncorrected(PicoChannel::C)];
+    calibration.apply(PicoChannel::C, &mut others);
+    assert_eq!(others[0].samples, uncorrected(PicoChannel::C).samples);
+}
+
+#[test]
+fn check_matches_the_enabled_layouts() {
+    let calibration = calibration();
+    assert!(calibration.check(&enabled()).is_ok());
+
+    let only_a = vec![PicoChannel::A.to_string()];
+    let error = calibration.check(&only_a).unwrap_err().to_string();
+    assert!(error.contains("isn't enabled"), "{}", error);
+
+    let mut missing = calibration.clone();
+    missing.fits.retain(|f| f.virt_channel != 1);
+    let error = missing.check(&enabled()).unwrap_err().to_string();
+    assert!(error.contains("virtual channel 1 of"), "{}", error);
+
+    let mut extra = calibration;
+    let mut past_the_end = extra.fits[0].clone();
+    past_the_end.virt_channel = slots(PicoChannel::A);
+    past_the_end.channel = PicoChannel::A.to_string();
+    extra.fits.push(past_the_end);
+    let error = extra.check(&enabled()).unwrap_err().to_string();
+    let expected = format!("A has no virtual channel {}", slots(PicoChannel::A));
+    assert!(error.contains(&expected), "{}", error);
+}
+
+#[test]
+fn saves_and_loads() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("calibration.json");
+    assert!(Calibration::load(&path).unwrap().is_none());
+
+    let calibration = calibration();
+    calibration.save(&path).unwrap();
+    let loaded = Calibration::load(&path).unwrap().unwrap();
+    assert_eq!(loaded.references, calibration.references);
+    assert_eq!(loaded.fits.len(), calibration.fits.len());
+    assert_eq!(loaded.fits[5].gain, calibration.fits[5].gain);
+}
+
+#[test]
+fn measures_the_mean_of_every_slot() {
+    let const_config = ConstConfig::get_config();
+    let samples_per_slot = 10;
+    let virt_channels = slots(PicoChannel::A);
+    let levels: Vec<f64> = (1..=virt_channels).map(|v| 0.2 * v as f64).collect();
+    let samples: Vec<f64> = (0..200 * (virt_channels + 1) * samples_per_slot)
+        .map(|i| match (i / samples_per_slot) % (virt_channels + 1) {
+            0 => 4.5,
+            slot => levels[slot - 1],
+        })
+        .collect();
+    let mut raw = HashMap::new();
+    raw.insert(PicoChannel::A, samples);
+
+    let readings = measure(&raw, (samples_per_slot * const_config.arduino_hz) as f64).unwrap();
+    assert_eq!(readings.len(), virt_channels);
+    for (virt_channel, level) in levels.iter().enumerate() {
+        let reading = readings[&(PicoChannel::A, virt_channel)];
+        assert!(
+            (reading - level).abs() < 1e-9,
+            "slot {} read {}",
+            virt_channel,
+            reading
+        );
+    }
+
+    raw.insert(PicoChannel::B, vec![0.0; 1000]);
+    assert!(measure(&raw, (samples_per_slot * const_config.arduino_hz) as f64).is_err());
+}
@@ -0,0 +1,411 @@
+use crate::{session::SessionMetadata, ConstConfig};
+
+use anyhow::Result;
+use chrono::Local;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde::Serialize;
+
+use std::{
+    path::Path,
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// Name of the database, kept in the output directory next to the sessions
+pub const CATALOGUE_FILE: &str = "catalogue.sqlite";
+
+/// Events waiting to be written before more are dropped
+const EVENT_LOG_DEPTH: usize = 256;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS subjects (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    notes TEXT NOT NULL DEFAULT '',
+    created TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    subject_id INTEGER REFERENCES subjects(id),
+    started TEXT NOT NULL,
+    stopped TEXT,
+    pico_scope_type TEXT NOT NULL,
+    channels TEXT NOT NULL,
+    sample_rate INTEGER NOT NULL,
+    frame_count INTEGER NOT NULL DEFAULT 0,
+    gap_count INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS config_snapshots (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions(id),
+    created TEXT NOT NULL,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS markers (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions(id),
+    time REAL NOT NULL,
+    label TEXT NOT NULL,
+    created TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER REFERENCES sessions(id),
+    time TEXT NOT NULL,
+    level TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_by_session ON events(session_id, time);
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    RecordingStarted,
+    RecordingStopped,
+    /// Sync pulses disappeared after having been found
+    SyncLost,
+    SyncRegained,
+    VirtChannelError,
+    SessionDeleted,
+    FiltersChanged,
+    CalibrationChanged,
+    /// Processing fell behind, so samples were dropped from the recording
+    StreamOverflow,
+    /// Recording started without the preflight checks passing
+    PreflightProblem,
+    Error,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::RecordingStarted => "recording_started",
+            EventKind::RecordingStopped => "recording_stopped",
+            EventKind::SyncLost => "sync_lost",
+            EventKind::SyncRegained => "sync_regained",
+            EventKind::VirtChannelError => "virt_channel_error",
+            EventKind::SessionDeleted => "session_deleted",
+            EventKind::FiltersChanged => "filters_changed",
+            EventKind::CalibrationChanged => "calibration_changed",
+            EventKind::StreamOverflow => "stream_overflow",
+            EventKind::PreflightProblem => "preflight_problem",
+            EventKind::Error => "error",
+        }
+    }
+
+    pub fn level(&self) -> &'static str {
+        match self {
+            EventKind::SyncLost
+            | EventKind::VirtChannelError
+            | EventKind::StreamOverflow
+            | EventKind::PreflightProblem => "warn",
+            EventKind::Error => "error",
+            _ => "info",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EventRow {
+    pub id: i64,
+    pub session: Option<String>,
+    pub time: String,
+    pub level: String,
+    pub kind: String,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MarkerRow {
+    pub id: i64,
+    /// Seconds since the session started
+    pub time: f64,
+    pub label: String,
+    pub created: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubjectRow {
+    pub id: i64,
+    pub name: String,
+    pub notes: String,
+    pub created: String,
+}
+
+/// Embedded database of every session, subject, marker and runtime event. The
+/// connection has its own lock, so queries never wait on the app state
+pub struct Catalogue {
+    connection: Mutex<Connection>,
+}
+
+impl Catalogue {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Catalogue {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Opens the catalogue in the configured output directory
+    pub fn open_default() -> Result<Self> {
+        Catalogue::open(&Path::new(ConstConfig::get_config().output_dir).join(CATALOGUE_FILE))
+    }
+
+    fn session_id(connection: &Connection, name: &str) -> Result<Option<i64>> {
+        Ok(connection
+            .query_row(
+                "SELECT id FROM sessions WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Catalogues a new session along with the configuration it's recorded with
+    pub fn start_session(&self, session: &SessionMetadata) -> Result<()> {
+        let connection = self.connection.lock();
+        connection.execute(
+            "INSERT INTO sessions (name, started, pico_scope_type, channels, sample_rate)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                session.name,
+                session.started.to_rfc3339(),
+                session.pico_scope_type,
+                session.channels.join(","),
+                session.sample_rate,
+            ],
+        )?;
+        let session_id = connection.last_insert_rowid();
+
+        connection.execute(
+            "INSERT INTO config_snapshots (session_id, created, config) VALUES (?1, ?2, ?3)",
+            params![
+                session_id,
+                Local::now().to_rfc3339(),
+                serde_json::to_string(&ConstConfig::get_config())?,
+            ],
+        )?;
+        drop(connection);
+
+        self.log(
+            Some(&session.name),
+            EventKind::RecordingStarted,
+            &format!("Recording {} started", session.name),
+        )
+    }
+
+    pub fn stop_session(&self, session: &SessionMetadata) -> Result<()> {
+        self.connection.lock().execute(
+            "UPDATE sessions SET stopped = ?2, frame_count = ?3, gap_count = ?4 WHERE name = ?1",
+            params![
+                session.name,
+                session.stopped.map(|s| s.to_rfc3339()),
+                session.frame_count as i64,
+                session.gaps.len() as i64,
+            ],
+        )?;
+
+        self.log(
+            Some(&session.name),
+            EventKind::RecordingStopped,
+            &format!(
+                "Recording {} stopped after {} frames",
+                session.name, session.frame_count
+            ),
+        )
+    }
+
+    pub fn log(&self, session: Option<&str>, kind: EventKind, detail: &str) -> Result<()> {
+        let connection = self.connection.lock();
+        let session_id = match session {
+            Some(name) => Catalogue::session_id(&connection, name)?,
+            None => None,
+        };
+
+        connection.execute(
+            "INSERT INTO events (session_id, time, level, kind, detail) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                session_id,
+                Local::now().to_rfc3339(),
+                kind.level(),
+                kind.as_str(),
+                detail,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Newest first, optionally only for one session or one kind of event
+    pub fn events(
+        &self,
+        session: Option<&str>,
+        kind: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<EventRow>> {
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare(
+            "SELECT events.id, sessions.name, events.time, events.level, events.kind, events.detail
+             FROM events LEFT JOIN sessions ON sessions.id = events.session_id
+             WHERE (?1 IS NULL OR sessions.name = ?1) AND (?2 IS NULL OR events.kind = ?2)
+             ORDER BY events.id DESC LIMIT ?3",
+        )?;
+
+        let rows = statement.query_map(params![session, kind, limit], |row| {
+            Ok(EventRow {
+                id: row.get(0)?,
+                session: row.get(1)?,
+                time: row.get(2)?,
+                level: row.get(3)?,
+                kind: row.get(4)?,
+                detail: row.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<EventRow>, _>>()?)
+    }
+
+    pub fn add_subject(&self, name: &str, notes: &str) -> Result<i64> {
+        let connection = self.connection.lock();
+        connection.execute(
+            "INSERT INTO subjects (name, notes, created) VALUES (?1, ?2, ?3)",
+            params![name, notes, Local::now().to_rfc3339()],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn subjects(&self) -> Result<Vec<SubjectRow>> {
+        let connection = self.connection.lock();
+        let mut statement =
+            connection.prepare("SELECT id, name, notes, created FROM subjects ORDER BY name")?;
+
+        let rows = statement.query_map(NO_PARAMS, |row| {
+            Ok(SubjectRow {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                notes: row.get(2)?,
+                created: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<SubjectRow>, _>>()?)
+    }
+
+    /// Links a session to the subject it was recorded from. Returns false if either is unknown
+    pub fn set_session_subject(&self, session: &str, subject: &str) -> Result<bool> {
+        let updated = self.connection.lock().execute(
+            "UPDATE sessions SET subject_id = (SELECT id FROM subjects WHERE name = ?2)
+             WHERE name = ?1 AND EXISTS (SELECT 1 FROM subjects WHERE name = ?2)",
+            params![session, subject],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Adds a marker `time` seconds into a session. Returns `None` if the session is unknown
+    pub fn add_marker(&self, session: &str, time: f64, label: &str) -> Result<Option<i64>> {
+        let connection = self.connection.lock();
+        let session_id = match Catalogue::session_id(&connection, session)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        connection.execute(
+            "INSERT INTO markers (session_id, time, label, created) VALUES (?1, ?2, ?3, ?4)",
+            params![session_id, time, label, Local::now().to_rfc3339()],
+        )?;
+        Ok(Some(connection.last_insert_rowid()))
+    }
+
+    pub fn markers(&self, session: &str) -> Result<Vec<MarkerRow>> {
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare(
+            "SELECT markers.id, markers.time, markers.label, markers.created
+             FROM markers JOIN sessions ON sessions.id = markers.session_id
+             WHERE sessions.name = ?1 ORDER BY markers.time",
+        )?;
+
+        let rows = statement.query_map(params![session], |row| {
+            Ok(MarkerRow {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                label: row.get(2)?,
+                created: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<MarkerRow>, _>>()?)
+    }
+
+    /// Removes a session with its markers, events and config snapshot. Returns false if
+    /// the session is unknown
+    pub fn delete_session(&self, session: &str) -> Result<bool> {
+        let mut connection = self.connection.lock();
+        let session_id = match Catalogue::session_id(&connection, session)? {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        let transaction = connection.transaction()?;
+        for table in ["markers", "events", "config_snapshots"].iter() {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE session_id = ?1", table),
+                params![session_id],
+            )?;
+        }
+        transaction.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
+        transaction.commit()?;
+        Ok(true)
+    }
+}
+
+struct LoggedEvent {
+    session: Option<String>,
+    kind: EventKind,
+    detail: String,
+}
+
+/// Logs events from any thread without waiting on the database. They're written into
+/// the catalogue by a thread of their own, and only traced if it falls far behind
+#[derive(Clone, Default)]
+pub struct EventLog {
+    sender: Option<mpsc::SyncSender<LoggedEvent>>,
+}
+
+impl EventLog {
+    pub fn start(catalogue: Arc<Catalogue>) -> Self {
+        let (sender, events) = mpsc::sync_channel::<LoggedEvent>(EVENT_LOG_DEPTH);
+        thread::spawn(move || {
+            for event in events {
+                if let Err(err) = catalogue.log(event.session.as_deref(), event.kind, &event.detail)
+                {
+                    tracing::error!(?err, kind = event.kind.as_str(), "could not log event");
+                }
+            }
+        });
+        EventLog {
+            sender: Some(sender),
+        }
+    }
+
+    pub fn log(&self, session: Option<&str>, kind: EventKind, detail: &str) {
+        match kind.level() {
+            "error" => tracing::error!(kind = kind.as_str(), session, "{}", detail),
+            "warn" => tracing::warn!(kind = kind.as_str(), session, "{}", detail),
+            _ => tracing::info!(kind = kind.as_str(), session, "{}", detail),
+        }
+        if let Some(sender) = self.sender.as_ref() {
+            let event = LoggedEvent {
+                session: session.map(str::to_string),
+                kind,
+                detail: detail.to_string(),
+            };
+            if sender.try_send(event).is_err() {
+                tracing::warn!(kind = kind.as_str(), "event log is behind, not catalogued");
+            }
+        }
+    }
+}
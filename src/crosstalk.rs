@@ -0,0 +1,280 @@
+//! Charge carried over from one multiplexer slot into the next. Estimated from
+//! demultiplexed frames, and optionally taken back out of every frame as it's
+//! demultiplexed.
+//!
+//! Each virtual channel is taken to read its own input plus a share of the slot before
+//! it. Genuinely correlated inputs look like crosstalk too, so the share is measured on
+//! frame to frame changes, which are mostly independent noise on every electrode
+
+use crate::{
+    calibration::Calibration,
+    virt_channels::{demultiplex_channel, VirtFrame},
+    ConstConfig,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use pico_sdk::common::PicoChannel;
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashMap, fs::File, path::Path};
+
+/// Crosstalk between the virtual channels of one Pico channel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelCrosstalk {
+    pub channel: String,
+    /// Row `i` is how much of each virtual channel reads into virtual channel `i`, so
+    /// it's 1 on the diagonal. Only the slot before is estimated, which for the first
+    /// virtual channel is the sync pulse and only shifts its offset
+    pub matrix: Vec<Vec<f64>>,
+    /// Mean carry over between adjacent slots, before and after compensating, measured
+    /// on frames that weren't used to estimate the matrix
+    pub before: f64,
+    pub after: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Crosstalk {
+    pub created: DateTime<Local>,
+    /// Frames per Pico channel it was estimated and checked on
+    pub frames: usize,
+    pub channels: Vec<ChannelCrosstalk>,
+}
+
+/// Which crosstalk compensation a session was recorded with, kept in its metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CrosstalkProvenance {
+    pub file: String,
+    pub created: DateTime<Local>,
+    pub reduction: f64,
+}
+
+impl Crosstalk {
+    /// Demultiplexes the latest raw samples of every Pico channel, calibrating them if
+    /// there's a calibration, and estimates the crosstalk from the first half of the
+    /// frames. The second half is kept back to check how much compensating removes
+    pub fn estimate(
+        raw: &HashMap<PicoChannel, Vec<f64>>,
+        sample_rate: f64,
+        calibration: Option<&Calibration>,
+    ) -> Result<Self> {
+        let const_config = ConstConfig::get_config();
+        let mut channels: Vec<&PicoChannel> = raw.keys().collect();
+        channels.sort();
+        if channels.is_empty() {
+            return Err(anyhow!("no samples have been streamed yet"));
+        }
+
+        let mut estimates = vec![];
+        let mut frame_count = usize::MAX;
+        for channel in channels {
+            // Only slots after another slot can pick anything up
+            let virt_channel_count = const_config
+                .mux_layout(&channel.to_string())
+                .virt_channels();
+            if virt_channel_count < 2 {
+                continue;
+            }
+            let mut frames =
+                demultiplex_channel(*channel, &raw[channel], sample_rate as u64).frames?;
+            if let Some(calibration) = calibration {
+                calibration.apply(*channel, &mut frames);
+            }
+            // Two changes per half, at the least, to get a covariance from
+            if frames.len() < 6 {
+                return Err(anyhow!(
+                    "only {} frames on {}, stream for longer",
+                    frames.len(),
+                    channel
+                ));
+            }
+            frame_count = frame_count.min(frames.len());
+
+            let (estimate_from, check_on) = frames.split_at(frames.len() / 2);
+            let mut check_on = check_on.to_vec();
+            let estimated = coupling(estimate_from, virt_channel_count);
+            let mut matrix: Vec<Vec<f64>> = (0..virt_channel_count)
+                .map(|i| {
+                    (0..virt_channel_count)
+                        .map(|j| if i == j { 1.0 } else { 0.0 })
+                        .collect()
+                })
+                .collect();
+            for i in 1..virt_channel_count {
+                matrix[i][i - 1] = estimated[i];
+            }
+
+            let before = mean_abs(&coupling(&check_on, virt_channel_count));
+            compensate_frames(&matrix, &mut check_on);
+            let after = mean_abs(&coupling(&check_on, virt_channel_count));
+
+            estimates.push(ChannelCrosstalk {
+                channel: channel.to_string(),
+                matrix,
+                before,
+                after,
+            });
+        }
+        if estimates.is_empty() {
+            return Err(anyhow!("no channel multiplexes more than one slot"));
+        }
+
+        Ok(Crosstalk {
+            created: Local::now(),
+            frames: frame_count,
+            channels: estimates,
+        })
+    }
+
+    /// Share of the crosstalk compensating removes, over every Pico channel
+    pub fn reduction(&self) -> f64 {
+        let before: f64 = self.channels.iter().map(|c| c.before).sum();
+        let after: f64 = self.channels.iter().map(|c| c.after).sum();
+        if before > 0.0 {
+            1.0 - after / before
+        } else {
+            0.0
+        }
+    }
+
+    /// Takes the crosstalk back out of the frames of one Pico channel, in place
+    pub fn compensate(&self, channel: PicoChannel, frames: &mut [VirtFrame]) {
+        let name = channel.to_string();
+        if let Some(crosstalk) = self.channels.iter().find(|c| c.channel == name) {
+            compensate_frames(&crosstalk.matrix, frames);
+        }
+    }
+
+    /// Makes sure every matrix fits its Pico channel's layout, which may have changed
+    /// since the crosstalk was estimated
+    pub fn check(&self, pico_channels: &[String]) -> Result<()> {
+        let const_config = ConstConfig::get_config();
+        let mut problems = vec![];
+
+        for crosstalk in self.channels.iter() {
+            if !pico_channels.contains(&crosstalk.channel) {
+                problems.push(format!("{} isn't enabled", crosstalk.channel));
+                continue;
+            }
+            let layout = const_config.mux_layout(&crosstalk.channel);
+            let size = layout.virt_channels();
+            if crosstalk.matrix.len() != size || crosstalk.matrix.iter().any(|r| r.len() != size) {
+                problems.push(format!(
+                    "{} has a {} slot matrix with {} layout",
+                    crosstalk.channel,
+                    crosstalk.matrix.len(),
+                    layout
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "crosstalk doesn't match the channel layouts: {}",
+                problems.join(", ")
+            ))
+        }
+    }
+
+    pub fn provenance(&self, file: &Path) -> CrosstalkProvenance {
+        CrosstalkProvenance {
+            file: file.display().to_string(),
+            created: self.created,
+            reduction: self.reduction(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Reads the crosstalk file, or `None` if crosstalk hasn't been estimated yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(File::open(path)?)?))
+    }
+}
+
+/// Share of each slot that turns up in the slot after it, by the slot it turns up in.
+/// The first is always 0, having the sync pulse before it
+fn coupling(frames: &[VirtFrame], virt_channel_count: usize) -> Vec<f64> {
+    // Frame to frame changes, dropping any pair with a sample missing
+    let changes: Vec<Vec<f64>> = frames
+        .windows(2)
+        .filter_map(|pair| {
+            (0..virt_channel_count)
+                .map(|i| {
+                    let change = pair[1].samples.get(&i)? - pair[0].samples.get(&i)?;
+                    if change.is_finite() {
+                        Some(change)
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Option<Vec<f64>>>()
+        })
+        .collect();
+
+    let mut coupling = vec![0.0; virt_channel_count];
+    if changes.len() < 2 {
+        return coupling;
+    }
+    let n = changes.len() as f64;
+    let means: Vec<f64> = (0..virt_channel_count)
+        .map(|i| changes.iter().map(|c| c[i]).sum::<f64>() / n)
+        .collect();
+    for i in 1..virt_channel_count {
+        let covariance: f64 = changes
+            .iter()
+            .map(|c| (c[i] - means[i]) * (c[i - 1] - means[i - 1]))
+            .sum();
+        let variance: f64 = changes
+            .iter()
+            .map(|c| (c[i - 1] - means[i - 1]).powi(2))
+            .sum();
+        if variance > 0.0 {
+            coupling[i] = covariance / variance;
+        }
+    }
+    coupling
+}
+
+fn mean_abs(coupling: &[f64]) -> f64 {
+    // The first is never estimated
+    let adjacent = &coupling[1.min(coupling.len())..];
+    if adjacent.is_empty() {
+        return 0.0;
+    }
+    adjacent.iter().map(|k| k.abs()).sum::<f64>() / adjacent.len() as f64
+}
+
+/// Inverts the mixing by forward substitution, each virtual channel only having
+/// crosstalk from the ones before it. Frames with a sample missing are left alone
+fn compensate_frames(matrix: &[Vec<f64>], frames: &mut [VirtFrame]) {
+    for frame in frames.iter_mut() {
+        let measured = match (0..matrix.len())
+            .map(|i| frame.samples.get(&i).copied())
+            .collect::<Option<Vec<f64>>>()
+        {
+            Some(measured) => measured,
+            None => continue,
+        };
+
+        let mut corrected: Vec<f64> = Vec::with_capacity(matrix.len());
+        for (i, row) in matrix.iter().enumerate() {
+            let carried: f64 = (0..i).map(|j| row[j] * corrected[j]).sum();
+            corrected.push((measured[i] - carried) / row[i]);
+        }
+        for (i, value) in corrected.into_iter().enumerate() {
+            frame.samples.insert(i, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
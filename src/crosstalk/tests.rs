@@ -0,0 +1,262 @@
+//! Crosstalk estimated from frames mixed with a known share of the slot before, on
+//! channel A
+
+use super::*;
+use crate::virt_channels::testing::{frame, slots};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const SAMPLES_PER_SLOT: usize = 10;
+
+fn virt_channels() -> usize {
+    slots(PicoChannel::A)
+}
+
+/// Share of the slot before that each slot reads, the first having none
+fn shares() -> Vec<f64> {
+    let shares = [0.08, 0.03, 0.12];
+    (0..virt_channels())
+        .map(|i| {
+            if i == 0 {
+                0.0
+            } else {
+                shares[(i - 1) % shares.len()]
+            }
+        })
+        .collect()
+}
+
+fn level(virt_channel: usize, rng: &mut StdRng) -> f64 {
+    0.5 + 0.2 * virt_channel as f64 + rng.gen_range(-0.1, 0.1)
+}
+
+/// Independent inputs, one stepping to a new level around its own each frame so
+/// what the next slot picks up from it isn't buried in that slot's own changes
+fn inputs(frames: usize, seed: u64) -> Vec<Vec<f64>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let virt_channels = virt_channels();
+    let mut inputs: Vec<f64> = (0..virt_channels).map(|i| level(i, &mut rng)).collect();
+    (0..frames)
+        .map(|n| {
+            inputs[n % virt_channels] = level(n % virt_channels, &mut rng);
+            inputs.clone()
+        })
+        .collect()
+}
+
+/// What each slot reads, its own input plus a share of the one before
+fn mix(inputs: &[f64]) -> Vec<f64> {
+    let shares = shares();
+    (0..inputs.len())
+        .map(|i| {
+            inputs[i]
+                + if i > 0 {
+                    shares[i] * inputs[i - 1]
+                } else {
+                    0.0
+                }
+        })
+        .collect()
+}
+
+/// Frames of channel A, each reading its inputs mixed
+fn mixed(inputs: &[Vec<f64>]) -> Vec<VirtFrame> {
+    inputs
+        .iter()
+        .map(|x| frame(PicoChannel::A, mix(x).into_iter().enumerate()))
+        .collect()
+}
+
+fn matrix(shares: &[f64]) -> Vec<Vec<f64>> {
+    (0..shares.len())
+        .map(|i| {
+            (0..shares.len())
+                .map(|j| match () {
+                    _ if i == j => 1.0,
+                    _ if j + 1 == i => shares[i],
+                    _ => 0.0,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The mixed inputs multiplexed onto one Pico channel, as the scope streams them
+fn mux_signal(inputs: &[Vec<f64>]) -> Vec<f64> {
+    let mut samples = vec![];
+    for frame in inputs {
+        for value in std::iter::once(4.5).chain(mix(frame)) {
+            samples.resize(samples.len() + SAMPLES_PER_SLOT, value);
+        }
+    }
+    samples
+}
+
+fn sample_rate() -> f64 {
+    (SAMPLES_PER_SLOT * ConstConfig::get_config().arduino_hz) as f64
+}
+
+#[test]
+fn coupling_finds_the_shares() {
+    let (virt_channels, shares) = (virt_channels(), shares());
+    let frames = mixed(&inputs(400, 1));
+    let estimated = coupling(&frames, virt_channels);
+    assert_eq!(estimated[0], 0.0);
+    for i in 1..virt_channels {
+        assert!(
+            (estimated[i] - shares[i]).abs() < 0.002,
+            "slot {}: {} for {}",
+            i,
+            estimated[i],
+            shares[i]
+        );
+    }
+
+    // Unmixed inputs, and too few frames, show none
+    let clean: Vec<VirtFrame> = inputs(400, 2)
+        .iter()
+        .map(|x| frame(PicoChannel::A, x.iter().copied().enumerate()))
+        .collect();
+    assert!(mean_abs(&coupling(&clean, virt_channels)) < 1e-3);
+    assert_eq!(
+        coupling(&frames[..2], virt_channels),
+        vec![0.0; virt_channels]
+    );
+}
+
+#[test]
+fn compensating_recovers_the_inputs() {
+    let inputs = inputs(50, 3);
+    let mut frames = mixed(&inputs);
+    frames[7].samples.remove(&2);
+    let untouched = frames[7].samples.clone();
+
+    compensate_frames(&matrix(&shares()), &mut frames);
+    for (n, (frame, input)) in frames.iter().zip(inputs.iter()).enumerate() {
+        if n == 7 {
+            // A sample missing, so there's nothing to take the crosstalk out of
+            assert_eq!(frame.samples, untouched);
+            continue;
+        }
+        for (i, value) in input.iter().enumerate() {
+            assert!(
+                (frame.samples[&i] - value).abs() < 1e-12,
+                "frame {} slot {}",
+                n,
+                i
+            );
+        }
+    }
+}
+
+#[test]
+fn estimates_and_compensates_a_streamed_channel() {
+    let mut raw = HashMap::new();
+    raw.insert(PicoChannel::A, mux_signal(&inputs(600, 4)));
+    let crosstalk = Crosstalk::estimate(&raw, sample_rate(), None).unwrap();
+
+    assert_eq!(crosstalk.channels.len(), 1);
+    let estimate = &crosstalk.channels[0];
+    let shares = shares();
+    assert_eq!(estimate.channel, PicoChannel::A.to_string());
+    for (i, row) in estimate.matrix.iter().enumerate() {
+        assert_eq!(row[i], 1.0);
+        if i > 0 {
+            assert!(
+                (row[i - 1] - shares[i]).abs() < 0.002,
+                "slot {}: {}",
+                i,
+                row[i - 1]
+            );
+        }
+    }
+    assert!(crosstalk.frames >= 590);
+    assert!(estimate.before > 0.05);
+    assert!(
+        crosstalk.reduction() > 0.9,
+        "only {}",
+        crosstalk.reduction()
+    );
+
+    // Taken out of the frames of its own channel only
+    let inputs = inputs(5, 5);
+    let frames = mixed(&inputs);
+    let mut other = frames.clone();
+    crosstalk.compensate(PicoChannel::B, &mut other);
+    assert_eq!(other[0].samples, frames[0].samples);
+
+    let mut own = frames;
+    crosstalk.compensate(PicoChannel::A, &mut own);
+    for (i, input) in inputs[0].iter().enumerate() {
+        assert!((own[0].samples[&i] - input).abs() < 1e-3, "slot {}", i);
+    }
+}
+
+#[test]
+fn estimating_needs_enough_frames() {
+    let error = |raw: HashMap<PicoChannel, Vec<f64>>| {
+        Crosstalk::estimate(&raw, sample_rate(), None)
+            .unwrap_err()
+            .to_string()
+    };
+    assert!(error(HashMap::new()).contains("no samples"));
+
+    let mut raw = HashMap::new();
+    raw.insert(PicoChannel::A, mux_signal(&inputs(5, 6)));
+    assert!(error(raw).contains("stream for longer"));
+}
+
+#[test]
+fn reduction_of_nothing_is_nothing() {
+    let crosstalk = Crosstalk {
+        created: Local::now(),
+        frames: 0,
+        channels: vec![ChannelCrosstalk {
+            channel: PicoChannel::A.to_string(),
+            matrix: matrix(&vec![0.0; virt_channels()]),
+            before: 0.0,
+            after: 0.0,
+        }],
+    };
+    assert_eq!(crosstalk.reduction(), 0.0);
+}
+
+#[test]
+fn saves_and_loads() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("crosstalk.json");
+    assert!(Crosstalk::load(&path).unwrap().is_none());
+
+    let mut raw = HashMap::new();
+    raw.insert(PicoChannel::A, mux_signal(&inputs(100, 7)));
+    let crosstalk = Crosstalk::estimate(&raw, sample_rate(), None).unwrap();
+    crosstalk.save(&path).unwrap();
+    let loaded = Crosstalk::load(&path).unwrap().unwrap();
+    assert_eq!(loaded.frames, crosstalk.frames);
+    assert_eq!(loaded.channels[0].matrix, crosstalk.channels[0].matrix);
+}
+
+#[test]
+fn checks_the_matrices_fit_the_layouts() {
+    let channel = |name: &str, size: usize| ChannelCrosstalk {
+        channel: name.to_string(),
+        matrix: vec![vec![0.0; size]; size],
+        before: 0.0,
+        after: 0.0,
+    };
+    let (a, b) = (PicoChannel::A.to_string(), PicoChannel::B.to_string());
+    let mut crosstalk = Crosstalk {
+        created: Local::now(),
+        frames: 0,
+        channels: vec![channel(&a, virt_channels())],
+    };
+    assert!(crosstalk.check(&[a.clone(), b.clone()]).is_ok());
+
+    let too_many = slots(PicoChannel::B) + 1;
+    crosstalk.channels.push(channel(&b, too_many));
+    let error = crosstalk.check(&[a.clone(), b]).unwrap_err().to_string();
+    let expected = format!("B has a {} slot matrix", too_many);
+    assert!(error.contains(&expected), "{}", error);
+    let error = crosstalk.check(&[a]).unwrap_err().to_string();
+    assert!(error.contains("B isn't enabled"), "{}", error);
+}
@@ -0,0 +1,362 @@
+//! Names, positions and colours of the electrodes on the end of every multiplexer
+//! slot, and the montage channels derived from them.
+//!
+//! Frames from the demultiplexer are keyed by slot, which only means something
+//! together with the Pico channel. The channel map gives every slot of every enabled
+//! Pico channel a key of its own, configured electrodes first, then any slots left
+//! over, then montages. Frames are re-keyed as they're demultiplexed, so everything
+//! after uses these keys
+
+use crate::{
+    virt_channels::{VirtChannel, VirtFrame},
+    ConstConfig,
+};
+
+use pico_sdk::common::PicoChannel;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Colours for slots and montages without one configured
+const PALETTE: [&str; 10] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac",
+];
+
+/// One electrode, as configured
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Electrode {
+    /// Pico channel it's multiplexed onto, by letter
+    pub channel: &'static str,
+    pub virt_channel: VirtChannel,
+    pub name: &'static str,
+    /// x and y in cm, only used to lay the electrodes out
+    pub position: [f64; 2],
+    /// CSS colour for charts
+    pub colour: &'static str,
+}
+
+/// Channel derived live from electrodes, by name
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Montage {
+    /// `positive` minus `negative`
+    Bipolar {
+        name: &'static str,
+        positive: &'static str,
+        negative: &'static str,
+    },
+    /// Each of `electrodes` minus their mean, named after the electrode with `suffix`.
+    /// Every electrode of each Pico channel, averaged per channel, when `electrodes` is
+    /// empty
+    CommonAverage {
+        suffix: &'static str,
+        electrodes: &'static [&'static str],
+    },
+}
+
+/// A channel of every frame, with what it's called and where it comes from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MappedChannel {
+    /// Key of the channel in frames
+    pub key: VirtChannel,
+    pub name: String,
+    /// Pico channel and slot, none for a montage
+    pub channel: Option<String>,
+    pub virt_channel: Option<VirtChannel>,
+    pub position: Option<[f64; 2]>,
+    pub colour: String,
+    /// How a montage channel is derived, none for an electrode
+    pub derived: Option<String>,
+    /// Frames per second, from the layout of the Pico channel it comes from. Left at 0
+    /// in sessions recorded before channels could differ
+    #[serde(default)]
+    pub frame_rate: f64,
+}
+
+/// Name of the channel with `key`, or the key itself if it isn't mapped
+pub fn name_of(channels: &[MappedChannel], key: VirtChannel) -> String {
+    channels
+        .iter()
+        .find(|c| c.key == key)
+        .map(|c| c.name.clone())
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// Re-keys anything keyed by channel with the channels' names
+pub fn by_name<'a, T>(
+    channels: &[MappedChannel],
+    keyed: impl IntoIterator<Item = (&'a VirtChannel, T)>,
+) -> BTreeMap<String, T> {
+    keyed
+        .into_iter()
+        .map(|(key, value)| (name_of(channels, *key), value))
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+enum Derivation {
+    Difference {
+        key: VirtChannel,
+        positive: VirtChannel,
+        negative: VirtChannel,
+    },
+    /// `(output, input)` pairs
+    Average {
+        keys: Vec<(VirtChannel, VirtChannel)>,
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelMap {
+    pub channels: Vec<MappedChannel>,
+    /// Key of each slot, by Pico channel name
+    slots: HashMap<String, HashMap<VirtChannel, VirtChannel>>,
+    derivations: Vec<Derivation>,
+}
+
+impl ChannelMap {
+    /// Maps every slot of the enabled Pico channels, by their layouts, named by letter
+    pub fn new(pico_channels: &[String]) -> Self {
+        let const_config = ConstConfig::get_config();
+        let mut map = ChannelMap::default();
+
+        for electrode in const_config.electrodes.iter() {
+            if pico_channels.iter().any(|c| c == electrode.channel) {
+                map.add_slot(
+                    electrode.channel,
+                    electrode.virt_channel,
+                    electrode.name.to_string(),
+                    Some(electrode.position),
+                    electrode.colour.to_string(),
+                );
+            }
+        }
+        let mut sorted = pico_channels.to_vec();
+        sorted.sort();
+        for channel in sorted.iter() {
+            for slot in 0..const_config.mux_layout(channel).virt_channels() {
+                let mapped = map.slots.get(channel).and_then(|s| s.get(&slot));
+                if mapped.is_none() {
+                    let colour = PALETTE[map.channels.len() % PALETTE.len()].to_string();
+                    map.add_slot(channel, slot, format!("{}{}", channel, slot), None, colour);
+                }
+            }
+        }
+
+        for montage in const_config.montages.iter() {
+            map.add_montage(montage);
+        }
+        map
+    }
+
+    fn add_slot(
+        &mut self,
+        channel: &str,
+        slot: VirtChannel,
+        name: String,
+        position: Option<[f64; 2]>,
+        colour: String,
+    ) {
+        let key = self.channels.len();
+        self.slots
+            .entry(channel.to_string())
+            .or_default()
+            .insert(slot, key);
+        self.channels.push(MappedChannel {
+            key,
+            name,
+            channel: Some(channel.to_string()),
+            virt_channel: Some(slot),
+            position,
+            colour,
+            derived: None,
+            frame_rate: ConstConfig::get_config().channel_frame_rate(channel),
+        });
+    }
+
+    /// Adds a montage channel, which comes at the rate of the electrode it's derived from
+    fn add_derived(&mut self, name: String, derived: String, from: VirtChannel) -> VirtChannel {
+        let key = self.channels.len();
+        let frame_rate = self.channels[from].frame_rate;
+        self.channels.push(MappedChannel {
+            key,
+            name,
+            channel: None,
+            virt_channel: None,
+            position: None,
+            colour: PALETTE[key % PALETTE.len()].to_string(),
+            derived: Some(derived),
+            frame_rate,
+        });
+        key
+    }
+
+    /// Key of an electrode or montage by name, or by the key itself
+    pub fn lookup(&self, name: &str) -> Option<VirtChannel> {
+        self.channels
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.key)
+            .or_else(|| name.parse().ok())
+    }
+
+    /// Key of an electrode by name
+    pub fn key(&self, name: &str) -> Option<VirtChannel> {
+        self.channels
+            .iter()
+            .find(|c| c.name == name && c.derived.is_none())
+            .map(|c| c.key)
+    }
+
+    pub fn name(&self, key: VirtChannel) -> String {
+        name_of(&self.channels, key)
+    }
+
+    /// Frames per second of every channel, by key
+    pub fn frame_rates(&self) -> BTreeMap<VirtChannel, f64> {
+        self.channels
+            .iter()
+            .map(|c| (c.key, c.frame_rate))
+            .collect()
+    }
+
+    fn add_montage(&mut self, montage: &Montage) {
+        match *montage {
+            Montage::Bipolar {
+                name,
+                positive,
+                negative,
+            } => match (self.key(positive), self.key(negative)) {
+                (Some(positive_key), Some(negative_key))
+                    if !self.same_channel(&[positive_key, negative_key]) =>
+                {
+                    tracing::warn!(name, positive, negative, "montage across Pico channels")
+                }
+                (Some(positive_key), Some(negative_key)) => {
+                    let key = self.add_derived(
+                        name.to_string(),
+                        format!("{} - {}", positive, negative),
+                        positive_key,
+                    );
+                    self.derivations.push(Derivation::Difference {
+                        key,
+                        positive: positive_key,
+                        negative: negative_key,
+                    });
+                }
+                _ => tracing::warn!(name, positive, negative, "montage of unknown electrodes"),
+            },
+            Montage::CommonAverage { suffix, electrodes } => {
+                if !electrodes.is_empty() {
+                    let inputs: Vec<(String, VirtChannel)> = electrodes
+                        .iter()
+                        .filter_map(|name| self.key(name).map(|key| (name.to_string(), key)))
+                        .collect();
+                    let keys: Vec<VirtChannel> = inputs.iter().map(|(_, key)| *key).collect();
+                    if self.same_channel(&keys) {
+                        self.add_average(suffix, inputs);
+                    } else {
+                        tracing::warn!(suffix, "montage across Pico channels");
+                    }
+                    return;
+                }
+                // Every electrode, averaged per Pico channel as that's all a frame holds
+                let mut channels: Vec<String> = self.slots.keys().cloned().collect();
+                channels.sort();
+                for channel in channels {
+                    let inputs = self
+                        .channels
+                        .iter()
+                        .filter(|c| c.channel.as_ref() == Some(&channel))
+                        .map(|c| (c.name.clone(), c.key))
+                        .collect();
+                    self.add_average(suffix, inputs);
+                }
+            }
+        }
+    }
+
+    /// Whether the electrodes are all on one Pico channel, as frames only hold one
+    fn same_channel(&self, keys: &[VirtChannel]) -> bool {
+        let mut channels = keys.iter().map(|key| &self.channels[*key].channel);
+        match channels.next() {
+            Some(first) => channels.all(|channel| channel == first),
+            None => true,
+        }
+    }
+
+    fn add_average(&mut self, suffix: &str, inputs: Vec<(String, VirtChannel)>) {
+        if inputs.len() < 2 {
+            tracing::warn!(suffix, "common average of fewer than two electrodes");
+            return;
+        }
+
+        let names: Vec<&str> = inputs.iter().map(|(name, _)| name.as_str()).collect();
+        let average = format!("mean({})", names.join(", "));
+        let mut keys = vec![];
+        for (name, input) in inputs.iter() {
+            let key = self.add_derived(
+                format!("{}{}", name, suffix),
+                format!("{} - {}", name, average),
+                *input,
+            );
+            keys.push((key, *input));
+        }
+        self.derivations.push(Derivation::Average { keys });
+    }
+
+    /// Re-keys the frames of one Pico channel from slots to mapped keys, dropping any
+    /// slot that isn't mapped
+    pub fn rekey(&self, channel: PicoChannel, frames: &mut [VirtFrame]) {
+        let slots = match self.slots.get(&channel.to_string()) {
+            Some(slots) => slots,
+            None => return,
+        };
+        for frame in frames.iter_mut() {
+            frame.samples = frame
+                .samples
+                .drain()
+                .filter_map(|(slot, value)| slots.get(&slot).map(|key| (*key, value)))
+                .collect();
+        }
+    }
+
+    /// Adds every montage to re-keyed frames. Frames are per Pico channel, so a montage
+    /// only gets a value where all its electrodes are in the frame
+    pub fn derive(&self, frames: &mut [VirtFrame]) {
+        for frame in frames.iter_mut() {
+            for derivation in self.derivations.iter() {
+                match derivation {
+                    Derivation::Difference {
+                        key,
+                        positive,
+                        negative,
+                    } => {
+                        if let (Some(p), Some(n)) =
+                            (frame.samples.get(positive), frame.samples.get(negative))
+                        {
+                            let value = p - n;
+                            frame.samples.insert(*key, value);
+                        }
+                    }
+                    Derivation::Average { keys } => {
+                        let inputs = keys
+                            .iter()
+                            .map(|(_, input)| frame.samples.get(input).copied())
+                            .collect::<Option<Vec<f64>>>();
+                        if let Some(inputs) = inputs {
+                            let mean = inputs.iter().sum::<f64>() / inputs.len() as f64;
+                            for ((key, _), value) in keys.iter().zip(inputs) {
+                                frame.samples.insert(*key, value - mean);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,156 @@
+//! Slots keyed by channel map, and the montages derived from them
+
+use super::*;
+use crate::virt_channels::testing::{frame, slots};
+
+/// Fz, Cz and Pz on the first three slots of A, Oz on the first of B
+fn electrodes() -> ChannelMap {
+    let mut map = ChannelMap::default();
+    let (a, b) = (PicoChannel::A.to_string(), PicoChannel::B.to_string());
+    let electrodes = [(&a, 0, "Fz"), (&a, 1, "Cz"), (&a, 2, "Pz"), (&b, 0, "Oz")];
+    for (channel, slot, name) in electrodes.iter() {
+        let colour = PALETTE[0].to_string();
+        map.add_slot(channel, *slot, name.to_string(), None, colour);
+    }
+    map
+}
+
+fn derived(map: &ChannelMap) -> Vec<&str> {
+    map.channels
+        .iter()
+        .filter(|c| c.derived.is_some())
+        .map(|c| c.name.as_str())
+        .collect()
+}
+
+#[test]
+fn maps_every_slot_of_the_enabled_channels() {
+    let (a, b) = (PicoChannel::A.to_string(), PicoChannel::B.to_string());
+    let map = ChannelMap::new(&[b.clone(), a.clone()]);
+    let a_slots = slots(PicoChannel::A);
+    assert_eq!(map.channels.len(), a_slots + slots(PicoChannel::B));
+
+    // Sorted by Pico channel, then slot, each named after both
+    for (key, channel) in map.channels.iter().enumerate() {
+        let (pico_channel, slot) = if key < a_slots {
+            (&a, key)
+        } else {
+            (&b, key - a_slots)
+        };
+        assert_eq!(channel.key, key);
+        assert_eq!(channel.name, format!("{}{}", pico_channel, slot));
+        assert_eq!(channel.channel.as_ref(), Some(pico_channel));
+        assert_eq!(channel.virt_channel, Some(slot));
+        assert_eq!(channel.frame_rate, ConstConfig::get_config().frame_rate());
+        assert_eq!(map.lookup(&channel.name), Some(key));
+    }
+
+    // Keys stand in for names, but aren't electrodes
+    let last = map.channels.len() - 1;
+    assert_eq!(map.lookup(&last.to_string()), Some(last));
+    assert_eq!(map.key(&last.to_string()), None);
+    assert_eq!(map.lookup("Fz"), None);
+    assert_eq!(map.name(99), "99");
+}
+
+#[test]
+fn rekeys_slots_of_their_own_pico_channel() {
+    let map = electrodes();
+    let mut frames = vec![frame(PicoChannel::B, vec![(0, 1.0), (1, 2.0)])];
+    map.rekey(PicoChannel::B, &mut frames);
+    // Only the first slot of B is mapped, so the second is dropped
+    let oz = map.key("Oz").unwrap();
+    assert_eq!(frames[0].samples, [(oz, 1.0)].iter().copied().collect());
+
+    let mut frames = vec![frame(PicoChannel::A, vec![(0, 1.0), (2, 3.0)])];
+    map.rekey(PicoChannel::A, &mut frames);
+    let expected = [(map.key("Fz").unwrap(), 1.0), (map.key("Pz").unwrap(), 3.0)];
+    assert_eq!(frames[0].samples, expected.iter().copied().collect());
+
+    // Nothing mapped at all, so it's left alone
+    let mut frames = vec![frame(PicoChannel::C, vec![(0, 1.0)])];
+    map.rekey(PicoChannel::C, &mut frames);
+    assert_eq!(
+        frames[0].samples,
+        frame(PicoChannel::C, vec![(0, 1.0)]).samples
+    );
+}
+
+#[test]
+fn derives_bipolar_and_common_average_montages() {
+    let mut map = electrodes();
+    map.add_montage(&Montage::Bipolar {
+        name: "Fz-Cz",
+        positive: "Fz",
+        negative: "Cz",
+    });
+    map.add_montage(&Montage::CommonAverage {
+        suffix: "-avg",
+        electrodes: &["Fz", "Cz", "Pz"],
+    });
+    assert_eq!(derived(&map), ["Fz-Cz", "Fz-avg", "Cz-avg", "Pz-avg"]);
+    let bipolar = &map.channels[map.lookup("Fz-Cz").unwrap()];
+    assert_eq!(bipolar.derived.as_deref(), Some("Fz - Cz"));
+    assert_eq!(bipolar.frame_rate, map.channels[0].frame_rate);
+
+    let key = |name| map.lookup(name).unwrap();
+    let mut frames = vec![
+        frame(PicoChannel::A, vec![(0, 1.0), (1, 2.0), (2, 6.0)]),
+        // Pz missing, so there's no average to take it from
+        frame(PicoChannel::A, vec![(0, 1.0), (1, 2.0)]),
+    ];
+    map.rekey(PicoChannel::A, &mut frames);
+    map.derive(&mut frames);
+
+    assert_eq!(frames[0].samples[&key("Fz-Cz")], -1.0);
+    assert_eq!(frames[0].samples[&key("Fz-avg")], -2.0);
+    assert_eq!(frames[0].samples[&key("Cz-avg")], -1.0);
+    assert_eq!(frames[0].samples[&key("Pz-avg")], 3.0);
+    assert_eq!(frames[1].samples[&key("Fz-Cz")], -1.0);
+    assert!(!frames[1].samples.contains_key(&key("Fz-avg")));
+}
+
+#[test]
+fn rejects_montages_across_pico_channels() {
+    let mut map = electrodes();
+    map.add_montage(&Montage::Bipolar {
+        name: "Fz-Oz",
+        positive: "Fz",
+        negative: "Oz",
+    });
+    map.add_montage(&Montage::CommonAverage {
+        suffix: "-avg",
+        electrodes: &["Fz", "Oz"],
+    });
+    map.add_montage(&Montage::Bipolar {
+        name: "Fz-T3",
+        positive: "Fz",
+        negative: "T3",
+    });
+    assert!(derived(&map).is_empty());
+    assert!(map.same_channel(&[map.key("Fz").unwrap(), map.key("Pz").unwrap()]));
+    assert!(!map.same_channel(&[map.key("Fz").unwrap(), map.key("Oz").unwrap()]));
+
+    // Averaged per Pico channel, B having only the one electrode to average
+    map.add_montage(&Montage::CommonAverage {
+        suffix: "-car",
+        electrodes: &[],
+    });
+    assert_eq!(derived(&map), ["Fz-car", "Cz-car", "Pz-car"]);
+}
+
+#[test]
+fn renames_by_key() {
+    let map = electrodes();
+    let keyed: BTreeMap<VirtChannel, f64> =
+        [(0, 1.0), (3, 2.0), (9, 3.0)].iter().copied().collect();
+    let named = by_name(&map.channels, keyed.iter());
+    let expected = [("Fz", 1.0), ("Oz", 2.0), ("9", 3.0)];
+    assert_eq!(
+        named,
+        expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    );
+}
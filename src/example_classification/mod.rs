@@ -38,6 +38,6 @@ pub fn initialize_example_classification(state: Data<Mutex<AppState>>) {
     }
 }
 
-fn prompt_user_reactions(state: Data<Mutex<AppState>>,num_of_rounds: u32) {
+fn prompt_user_reactions(_state: Data<Mutex<AppState>>,num_of_rounds: u32) {
     print!("If I was functional, I would run {} rounds of prompts",num_of_rounds)
 }
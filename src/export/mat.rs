@@ -0,0 +1,216 @@
+use super::FrameTable;
+use crate::session::SessionMetadata;
+
+use anyhow::Result;
+
+use std::io::Write;
+
+// MAT-file level 5 data types
+const MI_INT8: u32 = 1;
+const MI_UINT16: u32 = 4;
+const MI_INT32: u32 = 5;
+const MI_UINT32: u32 = 6;
+const MI_DOUBLE: u32 = 9;
+const MI_MATRIX: u32 = 14;
+
+// MAT-file level 5 array classes
+const MX_STRUCT_CLASS: u32 = 2;
+const MX_CHAR_CLASS: u32 = 4;
+const MX_DOUBLE_CLASS: u32 = 6;
+
+/// Longest field name MATLAB accepts in a struct
+const MAX_FIELD_NAME: usize = 32;
+
+/// Values we know how to encode into a MAT-file
+pub enum MatValue {
+    /// Dimensions and column-major data
+    Double(Vec<usize>, Vec<f64>),
+    Char(String),
+    Struct(Vec<(&'static str, MatValue)>),
+}
+
+impl MatValue {
+    pub fn scalar(value: f64) -> Self {
+        MatValue::Double(vec![1, 1], vec![value])
+    }
+
+    pub fn column(values: &[f64]) -> Self {
+        MatValue::Double(vec![values.len(), 1], values.to_vec())
+    }
+}
+
+/// Writes a level 5 MAT-file with the variables
+/// `frames` (frames × virt_channels), `timestamps`, `channels`, a `metadata` struct and
+/// `names` (comma separated) if the channels are named
+pub fn write_mat<W: Write>(
+    output: &mut W,
+    table: &FrameTable,
+    metadata: &SessionMetadata,
+) -> Result<()> {
+    // MATLAB is column-major
+    let mut frames = Vec::with_capacity(table.rows.len() * table.channels.len());
+    for column in 0..table.channels.len() {
+        frames.extend(
+            table
+                .rows
+                .iter()
+                .map(|row| row.get(column).copied().unwrap_or(f64::NAN)),
+        );
+    }
+
+    let mut header = format!(
+        "MATLAB 5.0 MAT-file, Platform: {}, Created on: {}",
+        std::env::consts::OS,
+        metadata.started.format("%a %b %e %T %Y")
+    )
+    .into_bytes();
+    header.resize(116, b' ');
+    output.write_all(&header)?;
+    // Subsystem data offset, version and endian indicator
+    output.write_all(&[0; 8])?;
+    output.write_all(&0x0100u16.to_le_bytes())?;
+    output.write_all(b"IM")?;
+
+    output.write_all(&encode_variable(
+        "frames",
+        &MatValue::Double(vec![table.rows.len(), table.channels.len()], frames),
+    ))?;
+    output.write_all(&encode_variable(
+        "timestamps",
+        &MatValue::column(&table.timestamps),
+    ))?;
+    output.write_all(&encode_variable(
+        "channels",
+        &MatValue::column(
+            &table
+                .channels
+                .iter()
+                .map(|c| *c as f64)
+                .collect::<Vec<f64>>(),
+        ),
+    ))?;
+    output.write_all(&encode_variable("metadata", &metadata_struct(metadata)))?;
+    if !table.names.is_empty() {
+        output.write_all(&encode_variable(
+            "names",
+            &MatValue::Char(table.names.join(",")),
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn metadata_struct(metadata: &SessionMetadata) -> MatValue {
+    MatValue::Struct(vec![
+        ("name", MatValue::Char(metadata.name.clone())),
+        ("started", MatValue::Char(metadata.started.to_rfc3339())),
+        (
+            "stopped",
+            MatValue::Char(
+                metadata
+                    .stopped
+                    .map(|s| s.to_rfc3339())
+                    .unwrap_or_default(),
+            ),
+        ),
+        (
+            "pico_scope_type",
+            MatValue::Char(metadata.pico_scope_type.clone()),
+        ),
+        ("channels", MatValue::Char(metadata.channels.join(","))),
+        ("sample_rate", MatValue::scalar(metadata.sample_rate as f64)),
+        ("arduino_hz", MatValue::scalar(metadata.arduino_hz as f64)),
+        (
+            "virt_channel_count",
+            MatValue::scalar(metadata.virt_channel_count as f64),
+        ),
+        (
+            "layouts",
+            MatValue::Char(
+                metadata
+                    .layouts
+                    .iter()
+                    .map(|(channel, layout)| format!("{}: {}", channel, layout))
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+        ),
+    ])
+}
+
+/// Encodes a top level miMATRIX element
+pub fn encode_variable(name: &str, value: &MatValue) -> Vec<u8> {
+    let mut body = vec![];
+
+    let (class, dimensions) = match value {
+        MatValue::Double(dimensions, _) => (MX_DOUBLE_CLASS, dimensions.clone()),
+        MatValue::Char(text) => (MX_CHAR_CLASS, vec![1, text.encode_utf16().count()]),
+        MatValue::Struct(_) => (MX_STRUCT_CLASS, vec![1, 1]),
+    };
+
+    // Array flags
+    let mut flags = class.to_le_bytes().to_vec();
+    flags.extend_from_slice(&[0; 4]);
+    push_element(&mut body, MI_UINT32, &flags);
+
+    push_element(
+        &mut body,
+        MI_INT32,
+        &dimensions
+            .iter()
+            .flat_map(|d| (*d as i32).to_le_bytes().to_vec())
+            .collect::<Vec<u8>>(),
+    );
+    push_element(&mut body, MI_INT8, name.as_bytes());
+
+    match value {
+        MatValue::Double(_, values) => push_element(
+            &mut body,
+            MI_DOUBLE,
+            &values
+                .iter()
+                .flat_map(|v| v.to_le_bytes().to_vec())
+                .collect::<Vec<u8>>(),
+        ),
+        MatValue::Char(text) => push_element(
+            &mut body,
+            MI_UINT16,
+            &text
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes().to_vec())
+                .collect::<Vec<u8>>(),
+        ),
+        MatValue::Struct(fields) => {
+            push_element(
+                &mut body,
+                MI_INT32,
+                &(MAX_FIELD_NAME as i32).to_le_bytes(),
+            );
+
+            let mut names = vec![];
+            for (field, _) in fields.iter() {
+                let mut padded = field.as_bytes().to_vec();
+                padded.resize(MAX_FIELD_NAME, 0);
+                names.extend(padded);
+            }
+            push_element(&mut body, MI_INT8, &names);
+
+            // Fields are nested matrices without names
+            for (_, field) in fields.iter() {
+                body.extend(encode_variable("", field));
+            }
+        }
+    }
+
+    let mut element = vec![];
+    push_element(&mut element, MI_MATRIX, &body);
+    element
+}
+
+/// Appends a tagged data element, padded to an 8 byte boundary
+fn push_element(output: &mut Vec<u8>, data_type: u32, data: &[u8]) {
+    output.extend_from_slice(&data_type.to_le_bytes());
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    output.extend(std::iter::repeat(0).take((8 - data.len() % 8) % 8));
+}
@@ -0,0 +1,250 @@
+pub mod mat;
+pub mod npy;
+
+use crate::{
+    electrodes::{self, MappedChannel},
+    session::SessionMetadata,
+    virt_channels::{VirtChannel, VirtSamples},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::{Seek, Write},
+    path::Path,
+};
+
+/// Header of the first CSV column, seconds since the session started
+const TIME_COLUMN: &str = "time";
+
+/// File formats virtual channel frames can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Csv,
+    Npz,
+    Mat,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Npz => "npz",
+            OutputFormat::Mat => "mat",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "CSV File",
+            OutputFormat::Npz => "NumPy Archive",
+            OutputFormat::Mat => "MATLAB File",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "csv" => Some(OutputFormat::Csv),
+            "npz" | "npy" => Some(OutputFormat::Npz),
+            "mat" => Some(OutputFormat::Mat),
+            _ => None,
+        }
+    }
+}
+
+/// Dense frames × virt_channels view of demultiplexed data, ready to be written out
+#[derive(Clone, Debug, Default)]
+pub struct FrameTable {
+    pub channels: Vec<VirtChannel>,
+    /// Electrode or montage name of each entry in `channels`, if they're known
+    pub names: Vec<String>,
+    /// One row per frame, with a column per entry in `channels`. Missing samples are NaN
+    pub rows: Vec<Vec<f64>>,
+    /// Seconds since the start of the session, one per row
+    pub timestamps: Vec<f64>,
+}
+
+impl FrameTable {
+    pub fn from_frames(frames: &[VirtSamples], timestamps: Vec<f64>) -> Self {
+        let mut channels: Vec<VirtChannel> = frames
+            .iter()
+            .flat_map(|frame| frame.keys().copied())
+            .collect();
+        channels.sort();
+        channels.dedup();
+
+        let rows = frames
+            .iter()
+            .map(|frame| {
+                channels
+                    .iter()
+                    .map(|c| frame.get(c).copied().unwrap_or(f64::NAN))
+                    .collect()
+            })
+            .collect();
+
+        FrameTable {
+            channels,
+            names: vec![],
+            rows,
+            timestamps,
+        }
+    }
+
+    /// Names every channel from the channel map
+    pub fn named(mut self, mapped: &[MappedChannel]) -> Self {
+        self.names = self
+            .channels
+            .iter()
+            .map(|c| electrodes::name_of(mapped, *c))
+            .collect();
+        self
+    }
+
+    /// Reads a CSV written by `write_csv`, taking the timestamps from its time column.
+    /// Older files without one are spaced at the frame rate of the first channel. Named
+    /// columns get their keys back from the session's channels, files without a header
+    /// are keyed by column
+    pub fn from_csv(path: &Path, metadata: &SessionMetadata) -> Result<Self> {
+        let mapped = &metadata.electrodes;
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)?;
+
+        let mut names = vec![];
+        let mut rows = vec![];
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let row = record
+                .iter()
+                .map(|v| v.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>();
+            match row {
+                Ok(row) => rows.push(row),
+                Err(_) if i == 0 => names = record.iter().map(|v| v.to_string()).collect(),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let timed = names.first().map(|n| n.as_str()) == Some(TIME_COLUMN);
+        let mut timestamps = vec![];
+        if timed {
+            names.remove(0);
+            for row in rows.iter_mut() {
+                timestamps.push(if row.is_empty() {
+                    f64::NAN
+                } else {
+                    row.remove(0)
+                });
+            }
+        }
+
+        let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        let channels: Vec<VirtChannel> = (0..width.max(names.len()))
+            .map(|i| {
+                let name = names.get(i);
+                name.and_then(|name| mapped.iter().find(|c| &c.name == name))
+                    .map(|c| c.key)
+                    .or_else(|| name?.parse().ok())
+                    .unwrap_or(i)
+            })
+            .collect();
+        if !timed {
+            let frame_period = 1.0 / metadata.frame_rate(channels.first().copied().unwrap_or(0));
+            timestamps = (0..rows.len()).map(|i| i as f64 * frame_period).collect();
+        }
+        Ok(FrameTable {
+            channels,
+            names,
+            timestamps,
+            rows,
+        })
+    }
+
+    /// Adds the rows of another table below these, lining its channels up with ours. A
+    /// channel only one of the tables has is NaN in the other's rows
+    pub fn append(&mut self, other: FrameTable) {
+        for (i, channel) in other.channels.iter().enumerate() {
+            if self.channels.contains(channel) {
+                continue;
+            }
+            self.channels.push(*channel);
+            if let Some(name) = other.names.get(i) {
+                self.names.push(name.clone());
+            }
+            for row in self.rows.iter_mut() {
+                row.push(f64::NAN);
+            }
+        }
+
+        let columns: Vec<Option<usize>> = self
+            .channels
+            .iter()
+            .map(|c| other.channels.iter().position(|o| o == c))
+            .collect();
+        for row in other.rows {
+            self.rows.push(
+                columns
+                    .iter()
+                    .map(|c| c.and_then(|c| row.get(c).copied()).unwrap_or(f64::NAN))
+                    .collect(),
+            );
+        }
+        self.timestamps.extend(other.timestamps);
+    }
+
+    /// Row-major copy of the frame data
+    pub fn flatten(&self) -> Vec<f64> {
+        self.rows
+            .iter()
+            .flat_map(|row| {
+                (0..self.channels.len()).map(move |i| row.get(i).copied().unwrap_or(f64::NAN))
+            })
+            .collect()
+    }
+}
+
+/// Writes the table to `file` in the requested format
+pub fn write_table<W: Write + Seek>(
+    file: &mut W,
+    format: OutputFormat,
+    table: &FrameTable,
+    metadata: &SessionMetadata,
+) -> Result<()> {
+    match format {
+        OutputFormat::Csv => write_csv(file, table),
+        OutputFormat::Npz => npy::write_npz(file, table, metadata),
+        OutputFormat::Mat => mat::write_mat(file, table, metadata),
+    }
+}
+
+/// Writes one row per frame, its time first then the channels sorted, under a header of
+/// their names, or their keys if the table has no names
+pub fn write_csv<W: Write>(output: &mut W, table: &FrameTable) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let names = if table.names.is_empty() {
+        table.channels.iter().map(|c| c.to_string()).collect()
+    } else {
+        table.names.clone()
+    };
+    writer.write_record(std::iter::once(TIME_COLUMN.to_string()).chain(names))?;
+    for (row, time) in table.rows.iter().zip(table.timestamps.iter()) {
+        let record = std::iter::once(time)
+            .chain(row.iter())
+            .map(|a| format!("{}", a))
+            .collect::<Vec<String>>();
+        writer.write_record(record.as_slice())?;
+    }
+
+    output.write_all(
+        &writer
+            .into_inner()
+            .map_err(|e| anyhow!("could not flush csv: {}", e))?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
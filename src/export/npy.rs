@@ -0,0 +1,99 @@
+use super::FrameTable;
+use crate::session::SessionMetadata;
+
+use anyhow::Result;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use std::io::{Seek, Write};
+
+/// Writes an uncompressed `.npz` archive with the arrays
+/// `frames` (frames × virt_channels), `timestamps`, `channels`, `metadata` (JSON string)
+/// and `names` (comma separated string) if the channels are named
+pub fn write_npz<W: Write + Seek>(
+    output: W,
+    table: &FrameTable,
+    metadata: &SessionMetadata,
+) -> Result<()> {
+    let mut archive = ZipWriter::new(output);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    archive.start_file("frames.npy", options)?;
+    archive.write_all(&encode_f64(
+        &[table.rows.len(), table.channels.len()],
+        &table.flatten(),
+    ))?;
+
+    archive.start_file("timestamps.npy", options)?;
+    archive.write_all(&encode_f64(&[table.timestamps.len()], &table.timestamps))?;
+
+    archive.start_file("channels.npy", options)?;
+    archive.write_all(&encode_f64(
+        &[table.channels.len()],
+        &table.channels.iter().map(|c| *c as f64).collect::<Vec<f64>>(),
+    ))?;
+
+    archive.start_file("metadata.npy", options)?;
+    archive.write_all(&encode_str(&serde_json::to_string(metadata)?))?;
+
+    if !table.names.is_empty() {
+        archive.start_file("names.npy", options)?;
+        archive.write_all(&encode_str(&table.names.join(",")))?;
+    }
+
+    archive.finish()?;
+    Ok(())
+}
+
+/// Encodes a C-ordered little endian float64 array
+pub fn encode_f64(shape: &[usize], values: &[f64]) -> Vec<u8> {
+    let mut output = encode_header("<f8", shape);
+    for value in values {
+        output.extend_from_slice(&value.to_le_bytes());
+    }
+    output
+}
+
+/// Encodes a 0-d unicode array, which numpy stores as UTF-32
+pub fn encode_str(value: &str) -> Vec<u8> {
+    let length = value.chars().count().max(1);
+    let mut output = encode_header(&format!("<U{}", length), &[]);
+    for c in value.chars() {
+        output.extend_from_slice(&(c as u32).to_le_bytes());
+    }
+    if value.is_empty() {
+        output.extend_from_slice(&[0; 4]);
+    }
+    output
+}
+
+/// Version 1.0 `.npy` header, padded so the data starts on a 64 byte boundary
+fn encode_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape.len() {
+        0 => "()".to_string(),
+        1 => format!("({},)", shape[0]),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // Magic (6) + version (2) + header length (2) + header + newline
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut output = Vec::with_capacity(10 + header.len());
+    output.extend_from_slice(b"\x93NUMPY");
+    output.extend_from_slice(&[1, 0]);
+    output.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    output.extend_from_slice(header.as_bytes());
+    output
+}
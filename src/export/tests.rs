@@ -0,0 +1,379 @@
+//! The encoders, read back with just enough of each format to check what they wrote
+
+use super::{mat::write_mat, npy::*, *};
+use crate::{app::state::DeviceInfo, electrodes::MappedChannel};
+
+use pico_sdk::common::PicoChannel;
+
+use std::io::{Cursor, Read, Write};
+
+fn table() -> FrameTable {
+    FrameTable {
+        channels: vec![0, 1, 2],
+        names: vec!["Fz".to_string(), "Cz".to_string(), "Pz".to_string()],
+        rows: vec![vec![0.1, -0.2, 0.3], vec![1.5, f64::NAN, -2.25]],
+        timestamps: vec![0.0, 0.001],
+    }
+}
+
+fn metadata() -> SessionMetadata {
+    SessionMetadata::new(
+        "export".to_string(),
+        &DeviceInfo {
+            pico_scope_type: "test".to_string(),
+            channel_info: vec![],
+            refresh_rate: 147_000,
+        },
+    )
+}
+
+/// NaN compares equal to itself, as it should round trip
+fn assert_same(actual: &[f64], expected: &[f64]) {
+    let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<u64>>();
+    assert_eq!(
+        bits(actual),
+        bits(expected),
+        "{:?} != {:?}",
+        actual,
+        expected
+    );
+}
+
+/// Splits a `.npy` file into its header dictionary and data
+fn read_npy(bytes: &[u8]) -> (String, &[u8]) {
+    assert_eq!(&bytes[..6], b"\x93NUMPY");
+    assert_eq!(&bytes[6..8], &[1, 0]);
+    let header_length = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    let data_start = 10 + header_length;
+    assert_eq!(data_start % 64, 0, "data isn't aligned");
+    assert_eq!(bytes[data_start - 1], b'\n');
+
+    let header = std::str::from_utf8(&bytes[10..data_start]).unwrap();
+    (header.trim_end().to_string(), &bytes[data_start..])
+}
+
+fn f64s(data: &[u8]) -> Vec<f64> {
+    data.chunks(8)
+        .map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        .collect()
+}
+
+fn utf32(data: &[u8]) -> String {
+    data.chunks(4)
+        .map(|b| std::char::from_u32(u32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap())
+        .collect()
+}
+
+#[test]
+fn npy_headers_describe_the_shape() {
+    let encoded = encode_f64(&[2, 3], &[0.0; 6]);
+    let (header, data) = read_npy(&encoded);
+    assert_eq!(
+        header,
+        "{'descr': '<f8', 'fortran_order': False, 'shape': (2, 3), }"
+    );
+    assert_eq!(data.len(), 6 * 8);
+
+    let (header, _) = read_npy(&encode_f64(&[4], &[0.0; 4]));
+    assert!(header.contains("'shape': (4,)"), "{}", header);
+    let (header, _) = read_npy(&encode_f64(&[0], &[]));
+    assert!(header.contains("'shape': (0,)"), "{}", header);
+}
+
+#[test]
+fn npy_strings_are_utf32() {
+    let encoded = encode_str("5 µV");
+    let (header, data) = read_npy(&encoded);
+    assert!(header.contains("'descr': '<U4'"), "{}", header);
+    assert!(header.contains("'shape': ()"), "{}", header);
+    assert_eq!(utf32(data), "5 µV");
+
+    // numpy has no zero length strings, so it's one NUL
+    let encoded = encode_str("");
+    let (header, data) = read_npy(&encoded);
+    assert!(header.contains("'descr': '<U1'"), "{}", header);
+    assert_eq!(data, &[0; 4]);
+}
+
+#[test]
+fn npz_round_trip() {
+    let (table, metadata) = (table(), metadata());
+    let mut output = Cursor::new(vec![]);
+    npy::write_npz(&mut output, &table, &metadata).unwrap();
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(output.into_inner())).unwrap();
+    let mut read = |name: &str| {
+        let mut bytes = vec![];
+        archive
+            .by_name(name)
+            .unwrap_or_else(|e| panic!("{}: {}", name, e))
+            .read_to_end(&mut bytes)
+            .unwrap();
+        bytes
+    };
+
+    let frames = read("frames.npy");
+    let (header, data) = read_npy(&frames);
+    assert!(header.contains("'shape': (2, 3)"), "{}", header);
+    // Row major, as numpy reads it
+    assert_same(&f64s(data), &[0.1, -0.2, 0.3, 1.5, f64::NAN, -2.25]);
+
+    let timestamps = read("timestamps.npy");
+    assert_same(&f64s(read_npy(&timestamps).1), &table.timestamps);
+    let channels = read("channels.npy");
+    assert_same(&f64s(read_npy(&channels).1), &[0.0, 1.0, 2.0]);
+    let names = read("names.npy");
+    assert_eq!(utf32(read_npy(&names).1), "Fz,Cz,Pz");
+
+    let json = read("metadata.npy");
+    let read_back: SessionMetadata = serde_json::from_str(&utf32(read_npy(&json).1)).unwrap();
+    assert_eq!(read_back.name, metadata.name);
+    assert_eq!(read_back.sample_rate, metadata.sample_rate);
+}
+
+#[test]
+fn npz_leaves_out_names_it_doesnt_have() {
+    let table = FrameTable {
+        names: vec![],
+        ..table()
+    };
+    let mut output = Cursor::new(vec![]);
+    npy::write_npz(&mut output, &table, &metadata()).unwrap();
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(output.into_inner())).unwrap();
+    assert!(archive.by_name("names.npy").is_err());
+    assert!(archive.by_name("frames.npy").is_ok());
+}
+
+/// What a MAT-file variable decodes to
+#[derive(Debug, PartialEq)]
+enum Decoded {
+    Double(Vec<usize>, Vec<f64>),
+    Char(String),
+    Struct(Vec<(String, Decoded)>),
+}
+
+/// Reads the tagged data element at the front of `bytes`, returning its type, data, and
+/// what follows it
+/// Fz, Cz and Pz as the session mapped them, at 100 frames per second
+fn mapped_metadata() -> SessionMetadata {
+    let mut metadata = metadata();
+    metadata.electrodes = table()
+        .names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| MappedChannel {
+            key: 4 + i,
+            name,
+            channel: Some(PicoChannel::A.to_string()),
+            virt_channel: Some(i),
+            position: None,
+            colour: "#000000".to_string(),
+            derived: None,
+            frame_rate: 100.0,
+        })
+        .collect();
+    metadata
+}
+
+fn csv_file(contents: &[u8]) -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(contents).unwrap();
+    file
+}
+
+#[test]
+fn csv_round_trip() {
+    let table = table();
+    let mut output = vec![];
+    write_csv(&mut output, &table).unwrap();
+    let written = String::from_utf8(output.clone()).unwrap();
+    assert!(written.starts_with("time,Fz,Cz,Pz\n"), "{}", written);
+
+    let file = csv_file(&output);
+    let read_back = FrameTable::from_csv(file.path(), &mapped_metadata()).unwrap();
+    assert_eq!(read_back.names, table.names);
+    assert_same(&read_back.timestamps, &table.timestamps);
+    assert_same(&read_back.flatten(), &table.flatten());
+    // Keyed as the session mapped them, by name
+    assert_eq!(read_back.channels, [4, 5, 6]);
+
+    // Names the session doesn't know are keyed by column
+    let read_back = FrameTable::from_csv(file.path(), &metadata()).unwrap();
+    assert_eq!(read_back.channels, [0, 1, 2]);
+}
+
+#[test]
+fn csv_without_a_time_column_is_spaced_at_the_frame_rate() {
+    let file = csv_file(b"Fz,Cz,Pz\n0.1,-0.2,0.3\n1.5,NaN,-2.25\n3,4,5\n");
+    let read_back = FrameTable::from_csv(file.path(), &mapped_metadata()).unwrap();
+    assert_eq!(read_back.channels, [4, 5, 6]);
+    assert_same(&read_back.timestamps, &[0.0, 0.01, 0.02]);
+    assert_eq!(read_back.rows[2], [3.0, 4.0, 5.0]);
+
+    // Nor a header, keyed by column
+    let file = csv_file(b"1,2\n3,4\n");
+    let read_back = FrameTable::from_csv(file.path(), &mapped_metadata()).unwrap();
+    assert!(read_back.names.is_empty());
+    assert_eq!(read_back.channels, [0, 1]);
+    assert_eq!(read_back.rows, [[1.0, 2.0], [3.0, 4.0]]);
+}
+
+fn read_element(bytes: &[u8]) -> (u32, &[u8], &[u8]) {
+    let data_type = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let length = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    let padded = length + (8 - length % 8) % 8;
+    (data_type, &bytes[8..8 + length], &bytes[8 + padded..])
+}
+
+/// Reads the miMATRIX element at the front of `bytes`, returning its name and value, and
+/// what follows it
+fn read_matrix(bytes: &[u8]) -> (String, Decoded, &[u8]) {
+    let (data_type, body, rest) = read_element(bytes);
+    assert_eq!(data_type, 14, "not a matrix");
+
+    let (_, flags, body) = read_element(body);
+    let class = flags[0];
+    let (_, dimensions, body) = read_element(body);
+    let dimensions: Vec<usize> = dimensions
+        .chunks(4)
+        .map(|d| i32::from_le_bytes([d[0], d[1], d[2], d[3]]) as usize)
+        .collect();
+    let (_, name, body) = read_element(body);
+    let name = String::from_utf8(name.to_vec()).unwrap();
+
+    let value = match class {
+        6 => Decoded::Double(dimensions, f64s(read_element(body).1)),
+        4 => {
+            let (_, text, _) = read_element(body);
+            let units: Vec<u16> = text
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            Decoded::Char(String::from_utf16(&units).unwrap())
+        }
+        2 => {
+            let (_, field_length, body) = read_element(body);
+            let field_length = i32::from_le_bytes([
+                field_length[0],
+                field_length[1],
+                field_length[2],
+                field_length[3],
+            ]) as usize;
+            let (_, names, mut body) = read_element(body);
+
+            let mut fields = vec![];
+            for name in names.chunks(field_length) {
+                let name = String::from_utf8(name.to_vec()).unwrap();
+                let (unnamed, value, rest) = read_matrix(body);
+                assert_eq!(unnamed, "");
+                fields.push((name.trim_end_matches('\0').to_string(), value));
+                body = rest;
+            }
+            Decoded::Struct(fields)
+        }
+        class => panic!("unexpected class {}", class),
+    };
+    (name, value, rest)
+}
+
+fn read_mat(bytes: &[u8]) -> Vec<(String, Decoded)> {
+    assert!(bytes.starts_with(b"MATLAB 5.0 MAT-file"));
+    assert_eq!(&bytes[124..128], &[0, 1, b'I', b'M']);
+
+    let mut variables = vec![];
+    let mut rest = &bytes[128..];
+    while !rest.is_empty() {
+        let (name, value, next) = read_matrix(rest);
+        variables.push((name, value));
+        rest = next;
+    }
+    variables
+}
+
+#[test]
+fn mat_round_trip() {
+    let (table, metadata) = (table(), metadata());
+    let mut output = vec![];
+    write_mat(&mut output, &table, &metadata).unwrap();
+    assert_eq!(output.len() % 8, 0, "elements aren't padded");
+
+    let variables = read_mat(&output);
+    let names: Vec<&str> = variables.iter().map(|v| v.0.as_str()).collect();
+    assert_eq!(
+        names,
+        ["frames", "timestamps", "channels", "metadata", "names"]
+    );
+
+    match &variables[0].1 {
+        Decoded::Double(dimensions, values) => {
+            assert_eq!(dimensions, &[2, 3]);
+            // Column major, as MATLAB reads it
+            assert_same(values, &[0.1, 1.5, -0.2, f64::NAN, 0.3, -2.25]);
+        }
+        other => panic!("frames: {:?}", other),
+    }
+    assert_eq!(
+        variables[1].1,
+        Decoded::Double(vec![2, 1], table.timestamps.clone())
+    );
+    assert_eq!(
+        variables[2].1,
+        Decoded::Double(vec![3, 1], vec![0.0, 1.0, 2.0])
+    );
+    assert_eq!(variables[4].1, Decoded::Char("Fz,Cz,Pz".to_string()));
+
+    match &variables[3].1 {
+        Decoded::Struct(fields) => {
+            assert_eq!(
+                fields[0],
+                ("name".to_string(), Decoded::Char("export".to_string()))
+            );
+            let sample_rate = fields.iter().find(|f| f.0 == "sample_rate").unwrap();
+            assert_eq!(sample_rate.1, Decoded::Double(vec![1, 1], vec![147_000.0]));
+        }
+        other => panic!("metadata: {:?}", other),
+    }
+}
+
+#[test]
+fn mat_strings_are_utf16() {
+    let encoded = mat::encode_variable("unit", &mat::MatValue::Char("µV".to_string()));
+    let (name, value, rest) = read_matrix(&encoded);
+    assert_eq!(name, "unit");
+    assert_eq!(value, Decoded::Char("µV".to_string()));
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn appending_lines_up_channels() {
+    let mut joined = FrameTable::default();
+    joined.append(table());
+    joined.append(FrameTable {
+        channels: vec![2, 3],
+        names: vec!["Pz".to_string(), "Oz".to_string()],
+        rows: vec![vec![4.0, 5.0]],
+        timestamps: vec![0.002],
+    });
+
+    assert_eq!(joined.channels, [0, 1, 2, 3]);
+    assert_eq!(joined.names, ["Fz", "Cz", "Pz", "Oz"]);
+    assert_eq!(joined.timestamps, [0.0, 0.001, 0.002]);
+    assert_same(
+        &joined.flatten(),
+        &[
+            0.1,
+            -0.2,
+            0.3,
+            f64::NAN,
+            1.5,
+            f64::NAN,
+            -2.25,
+            f64::NAN,
+            f64::NAN,
+            f64::NAN,
+            4.0,
+            5.0,
+        ],
+    );
+}
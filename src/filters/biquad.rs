@@ -0,0 +1,75 @@
+use std::f64::consts::PI;
+
+/// Q of a second order Butterworth section, flat passband with no peaking
+pub const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// Second order IIR section, in transposed direct form II so only two values of state
+/// need carrying from one block to the next
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    /// Builds a section from unnormalised coefficients
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// `(cos(w0), alpha)` as used by all the cookbook designs
+    fn prewarp(frequency: f64, q: f64, sample_rate: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    pub fn low_pass(cutoff: f64, q: f64, sample_rate: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(cutoff, q, sample_rate);
+        Biquad::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn high_pass(cutoff: f64, q: f64, sample_rate: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(cutoff, q, sample_rate);
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn notch(frequency: f64, q: f64, sample_rate: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(frequency, q, sample_rate);
+        Biquad::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    /// Forgets previous samples, as if the section had only ever seen zeros
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
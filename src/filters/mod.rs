@@ -0,0 +1,229 @@
+pub mod biquad;
+
+use crate::virt_channels::{VirtChannel, VirtFrame, VirtSamples};
+use biquad::{Biquad, BUTTERWORTH_Q};
+
+use anyhow::{anyhow, Result};
+use pico_sdk::common::PicoChannel;
+use serde::{Deserialize, Serialize};
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+fn default_notch_q() -> f64 {
+    30.0
+}
+
+/// A single stage of a channel's filter chain, frequencies are in Hz
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterKind {
+    HighPass {
+        cutoff: f64,
+    },
+    LowPass {
+        cutoff: f64,
+    },
+    /// High-pass at `low` followed by low-pass at `high`
+    BandPass {
+        low: f64,
+        high: f64,
+    },
+    /// Rejects mains hum, usually at 50 or 60 Hz
+    Notch {
+        frequency: f64,
+        #[serde(default = "default_notch_q")]
+        q: f64,
+    },
+}
+
+impl FilterKind {
+    /// Designs the biquads for this stage, checking every frequency is below Nyquist
+    fn design(&self, sample_rate: f64) -> Result<Vec<Biquad>> {
+        let nyquist = sample_rate / 2.0;
+        let check = |frequency: f64| {
+            if frequency > 0.0 && frequency < nyquist {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "{} Hz is outside 0-{} Hz, the range this frame rate can filter",
+                    frequency,
+                    nyquist
+                ))
+            }
+        };
+
+        Ok(match *self {
+            FilterKind::HighPass { cutoff } => {
+                check(cutoff)?;
+                vec![Biquad::high_pass(cutoff, BUTTERWORTH_Q, sample_rate)]
+            }
+            FilterKind::LowPass { cutoff } => {
+                check(cutoff)?;
+                vec![Biquad::low_pass(cutoff, BUTTERWORTH_Q, sample_rate)]
+            }
+            FilterKind::BandPass { low, high } => {
+                check(low)?;
+                check(high)?;
+                if low >= high {
+                    return Err(anyhow!("Band-pass needs low ({}) below high ({})", low, high));
+                }
+                vec![
+                    Biquad::high_pass(low, BUTTERWORTH_Q, sample_rate),
+                    Biquad::low_pass(high, BUTTERWORTH_Q, sample_rate),
+                ]
+            }
+            FilterKind::Notch { frequency, q } => {
+                check(frequency)?;
+                if q <= 0.0 {
+                    return Err(anyhow!("Notch Q must be positive, got {}", q));
+                }
+                vec![Biquad::notch(frequency, q, sample_rate)]
+            }
+        })
+    }
+}
+
+/// The filters selected for one virtual channel, in the order they're applied
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelFilters {
+    pub channel: VirtChannel,
+    pub filters: Vec<FilterKind>,
+}
+
+/// A frame with and without the filters applied
+#[derive(Clone, Debug, Serialize)]
+pub struct FilteredFrame {
+    /// Seconds since the session started
+    pub time: f64,
+    /// Pico channel it was demultiplexed from
+    #[serde(skip)]
+    pub channel: PicoChannel,
+    pub raw: VirtSamples,
+    pub filtered: VirtSamples,
+}
+
+struct FilterChain {
+    filters: Vec<FilterKind>,
+    sections: Vec<Biquad>,
+}
+
+/// Per virtual channel filter chains, keeping their state from one block to the next
+pub struct FilterBank {
+    /// Frames per second of each channel, as each of its frames holds one sample. Pico
+    /// channels with different layouts frame at different rates
+    frame_rates: BTreeMap<VirtChannel, f64>,
+    chains: BTreeMap<VirtChannel, FilterChain>,
+    /// Filter state only makes sense sample after sample, so blocks that finish
+    /// demultiplexing out of order are held until it's their turn
+    pending: BTreeMap<u64, Vec<(f64, VirtFrame)>>,
+    next_block: u64,
+}
+
+impl FilterBank {
+    pub fn new(frame_rates: BTreeMap<VirtChannel, f64>) -> Self {
+        FilterBank {
+            frame_rates,
+            chains: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            next_block: 0,
+        }
+    }
+
+    /// Replaces the filters of a channel, an empty list leaves it unfiltered
+    pub fn set(&mut self, channel: VirtChannel, filters: Vec<FilterKind>) -> Result<()> {
+        if filters.is_empty() {
+            self.chains.remove(&channel);
+            return Ok(());
+        }
+
+        let sample_rate = match self.frame_rates.get(&channel) {
+            Some(frame_rate) => *frame_rate,
+            None => return Err(anyhow!("Channel {} isn't streamed", channel)),
+        };
+        let mut sections = vec![];
+        for filter in filters.iter() {
+            sections.extend(filter.design(sample_rate)?);
+        }
+        self.chains
+            .insert(channel, FilterChain { filters, sections });
+        Ok(())
+    }
+
+    /// Current selection of every filtered channel
+    pub fn selection(&self) -> Vec<ChannelFilters> {
+        self.chains
+            .iter()
+            .map(|(channel, chain)| ChannelFilters {
+                channel: *channel,
+                filters: chain.filters.clone(),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+
+    /// Clears the state of every chain and restarts block numbering from 0, so a new
+    /// recording doesn't ring from the last one
+    pub fn reset(&mut self) {
+        for chain in self.chains.values_mut() {
+            chain.sections.iter_mut().for_each(Biquad::reset);
+        }
+        self.pending.clear();
+        self.next_block = 0;
+    }
+
+    /// Adds the `(timestamp, frame)` frames of one block, returning every frame that's
+    /// now ready in order. Blocks must be numbered consecutively from 0, including blocks
+    /// that produced no frames
+    pub fn filter_block(
+        &mut self,
+        sequence: u64,
+        frames: Vec<(f64, VirtFrame)>,
+    ) -> Vec<FilteredFrame> {
+        self.pending.insert(sequence, frames);
+
+        let mut ready = vec![];
+        while let Some(mut frames) = self.pending.remove(&self.next_block) {
+            frames.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            for (time, frame) in frames {
+                ready.push(FilteredFrame {
+                    time,
+                    channel: frame.channel,
+                    filtered: self.apply(&frame.samples),
+                    raw: frame.samples,
+                });
+            }
+            self.next_block += 1;
+        }
+        ready
+    }
+
+    /// Adds the frames of a block that follows straight on from the last one, for blocks
+    /// that arrive in order and aren't numbered, like the preview's
+    pub fn filter_next(&mut self, frames: Vec<(f64, VirtFrame)>) -> Vec<FilteredFrame> {
+        self.filter_block(self.next_block, frames)
+    }
+
+    /// Filters one frame, channels without a chain pass through untouched
+    pub fn apply(&mut self, frame: &VirtSamples) -> VirtSamples {
+        frame
+            .iter()
+            .map(|(channel, value)| {
+                let filtered = match self.chains.get_mut(channel) {
+                    // A missing sample would poison the state of every later one
+                    Some(chain) if !value.is_nan() => chain
+                        .sections
+                        .iter_mut()
+                        .fold(*value, |x, section| section.process(x)),
+                    _ => *value,
+                };
+                (*channel, filtered)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,259 @@
+use super::*;
+
+use std::f64::consts::PI;
+
+const FRAME_RATE: f64 = 1000.0;
+
+/// First few samples out for a unit impulse in
+fn impulse_response(mut section: Biquad, length: usize) -> Vec<f64> {
+    (0..length)
+        .map(|i| section.process(if i == 0 { 1.0 } else { 0.0 }))
+        .collect()
+}
+
+/// What a direct form filter with these coefficients answers an impulse with
+fn expected_response(b: [f64; 3], a: [f64; 3], length: usize) -> Vec<f64> {
+    let mut y: Vec<f64> = vec![];
+    for n in 0..length {
+        let x = |k: usize| if n == k { 1.0 } else { 0.0 };
+        let past = |k: usize| if n >= k { y[n - k] } else { 0.0 };
+        y.push(b[0] * x(0) + b[1] * x(1) + b[2] * x(2) - a[1] * past(1) - a[2] * past(2));
+    }
+    y
+}
+
+fn assert_close(actual: &[f64], expected: &[f64], tolerance: f64) {
+    assert_eq!(actual.len(), expected.len());
+    for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+        assert!((a - e).abs() <= tolerance, "sample {}: {} != {}", i, a, e);
+    }
+}
+
+/// Amplitude a sine of `frequency` comes out at once the filter has settled, from the
+/// RMS of the last two seconds, a whole number of cycles for the frequencies used here
+fn gain(filters: &mut [Biquad], frequency: f64, sample_rate: f64) -> f64 {
+    let samples = (sample_rate * 4.0) as usize;
+    let settled: Vec<f64> = (0..samples)
+        .map(|i| {
+            let x = (2.0 * PI * frequency * i as f64 / sample_rate).sin();
+            filters.iter_mut().fold(x, |x, section| section.process(x))
+        })
+        .skip(samples / 2)
+        .collect();
+    (settled.iter().map(|y| y * y).sum::<f64>() / settled.len() as f64 * 2.0).sqrt()
+}
+
+#[test]
+fn butterworth_coefficients() {
+    // Second order Butterworth at 1 kHz and 48 kHz, as scipy.signal.butter designs them
+    let low = expected_response(
+        [0.003_916_126_661, 0.007_832_253_321, 0.003_916_126_661],
+        [1.0, -1.815_341_082_705, 0.831_005_589_347],
+        8,
+    );
+    let high = expected_response(
+        [0.911_586_668_013, -1.823_173_336_026, 0.911_586_668_013],
+        [1.0, -1.815_341_082_705, 0.831_005_589_347],
+        8,
+    );
+    assert_close(
+        &impulse_response(Biquad::low_pass(1000.0, BUTTERWORTH_Q, 48000.0), 8),
+        &low,
+        1e-8,
+    );
+    assert_close(
+        &impulse_response(Biquad::high_pass(1000.0, BUTTERWORTH_Q, 48000.0), 8),
+        &high,
+        1e-8,
+    );
+}
+
+#[test]
+fn notch_coefficients() {
+    // The audio EQ cookbook notch, whose bandwidth differs slightly from scipy's iirnotch
+    let notch = expected_response(
+        [0.994_876_106_264, -1.892_366_807_538, 0.994_876_106_264],
+        [1.0, -1.892_366_807_538, 0.989_752_212_529],
+        8,
+    );
+    assert_close(
+        &impulse_response(Biquad::notch(50.0, 30.0, FRAME_RATE), 8),
+        &notch,
+        1e-8,
+    );
+}
+
+#[test]
+fn responses_pass_and_stop_where_they_should() {
+    let low_pass = || vec![Biquad::low_pass(100.0, BUTTERWORTH_Q, FRAME_RATE)];
+    assert!((gain(&mut low_pass(), 5.0, FRAME_RATE) - 1.0).abs() < 0.01);
+    assert!((gain(&mut low_pass(), 100.0, FRAME_RATE) - BUTTERWORTH_Q).abs() < 0.01);
+    assert!(gain(&mut low_pass(), 400.0, FRAME_RATE) < 0.05);
+
+    let high_pass = || vec![Biquad::high_pass(10.0, BUTTERWORTH_Q, FRAME_RATE)];
+    assert!(gain(&mut high_pass(), 0.5, FRAME_RATE) < 0.01);
+    assert!((gain(&mut high_pass(), 10.0, FRAME_RATE) - BUTTERWORTH_Q).abs() < 0.01);
+    assert!((gain(&mut high_pass(), 200.0, FRAME_RATE) - 1.0).abs() < 0.01);
+
+    let notch = || vec![Biquad::notch(50.0, 30.0, FRAME_RATE)];
+    assert!(gain(&mut notch(), 50.0, FRAME_RATE) < 0.01);
+    assert!((gain(&mut notch(), 10.0, FRAME_RATE) - 1.0).abs() < 0.01);
+    assert!((gain(&mut notch(), 150.0, FRAME_RATE) - 1.0).abs() < 0.01);
+}
+
+fn frame(values: &[(VirtChannel, f64)]) -> VirtFrame {
+    VirtFrame {
+        channel: PicoChannel::A,
+        offset: 0,
+        samples: values.iter().copied().collect(),
+    }
+}
+
+fn bank(filters: Vec<FilterKind>) -> FilterBank {
+    let mut bank = FilterBank::new((0..2).map(|c| (c, FRAME_RATE)).collect());
+    bank.set(0, filters).unwrap();
+    bank
+}
+
+/// A block of `length` frames starting at frame `start`, channel 0 a slow ramp and
+/// channel 1 constant
+fn block(start: usize, length: usize) -> Vec<(f64, VirtFrame)> {
+    (start..start + length)
+        .map(|i| {
+            let time = i as f64 / FRAME_RATE;
+            (time, frame(&[(0, 1.0 + (i as f64).sqrt()), (1, 0.5)]))
+        })
+        .collect()
+}
+
+#[test]
+fn state_carries_across_blocks() {
+    let filters = vec![FilterKind::BandPass {
+        low: 1.0,
+        high: 40.0,
+    }];
+    let whole: Vec<f64> = bank(filters.clone())
+        .filter_block(0, block(0, 300))
+        .iter()
+        .map(|f| f.filtered[&0])
+        .collect();
+
+    let mut split = bank(filters);
+    let mut filtered = vec![];
+    for (sequence, start) in [0, 100, 150, 299].iter().enumerate() {
+        let length = [100, 50, 149, 1][sequence];
+        filtered.extend(split.filter_block(sequence as u64, block(*start, length)));
+    }
+    let split: Vec<f64> = filtered.iter().map(|f| f.filtered[&0]).collect();
+    assert_eq!(split, whole);
+
+    // Unfiltered channels pass through, and the raw samples are kept
+    assert!(filtered.iter().all(|f| f.filtered[&1] == 0.5));
+    assert_eq!(filtered[7].raw[&0], 1.0 + 7f64.sqrt());
+}
+
+#[test]
+fn blocks_are_filtered_in_sequence() {
+    let filters = vec![FilterKind::HighPass { cutoff: 5.0 }];
+    let in_order: Vec<(f64, f64)> = {
+        let mut bank = bank(filters.clone());
+        let mut frames = bank.filter_block(0, block(0, 20));
+        frames.extend(bank.filter_block(1, vec![]));
+        frames.extend(bank.filter_block(2, block(20, 20)));
+        frames.iter().map(|f| (f.time, f.filtered[&0])).collect()
+    };
+
+    let mut bank = bank(filters);
+    assert!(bank.filter_block(2, block(20, 20)).is_empty());
+    assert!(bank.filter_block(1, vec![]).is_empty());
+    let frames = bank.filter_block(0, block(0, 20));
+    let out_of_order: Vec<(f64, f64)> = frames.iter().map(|f| (f.time, f.filtered[&0])).collect();
+    assert_eq!(out_of_order, in_order);
+}
+
+#[test]
+fn unnumbered_blocks_follow_on() {
+    let filters = vec![FilterKind::HighPass { cutoff: 5.0 }];
+    let numbered: Vec<f64> = {
+        let mut bank = bank(filters.clone());
+        let mut frames = bank.filter_block(0, block(0, 20));
+        frames.extend(bank.filter_block(1, block(20, 20)));
+        frames.iter().map(|f| f.filtered[&0]).collect()
+    };
+
+    // Whatever number the bank is up to, even after it was replaced mid-stream
+    let mut bank = bank(filters);
+    let mut frames = bank.filter_next(block(0, 20));
+    frames.extend(bank.filter_next(block(20, 20)));
+    let values: Vec<f64> = frames.iter().map(|f| f.filtered[&0]).collect();
+    assert_eq!(values, numbered);
+}
+
+#[test]
+fn reset_starts_afresh() {
+    let filters = vec![FilterKind::LowPass { cutoff: 20.0 }];
+    let mut bank = bank(filters.clone());
+    let first = bank.filter_block(0, block(0, 50));
+    bank.filter_block(1, block(50, 50));
+
+    bank.reset();
+    let again = bank.filter_block(0, block(0, 50));
+    let values =
+        |frames: &[FilteredFrame]| -> Vec<f64> { frames.iter().map(|f| f.filtered[&0]).collect() };
+    assert_eq!(values(&again), values(&first));
+}
+
+#[test]
+fn missing_samples_dont_poison_the_state() {
+    let mut clean = bank(vec![FilterKind::LowPass { cutoff: 20.0 }]);
+    let mut lossy = bank(vec![FilterKind::LowPass { cutoff: 20.0 }]);
+    for i in 0..10 {
+        let value = i as f64;
+        let expected = clean.apply(&frame(&[(0, value)]).samples)[&0];
+        assert_eq!(lossy.apply(&frame(&[(0, value)]).samples)[&0], expected);
+        // Every other frame lost
+        assert!(lossy.apply(&frame(&[(0, f64::NAN)]).samples)[&0].is_nan());
+    }
+}
+
+#[test]
+fn only_designable_filters_are_set() {
+    let mut bank = bank(vec![]);
+    assert!(bank.is_empty());
+
+    let error = |bank: &mut FilterBank, channel, filter| {
+        bank.set(channel, vec![filter]).unwrap_err().to_string()
+    };
+    assert!(error(&mut bank, 7, FilterKind::LowPass { cutoff: 10.0 }).contains("isn't streamed"));
+    assert!(error(&mut bank, 0, FilterKind::LowPass { cutoff: 500.0 }).contains("outside"));
+    assert!(error(&mut bank, 0, FilterKind::HighPass { cutoff: 0.0 }).contains("outside"));
+    assert!(error(
+        &mut bank,
+        0,
+        FilterKind::BandPass {
+            low: 40.0,
+            high: 10.0
+        }
+    )
+    .contains("below high"));
+    assert!(error(
+        &mut bank,
+        0,
+        FilterKind::Notch {
+            frequency: 50.0,
+            q: 0.0
+        }
+    )
+    .contains("positive"));
+    assert!(bank.is_empty());
+
+    let notch = FilterKind::Notch {
+        frequency: 50.0,
+        q: 30.0,
+    };
+    bank.set(1, vec![notch]).unwrap();
+    assert_eq!(bank.selection()[0].channel, 1);
+    assert_eq!(bank.selection()[0].filters, [notch]);
+    bank.set(1, vec![]).unwrap();
+    assert!(bank.is_empty());
+}
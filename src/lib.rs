@@ -0,0 +1,320 @@
+#![forbid(unsafe_code)]
+
+pub mod app;
+pub mod calibration;
+pub mod catalogue;
+pub mod crosstalk;
+pub mod electrodes;
+pub mod example_classification;
+pub mod export;
+pub mod filters;
+pub mod memory;
+pub mod metrics;
+pub mod pico;
+pub mod pipeline;
+pub mod planner;
+pub mod preflight;
+pub mod pyramid;
+pub mod quality;
+pub mod recorder;
+pub mod session;
+pub mod spectrum;
+pub mod telemetry;
+pub mod tools;
+pub mod validation;
+pub mod virt_channels;
+
+use crate::{
+    electrodes::{Electrode, Montage},
+    export::{write_table, FrameTable, OutputFormat},
+    memory::OverflowPolicy,
+    metrics::{Output, COUNTERS},
+    pipeline::queue::Backpressure,
+    session::SessionMetadata,
+    spectrum::Band,
+    telemetry::{LogRotation, RollingLog},
+    virt_channels::{ChannelLayout, MuxLayout},
+};
+
+use console::{style, Term};
+use native_dialog::FileDialog;
+use serde::Serialize;
+use std::{fs::File, time::Duration};
+
+#[derive(Serialize)]
+pub struct ConstConfig {
+    pub sync_point_threshold: f64,
+    pub web_interface_bind: &'static str,
+    pub cli_enabled: bool,
+    pub arduino_hz: usize,
+    pub virt_channel_count: usize,
+    /// Pico channels that aren't multiplexed into `virt_channel_count` slots, by letter
+    pub channel_layouts: &'static [ChannelLayout],
+    pub arduino_hz_tolerance: f32,
+    pub virt_channel_noise_threshold: f64,
+    pub output_dir: &'static str,
+    /// Format of the session's frame files. Blocks are staged as CSV while recording and
+    /// joined into one file per channel in this format when it stops
+    pub output_format: OutputFormat,
+    pub chunk_max_bytes: u64,
+    pub chunk_max_duration: Duration,
+    pub chunk_fsync_interval: Duration,
+    /// Hz the frames are averaged down to before spectral analysis
+    pub spectral_rate: f64,
+    pub spectral_window: Duration,
+    /// Samples per Welch segment, which sets the frequency resolution
+    pub spectral_segment: usize,
+    pub spectrogram_interval: Duration,
+    pub spectral_bands: &'static [Band],
+    /// Seconds of signal each quality check looks at
+    pub quality_window: Duration,
+    /// Quality limits, as fractions of the channel's input range
+    pub quality_noise_limit: f64,
+    pub quality_line_limit: f64,
+    /// Per second, loose enough that a slow wave itself doesn't count as drift
+    pub quality_drift_limit: f64,
+    /// Share of samples that may sit at the edge of the input range
+    pub quality_clip_limit: f64,
+    /// Samples per multiplexer slot needed to average each virtual channel cleanly
+    pub min_samples_per_slot: usize,
+    /// Past this many samples per slot, faster capture only costs memory
+    pub max_samples_per_slot: usize,
+    /// Samples per second the device can stream, shared across enabled channels
+    pub max_streaming_rate: u64,
+    /// Latest raw samples kept per channel for clients to collect
+    pub memory_queue_duration: Duration,
+    pub memory_queue_policy: OverflowPolicy,
+    /// Raw samples per channel waiting to be cut into blocks, which are a second long
+    pub stream_buffer_duration: Duration,
+    pub stream_buffer_policy: OverflowPolicy,
+    /// Resident memory past which the memory report warns
+    pub memory_warning_bytes: u64,
+    pub memory_critical_bytes: u64,
+    /// Streaming events waiting to be buffered. Always dropped when full, so the
+    /// callback never waits
+    pub pipeline_event_queue_depth: usize,
+    /// Blocks waiting between each of the later stages
+    pub pipeline_block_queue_depth: usize,
+    pub pipeline_backpressure: Backpressure,
+    /// Workers demultiplexing channels, of one block or consecutive ones, in parallel
+    pub demux_threads: usize,
+    /// Which diagnostics are logged, as `RUST_LOG` style directives. `--trace` logs all
+    pub log_filter: &'static str,
+    /// Pretty printed to stderr
+    pub log_console: bool,
+    /// One JSON object per line, appended to
+    pub log_json_file: Option<&'static str>,
+    pub log_rolling: Option<RollingLog>,
+    /// Gain and offset of every virtual channel, written by the calibration workflow
+    pub calibration_file: &'static str,
+    /// Crosstalk between adjacent slots, written by the crosstalk estimate
+    pub crosstalk_file: &'static str,
+    /// Takes the estimated crosstalk out of every frame as it's demultiplexed
+    pub crosstalk_compensation: bool,
+    /// Electrode on the end of each slot. Slots left out are named by channel and slot,
+    /// such as `A0`
+    pub electrodes: &'static [Electrode],
+    /// Channels derived live from the electrodes
+    pub montages: &'static [Montage],
+}
+impl ConstConfig {
+    pub fn get_config() -> Self {
+        ConstConfig {
+            sync_point_threshold: 3.5,
+            web_interface_bind: "localhost:8000",
+            cli_enabled: false,
+            arduino_hz: 14700,
+            virt_channel_count: 4,
+            channel_layouts: &[],
+            arduino_hz_tolerance: 0.8,
+            virt_channel_noise_threshold: 0.5,
+            output_dir: "data_output",
+            output_format: OutputFormat::Csv,
+            chunk_max_bytes: 256 * 1024 * 1024,
+            chunk_max_duration: Duration::from_secs(10 * 60),
+            chunk_fsync_interval: Duration::from_secs(1),
+            spectral_rate: 4.0,
+            spectral_window: Duration::from_secs(5 * 60),
+            spectral_segment: 512,
+            spectrogram_interval: Duration::from_secs(15),
+            spectral_bands: &[
+                Band {
+                    name: "bradygastria",
+                    low_cpm: 0.5,
+                    high_cpm: 2.5,
+                },
+                Band {
+                    name: "normogastria",
+                    low_cpm: 2.5,
+                    high_cpm: 3.75,
+                },
+                Band {
+                    name: "tachygastria",
+                    low_cpm: 3.75,
+                    high_cpm: 10.0,
+                },
+            ],
+            quality_window: Duration::from_secs(5),
+            quality_noise_limit: 0.005,
+            quality_line_limit: 0.01,
+            quality_drift_limit: 0.05,
+            quality_clip_limit: 0.001,
+            min_samples_per_slot: 10,
+            max_samples_per_slot: 1000,
+            max_streaming_rate: 100_000_000,
+            memory_queue_duration: Duration::from_secs(10),
+            memory_queue_policy: OverflowPolicy::DropOldest,
+            stream_buffer_duration: Duration::from_secs(3),
+            stream_buffer_policy: OverflowPolicy::DropNewest,
+            memory_warning_bytes: 2 * 1024 * 1024 * 1024,
+            memory_critical_bytes: 6 * 1024 * 1024 * 1024,
+            pipeline_event_queue_depth: 32,
+            pipeline_block_queue_depth: 2,
+            pipeline_backpressure: Backpressure::Block,
+            demux_threads: 4,
+            log_filter: "info",
+            log_console: true,
+            log_json_file: None,
+            log_rolling: Some(RollingLog {
+                directory: "logs",
+                prefix: "picoscope.log",
+                rotation: LogRotation::Daily,
+            }),
+            calibration_file: "calibration.json",
+            crosstalk_file: "crosstalk.json",
+            crosstalk_compensation: false,
+            electrodes: &[],
+            montages: &[],
+        }
+    }
+
+    /// Frames per second, one frame being a full round of the multiplexer and its sync pulse
+    pub fn frame_rate(&self) -> f64 {
+        self.arduino_hz as f64 / (self.virt_channel_count + 1) as f64
+    }
+
+    /// Frames per second of a Pico channel, by letter, from its layout. A channel without
+    /// a multiplexer is averaged down to the default frame rate
+    pub fn channel_frame_rate(&self, channel: &str) -> f64 {
+        match self.mux_layout(channel) {
+            MuxLayout::Slots(slots) => self.arduino_hz as f64 / (slots + 1) as f64,
+            MuxLayout::None | MuxLayout::SyncOnly => self.frame_rate(),
+        }
+    }
+
+    /// Frame rate of the slowest layout, the default one included
+    pub fn slowest_frame_rate(&self) -> f64 {
+        self.channel_layouts
+            .iter()
+            .map(|l| self.channel_frame_rate(l.channel))
+            .fold(self.frame_rate(), f64::min)
+    }
+
+    /// How a Pico channel is multiplexed, by letter
+    pub fn mux_layout(&self, channel: &str) -> MuxLayout {
+        self.channel_layouts
+            .iter()
+            .find(|l| l.channel == channel)
+            .map_or(MuxLayout::Slots(self.virt_channel_count), |l| l.layout)
+    }
+}
+
+#[tracing::instrument(level = "debug", skip(table, session), fields(frames = table.rows.len()))]
+pub fn write_data(
+    table: FrameTable,
+    session: &SessionMetadata,
+    format: OutputFormat,
+    defaults: Option<String>,
+) {
+    let cwd = std::env::current_dir().unwrap();
+    let terminal = Term::stdout();
+    let save_path;
+    let interactive = defaults.is_none();
+
+    if !interactive {
+        save_path = cwd.join(session.directory()).join(format!(
+            "{}.{}",
+            defaults.unwrap(),
+            format.extension()
+        ));
+    } else {
+        save_path = match match FileDialog::new()
+            .set_location(&cwd)
+            .add_filter(format.description(), &[format.extension()])
+            .show_save_single_file()
+        {
+            Ok(a) => a,
+            Err(err) => {
+                terminal
+                    .write_line(&format!(
+                        "{} {}{}\n        {:?}\n",
+                        style("✘").bold().red(),
+                        style("Error ").bold().red(),
+                        style("could not display dialog").bold().green(),
+                        err
+                    ))
+                    .unwrap();
+                return ();
+            }
+        } {
+            Some(a) => a,
+            None => {
+                terminal
+                    .write_line(&format!(
+                        "{} {}{}\n",
+                        style("✘").bold().red(),
+                        style("Error ").bold().red(),
+                        style("no file selected").bold().green(),
+                    ))
+                    .unwrap();
+                return ();
+            }
+        };
+    }
+
+    // Blocks are saved every second while recording, which is too often for the terminal
+    if interactive {
+        println!("Saving to: {}", save_path.display());
+        terminal
+            .write_line(&format!("{} {}", "⌛", style("Saving... ").bold(),))
+            .unwrap();
+    } else {
+        tracing::debug!(path = %save_path.display(), "saving frames");
+    }
+
+    let mut file: File = match File::create(save_path) {
+        Err(err) => {
+            terminal
+                .write_line(&format!(
+                    "{} {}{}\n        {}\n",
+                    style("✘").bold().red(),
+                    style("Error ").bold().red(),
+                    style("could not create file").bold().green(),
+                    err
+                ))
+                .unwrap();
+            return ();
+        }
+        Ok(a) => a,
+    };
+
+    match write_table(&mut file, format, &table, session) {
+        Ok(_) => {
+            if let Ok(metadata) = file.metadata() {
+                COUNTERS.written(Output::Frames, metadata.len());
+            }
+        }
+        Err(err) => {
+            terminal
+                .write_line(&format!(
+                    "{} {}{}\n        {}\n",
+                    style("✘").bold().red(),
+                    style("Error ").bold().red(),
+                    style("could not write to file").bold().green(),
+                    err
+                ))
+                .unwrap();
+            return ();
+        }
+    }
+}
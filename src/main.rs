@@ -1,64 +1,53 @@
 #![forbid(unsafe_code)]
 
-pub mod app;
-pub mod example_classification;
-pub mod pico;
-pub mod virt_channels;
-
 use actix_web::{middleware, web, App, HttpServer};
 use anyhow::Result;
-use chrono::prelude::Local;
 use console::{style, Term};
-use dialoguer::{Input, Select};
+use dialoguer::{Confirm, Input, Select};
 
 use pico_sdk::prelude::*;
 
-use crate::{
+use esquaredg_picoscope_monitering::{
     app::{
-        state::{AppState, DeviceInfo},
+        self,
+        state::{AppState, ChannelInfo, DeviceInfo},
         *,
     },
+    calibration::Calibration,
+    catalogue::{Catalogue, EventLog},
+    crosstalk::Crosstalk,
+    electrodes::ChannelMap,
     example_classification::initialize_example_classification,
     pico::*,
+    preflight::CheckStatus,
+    session::SessionMetadata,
+    telemetry, tools, validation,
+    virt_channels::MuxLayout,
+    ConstConfig,
 };
 
 use parking_lot::Mutex;
-use std::{collections::HashMap, io, io::prelude::Read, sync::Arc};
-
-use crate::app::state::ChannelInfo;
-use native_dialog::FileDialog;
-use std::fs::File;
-use std::io::Write;
-
-pub struct ConstConfig {
-    sync_point_threshold: f64,
-    web_interface_bind: &'static str,
-    cli_enabled: bool,
-    arduino_hz: usize,
-    virt_channel_count: usize,
-    arduino_hz_tolerance: f32,
-    virt_channel_noise_threshold: f64,
-}
-impl ConstConfig {
-    pub fn get_config() -> Self {
-        ConstConfig {
-            sync_point_threshold: 3.5,
-            web_interface_bind: "localhost:8000",
-            cli_enabled: false,
-            arduino_hz: 14700,
-            virt_channel_count: 4,
-            arduino_hz_tolerance: 0.8,
-            virt_channel_noise_threshold: 0.5,
-        }
-    }
-}
+use std::{io, io::prelude::Read, path::Path, sync::Arc};
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(result) = tools::run(&args) {
+        return result;
+    }
+
     std::env::set_var("RUST_LOG", "actix_web=error,pico=error");
     env_logger::init();
+    // Held until exit, so buffered log lines get written
+    let _log_guards = telemetry::init(args.iter().any(|a| a == "--trace"))?;
     let const_config = ConstConfig::get_config();
 
+    let problems = validation::validate(&const_config, None);
+    print_config_problems(&problems);
+    if validation::has_errors(&problems) {
+        return Err(anyhow::anyhow!("invalid configuration"));
+    }
+
     // Setup actix webserver
     let state = web::Data::new(Mutex::new(AppState::new(DeviceInfo {
         pico_scope_type: "".to_string(),
@@ -73,8 +62,38 @@ async fn main() -> Result<()> {
             .service(index)
             .service(
                 // All /api routes
-                web::scope("/api").service(check_alive).service(device_info),
+                web::scope("/api")
+                    .service(check_alive)
+                    .service(device_info)
+                    .service(sessions::sessions)
+                    .service(sessions::session_info)
+                    .service(sessions::download_session)
+                    .service(sessions::delete_session)
+                    .service(history::session_range)
+                    .service(app::filters::filters)
+                    .service(app::filters::set_filters)
+                    .service(app::filters::recent_frames)
+                    .service(app::spectrum::spectrum)
+                    .service(app::spectrum::spectrogram)
+                    .service(app::quality::quality)
+                    .service(app::quality::sync)
+                    .service(app::quality::preflight)
+                    .service(app::config::config)
+                    .service(app::electrodes::electrodes)
+                    .service(app::memory::memory)
+                    .service(app::pipeline::pipeline)
+                    .service(app::calibration::current_calibration)
+                    .service(app::calibration::add_reading)
+                    .service(app::calibration::clear_readings)
+                    .service(app::calibration::fit_calibration)
+                    .service(app::catalogue::events)
+                    .service(app::catalogue::subjects)
+                    .service(app::catalogue::add_subject)
+                    .service(app::catalogue::set_session_subject)
+                    .service(app::catalogue::markers)
+                    .service(app::catalogue::add_marker),
             )
+            .service(app::metrics::metrics)
             .service(actix_files::Files::new("/", "./static"))
             .app_data(state2.clone())
             .wrap(middleware::Logger::default())
@@ -87,17 +106,34 @@ async fn main() -> Result<()> {
     let streaming_device = device.into_streaming_device();
     let ch_units = configure_channels(&streaming_device);
 
-    let samples_per_second = get_capture_rate();
+    let layouts: Vec<MuxLayout> = ch_units
+        .keys()
+        .map(|channel| const_config.mux_layout(&channel.to_string()))
+        .collect();
+    let samples_per_second = get_capture_rate(&layouts);
 
     // Initializing the state
     let mut locked_state = state.lock();
     let mut recording_cache = locked_state.recording.clone();
 
     for channel in streaming_device.get_channels().iter() {
+        // Disabled channels aren't streamed
+        let full_scale = match streaming_device.get_channel_config(*channel) {
+            Some(config) => config.range.get_max_scaled_value(),
+            None => continue,
+        };
+        locked_state
+            .live
+            .quality
+            .lock()
+            .set_full_scale(*channel, full_scale);
+
+        let layout = const_config.mux_layout(&channel.to_string());
         locked_state.device_info.channel_info.push(ChannelInfo {
             channel: channel.to_string(),
-            virt_channels: 1,
-            voltage_range: 200f32,
+            virt_channels: layout.virt_channels() as u32,
+            layout,
+            voltage_range: full_scale as f32,
         })
     }
 
@@ -106,12 +142,79 @@ async fn main() -> Result<()> {
 
     locked_state.device_info.refresh_rate = samples_per_second;
 
+    let enabled: Vec<String> = locked_state
+        .device_info
+        .channel_info
+        .iter()
+        .map(|c| c.channel.clone())
+        .collect();
+    locked_state.set_channel_map(ChannelMap::new(&enabled));
+
+    let problems = validation::validate(&const_config, Some(&locked_state.device_info));
+    print_config_problems(&problems);
+    if validation::has_errors(&problems) {
+        return Err(anyhow::anyhow!("configuration doesn't suit the device settings"));
+    }
+
+    locked_state.catalogue = match Catalogue::open_default() {
+        Ok(catalogue) => {
+            let catalogue = Arc::new(catalogue);
+            locked_state.events = EventLog::start(catalogue.clone());
+            Some(catalogue)
+        }
+        Err(err) => {
+            tracing::error!(?err, "could not open the session catalogue");
+            None
+        }
+    };
+
+    let mut corrections = locked_state.live.corrections.lock();
+    // Fitted to the layouts at the time, which may have changed since
+    let calibration =
+        Calibration::load(Path::new(const_config.calibration_file)).and_then(|c| match c {
+            Some(calibration) => calibration.check(&enabled).map(|_| Some(calibration)),
+            None => Ok(None),
+        });
+    corrections.calibration = match calibration {
+        Ok(calibration) => calibration.map(Arc::new),
+        Err(err) => {
+            tracing::error!(
+                ?err,
+                "could not load the calibration, recording uncalibrated"
+            );
+            None
+        }
+    };
+
+    if const_config.crosstalk_compensation {
+        let crosstalk =
+            Crosstalk::load(Path::new(const_config.crosstalk_file)).and_then(|c| match c {
+                Some(crosstalk) => crosstalk.check(&enabled).map(|_| Some(crosstalk)),
+                None => Ok(None),
+            });
+        corrections.crosstalk = match crosstalk {
+            Ok(Some(crosstalk)) => Some(Arc::new(crosstalk)),
+            Ok(None) => {
+                tracing::warn!("no crosstalk has been estimated, so none is compensated");
+                None
+            }
+            Err(err) => {
+                tracing::error!(
+                    ?err,
+                    "could not load the crosstalk, recording uncompensated"
+                );
+                None
+            }
+        };
+    }
+
+    drop(corrections);
     drop(locked_state);
 
     // Start the webserver
     web_server.run();
 
-    let capture_stats: Arc<dyn NewDataHandler> = CaptureStats::new(ch_units, state.clone());
+    let capture_stats: Arc<dyn NewDataHandler> = CaptureStats::new(state.clone());
     streaming_device.new_data.subscribe(capture_stats.clone());
 
     // let state3 = state.clone();
@@ -140,6 +243,8 @@ async fn main() -> Result<()> {
         ))
         .unwrap();
     if const_config.cli_enabled {
+        // Still being written in the background, so exiting waits for them
+        let mut manifests = vec![];
         loop {
             let cli_options = &[
                 "Status",
@@ -148,6 +253,10 @@ async fn main() -> Result<()> {
                 } else {
                     "Start Recording"
                 },
+                "Add Marker",
+                "Preflight Check",
+                "Calibrate",
+                "Estimate Crosstalk",
                 "Start Example AI",
                 "Clear Memory",
                 "Exit",
@@ -175,27 +284,72 @@ async fn main() -> Result<()> {
                     print_stats(&state.clone());
                 }
                 "Stop Recording" => {
-                    let cli_selection = Input::with_theme(&better_theme())
-                        .default(String::from("untitled_run"))
+                    recording_cache = false;
+                    let stopping = state.lock().stop_recording();
+                    // Waits for the stages to write what was streamed before stopping
+                    if let Some(stopped) = stopping.and_then(|s| s.recv().ok()) {
+                        terminal
+                            .write_line(&format!(
+                                "Saved session to {}",
+                                style(stopped.session.directory().display()).bold()
+                            ))
+                            .unwrap();
+                        manifests.push(stopped.manifest);
+                    }
+                }
+                "Start Recording" => {
+                    let session_name = Input::with_theme(&better_theme())
+                        .with_prompt("Session name")
+                        .default(SessionMetadata::default_name())
                         .interact()
                         .unwrap();
 
-                    recording_cache = false;
-                    let mut unlocked_state = state.lock();
-                    unlocked_state.recording = false;
-                    // write_data(unlocked_state.voltage_queue, Some(cli_selection));
+                    let (live, map) = state.lock().live_and_map();
+                    let report = live.preflight(&map);
+                    print_preflight(&report);
+                    if report.status == CheckStatus::Fail
+                        && !Confirm::with_theme(&better_theme())
+                            .with_prompt("Preflight failed, record anyway?")
+                            .default(false)
+                            .interact()
+                            .unwrap()
+                    {
+                        continue;
+                    }
 
-                    drop(unlocked_state);
-                }
-                "Start Recording" => {
                     // Start Stream
                     terminal
                         .write_line(&format!("{}", style("Resuming").green()))
                         .unwrap();
-                    recording_cache = true;
-                    let mut unlocked_state = state.lock();
-                    unlocked_state.recording = true;
-                    drop(unlocked_state);
+                    match AppState::start_recording(&state, session_name) {
+                        Ok(_) => recording_cache = true,
+                        Err(err) => eprintln!("Could not start recording: {:?}", err),
+                    }
+                }
+                "Preflight Check" => {
+                    let (live, map) = state.lock().live_and_map();
+                    print_preflight(&live.preflight(&map));
+                }
+                "Calibrate" => {
+                    if let Err(err) = calibrate(&state) {
+                        eprintln!("Could not calibrate: {:?}", err);
+                    }
+                }
+                "Estimate Crosstalk" => {
+                    if let Err(err) = estimate_crosstalk(&state) {
+                        eprintln!("Could not estimate crosstalk: {:?}", err);
+                    }
+                }
+                "Add Marker" => {
+                    let label: String = Input::with_theme(&better_theme())
+                        .with_prompt("Marker label")
+                        .interact()
+                        .unwrap();
+
+                    match state.lock().add_marker(&label) {
+                        Ok(time) => println!("Marked {} at {:.3}s", label, time),
+                        Err(err) => eprintln!("Could not add marker: {:?}", err),
+                    }
                 }
                 "Clear Memory" => {
                     let _ = clear_and_get_memory(state.clone(), true);
@@ -205,6 +359,9 @@ async fn main() -> Result<()> {
                 }
                 "Exit" => {
                     streaming_device.stop();
+                    for manifest in manifests {
+                        let _ = manifest.join();
+                    }
                     return Ok(());
                 }
 
@@ -217,118 +374,20 @@ async fn main() -> Result<()> {
         terminal
             .write_line(&format!("{}", style("Resuming").green()))
             .unwrap();
-        let mut unlocked_state = state.lock();
-        unlocked_state.recording = true;
-                    drop(unlocked_state);
+        let report = AppState::start_recording(&state, SessionMetadata::default_name())?;
+        // Nothing asks before recording here, so the checks are only shown
+        print_preflight(&report);
         // CLI disabled (mode used for debug output)
         println!("Press enter to stop");
 
         let _ = io::stdin().read(&mut [0u8]).unwrap();
 
         streaming_device.stop();
-        return Ok(());
-    }
-}
-
-fn write_data(state: Vec<HashMap<usize, f64>>, defaults: Option<String>) {
-    let cwd = std::env::current_dir().unwrap();
-    let terminal = Term::stdout();
-    let save_path;
-
-    if !defaults.is_none() {
-        save_path = cwd.join(format!("data_output/{}.csv", defaults.unwrap()));
-    } else {
-        save_path = match match FileDialog::new()
-            .set_location(&cwd)
-            .add_filter("CSV File", &["csv"])
-            .show_save_single_file()
-        {
-            Ok(a) => a,
-            Err(err) => {
-                terminal
-                    .write_line(&format!(
-                        "{} {}{}\n        {:?}\n",
-                        style("✘").bold().red(),
-                        style("Error ").bold().red(),
-                        style("could not display dialog").bold().green(),
-                        err
-                    ))
-                    .unwrap();
-                return ();
-            }
-        } {
-            Some(a) => a,
-            None => {
-                terminal
-                    .write_line(&format!(
-                        "{} {}{}\n",
-                        style("✘").bold().red(),
-                        style("Error ").bold().red(),
-                        style("no file selected").bold().green(),
-                    ))
-                    .unwrap();
-                return ();
-            }
-        };
-    }
-
-    println!("Saving to: {}", save_path.display());
-    terminal
-        .write_line(&format!("{} {}", "⌛", style("Saving... ").bold(),))
-        .unwrap();
-
-    let mut file: File = match File::create(save_path) {
-        Err(err) => {
-            terminal
-                .write_line(&format!(
-                    "{} {}{}\n        {}\n",
-                    style("✘").bold().red(),
-                    style("Error ").bold().red(),
-                    style("could not create file").bold().green(),
-                    err
-                ))
-                .unwrap();
-            return ();
+        let stopping = state.lock().stop_recording();
+        // Waits for the stages to write what was streamed before stopping
+        if let Some(stopped) = stopping.and_then(|s| s.recv().ok()) {
+            let _ = stopped.manifest.join();
         }
-        Ok(a) => a,
-    };
-
-    // let headers = (0..ConstConfig::get_config().virt_channel_count)
-    //     .map(|e| format!("{}", e))
-    //     .collect::<Vec<String>>();
-
-    let mut writer = csv::Writer::from_writer(vec![]);
-
-    // writer.write_record(headers.as_slice()).unwrap();
-
-    for channel in state.iter() {
-        // Sort Channel Hashmap
-        let mut channels_vec: Vec<(&usize,&f64)> = channel
-            .iter()
-            .collect();
-        channels_vec.sort_by(|x,y| x.0.cmp(&y.0));
-        // Remove channel key, to create a vec of channels 0..19
-        let record = channels_vec.iter()
-            .map(|(_, a)| format!("{}", a))
-            .collect::<Vec<String>>();
-        // Write channel vector
-        writer.write_record(record.as_slice()).unwrap();
-    }
-
-    let csv_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
-    match file.write_all(csv_data.as_bytes()) {
-        Err(err) => {
-            terminal
-                .write_line(&format!(
-                    "{} {}{}\n        {}\n",
-                    style("✘").bold().red(),
-                    style("Error ").bold().red(),
-                    style("could not write to file").bold().green(),
-                    err
-                ))
-                .unwrap();
-            return ();
-        }
-        Ok(_) => {}
+        return Ok(());
     }
 }
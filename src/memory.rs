@@ -0,0 +1,217 @@
+//! Bounded sample buffers, and the process memory they add up to
+
+use crate::ConstConfig;
+
+use procinfo::pid::statm_self;
+use serde::Serialize;
+
+use std::collections::VecDeque;
+
+/// Share of a buffer's capacity past which the memory report warns about it
+const BUFFER_WARNING_FILL: f64 = 0.9;
+
+/// What a full buffer does with more samples
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Makes room by dropping the oldest samples, for views of the latest signal
+    DropOldest,
+    /// Keeps what's buffered and drops what doesn't fit, so buffered samples stay contiguous
+    DropNewest,
+}
+
+/// Samples of one channel, never holding more than `capacity`
+pub struct RingBuffer {
+    samples: VecDeque<f64>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Samples dropped since the buffer was made
+    overflowed: u64,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        RingBuffer {
+            samples: VecDeque::new(),
+            capacity,
+            policy,
+            overflowed: 0,
+        }
+    }
+
+    /// Adds samples as the policy allows, returning how many were dropped to do it
+    pub fn push(&mut self, values: &[f64]) -> usize {
+        let dropped = match self.policy {
+            OverflowPolicy::DropOldest => {
+                // More than fits on its own, so only its tail is kept
+                let skipped = values.len().saturating_sub(self.capacity);
+                let values = &values[skipped..];
+                let excess = (self.samples.len() + values.len()).saturating_sub(self.capacity);
+                self.samples.drain(..excess);
+                self.samples.extend(values.iter().copied());
+                skipped + excess
+            }
+            OverflowPolicy::DropNewest => {
+                let room = self.capacity.saturating_sub(self.samples.len());
+                let kept = values.len().min(room);
+                self.samples.extend(values[..kept].iter().copied());
+                values.len() - kept
+            }
+        };
+        self.overflowed += dropped as u64;
+        dropped
+    }
+
+    /// Removes and returns the first `count` samples, or as many as there are
+    pub fn take_front(&mut self, count: usize) -> Vec<f64> {
+        if count >= self.samples.len() {
+            // Hands over the whole allocation, rather than copying out of it
+            return Vec::from(std::mem::take(&mut self.samples));
+        }
+        // Copied a slice at a time, which is much faster than draining sample by sample
+        let (front, back) = self.samples.as_slices();
+        let from_front = count.min(front.len());
+        let mut taken = Vec::with_capacity(count);
+        taken.extend_from_slice(&front[..from_front]);
+        taken.extend_from_slice(&back[..count - from_front]);
+        self.samples.drain(..count);
+        taken
+    }
+
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.samples.iter().copied().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Describes the buffer for the memory report
+    pub fn stats(&self, buffer: &'static str, channel: String) -> BufferStats {
+        BufferStats {
+            buffer,
+            channel,
+            samples: self.samples.len(),
+            capacity: self.capacity,
+            bytes: (self.samples.len() * std::mem::size_of::<f64>()) as u64,
+            policy: self.policy,
+            overflowed: self.overflowed,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BufferStats {
+    pub buffer: &'static str,
+    pub channel: String,
+    pub samples: usize,
+    pub capacity: usize,
+    pub bytes: u64,
+    pub policy: OverflowPolicy,
+    pub overflowed: u64,
+}
+
+/// Memory use of this process, from `/proc/self/statm`
+#[derive(Clone, Debug, Serialize)]
+pub struct ProcessMemory {
+    pub virtual_bytes: u64,
+    pub resident_bytes: u64,
+    pub shared_bytes: u64,
+    pub text_bytes: u64,
+    /// Data and stack, which is where the sample buffers live
+    pub data_bytes: u64,
+}
+
+/// None where `/proc` isn't available
+pub fn process_memory() -> Option<ProcessMemory> {
+    let statm = statm_self().ok()?;
+    // What `statm` counts in. Usually 4 KiB, but some ARM kernels use 16 or 64 KiB pages
+    let page_size = page_size::get() as u64;
+    Some(ProcessMemory {
+        virtual_bytes: statm.size as u64 * page_size,
+        resident_bytes: statm.resident as u64 * page_size,
+        shared_bytes: statm.share as u64 * page_size,
+        text_bytes: statm.text as u64 * page_size,
+        data_bytes: statm.data as u64 * page_size,
+    })
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryReport {
+    pub process: Option<ProcessMemory>,
+    pub buffers: Vec<BufferStats>,
+    pub buffered_bytes: u64,
+    /// Samples dropped across every buffer
+    pub overflowed: u64,
+    pub warnings: Vec<String>,
+}
+
+fn mebibytes(bytes: u64) -> String {
+    format!("{:.0} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Puts buffer figures together with the process's, warning about whatever has crossed
+/// the configured limits
+pub fn report(buffers: Vec<BufferStats>) -> MemoryReport {
+    let const_config = ConstConfig::get_config();
+    let process = process_memory();
+    let mut warnings = vec![];
+
+    if let Some(process) = process.as_ref() {
+        if process.resident_bytes >= const_config.memory_critical_bytes {
+            warnings.push(format!(
+                "resident memory is {}, past the critical limit of {}",
+                mebibytes(process.resident_bytes),
+                mebibytes(const_config.memory_critical_bytes)
+            ));
+        } else if process.resident_bytes >= const_config.memory_warning_bytes {
+            warnings.push(format!(
+                "resident memory is {}, past the warning limit of {}",
+                mebibytes(process.resident_bytes),
+                mebibytes(const_config.memory_warning_bytes)
+            ));
+        }
+    }
+    for buffer in buffers.iter() {
+        if buffer.overflowed > 0 {
+            warnings.push(format!(
+                "{} of channel {} has dropped {} samples",
+                buffer.buffer, buffer.channel, buffer.overflowed
+            ));
+        } else if buffer.samples as f64 >= buffer.capacity as f64 * BUFFER_WARNING_FILL {
+            warnings.push(format!(
+                "{} of channel {} is {:.0}% full",
+                buffer.buffer,
+                buffer.channel,
+                buffer.samples as f64 / buffer.capacity as f64 * 100.0
+            ));
+        }
+    }
+
+    MemoryReport {
+        process,
+        buffered_bytes: buffers.iter().map(|b| b.bytes).sum(),
+        overflowed: buffers.iter().map(|b| b.overflowed).sum(),
+        buffers,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,94 @@
+//! Ring buffers under both overflow policies, and the report made from them
+
+use super::*;
+
+fn buffer(policy: OverflowPolicy, values: &[f64]) -> RingBuffer {
+    let mut buffer = RingBuffer::new(8, policy);
+    buffer.push(values);
+    buffer
+}
+
+#[test]
+fn dropping_the_oldest_keeps_the_latest() {
+    let mut buffer = buffer(OverflowPolicy::DropOldest, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(buffer.push(&[6.0, 7.0, 8.0, 9.0]), 2);
+    assert_eq!(buffer.to_vec(), [2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+
+    // More than fits at once, so only its tail is kept
+    let values: Vec<f64> = (10..30).map(f64::from).collect();
+    assert_eq!(buffer.push(&values), 20);
+    assert_eq!(buffer.to_vec(), values[12..]);
+    assert_eq!(buffer.overflowed(), 22);
+    assert_eq!(buffer.len(), buffer.capacity());
+}
+
+#[test]
+fn dropping_the_newest_keeps_what_was_buffered() {
+    let mut buffer = buffer(OverflowPolicy::DropNewest, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(buffer.push(&[6.0, 7.0, 8.0, 9.0]), 2);
+    assert_eq!(buffer.to_vec(), [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+
+    // Full, so everything is dropped until some is taken
+    assert_eq!(buffer.push(&[10.0; 20]), 20);
+    assert_eq!(buffer.take_front(3), [0.0, 1.0, 2.0]);
+    assert_eq!(buffer.push(&[11.0, 12.0, 13.0, 14.0]), 1);
+    assert_eq!(buffer.to_vec(), [3.0, 4.0, 5.0, 6.0, 7.0, 11.0, 12.0, 13.0]);
+    assert_eq!(buffer.overflowed(), 23);
+}
+
+#[test]
+fn takes_across_the_wrap() {
+    // Filled to the end of its allocation, so what comes after the front is taken wraps
+    let mut buffer = RingBuffer::new(64, OverflowPolicy::DropOldest);
+    buffer.push(&[0.0]);
+    let allocated = buffer.samples.capacity();
+    let values: Vec<f64> = (1..allocated).map(|i| i as f64).collect();
+    buffer.push(&values);
+    assert_eq!(buffer.take_front(allocated - 2).len(), allocated - 2);
+    buffer.push(&[-1.0, -2.0]);
+    let (front, back) = buffer.samples.as_slices();
+    assert!(!front.is_empty() && !back.is_empty());
+
+    let last = (allocated - 1) as f64;
+    assert_eq!(buffer.take_front(3), [last - 1.0, last, -1.0]);
+    assert_eq!(buffer.to_vec(), [-2.0]);
+    // Asking for more than there is takes it all
+    assert_eq!(buffer.take_front(5), [-2.0]);
+    assert!(buffer.is_empty());
+    assert!(buffer.take_front(1).is_empty());
+}
+
+#[test]
+fn reports_fill_and_overflow() {
+    let mut full = buffer(OverflowPolicy::DropOldest, &[0.0; 8]);
+    let filling = buffer(OverflowPolicy::DropNewest, &[0.0; 8]);
+    let overflowing = buffer(OverflowPolicy::DropNewest, &[0.0; 10]);
+    full.clear();
+
+    let stats = overflowing.stats("stream buffer", "A".to_string());
+    assert_eq!((stats.samples, stats.capacity, stats.bytes), (8, 8, 64));
+    assert_eq!(
+        (stats.policy, stats.overflowed),
+        (OverflowPolicy::DropNewest, 2)
+    );
+
+    let report = report(vec![
+        full.stats("voltage queue", "A".to_string()),
+        filling.stats("voltage queue", "B".to_string()),
+        stats,
+    ]);
+    assert_eq!(report.buffered_bytes, 16 * 8);
+    assert_eq!(report.overflowed, 2);
+    let buffer_warnings: Vec<&String> = report
+        .warnings
+        .iter()
+        .filter(|w| w.contains("of channel"))
+        .collect();
+    assert_eq!(
+        buffer_warnings,
+        [
+            "voltage queue of channel B is 100% full",
+            "stream buffer of channel A has dropped 2 samples",
+        ]
+    );
+}
@@ -0,0 +1,247 @@
+//! Counters for the Prometheus `/metrics` endpoint, and the text format it's served in.
+//! The counters are atomics in a static, so the pipeline can count without taking the
+//! state lock. They only go up, Prometheus works out rates over them
+
+use crate::{
+    memory::ProcessMemory, pipeline::queue::QueueSnapshot, virt_channels::VirtChannelError,
+    ConstConfig,
+};
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Files the recording is written to, for counting bytes written
+#[derive(Clone, Copy, Debug)]
+pub enum Output {
+    /// Crash-safe chunks of the raw stream
+    Raw,
+    /// Demultiplexed frames, raw and filtered
+    Frames,
+    Pyramid,
+}
+
+impl Output {
+    const ALL: [Output; 3] = [Output::Raw, Output::Frames, Output::Pyramid];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Output::Raw => "raw",
+            Output::Frames => "frames",
+            Output::Pyramid => "pyramid",
+        }
+    }
+}
+
+pub struct Counters {
+    frames_demuxed: AtomicU64,
+    /// By `VirtChannelError::kind`, in the order of `VirtChannelError::KINDS`
+    demux_failures: [AtomicU64; 6],
+    dropped_frames: AtomicU64,
+    /// By `Output`
+    bytes_written: [AtomicU64; 3],
+    /// Bits of the `f64` frames per second of every Pico channel together, 0 until the
+    /// channels are configured
+    frame_rate: AtomicU64,
+}
+
+pub static COUNTERS: Counters = Counters {
+    frames_demuxed: AtomicU64::new(0),
+    demux_failures: [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ],
+    dropped_frames: AtomicU64::new(0),
+    bytes_written: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+    frame_rate: AtomicU64::new(0),
+};
+
+impl Counters {
+    pub fn frames_demuxed(&self, frames: usize) {
+        self.frames_demuxed
+            .fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a block that couldn't be demultiplexed, by the kind of error
+    pub fn demux_failed(&self, kind: &str) {
+        if let Some(index) = VirtChannelError::KINDS.iter().position(|k| *k == kind) {
+            self.demux_failures[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the frames per second of every Pico channel together, as each is counted
+    pub fn set_frame_rate(&self, frame_rate: f64) {
+        self.frame_rate
+            .store(frame_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Counts the frames a gap of `seconds` in the recording would have held
+    pub fn dropped(&self, seconds: f64) {
+        let frame_rate = match f64::from_bits(self.frame_rate.load(Ordering::Relaxed)) {
+            rate if rate > 0.0 => rate,
+            _ => ConstConfig::get_config().frame_rate(),
+        };
+        let frames = (seconds * frame_rate).round() as u64;
+        self.dropped_frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, output: Output, bytes: u64) {
+        self.bytes_written[output as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Everything reported that isn't a counter, read from the state when scraped
+pub struct Gauges {
+    /// Samples per second, measured over the last few seconds
+    pub streaming_speed: u64,
+    pub recording: bool,
+    pub queues: Vec<QueueSnapshot>,
+    pub process: Option<ProcessMemory>,
+    /// Bytes held in the sample buffers
+    pub buffered_bytes: u64,
+}
+
+/// Adds one metric with its help and type lines, `samples` being label sets and values
+fn metric(
+    output: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(output, "# HELP picoscope_{} {}", name, help);
+    let _ = writeln!(output, "# TYPE picoscope_{} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(output, "picoscope_{}{} {}", name, labels, value);
+    }
+}
+
+fn unlabelled(value: f64) -> Vec<(String, f64)> {
+    vec![(String::new(), value)]
+}
+
+/// Renders every metric in the Prometheus text format
+pub fn render(gauges: &Gauges) -> String {
+    let counters = &COUNTERS;
+    let mut output = String::new();
+
+    metric(
+        &mut output,
+        "streaming_samples_per_second",
+        "gauge",
+        "Samples per second streamed from the device, over the last few seconds",
+        unlabelled(gauges.streaming_speed as f64),
+    );
+    metric(
+        &mut output,
+        "recording",
+        "gauge",
+        "1 while a session is being recorded",
+        unlabelled(if gauges.recording { 1.0 } else { 0.0 }),
+    );
+    metric(
+        &mut output,
+        "frames_demuxed_total",
+        "counter",
+        "Frames demultiplexed, recording or not",
+        unlabelled(counters.frames_demuxed.load(Ordering::Relaxed) as f64),
+    );
+    metric(
+        &mut output,
+        "demux_failures_total",
+        "counter",
+        "Blocks that couldn't be demultiplexed, by why. NoSyncPulse and NotEnoughData \
+         are sync failures",
+        VirtChannelError::KINDS
+            .iter()
+            .zip(counters.demux_failures.iter())
+            .map(|(kind, count)| {
+                (
+                    format!("{{kind=\"{}\"}}", kind),
+                    count.load(Ordering::Relaxed) as f64,
+                )
+            }),
+    );
+    metric(
+        &mut output,
+        "dropped_frames_total",
+        "counter",
+        "Frames estimated lost to gaps in the recording",
+        unlabelled(counters.dropped_frames.load(Ordering::Relaxed) as f64),
+    );
+    metric(
+        &mut output,
+        "bytes_written_total",
+        "counter",
+        "Bytes written to the recording, by file",
+        Output::ALL.iter().map(|output| {
+            (
+                format!("{{output=\"{}\"}}", output.as_str()),
+                counters.bytes_written[*output as usize].load(Ordering::Relaxed) as f64,
+            )
+        }),
+    );
+
+    let queue_label = |queue: &QueueSnapshot| format!("{{queue=\"{}\"}}", queue.name);
+    metric(
+        &mut output,
+        "pipeline_queue_depth",
+        "gauge",
+        "Items waiting between processing stages",
+        gauges
+            .queues
+            .iter()
+            .map(|q| (queue_label(q), q.depth as f64)),
+    );
+    metric(
+        &mut output,
+        "pipeline_queue_capacity",
+        "gauge",
+        "Items each queue between processing stages holds",
+        gauges
+            .queues
+            .iter()
+            .map(|q| (queue_label(q), q.capacity as f64)),
+    );
+    metric(
+        &mut output,
+        "pipeline_queue_dropped_total",
+        "counter",
+        "Items dropped by a full queue between processing stages",
+        gauges
+            .queues
+            .iter()
+            .map(|q| (queue_label(q), q.dropped as f64)),
+    );
+
+    if let Some(process) = gauges.process.as_ref() {
+        metric(
+            &mut output,
+            "process_resident_memory_bytes",
+            "gauge",
+            "Resident memory of the process",
+            unlabelled(process.resident_bytes as f64),
+        );
+        metric(
+            &mut output,
+            "process_virtual_memory_bytes",
+            "gauge",
+            "Virtual memory of the process",
+            unlabelled(process.virtual_bytes as f64),
+        );
+    }
+    metric(
+        &mut output,
+        "buffered_bytes",
+        "gauge",
+        "Bytes held in the sample buffers",
+        unlabelled(gauges.buffered_bytes as f64),
+    );
+
+    output
+}
@@ -1,26 +1,32 @@
 use crate::{
-    app::state::AppState, virt_channels::split_into_virt_channels, write_data, ConstConfig,
+    app::state::AppState,
+    calibration::{self, Calibration},
+    crosstalk::Crosstalk,
+    pipeline::{self, queue, Item, RawEvent},
+    planner::{CapturePlanner, RatePlan, Verdict, CAPTURE_RATES},
+    preflight::{CheckStatus, PreflightReport},
+    validation::{ConfigProblem, Severity},
+    virt_channels::MuxLayout,
+    ConstConfig,
 };
-use actix_web::web::{self, Data};
+use actix_web::web;
 use anyhow::{anyhow, Result};
-use chrono::Local;
 use console::{style, Style};
-use dialoguer::{theme::ColorfulTheme, Select};
-use futures::lock;
-use parking_lot::{Mutex, RawMutex};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use parking_lot::Mutex;
 use pico_sdk::prelude::*;
-use procinfo::pid::statm_self;
 use signifix::metric;
 
-use core::f64;
 use std::{
-    borrow::Borrow,
     collections::{HashMap, VecDeque},
     convert::TryFrom,
     iter::Iterator,
-    sync::Arc,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
-    thread
 };
 
 pub fn better_theme() -> ColorfulTheme {
@@ -72,32 +78,6 @@ impl RateCalc {
     }
 }
 
-// pub fn main() -> Result<()> {
-//     if std::env::args().any(|a| a.contains("--trace")) {
-//         tracing_subscriber::fmt()
-//             .with_max_level(tracing::Level::TRACE)
-//             .with_span_events(tracing_subscriber::fmt::format::FmtSpan::ACTIVE)
-//             .init();
-//     }
-
-//     let enumerator = DeviceEnumerator::with_resolution(cache_resolution());
-//     let device = select_device(&enumerator)?;
-//     let streaming_device = device.into_streaming_device();
-//     let ch_units = configure_channels(&streaming_device);
-//     let samples_per_second = get_capture_rate();
-//     let capture_stats: Arc<dyn NewDataHandler> = CaptureStats::new(ch_units);
-//     streaming_device.new_data.subscribe(capture_stats.clone());
-
-//     println!("Press Enter to stop streaming");
-//     streaming_device.start(samples_per_second).unwrap();
-
-//     Term::stdout().read_line().unwrap();
-
-//     streaming_device.stop();
-
-//     Ok(())
-// }
-
 pub fn select_device(enumerator: &DeviceEnumerator) -> Result<PicoDevice> {
     loop {
         println!("Searching for devices...",);
@@ -266,166 +246,110 @@ pub fn get_colour(ch: PicoChannel) -> Style {
 
 pub struct CaptureStats {
     rate_calc: RateCalc,
-    ch_units: HashMap<PicoChannel, String>,
-    state: web::Data<Mutex<AppState>>,
+    /// Samples per channel streamed so far
+    streamed: AtomicU64,
+    ingest: queue::Sender<Item<RawEvent>>,
 }
 
 impl CaptureStats {
-    pub fn new(
-        ch_units: HashMap<PicoChannel, String>,
-        state: web::Data<Mutex<AppState>>,
-    ) -> Arc<Self> {
+    /// Starts the processing pipeline
+    pub fn new(state: web::Data<Mutex<AppState>>) -> Arc<Self> {
         Arc::new(CaptureStats {
             rate_calc: RateCalc::new(Duration::from_secs(5)),
-            ch_units,
-            state,
+            streamed: AtomicU64::new(0),
+            ingest: pipeline::start(&state),
         })
     }
 }
 
 impl NewDataHandler for CaptureStats {
-    #[tracing::instrument(level = "trace", skip(self, event))]
+    #[tracing::instrument(level = "trace", skip(self, event), fields(samples = event.length))]
     fn handle_event(&self, event: &StreamingEvent) {
-        let mut state_unlocked = self.state.lock();
-        if state_unlocked.recording {
-            let mut data: Vec<(PicoChannel, usize, Vec<f64>, String)> = event
-                .channels
-                .iter()
-                .map(|(ch, v)| {
-                    (
-                        *ch,
-                        v.samples.len(),
-                        v.scale_samples(),
-                        self.ch_units
-                            .get(&ch)
-                            .unwrap_or(&"".to_string())
-                            .to_string(),
-                    )
-                })
-                .collect();
-
-            data.sort_by(|a, b| a.0.cmp(&b.0));
-
-            // println!("Data Len {:?}",data);
-
-            for channel in data.clone() {
-                let key = channel.0;
-
-                (*state_unlocked
-                    .voltage_stream
-                    .entry(key.clone())
-                    .or_insert_with(|| Vec::new()))
-                .extend(channel.2.clone().into_iter());
-                (*state_unlocked
-                    .voltage_queue
-                    .entry(key.clone())
-                    .or_insert_with(|| VecDeque::new()))
-                .extend(channel.2.clone().into_iter());
-            }
-
-            let state = self.state.clone();
-
-            thread::spawn(move || {
-                split_data(state);
-            });
+        let mut channels: Vec<(PicoChannel, Vec<f64>)> = event
+            .channels
+            .iter()
+            .map(|(ch, v)| (*ch, v.scale_samples()))
+            .collect();
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let length = channels.iter().map(|c| c.1.len()).max().unwrap_or(0) as u64;
+        let start = self.streamed.fetch_add(length, Ordering::Relaxed);
+
+        // Never waits on the pipeline. If it's behind, the event is dropped here and the
+        // buffer stage records the gap it leaves
+        let queued = self.ingest.send(Item::Data(RawEvent {
+            start,
+            samples_per_second: event.samples_per_second,
+            streaming_speed: self.rate_calc.get_value(event.length),
+            channels,
+        }));
+        if !queued {
+            tracing::debug!(start, "event queue full, dropped an event");
         }
-
-        state_unlocked.streaming_speed = self.rate_calc.get_value(event.length);
-
-
-        
-
-        drop(state_unlocked);
-        // println!("Time taking for data collection is {:?} ms",Instant::now()-start);
     }
 }
 
-fn split_data(state: web::Data<Mutex<AppState>>) {
-    let mut locked_state = state.lock();
-    let pico_sped = locked_state.streaming_speed;
-    let mut channels_block = HashMap::new();
-
-    for (channel, data) in locked_state.voltage_stream.iter_mut() {
-        while data.len() > pico_sped as usize {
-            let block: Vec<f64> = data.drain(0..pico_sped as usize).collect();
-            channels_block
-                .entry(channel.to_owned())
-                .or_insert_with(|| vec![])
-                .push(block);
-        }
-    }
-
-    drop(locked_state);
-
-    let mut map_to_be_processed = vec![];
-
-    let channels: Vec<PicoChannel> = channels_block.keys().map(|a| a.to_owned()).collect();
+/// Asks for a capture rate, suggesting what suits the layouts of the enabled channels
+pub fn get_capture_rate(layouts: &[MuxLayout]) -> u32 {
+    let planner = CapturePlanner::new(layouts);
+    let plans: Vec<RatePlan> = CAPTURE_RATES.iter().map(|r| planner.assess(*r)).collect();
 
-    if channels.is_empty() {
-        return;
-    }
-
-    for i in 0..channels_block.get(&channels[0]).unwrap().len() {
-        let mut blocks = HashMap::new();
-
-        for (a, vals) in channels_block.iter() {
-            blocks.insert(a.to_owned(), vals[i].to_owned());
-        }
-
-        map_to_be_processed.push(blocks);
-    }
-
-    for a in map_to_be_processed.iter() {
-        match split_into_virt_channels(&a, pico_sped) {
-            Ok(data) => write_data(data, Some(format!("{}", Local::now().format("%F_%T"),))),
-            Err(err) => match err {
-                crate::virt_channels::VirtChannelError::NotEnoughData => {
-                    eprintln!("Well, were fucking stupid, here error: {:?}", err);
-                    continue;
-                },
-                crate::virt_channels::VirtChannelError::NoSyncPulse => {
-                    eprintln!("Can't find synchronization pulse!!!")
-                }
-            },
-        };
-    }
-}
-
-pub fn get_capture_rate() -> u32 {
-    let rates: Vec<u32> = vec![
-        1_000,
-        10_000,
-        100_000,
-        1_000_000,
-        5_000_000,
-        10_000_000,
-        20_000_000,
-        50_000_000,
-        100_000_000,
-    ];
-
-    let rate_options: Vec<String> = rates
+    let mut rate_options: Vec<String> = plans
         .iter()
-        .map(|r| {
-            let sig = metric::Signifix::try_from(*r).unwrap();
-            format!(
+        .map(|plan| {
+            let sig = metric::Signifix::try_from(plan.rate).unwrap();
+            let rate = format!(
                 "{:>8}",
                 format!("{} {}S/s", sig.integer(), sig.symbol().unwrap_or(""))
+            );
+            format!(
+                "{} - {}",
+                rate,
+                match plan.verdict {
+                    Verdict::Recommended => style(&plan.reason).green(),
+                    Verdict::Usable => style(&plan.reason).yellow(),
+                    Verdict::Refused => style(&plan.reason).red(),
+                }
             )
         })
         .collect();
+    rate_options.push("Custom rate".to_string());
 
-    let rate_selection = Select::with_theme(&better_theme())
-        .with_prompt(&format!(
-            "{}",
-            style("Select capture rate").blue().underlined().bold()
-        ))
-        .default(0)
-        .items(&rate_options[..])
-        .interact()
-        .unwrap();
+    let recommended = planner.recommend(&CAPTURE_RATES);
+    loop {
+        let rate_selection = Select::with_theme(&better_theme())
+            .with_prompt(&format!(
+                "{}",
+                style("Select capture rate").blue().underlined().bold()
+            ))
+            .default(
+                recommended
+                    .and_then(|r| CAPTURE_RATES.iter().position(|c| *c == r))
+                    .unwrap_or(0),
+            )
+            .items(&rate_options[..])
+            .interact()
+            .unwrap();
 
-    rates[rate_selection]
+        let plan = match plans.get(rate_selection) {
+            Some(plan) => plan.clone(),
+            None => planner.assess(
+                Input::with_theme(&better_theme())
+                    .with_prompt("Samples per second")
+                    .interact()
+                    .unwrap(),
+            ),
+        };
+
+        match plan.verdict {
+            Verdict::Refused => println!(
+                "{} {}",
+                style("Can't capture at that rate:").red().bold(),
+                plan.reason
+            ),
+            _ => return plan.rate,
+        }
+    }
 }
 
 pub fn select_range(ranges: &[PicoRange]) -> Option<PicoRange> {
@@ -450,7 +374,7 @@ pub fn select_range(ranges: &[PicoRange]) -> Option<PicoRange> {
 }
 
 pub fn print_stats(state: &web::Data<Mutex<AppState>>) {
-    let unlocked_state = state.lock();
+    let (live, channel_map) = state.lock().live_and_map();
     // Streaming Rate
     println!(
         "{} @ {}",
@@ -459,7 +383,7 @@ pub fn print_stats(state: &web::Data<Mutex<AppState>>) {
             "{}",
             style(format!(
                 "{}S/s",
-                match metric::Signifix::try_from(unlocked_state.streaming_speed) {
+                match metric::Signifix::try_from(live.streaming_speed.load(Ordering::Relaxed)) {
                     Ok(v) => format!("{}", v),
                     Err(metric::Error::OutOfLowerBound(_)) => "0".to_string(),
                     _ => panic!("unknown error"),
@@ -468,25 +392,34 @@ pub fn print_stats(state: &web::Data<Mutex<AppState>>) {
             .bold()
         )
     );
+    let memory = live.memory_report();
     // Data Collected
     println!(
         "{} -> {}",
         format!("{}", style("Data Collected").green().bold()),
-        style(format!("{} samples", &unlocked_state.voltage_stream.len())).bold()
+        style(format!(
+            "{} samples",
+            live.voltage_queue
+                .lock()
+                .values()
+                .map(|b| b.len())
+                .sum::<usize>()
+        ))
+        .bold()
     );
     // Attempt to get memory usage
     let memory_usage = format!(
         "{}",
-        match statm_self() {
-            Ok(s) => format!(
+        match memory.process.as_ref() {
+            Some(process) => format!(
                 "{}B",
-                match metric::Signifix::try_from(s.data) {
+                match metric::Signifix::try_from(process.resident_bytes) {
                     Ok(v) => format!("{}", v),
                     Err(metric::Error::OutOfLowerBound(_)) => "0".to_string(),
                     _ => panic!("unknown error"),
                 }
             ),
-            Err(_) => format!("<Unsupported OS>"),
+            None => format!("<Unsupported OS>"),
         }
     );
     println!(
@@ -494,27 +427,226 @@ pub fn print_stats(state: &web::Data<Mutex<AppState>>) {
         format!("{}", style("Memory Usage").green().bold()),
         style(memory_usage).bold()
     );
-    drop(unlocked_state)
+    for warning in memory.warnings.iter() {
+        println!("{} {}", style("Memory").yellow().bold(), warning);
+    }
+    // Pipeline Queues
+    for queue in live.pipeline_report() {
+        let depth = format!("{}/{}", queue.depth, queue.capacity);
+        println!(
+            "{} {} -> {} ({} dropped)",
+            style("Pipeline").green().bold(),
+            queue.name,
+            if queue.depth >= queue.capacity {
+                style(depth).yellow()
+            } else {
+                style(depth).bold()
+            },
+            queue.dropped
+        );
+    }
+    // Signal Quality
+    for channel in live.quality_report(&channel_map) {
+        let score = format!("{:>3.0} ({})", channel.metrics.score, channel.metrics.rating);
+        println!(
+            "{} {} {} -> {} {}",
+            style("Quality").green().bold(),
+            channel.kind,
+            channel.channel,
+            match channel.metrics.rating {
+                "good" => style(score).green(),
+                "fair" => style(score).yellow(),
+                _ => style(score).red(),
+            }
+            .bold(),
+            channel.metrics.issues.join(", ")
+        );
+    }
+}
+pub fn print_preflight(report: &PreflightReport) {
+    for check in report.checks.iter() {
+        let mark = match check.status {
+            CheckStatus::Pass => style("✔").bold().green(),
+            CheckStatus::Warn => style("!").bold().yellow(),
+            CheckStatus::Fail => style("✘").bold().red(),
+        };
+        println!("{} {} -> {}", mark, style(&check.name).bold(), check.detail);
+    }
+    println!(
+        "{} {}",
+        style("Preflight").green().bold(),
+        match report.status {
+            CheckStatus::Pass => style("passed").green(),
+            CheckStatus::Warn => style("passed with warnings").yellow(),
+            CheckStatus::Fail => style("failed").red(),
+        }
+        .bold()
+    );
+}
+
+/// Walks through applying each reference voltage to every input, then fits, saves and
+/// applies the calibration. Readings come straight from the latest raw samples, so
+/// whatever calibration is in effect doesn't affect them
+pub fn calibrate(state: &web::Data<Mutex<AppState>>) -> Result<()> {
+    let const_config = ConstConfig::get_config();
+    if state.lock().recording {
+        return Err(anyhow!("stop recording before calibrating"));
+    }
+
+    let references: String = Input::with_theme(&better_theme())
+        .with_prompt("Reference voltages, comma separated")
+        .default("0, 0.5, 1".to_string())
+        .interact()?;
+    let references = references
+        .split(',')
+        .map(|r| r.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()?;
+
+    let mut readings = vec![];
+    for reference in references.iter() {
+        if !Confirm::with_theme(&better_theme())
+            .with_prompt(format!(
+                "Apply {} V to every input, then continue",
+                reference
+            ))
+            .default(true)
+            .interact()?
+        {
+            return Err(anyhow!("calibration cancelled"));
+        }
+        // Lets the quality window fill with samples at this reference only
+        std::thread::sleep(const_config.quality_window);
+        let quality = state.lock().live.quality.clone();
+        let (raw, sample_rate) = quality.lock().raw_samples();
+        readings.push(calibration::measure(&raw, sample_rate)?);
+        println!("{} {} V", style("Recorded").green().bold(), reference);
+    }
+
+    let calibration = Calibration::fit(&references, &readings)?;
+    for fit in calibration.fits.iter() {
+        println!(
+            "{} {} {} -> gain {:.4}, offset {:+.4} V, residual {:.4} V",
+            style("Calibration").green().bold(),
+            fit.channel,
+            fit.virt_channel,
+            fit.gain,
+            fit.offset,
+            fit.residual
+        );
+    }
+    if !Confirm::with_theme(&better_theme())
+        .with_prompt("Save and apply this calibration?")
+        .default(true)
+        .interact()?
+    {
+        return Ok(());
+    }
+
+    state.lock().apply_calibration(calibration)?;
+    println!(
+        "Saved calibration to {}",
+        style(const_config.calibration_file).bold()
+    );
+    Ok(())
 }
+
+/// Estimates the crosstalk between adjacent slots from the latest samples, reporting
+/// how much compensating would remove, and saves it. Best done with every input
+/// carrying its own unrelated signal
+pub fn estimate_crosstalk(state: &web::Data<Mutex<AppState>>) -> Result<()> {
+    let const_config = ConstConfig::get_config();
+    let locked_state = state.lock();
+    // A session is compensated all the same way, as its provenance says
+    if locked_state.recording {
+        return Err(anyhow!("stop recording before estimating crosstalk"));
+    }
+    let live = locked_state.live.clone();
+    drop(locked_state);
+    let (raw, sample_rate) = live.quality.lock().raw_samples();
+    let calibration = live.corrections.lock().calibration.clone();
+
+    let crosstalk = Crosstalk::estimate(&raw, sample_rate, calibration.as_deref())?;
+    for channel in crosstalk.channels.iter() {
+        let coupling: Vec<String> = (1..channel.matrix.len())
+            .map(|i| format!("{}→{} {:+.4}", i - 1, i, channel.matrix[i][i - 1]))
+            .collect();
+        println!(
+            "{} {} -> {} (mean {:.4} down to {:.4})",
+            style("Crosstalk").green().bold(),
+            channel.channel,
+            coupling.join(", "),
+            channel.before,
+            channel.after
+        );
+    }
+    println!(
+        "{} compensating removes {} of the crosstalk, checked on {} frames",
+        style("Crosstalk").green().bold(),
+        style(format!("{:.1}%", crosstalk.reduction() * 100.0)).bold(),
+        crosstalk.frames - crosstalk.frames / 2
+    );
+    if !Confirm::with_theme(&better_theme())
+        .with_prompt("Save this estimate?")
+        .default(true)
+        .interact()?
+    {
+        return Ok(());
+    }
+
+    let path = Path::new(const_config.crosstalk_file);
+    crosstalk.save(path)?;
+    println!("Saved crosstalk to {}", style(path.display()).bold());
+    if const_config.crosstalk_compensation {
+        let locked_state = state.lock();
+        // Recording may have started while the estimate was being confirmed
+        if locked_state.recording {
+            return Err(anyhow!("saved, but not applied as recording has started"));
+        }
+        locked_state.live.corrections.lock().crosstalk = Some(Arc::new(crosstalk));
+    } else {
+        println!("Turn on crosstalk_compensation to compensate it while recording");
+    }
+    Ok(())
+}
+
+pub fn print_config_problems(problems: &[ConfigProblem]) {
+    for problem in problems {
+        let (mark, severity) = match problem.severity {
+            Severity::Error => (style("✘").bold().red(), style("Error ").bold().red()),
+            Severity::Warning => (style("!").bold().yellow(), style("Warning ").bold().yellow()),
+        };
+        eprintln!(
+            "{} {}{} {}\n        {}",
+            mark,
+            severity,
+            style(problem.field).bold().green(),
+            problem.message,
+            problem.hint
+        );
+    }
+}
+
+/// Takes the latest samples out of the memory queue. The stream buffer is bounded and
+/// still owed to the demultiplexer, so it's left alone
 pub fn clear_and_get_memory(
     state: web::Data<Mutex<AppState>>,
     completely_clear: bool,
-) -> HashMap<PicoChannel, VecDeque<f64>> {
-    let mut state_unlocked = state.lock();
-    let voltages = state_unlocked.voltage_queue.clone();
+) -> HashMap<PicoChannel, Vec<f64>> {
+    let voltage_queue = state.lock().live.voltage_queue.clone();
+    let mut voltage_queue = voltage_queue.lock();
+    let voltages = voltage_queue
+        .iter()
+        .map(|(channel, buffer)| (*channel, buffer.to_vec()))
+        .collect();
     if completely_clear {
-        state_unlocked.voltage_queue.clear();
-        state_unlocked.voltage_stream.clear();
+        // Frees the buffers too, they're made again as samples come in
+        voltage_queue.clear();
     } else {
-        for channel in voltages.keys() {
-            state_unlocked
-                .voltage_queue
-                .get_mut(channel)
-                .unwrap()
-                .clear()
+        for buffer in voltage_queue.values_mut() {
+            buffer.clear();
         }
     }
 
-    drop(state_unlocked);
+    drop(voltage_queue);
     return voltages;
 }
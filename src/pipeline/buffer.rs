@@ -0,0 +1,390 @@
+use super::{
+    queue::{Receiver, Sender},
+    Block, Item, Marker, Purpose, RawEvent, Recording, Stop,
+};
+use crate::{
+    app::state::LiveState,
+    catalogue::{EventKind, EventLog},
+    electrodes::ChannelMap,
+    memory::{OverflowPolicy, RingBuffer},
+    metrics::COUNTERS,
+    recorder::ChunkRecorder,
+    session::SessionGap,
+    ConstConfig,
+};
+
+use pico_sdk::common::PicoChannel;
+
+use std::{collections::HashMap, sync::Arc};
+
+/// The recording blocks are being cut for
+struct ActiveRecording {
+    session: String,
+    /// Crash-safe copy of the raw stream
+    recorder: Option<ChunkRecorder>,
+    /// Samples per channel handed to the demultiplexer since recording started
+    processed_samples: u64,
+    processed_blocks: u64,
+    gaps: Vec<SessionGap>,
+}
+
+/// State of the buffer stage, which only it touches
+pub(super) struct Buffer {
+    live: LiveState,
+    channel_map: Arc<ChannelMap>,
+    events: EventLog,
+    /// Raw samples waiting to be cut into blocks for the demultiplexer
+    stream: HashMap<PicoChannel, RingBuffer>,
+    /// Samples collected while not recording, to check the virtual channels with
+    preview: HashMap<PicoChannel, Vec<f64>>,
+    recording: Option<ActiveRecording>,
+    /// Of the latest event, so the last block of a recording is cut the same size
+    block_size: u64,
+    sample_rate: f64,
+}
+
+/// Buffers every event, cutting the stream into blocks for the demultiplexer
+pub(super) fn run(
+    mut buffer: Buffer,
+    events: Receiver<Item<RawEvent>>,
+    blocks: Sender<Item<Block>>,
+) {
+    // Where the next event should start, to notice any the callback had to drop
+    let mut expected_start = None;
+
+    for item in events {
+        match item {
+            Item::Data(event) => {
+                let missing = expected_start
+                    .map(|start| event.start.saturating_sub(start))
+                    .unwrap_or(0);
+                let length = event.channels.iter().map(|c| c.1.len()).max().unwrap_or(0) as u64;
+                expected_start = Some(event.start + length);
+
+                for block in buffer.buffer_event(event, missing) {
+                    blocks.send(Item::Data(block));
+                }
+            }
+            Item::Marker(Marker::Start(mut recording)) => {
+                buffer.start(&mut recording);
+                blocks.send_wait(Item::Marker(Marker::Start(recording)));
+            }
+            Item::Marker(Marker::Stop(mut stop)) => {
+                // The end of the session, so it's worth waiting for
+                if let Some(block) = buffer.stop(&mut stop) {
+                    blocks.send_wait(Item::Data(block));
+                }
+                blocks.send_wait(Item::Marker(Marker::Stop(stop)));
+            }
+        }
+    }
+}
+
+impl Buffer {
+    pub(super) fn new(live: LiveState, channel_map: Arc<ChannelMap>, events: EventLog) -> Self {
+        Buffer {
+            live,
+            channel_map,
+            events,
+            stream: HashMap::new(),
+            preview: HashMap::new(),
+            recording: None,
+            block_size: 0,
+            sample_rate: 0.0,
+        }
+    }
+
+    fn log(&self, kind: EventKind, detail: &str) {
+        let session = self.recording.as_ref().map(|r| r.session.as_str());
+        self.events.log(session, kind, detail);
+    }
+
+    fn block(&self, purpose: Purpose, channels: HashMap<PicoChannel, Vec<f64>>) -> Block {
+        Block {
+            purpose,
+            size: self.block_size,
+            channels,
+            corrections: self.live.corrections.lock().clone(),
+            channel_map: self.channel_map.clone(),
+        }
+    }
+
+    /// Takes the raw recorder, starting the session's blocks from the next event
+    fn start(&mut self, recording: &mut Recording) {
+        self.recording = Some(ActiveRecording {
+            session: recording.session.name.clone(),
+            recorder: recording.recorder.take(),
+            processed_samples: 0,
+            processed_blocks: 0,
+            gaps: vec![],
+        });
+        self.preview.clear();
+        // Left over from the last recording
+        self.stream.clear();
+    }
+
+    /// Cuts whatever is still buffered into a last, shorter block, and finishes the raw
+    /// recording
+    fn stop(&mut self, stop: &mut Stop) -> Option<Block> {
+        let mut recording = self.recording.take()?;
+
+        let rest: HashMap<PicoChannel, Vec<f64>> = self
+            .stream
+            .iter_mut()
+            .map(|(channel, buffer)| (*channel, buffer.take_front(buffer.len())))
+            .collect();
+        let length = rest.values().map(|s| s.len()).max().unwrap_or(0) as u64;
+        let last = if length > 0 {
+            let block = self.block(
+                Purpose::Record {
+                    sequence: recording.processed_blocks,
+                    start: recording.processed_samples,
+                },
+                rest,
+            );
+            recording.processed_blocks += 1;
+            recording.processed_samples += length;
+            Some(block)
+        } else {
+            None
+        };
+
+        match recording.recorder.take().map(|r| r.finish()) {
+            Some(Ok(index)) => stop.index = index,
+            Some(Err(err)) => tracing::error!(?err, "could not finish raw recording"),
+            None => {}
+        }
+        stop.blocks = recording.processed_blocks;
+        stop.samples = recording.processed_samples;
+        stop.gaps = recording.gaps;
+        self.publish();
+        last
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, event), fields(start = event.start))]
+    fn buffer_event(&mut self, event: RawEvent, missing: u64) -> Vec<Block> {
+        self.sample_rate = event.samples_per_second as f64;
+        self.block_size = event.streaming_speed;
+        self.live
+            .streaming_speed
+            .store(event.streaming_speed, std::sync::atomic::Ordering::Relaxed);
+
+        // Quality is checked whether recording or not, so electrodes can be fixed first
+        let mut quality = self.live.quality.lock();
+        for (channel, samples) in event.channels.iter() {
+            quality.push_raw(*channel, samples, self.sample_rate);
+        }
+        drop(quality);
+
+        let blocks = if self.recording.is_some() {
+            self.record_event(event, missing)
+        } else {
+            self.preview_event(event)
+        };
+        self.publish();
+        blocks
+    }
+
+    fn record_event(&mut self, event: RawEvent, missing: u64) -> Vec<Block> {
+        if missing > 0 {
+            self.skip_missing(missing);
+        }
+
+        if let Some(recorder) = self.recording.as_mut().and_then(|r| r.recorder.as_mut()) {
+            for (channel, samples) in event.channels.iter() {
+                // Dropped events never reached the recorder, so it's moved past them.
+                // Samples the stream buffer drops have already been written, in place
+                recorder.skip(*channel, missing);
+                if let Err(err) = recorder.append(*channel, samples) {
+                    tracing::error!(?err, %channel, "could not write raw chunk");
+                }
+            }
+        }
+
+        let const_config = ConstConfig::get_config();
+        let sample_rate = self.sample_rate;
+        let mut voltage_queue = self.live.voltage_queue.lock();
+        let mut dropped = 0;
+        for (channel, samples) in event.channels.iter() {
+            voltage_queue
+                .entry(*channel)
+                .or_insert_with(|| {
+                    RingBuffer::new(
+                        (const_config.memory_queue_duration.as_secs_f64() * sample_rate) as usize,
+                        const_config.memory_queue_policy,
+                    )
+                })
+                .push(samples);
+            let stream_dropped = self
+                .stream
+                .entry(*channel)
+                .or_insert_with(|| {
+                    RingBuffer::new(
+                        (const_config.stream_buffer_duration.as_secs_f64() * sample_rate) as usize,
+                        const_config.stream_buffer_policy,
+                    )
+                })
+                .push(samples);
+            dropped = dropped.max(stream_dropped);
+        }
+        drop(voltage_queue);
+        // Dropped from the front, so blocks are cut after what's gone
+        let policy = const_config.stream_buffer_policy;
+        if dropped > 0 && policy == OverflowPolicy::DropOldest {
+            self.record_overflow(dropped);
+        }
+
+        // Blocks get their place in the session here, so they keep stream order
+        let mut blocks = vec![];
+        if self.block_size > 0 {
+            for channels in take_blocks(&mut self.stream, self.block_size as usize) {
+                let recording = self.recording.as_ref().unwrap();
+                let purpose = Purpose::Record {
+                    sequence: recording.processed_blocks,
+                    start: recording.processed_samples,
+                };
+                blocks.push(self.block(purpose, channels));
+
+                let recording = self.recording.as_mut().unwrap();
+                tracing::trace!(sequence = recording.processed_blocks, "cut block");
+                recording.processed_blocks += 1;
+                recording.processed_samples += self.block_size;
+            }
+        }
+        // Dropped from the end, so the whole blocks before the hole were still cut
+        if dropped > 0 && policy == OverflowPolicy::DropNewest {
+            self.record_overflow(dropped);
+        }
+        blocks
+    }
+
+    /// Demultiplexes a block now and then, just to check the virtual channels
+    fn preview_event(&mut self, event: RawEvent) -> Vec<Block> {
+        for (channel, mut samples) in event.channels {
+            self.preview
+                .entry(channel)
+                .or_insert_with(Vec::new)
+                .append(&mut samples);
+        }
+
+        let block_size = self.block_size as usize;
+        let full = self.preview.values().any(|v| v.len() >= block_size);
+        if block_size > 0 && full {
+            // Cut to the block size like the recording's blocks, so preview frames can be
+            // timed from the block count
+            let channels = self
+                .preview
+                .iter_mut()
+                .map(|(channel, samples)| {
+                    let rest = samples.split_off(block_size.min(samples.len()));
+                    (*channel, std::mem::replace(samples, rest))
+                })
+                .collect();
+            vec![self.block(Purpose::Preview, channels)]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Fill of the stream buffers, for the memory report
+    fn publish(&self) {
+        let mut channels: Vec<_> = self.stream.iter().collect();
+        channels.sort_by_key(|(channel, _)| **channel);
+        *self.live.stream_buffers.lock() = channels
+            .into_iter()
+            .map(|(channel, buffer)| buffer.stats("stream buffer", channel.to_string()))
+            .collect();
+    }
+
+    fn add_gap(&mut self, start: f64, duration: f64) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.gaps.push(SessionGap {
+                start,
+                duration,
+                reason: EventKind::StreamOverflow.as_str().to_string(),
+            });
+        }
+    }
+
+    /// Events dropped by the callback leave a hole in the stream. What's buffered before
+    /// it can't make a whole block any more, so it goes too, and the lot is noted as a gap
+    fn skip_missing(&mut self, missing: u64) {
+        let mut buffered = 0;
+        for buffer in self.stream.values_mut() {
+            buffered = buffered.max(buffer.len() as u64);
+            buffer.clear();
+        }
+
+        let sample_rate = self.sample_rate;
+        let recording = match self.recording.as_mut() {
+            Some(recording) => recording,
+            None => return,
+        };
+        let time = recording.processed_samples as f64 / sample_rate;
+        recording.processed_samples += buffered + missing;
+        COUNTERS.dropped((buffered + missing) as f64 / sample_rate);
+        self.add_gap(time, (buffered + missing) as f64 / sample_rate);
+        tracing::warn!(missing, buffered, time, "events dropped before buffering");
+        self.log(
+            EventKind::StreamOverflow,
+            &format!(
+                "Dropped {} streamed samples per channel at {:.3}s, processing fell behind",
+                missing, time
+            ),
+        );
+    }
+
+    /// Notes samples dropped by a full stream buffer as a gap in the session, moving the
+    /// next block past it
+    fn record_overflow(&mut self, dropped: usize) {
+        let sample_rate = self.sample_rate;
+        let lost = match ConstConfig::get_config().stream_buffer_policy {
+            // Lost from the front, so the next block starts that much later
+            OverflowPolicy::DropOldest => dropped as u64,
+            // Lost from the end of what's buffered. What's left before the hole can't make a
+            // whole block any more, so it goes too, as with dropped events
+            OverflowPolicy::DropNewest => {
+                let mut buffered = 0;
+                for buffer in self.stream.values_mut() {
+                    buffered = buffered.max(buffer.len() as u64);
+                    buffer.clear();
+                }
+                buffered + dropped as u64
+            }
+        };
+        let recording = match self.recording.as_mut() {
+            Some(recording) => recording,
+            None => return,
+        };
+        let time = recording.processed_samples as f64 / sample_rate;
+        recording.processed_samples += lost;
+
+        COUNTERS.dropped(lost as f64 / sample_rate);
+        self.add_gap(time, lost as f64 / sample_rate);
+        tracing::warn!(dropped, lost, time, "stream buffer overflowed");
+        self.log(
+            EventKind::StreamOverflow,
+            &format!("Dropped {} samples per channel at {:.3}s", lost, time),
+        );
+    }
+}
+
+/// Cuts every full block of `size` samples off the front of the buffers, leaving the
+/// rest buffered
+fn take_blocks(
+    buffers: &mut HashMap<PicoChannel, RingBuffer>,
+    size: usize,
+) -> Vec<HashMap<PicoChannel, Vec<f64>>> {
+    let count = buffers.values().map(|b| b.len() / size).min().unwrap_or(0);
+    (0..count)
+        .map(|_| {
+            buffers
+                .iter_mut()
+                .map(|(channel, buffer)| (*channel, buffer.take_front(size)))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,242 @@
+//! The buffer stage on its own, fed events and markers as the streaming callback and
+//! the app would send them
+
+use super::*;
+use crate::{
+    app::state::{AppState, DeviceInfo},
+    pipeline::queue::{bounded, Backpressure},
+    pyramid::PyramidWriter,
+    quality::sync::SyncLog,
+    recorder::{chunk::read_chunk, chunk_files, RecordingIndex},
+    session::SessionMetadata,
+};
+
+use chrono::Local;
+use tempfile::TempDir;
+
+use std::{fs::File, io::BufReader, sync::mpsc};
+
+/// Samples per second of every event, small enough to overflow the stream buffer
+const SAMPLE_RATE: u32 = 100;
+const BLOCK_SIZE: u64 = 50;
+
+fn device_info() -> DeviceInfo {
+    DeviceInfo {
+        pico_scope_type: "test".to_string(),
+        channel_info: vec![],
+        refresh_rate: SAMPLE_RATE,
+    }
+}
+
+fn event(start: u64, length: usize) -> Item<RawEvent> {
+    Item::Data(RawEvent {
+        start,
+        samples_per_second: SAMPLE_RATE,
+        streaming_speed: BLOCK_SIZE,
+        channels: vec![
+            (
+                PicoChannel::A,
+                (start..start + length as u64).map(|i| i as f64).collect(),
+            ),
+            (PicoChannel::B, vec![0.5; length]),
+        ],
+    })
+}
+
+/// A session recorded into `directory`, with the raw recorder the buffer stage takes
+fn start(directory: &TempDir) -> Item<RawEvent> {
+    let path = directory.path();
+    let session = SessionMetadata::new("buffer".to_string(), &device_info());
+    Item::Marker(Marker::Start(Box::new(Recording {
+        session,
+        recorder: Some(ChunkRecorder::create(path).unwrap()),
+        pyramid: PyramidWriter::create(path, vec![]).unwrap(),
+        sync_log: SyncLog::create(path).unwrap(),
+    })))
+}
+
+fn stop() -> Item<RawEvent> {
+    let (reply, _) = mpsc::channel();
+    Item::Marker(Marker::Stop(Stop {
+        stopped: Local::now(),
+        index: RecordingIndex::default(),
+        blocks: 0,
+        samples: 0,
+        gaps: vec![],
+        reply,
+    }))
+}
+
+/// Runs the items through the buffer stage, returning everything it sent on
+fn run_items(items: Vec<Item<RawEvent>>) -> Vec<Item<Block>> {
+    let state = AppState::new(device_info());
+    let buffer = Buffer::new(
+        state.live.clone(),
+        Arc::new(ChannelMap::default()),
+        EventLog::default(),
+    );
+    let (sender, events) = bounded("events", items.len(), Backpressure::Block);
+    for item in items {
+        sender.send(item);
+    }
+    drop(sender);
+
+    let (blocks, out) = bounded("blocks", 64, Backpressure::Block);
+    run(buffer, events, blocks);
+    out.collect()
+}
+
+/// As `run_items`, returning the blocks it cut and the stop marker it filled in
+fn run_buffer(items: Vec<Item<RawEvent>>) -> (Vec<Block>, Stop) {
+    let (mut cut, mut stopped) = (vec![], None);
+    for item in run_items(items) {
+        match item {
+            Item::Data(block) => cut.push(block),
+            Item::Marker(Marker::Stop(stop)) => stopped = Some(stop),
+            Item::Marker(Marker::Start(_)) => {}
+        }
+    }
+    (cut, stopped.expect("no stop marker"))
+}
+
+fn record_start(block: &Block) -> u64 {
+    match block.purpose {
+        Purpose::Record { start, .. } => start,
+        Purpose::Preview => panic!("a preview block while recording"),
+    }
+}
+
+/// First sample of every raw record of channel A
+fn raw_first_samples(directory: &TempDir) -> Vec<u64> {
+    let mut first_samples = vec![];
+    for path in chunk_files(directory.path()).unwrap() {
+        read_chunk(BufReader::new(File::open(path).unwrap()), |record| {
+            if record.channel == PicoChannel::A.to_string() {
+                first_samples.push(record.first_sample);
+            }
+        })
+        .unwrap();
+    }
+    first_samples
+}
+
+#[test]
+fn raw_chunks_keep_their_place_past_dropped_events() {
+    let directory = tempfile::tempdir().unwrap();
+    // 120 samples per channel dropped by the callback between the second and third
+    let (blocks, stop) = run_buffer(vec![
+        start(&directory),
+        event(0, 40),
+        event(40, 40),
+        event(200, 40),
+        stop(),
+    ]);
+
+    // A block from the first 50, then the 30 buffered before the hole go with it
+    let starts: Vec<u64> = blocks.iter().map(record_start).collect();
+    assert_eq!(starts, [0, 200]);
+    assert_eq!(blocks[1].channels[&PicoChannel::A][0], 200.0);
+    assert_eq!(stop.samples, 240);
+    assert_eq!(stop.gaps.len(), 1);
+    assert_eq!(stop.gaps[0].start, 0.5);
+    assert_eq!(stop.gaps[0].duration, 1.5);
+
+    // Raw records count from the start of the session too, past the dropped events
+    assert_eq!(raw_first_samples(&directory), [0, 40, 200]);
+    let span = stop.index.chunks[0].channels[&PicoChannel::A.to_string()];
+    assert_eq!((span.first, span.count, span.skipped), (0, 240, 120));
+    assert_eq!(stop.index.sample_counts()[&PicoChannel::A.to_string()], 120);
+}
+
+#[test]
+fn markers_keep_their_place_among_blocks() {
+    let directory = tempfile::tempdir().unwrap();
+    let items = run_items(vec![
+        event(0, 60),
+        start(&directory),
+        event(60, 60),
+        stop(),
+        event(120, 60),
+    ]);
+
+    // Previewed either side of the recording, which starts afresh and ends with the
+    // 10 samples left over. Preview blocks are cut to size too
+    let order: Vec<String> = items
+        .iter()
+        .map(|item| match item {
+            Item::Data(Block {
+                purpose: Purpose::Record { start, .. },
+                size,
+                channels,
+                ..
+            }) => format!(
+                "record {} {}/{}",
+                start,
+                channels[&PicoChannel::A].len(),
+                size
+            ),
+            Item::Data(block) => format!("preview {}", block.channels[&PicoChannel::A].len()),
+            Item::Marker(Marker::Start(_)) => "start".to_string(),
+            Item::Marker(Marker::Stop(stop)) => format!("stop {}", stop.samples),
+        })
+        .collect();
+    assert_eq!(
+        order,
+        [
+            "preview 50",
+            "start",
+            "record 0 50/50",
+            "record 50 10/50",
+            "stop 60",
+            "preview 50"
+        ]
+    );
+}
+
+#[test]
+fn overflow_moves_the_next_block_past_the_hole() {
+    let directory = tempfile::tempdir().unwrap();
+    let capacity = (ConstConfig::get_config()
+        .stream_buffer_duration
+        .as_secs_f64()
+        * SAMPLE_RATE as f64) as u64;
+    let (blocks, stop) = run_buffer(vec![
+        start(&directory),
+        event(0, capacity as usize + 40),
+        event(capacity + 40, 50),
+        stop(),
+    ]);
+
+    let starts: Vec<u64> = blocks.iter().map(record_start).collect();
+    let gap = stop.gaps[0].clone();
+    let (expected, hole): (Vec<u64>, u64) = match ConstConfig::get_config().stream_buffer_policy {
+        // The whole blocks before the hole are still cut, and the 40 that didn't fit
+        // are skipped
+        OverflowPolicy::DropNewest => (
+            (0..capacity / BLOCK_SIZE)
+                .map(|n| n * BLOCK_SIZE)
+                .chain(Some(capacity + 40))
+                .collect(),
+            capacity,
+        ),
+        // The oldest 40 make way, so blocks start after them
+        OverflowPolicy::DropOldest => (
+            (0..=capacity / BLOCK_SIZE)
+                .map(|n| 40 + n * BLOCK_SIZE)
+                .collect(),
+            0,
+        ),
+    };
+    assert_eq!(starts, expected);
+    // Each block still holds the samples it says it starts at
+    for block in blocks.iter() {
+        assert_eq!(
+            block.channels[&PicoChannel::A][0],
+            record_start(block) as f64
+        );
+    }
+    assert_eq!(stop.gaps.len(), 1);
+    assert_eq!(gap.start, hole as f64 / SAMPLE_RATE as f64);
+    assert_eq!(gap.duration, 0.4);
+    assert_eq!(stop.samples, capacity + 90);
+}
@@ -0,0 +1,166 @@
+//! Processing after acquisition, as a chain of stages on their own threads:
+//! buffer → demux → sinks, the demux stage being a pool of workers. Stages are joined
+//! by bounded queues, and
+//! the streaming callback only ever tries to queue, so a slow stage or a slow web
+//! request costs dropped events rather than stalling acquisition. Each stage owns its
+//! state, publishing what the web app shows through the `LiveState`, and recordings
+//! start and stop by markers that keep their place among the data
+
+mod buffer;
+mod pool;
+pub mod queue;
+mod sinks;
+
+use crate::{
+    app::state::AppState,
+    calibration::Calibration,
+    crosstalk::Crosstalk,
+    electrodes::ChannelMap,
+    pyramid::PyramidWriter,
+    quality::sync::{ChannelSync, SyncLog},
+    recorder::{ChunkRecorder, RecordingIndex},
+    session::{SessionGap, SessionMetadata},
+    virt_channels::{VirtChannelError, VirtFrame},
+    ConstConfig,
+};
+use queue::{bounded, Backpressure, Sender};
+
+use actix_web::web;
+use chrono::{DateTime, Local};
+use parking_lot::Mutex;
+use pico_sdk::common::PicoChannel;
+
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// Scaled samples of one streaming event
+pub struct RawEvent {
+    /// Samples per channel streamed before this event
+    pub start: u64,
+    pub samples_per_second: u32,
+    /// Measured over the last few seconds, which sets the block size
+    pub streaming_speed: u64,
+    pub channels: Vec<(PicoChannel, Vec<f64>)>,
+}
+
+/// What passes from stage to stage: data, or a marker that keeps its place among it
+pub enum Item<T> {
+    Data(T),
+    Marker(Marker),
+}
+
+pub enum Marker {
+    /// Everything after it is recorded into this session
+    Start(Box<Recording>),
+    /// Finishes the session once everything before it has been written
+    Stop(Stop),
+}
+
+/// Files of a session being started, each stage taking the ones it writes
+pub struct Recording {
+    pub session: SessionMetadata,
+    /// Taken by the buffer stage
+    pub recorder: Option<ChunkRecorder>,
+    pub pyramid: PyramidWriter,
+    pub sync_log: SyncLog,
+}
+
+pub struct Stop {
+    pub stopped: DateTime<Local>,
+    /// Filled in by the buffer stage, once it's written the last raw chunk
+    pub index: RecordingIndex,
+    /// Blocks and samples per channel the buffer stage cut, so the sinks can tell if
+    /// the last few were dropped
+    pub blocks: u64,
+    pub samples: u64,
+    /// Dropped by the buffer stage, before any block was cut
+    pub gaps: Vec<SessionGap>,
+    /// Told once the session is saved
+    pub reply: mpsc::Sender<Stopped>,
+}
+
+pub struct Stopped {
+    pub session: SessionMetadata,
+    /// Hashing every file takes a while, so the manifest is written in the background.
+    /// Join it before exiting
+    pub manifest: thread::JoinHandle<()>,
+}
+
+/// Corrections applied to every block demultiplexed from when they're set
+#[derive(Clone, Default)]
+pub struct Corrections {
+    pub calibration: Option<Arc<Calibration>>,
+    /// Only set if compensation is on
+    pub crosstalk: Option<Arc<Crosstalk>>,
+}
+
+/// What a block is demultiplexed for
+#[derive(Clone, Copy, Debug)]
+pub enum Purpose {
+    /// Part of the recording, `start` samples into the session
+    Record { sequence: u64, start: u64 },
+    /// Streamed while not recording, to check the virtual channels with
+    Preview,
+}
+
+pub struct Block {
+    pub purpose: Purpose,
+    /// Samples per channel, and the sample rate the demultiplexer assumes
+    pub size: u64,
+    pub channels: HashMap<PicoChannel, Vec<f64>>,
+    /// In effect when the block was cut, so a block is corrected all the same way
+    pub corrections: Corrections,
+    pub channel_map: Arc<ChannelMap>,
+}
+
+struct DemuxedBlock {
+    purpose: Purpose,
+    size: u64,
+    frames: Result<Vec<VirtFrame>, VirtChannelError>,
+    /// In channel order
+    sync: Vec<ChannelSync>,
+}
+
+/// Starts every stage, returning the queue into the first. A copy is kept in the app
+/// state for its markers, so the stages run as long as the app
+pub fn start(state: &web::Data<Mutex<AppState>>) -> Sender<Item<RawEvent>> {
+    let const_config = ConstConfig::get_config();
+    let policy = const_config.pipeline_backpressure;
+    let depth = const_config.pipeline_block_queue_depth;
+
+    // Always drops, whatever the policy, so the streaming callback never waits
+    let (ingest, events) = bounded(
+        "events",
+        const_config.pipeline_event_queue_depth,
+        Backpressure::Drop,
+    );
+    let (blocks_sender, blocks) = bounded("blocks", depth, policy);
+    let (demuxed_sender, demuxed) = bounded("demuxed", depth, policy);
+
+    let blocks_stats = blocks_sender.stats();
+    let demuxed_stats = demuxed_sender.stats();
+
+    let mut locked_state = state.lock();
+    let buffer = buffer::Buffer::new(
+        locked_state.live.clone(),
+        locked_state.channel_map.clone(),
+        locked_state.events.clone(),
+    );
+    let sinks = sinks::Sinks::new(
+        locked_state.live.clone(),
+        locked_state.events.clone(),
+        locked_state.catalogue.clone(),
+    );
+
+    thread::spawn(move || buffer::run(buffer, events, blocks_sender));
+    let jobs_stats = pool::start(const_config.demux_threads, blocks, demuxed_sender);
+    thread::spawn(move || sinks::run(sinks, demuxed));
+
+    locked_state.live.pipeline = vec![ingest.stats(), blocks_stats, jobs_stats, demuxed_stats];
+    locked_state.ingest = Some(ingest.clone());
+
+    ingest
+}
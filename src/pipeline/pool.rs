@@ -0,0 +1,237 @@
+use super::{
+    queue::{bounded, Backpressure, QueueStats, Receiver, Sender},
+    Block, Corrections, DemuxedBlock, Item, Marker, Purpose,
+};
+use crate::{
+    electrodes::ChannelMap,
+    metrics::COUNTERS,
+    quality::sync::ChannelSync,
+    virt_channels::{demultiplex_channel, ChannelDemux},
+};
+
+use parking_lot::Mutex;
+use pico_sdk::common::PicoChannel;
+
+use std::{
+    collections::BTreeMap,
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// One Pico channel of a block, the unit of work for the pool
+struct Job {
+    index: u64,
+    channel: PicoChannel,
+    size: u64,
+    samples: Vec<f64>,
+    corrections: Corrections,
+    channel_map: Arc<ChannelMap>,
+}
+
+enum Message {
+    /// Sent before any of the block's jobs, so the collector knows what to wait for
+    Dispatched {
+        index: u64,
+        purpose: Purpose,
+        size: u64,
+        channels: usize,
+    },
+    Done {
+        index: u64,
+        channel: PicoChannel,
+        demux: ChannelDemux,
+    },
+    /// Passed on once every block before it has been
+    Marker { index: u64, marker: Marker },
+}
+
+/// A block waiting on some of its channels
+struct Pending {
+    purpose: Purpose,
+    size: u64,
+    remaining: usize,
+    channels: Vec<(PicoChannel, ChannelDemux)>,
+}
+
+/// Demultiplexes blocks on `threads` workers, one job per channel, so channels of a block
+/// and consecutive blocks are worked on at once. Blocks come out in the order they went
+/// in, with their frames in channel order, and markers keep their place among them.
+/// Returns the job queue's stats
+pub(super) fn start(
+    threads: usize,
+    blocks: Receiver<Item<Block>>,
+    demuxed: Sender<Item<DemuxedBlock>>,
+) -> Arc<QueueStats> {
+    // Internal to the stage, so it always waits. Whole blocks are dropped before it if
+    // the policy allows, never single channels
+    let (job_sender, jobs) = bounded("demux jobs", threads * 2, Backpressure::Block);
+    let stats = job_sender.stats();
+    // Bounded too, so a stalled sink holds the workers up rather than piling results up
+    let (results, collected) = mpsc::sync_channel(threads * 2);
+
+    let jobs = Arc::new(Mutex::new(jobs));
+    for _ in 0..threads {
+        let jobs = jobs.clone();
+        let results = results.clone();
+        thread::spawn(move || loop {
+            // Released before working, so the others can take the next job meanwhile
+            let job: Job = match jobs.lock().next() {
+                Some(job) => job,
+                None => break,
+            };
+            let mut demux = demultiplex_channel(job.channel, &job.samples, job.size);
+            if let Ok(frames) = demux.frames.as_mut() {
+                // Crosstalk was estimated on calibrated frames
+                if let Some(calibration) = job.corrections.calibration {
+                    calibration.apply(job.channel, frames);
+                }
+                if let Some(crosstalk) = job.corrections.crosstalk {
+                    crosstalk.compensate(job.channel, frames);
+                }
+                // Slots become electrodes, only once they're corrected
+                job.channel_map.rekey(job.channel, frames);
+                job.channel_map.derive(frames);
+            }
+            let done = Message::Done {
+                index: job.index,
+                channel: job.channel,
+                demux,
+            };
+            if results.send(done).is_err() {
+                break;
+            }
+        });
+    }
+
+    thread::spawn(move || {
+        for (index, item) in (0u64..).zip(blocks) {
+            let block = match item {
+                Item::Data(block) => block,
+                Item::Marker(marker) => {
+                    let _ = results.send(Message::Marker { index, marker });
+                    continue;
+                }
+            };
+            let _ = results.send(Message::Dispatched {
+                index,
+                purpose: block.purpose,
+                size: block.size,
+                channels: block.channels.len(),
+            });
+            for (channel, samples) in block.channels {
+                job_sender.send(Job {
+                    index,
+                    channel,
+                    size: block.size,
+                    samples,
+                    corrections: block.corrections.clone(),
+                    channel_map: block.channel_map.clone(),
+                });
+            }
+        }
+    });
+
+    thread::spawn(move || collect(collected, demuxed));
+
+    stats
+}
+
+/// Puts each block back together once all its channels are done, passing blocks on in
+/// the order they were dispatched
+fn collect(collected: mpsc::Receiver<Message>, demuxed: Sender<Item<DemuxedBlock>>) {
+    let mut pending: BTreeMap<u64, Pending> = BTreeMap::new();
+    let mut markers: BTreeMap<u64, Marker> = BTreeMap::new();
+    let mut next_index = 0;
+
+    for message in collected {
+        match message {
+            Message::Dispatched {
+                index,
+                purpose,
+                size,
+                channels,
+            } => {
+                pending.insert(
+                    index,
+                    Pending {
+                        purpose,
+                        size,
+                        remaining: channels,
+                        channels: vec![],
+                    },
+                );
+            }
+            Message::Done {
+                index,
+                channel,
+                demux,
+            } => {
+                if let Some(block) = pending.get_mut(&index) {
+                    block.remaining -= 1;
+                    block.channels.push((channel, demux));
+                }
+            }
+            Message::Marker { index, marker } => {
+                markers.insert(index, marker);
+            }
+        }
+
+        loop {
+            if let Some(marker) = markers.remove(&next_index) {
+                next_index += 1;
+                demuxed.send_wait(Item::Marker(marker));
+                continue;
+            }
+            if !pending
+                .get(&next_index)
+                .is_some_and(|block| block.remaining == 0)
+            {
+                break;
+            }
+
+            let mut block = pending.remove(&next_index).unwrap();
+            next_index += 1;
+
+            // The first error by channel, as the serial demultiplexer would give
+            block.channels.sort_by_key(|(channel, _)| *channel);
+            let mut sync = vec![];
+            let mut channel_frames = vec![];
+            for (channel, demux) in block.channels {
+                sync.push(ChannelSync {
+                    channel: channel.to_string(),
+                    stats: demux.sync,
+                    error: demux.frames.as_ref().err().map(|err| err.kind()),
+                });
+                channel_frames.push(demux.frames);
+            }
+            let frames = channel_frames
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|frames| frames.into_iter().flatten().collect::<Vec<_>>());
+            match frames.as_ref() {
+                Ok(frames) => {
+                    COUNTERS.frames_demuxed(frames.len());
+                    tracing::debug!(
+                        index = next_index - 1,
+                        frames = frames.len(),
+                        "demultiplexed block"
+                    );
+                }
+                Err(err) => {
+                    COUNTERS.demux_failed(err.kind());
+                    tracing::debug!(index = next_index - 1, %err, "could not demultiplex block");
+                }
+            }
+            let block = DemuxedBlock {
+                purpose: block.purpose,
+                size: block.size,
+                frames,
+                sync,
+            };
+            demuxed.send(Item::Data(block));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
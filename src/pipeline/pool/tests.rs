@@ -0,0 +1,157 @@
+//! The collector on its own, fed the messages the dispatcher and workers would send, in
+//! an order they could arrive in
+
+use super::*;
+use crate::{
+    pipeline::{Stop, Stopped},
+    recorder::RecordingIndex,
+    virt_channels::{VirtChannelError, VirtFrame},
+};
+
+use chrono::Local;
+
+/// A demultiplexed channel with a single frame, its offset telling the block apart
+fn done(index: u64, channel: PicoChannel) -> Message {
+    Message::Done {
+        index,
+        channel,
+        demux: ChannelDemux {
+            frames: Ok(vec![VirtFrame {
+                channel,
+                offset: index as usize,
+                samples: Default::default(),
+            }]),
+            sync: Default::default(),
+        },
+    }
+}
+
+fn dispatched(index: u64, purpose: Purpose, channels: usize) -> Message {
+    Message::Dispatched {
+        index,
+        purpose,
+        size: 100,
+        channels,
+    }
+}
+
+/// A stop marker, with its reply channel kept open
+fn stop(index: u64) -> (Message, mpsc::Receiver<Stopped>) {
+    let (reply, stopped) = mpsc::channel();
+    let marker = Marker::Stop(Stop {
+        stopped: Local::now(),
+        index: RecordingIndex::default(),
+        blocks: 0,
+        samples: 0,
+        gaps: vec![],
+        reply,
+    });
+    (Message::Marker { index, marker }, stopped)
+}
+
+/// Runs every message through the collector, returning what it passed on
+fn run(messages: Vec<Message>) -> Vec<Item<DemuxedBlock>> {
+    let (results, collected) = mpsc::sync_channel(messages.len());
+    for message in messages {
+        results.send(message).unwrap();
+    }
+    drop(results);
+
+    let (demuxed, out) = bounded("demuxed", 16, Backpressure::Block);
+    collect(collected, demuxed);
+    out.collect()
+}
+
+#[test]
+fn blocks_and_markers_come_out_in_dispatch_order() {
+    let (marker, _stopped) = stop(2);
+    let record = |sequence| Purpose::Record {
+        sequence,
+        start: sequence * 100,
+    };
+    let items = run(vec![
+        dispatched(0, record(0), 2),
+        dispatched(1, record(1), 2),
+        marker,
+        dispatched(3, Purpose::Preview, 1),
+        // Workers finish whenever they do, the last block first
+        done(3, PicoChannel::A),
+        done(1, PicoChannel::B),
+        done(0, PicoChannel::B),
+        done(1, PicoChannel::A),
+        done(0, PicoChannel::A),
+    ]);
+
+    let order: Vec<String> = items
+        .iter()
+        .map(|item| match item {
+            Item::Data(block) => match block.purpose {
+                Purpose::Record { sequence, .. } => format!("record {}", sequence),
+                Purpose::Preview => "preview".to_string(),
+            },
+            Item::Marker(Marker::Stop(_)) => "stop".to_string(),
+            Item::Marker(Marker::Start(_)) => "start".to_string(),
+        })
+        .collect();
+    assert_eq!(order, ["record 0", "record 1", "stop", "preview"]);
+
+    for (index, item) in [0, 1].iter().zip(items.iter()) {
+        let block = match item {
+            Item::Data(block) => block,
+            Item::Marker(_) => unreachable!(),
+        };
+        let frames = block.frames.as_ref().unwrap();
+        let channels: Vec<PicoChannel> = frames.iter().map(|f| f.channel).collect();
+        assert_eq!(channels, [PicoChannel::A, PicoChannel::B]);
+        assert!(frames.iter().all(|f| f.offset == *index));
+
+        let sync: Vec<&str> = block.sync.iter().map(|s| s.channel.as_str()).collect();
+        assert_eq!(sync, ["A", "B"]);
+    }
+}
+
+#[test]
+fn a_marker_waits_for_the_blocks_before_it() {
+    let (marker, _stopped) = stop(1);
+    let items = run(vec![
+        dispatched(0, Purpose::Preview, 1),
+        marker,
+        dispatched(2, Purpose::Preview, 1),
+        done(2, PicoChannel::A),
+    ]);
+
+    // Block 0 never finished, so nothing after it can go
+    assert!(items.is_empty());
+}
+
+#[test]
+fn a_failed_channel_fails_the_block_with_the_first_error() {
+    let error = |channel| Message::Done {
+        index: 0,
+        channel,
+        demux: ChannelDemux {
+            frames: Err(VirtChannelError::NoSyncPulse { channel }),
+            sync: Default::default(),
+        },
+    };
+    let items = run(vec![
+        dispatched(0, Purpose::Preview, 3),
+        error(PicoChannel::C),
+        done(0, PicoChannel::A),
+        error(PicoChannel::B),
+    ]);
+
+    match &items[..] {
+        [Item::Data(block)] => {
+            assert_eq!(
+                block.frames.as_ref().unwrap_err(),
+                &VirtChannelError::NoSyncPulse {
+                    channel: PicoChannel::B
+                }
+            );
+            let errors: Vec<Option<&str>> = block.sync.iter().map(|s| s.error).collect();
+            assert_eq!(errors, [None, Some("NoSyncPulse"), Some("NoSyncPulse")]);
+        }
+        _ => panic!("expected one block"),
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    mpsc, Arc,
+};
+
+/// What a stage does when the queue to the next one is full
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Waits for room, which passes the pressure back to the stage before
+    Block,
+    /// Drops what doesn't fit, so the stage never waits
+    Drop,
+}
+
+/// Counters shared between the two ends of a queue, read without stopping either
+pub struct QueueStats {
+    name: &'static str,
+    capacity: usize,
+    policy: Backpressure,
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueSnapshot {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub policy: Backpressure,
+    /// Items waiting for the next stage
+    pub depth: usize,
+    /// Deepest the queue has been
+    pub high_water: usize,
+    pub sent: u64,
+    pub dropped: u64,
+}
+
+impl QueueStats {
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            name: self.name,
+            capacity: self.capacity,
+            policy: self.policy,
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: mpsc::SyncSender<T>,
+    stats: Arc<QueueStats>,
+}
+
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<T>,
+    stats: Arc<QueueStats>,
+}
+
+/// A queue between two stages that holds at most `capacity` items
+pub fn bounded<T>(
+    name: &'static str,
+    capacity: usize,
+    policy: Backpressure,
+) -> (Sender<T>, Receiver<T>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let stats = Arc::new(QueueStats {
+        name,
+        capacity,
+        policy,
+        depth: AtomicUsize::new(0),
+        high_water: AtomicUsize::new(0),
+        sent: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    });
+
+    (
+        Sender {
+            inner: sender,
+            stats: stats.clone(),
+        },
+        Receiver {
+            inner: receiver,
+            stats,
+        },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Queues an item as the policy allows, returning false if it was dropped, either
+    /// for lack of room or because the next stage has stopped
+    pub fn send(&self, item: T) -> bool {
+        // Counted first, so the receiver can never take the depth below zero
+        let depth = self.stats.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let sent = match self.stats.policy {
+            Backpressure::Block => self.inner.send(item).is_ok(),
+            Backpressure::Drop => self.inner.try_send(item).is_ok(),
+        };
+
+        if sent {
+            self.stats.sent.fetch_add(1, Ordering::Relaxed);
+            self.stats.high_water.fetch_max(depth, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Waits for room whatever the policy, for what mustn't be dropped. Returns false if
+    /// the next stage has stopped
+    pub fn send_wait(&self, item: T) -> bool {
+        let depth = self.stats.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let sent = self.inner.send(item).is_ok();
+
+        if sent {
+            self.stats.sent.fetch_add(1, Ordering::Relaxed);
+            self.stats.high_water.fetch_max(depth, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.stats.clone()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            inner: self.inner.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    /// Waits for the next item, ending once every sender is gone
+    fn next(&mut self) -> Option<T> {
+        let item = self.inner.recv().ok()?;
+        self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,68 @@
+//! Both ends of a queue, and the counters they keep
+
+use super::*;
+
+use std::thread;
+
+#[test]
+fn dropping_never_waits() {
+    let (sender, receiver) = bounded("test", 2, Backpressure::Drop);
+    assert!(sender.send(1));
+    assert!(sender.send(2));
+    assert!(!sender.send(3));
+
+    let stats = sender.stats().snapshot();
+    assert_eq!((stats.depth, stats.high_water), (2, 2));
+    assert_eq!((stats.sent, stats.dropped), (2, 1));
+
+    let mut receiver = receiver;
+    assert_eq!(receiver.next(), Some(1));
+    assert!(sender.send(4));
+    assert_eq!(receiver.next(), Some(2));
+    assert_eq!(receiver.next(), Some(4));
+    let stats = sender.stats().snapshot();
+    assert_eq!((stats.depth, stats.high_water), (0, 2));
+    assert_eq!((stats.sent, stats.dropped), (3, 1));
+}
+
+#[test]
+fn blocking_waits_for_room() {
+    let (sender, receiver) = bounded("test", 1, Backpressure::Block);
+    let stats = sender.stats();
+    let sending = thread::spawn(move || (0..10).all(|i| sender.send(i)));
+
+    // Every item gets through, however far behind the receiver is
+    let received: Vec<i32> = receiver.collect();
+    assert!(sending.join().unwrap());
+    assert_eq!(received, (0..10).collect::<Vec<i32>>());
+    let stats = stats.snapshot();
+    assert_eq!((stats.depth, stats.sent, stats.dropped), (0, 10, 0));
+    assert!(stats.high_water >= 1);
+}
+
+#[test]
+fn waiting_ignores_the_policy() {
+    let (sender, mut receiver) = bounded("test", 1, Backpressure::Drop);
+    assert!(sender.send(1));
+    let waiting = sender.clone();
+    let sending = thread::spawn(move || waiting.send_wait(2));
+
+    assert_eq!(receiver.next(), Some(1));
+    assert_eq!(receiver.next(), Some(2));
+    assert!(sending.join().unwrap());
+    let stats = sender.stats().snapshot();
+    assert_eq!((stats.depth, stats.sent, stats.dropped), (0, 2, 0));
+}
+
+#[test]
+fn nothing_is_sent_once_the_receiver_is_gone() {
+    let (sender, receiver) = bounded::<i32>("test", 4, Backpressure::Block);
+    drop(receiver);
+    assert!(!sender.send(1));
+    assert!(!sender.send_wait(2));
+
+    // Only what the policy drops counts as dropped
+    let stats = sender.stats().snapshot();
+    assert_eq!((stats.depth, stats.high_water), (0, 0));
+    assert_eq!((stats.sent, stats.dropped), (0, 1));
+}
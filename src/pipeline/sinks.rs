@@ -0,0 +1,413 @@
+use super::{queue::Receiver, DemuxedBlock, Item, Marker, Purpose, Recording, Stop, Stopped};
+use crate::{
+    app::state::{LiveState, RECENT_FRAME_LIMIT},
+    catalogue::{Catalogue, EventKind, EventLog},
+    export::{FrameTable, OutputFormat},
+    filters::FilteredFrame,
+    metrics::COUNTERS,
+    pyramid::PyramidWriter,
+    quality::sync::{BlockSync, ChannelSync, SyncLog},
+    session::{archive, block_file, manifest::Manifest, SessionMetadata},
+    virt_channels::{VirtChannelError, VirtFrame, VirtSamples},
+    write_data, ConstConfig,
+};
+
+use chrono::Local;
+use pico_sdk::common::PicoChannel;
+
+use std::{collections::BTreeMap, ops::Range, sync::Arc, thread};
+
+/// The recording the sinks are writing, from its start marker to its stop
+struct ActiveRecording {
+    session: SessionMetadata,
+    /// Multi-resolution copy of the virtual channels, for browsing the session later
+    pyramid: PyramidWriter,
+    /// Sync stats of every recorded block
+    sync_log: SyncLog,
+    sample_rate: f64,
+    /// Where the sinks are up to, to notice blocks dropped on the way
+    next_sequence: u64,
+    next_start: u64,
+    /// Set once sync pulses go missing, so only the change gets logged
+    sync_lost: bool,
+}
+
+/// State of the sinks stage, which only it touches
+pub(super) struct Sinks {
+    live: LiveState,
+    events: EventLog,
+    catalogue: Option<Arc<Catalogue>>,
+    recording: Option<ActiveRecording>,
+    /// Samples into the preview its next block starts, to time preview frames by
+    preview_start: u64,
+}
+
+/// Hands demultiplexed frames to everything that keeps or shows them
+pub(super) fn run(mut sinks: Sinks, demuxed: Receiver<Item<DemuxedBlock>>) {
+    for item in demuxed {
+        match item {
+            Item::Data(block) => match block.purpose {
+                Purpose::Record { sequence, start } => {
+                    sinks.record_block(sequence, start, block.size, block.frames, block.sync)
+                }
+                Purpose::Preview => sinks.preview_block(block.size, block.frames, block.sync),
+            },
+            Item::Marker(Marker::Start(recording)) => sinks.start(*recording),
+            Item::Marker(Marker::Stop(stop)) => sinks.stop(stop),
+        }
+    }
+}
+
+impl Sinks {
+    pub(super) fn new(
+        live: LiveState,
+        events: EventLog,
+        catalogue: Option<Arc<Catalogue>>,
+    ) -> Self {
+        Sinks {
+            live,
+            events,
+            catalogue,
+            recording: None,
+            preview_start: 0,
+        }
+    }
+
+    fn start(&mut self, recording: Recording) {
+        self.live.filters.lock().reset();
+        self.live.recent_frames.lock().clear();
+        self.live.spectrum.lock().reset();
+        self.live.sync.lock().set_session(BTreeMap::new());
+
+        self.recording = Some(ActiveRecording {
+            sample_rate: recording.session.sample_rate as f64,
+            session: recording.session,
+            pyramid: recording.pyramid,
+            sync_log: recording.sync_log,
+            next_sequence: 0,
+            next_start: 0,
+            sync_lost: false,
+        });
+    }
+
+    /// Finishes the session's files once every block before the marker is written
+    fn stop(&mut self, stop: Stop) {
+        let mut recording = match self.recording.take() {
+            Some(recording) => recording,
+            None => return,
+        };
+        if stop.blocks > recording.next_sequence {
+            let (sequences, samples) = (
+                recording.next_sequence..stop.blocks,
+                recording.next_start..stop.samples,
+            );
+            recording.skip_blocks(&self.live, &self.events, sequences, samples);
+        }
+
+        let ActiveRecording {
+            mut session,
+            pyramid,
+            ..
+        } = recording;
+        if let Err(err) = pyramid.finish() {
+            tracing::error!(?err, "could not finish summary pyramid");
+        }
+
+        // The buffer's gaps only arrive now, so everything is put back in time order
+        let mut gaps = std::mem::take(&mut session.gaps);
+        gaps.extend(stop.gaps);
+        gaps.sort_by(|a, b| a.start.total_cmp(&b.start));
+        for gap in gaps {
+            session.add_gap(gap.start, gap.duration, &gap.reason);
+        }
+
+        session.stopped = Some(stop.stopped);
+        if let Err(err) = session.save() {
+            tracing::error!(?err, "could not save session metadata");
+        }
+        if let Some(catalogue) = self.catalogue.as_ref() {
+            if let Err(err) = catalogue.stop_session(&session) {
+                tracing::error!(?err, "could not catalogue session");
+            }
+        }
+        self.live.sync.lock().set_session(BTreeMap::new());
+        // The preview starts afresh, without the recording's filter state or spectrum,
+        // as its frames are timed from 0 again
+        self.live.filters.lock().reset();
+        self.live.recent_frames.lock().clear();
+        self.live.spectrum.lock().reset();
+        self.preview_start = 0;
+
+        // Every other file of the session is written by now, so once its blocks are joined
+        // the manifest covers them all
+        let (manifest_session, index) = (session.clone(), stop.index);
+        let format = ConstConfig::get_config().output_format;
+        let pending = self.live.pending_manifests.clone();
+        let manifest = thread::spawn(move || {
+            let directory = manifest_session.directory();
+            if let Err(err) = archive::consolidate(&directory, format) {
+                tracing::error!(?err, "could not join frame files");
+            }
+            if let Err(err) = Manifest::create(&directory, &manifest_session, &index) {
+                tracing::error!(?err, "could not write session manifest");
+            }
+            pending.lock().remove(&manifest_session.name);
+        });
+        let _ = stop.reply.send(Stopped { session, manifest });
+    }
+
+    /// Feeds the live views while nothing is being recorded, without writing anything.
+    /// Preview blocks are all `size` samples, so their frames are timed from the first
+    fn preview_block(
+        &mut self,
+        size: u64,
+        frames: Result<Vec<VirtFrame>, VirtChannelError>,
+        sync: Vec<ChannelSync>,
+    ) {
+        self.live.sync.lock().push(BlockSync {
+            sequence: None,
+            received: Local::now(),
+            channels: sync,
+        });
+
+        let block_start = self.preview_start;
+        self.preview_start += size;
+        let frames: Vec<(f64, VirtFrame)> = frames
+            .unwrap_or_default()
+            .into_iter()
+            .map(|f| ((block_start + f.offset as u64) as f64 / size as f64, f))
+            .collect();
+        let ready = self.live.filters.lock().filter_next(frames);
+        show_frames(&self.live, &ready);
+    }
+
+    /// Writes and logs what came of demultiplexing one block of the recording
+    #[tracing::instrument(level = "debug", skip(self, frames, sync))]
+    fn record_block(
+        &mut self,
+        sequence: u64,
+        block_start: u64,
+        pico_sped: u64,
+        frames: Result<Vec<VirtFrame>, VirtChannelError>,
+        sync: Vec<ChannelSync>,
+    ) {
+        let recording = match self.recording.as_mut() {
+            Some(recording) => recording,
+            None => return,
+        };
+        let sample_rate = recording.sample_rate;
+        recording.record_sync(&self.live, sequence, block_start as f64 / sample_rate, sync);
+
+        if sequence > recording.next_sequence {
+            let (sequences, samples) = (
+                recording.next_sequence..sequence,
+                recording.next_start..block_start,
+            );
+            recording.skip_blocks(&self.live, &self.events, sequences, samples);
+        }
+        recording.next_sequence = sequence + 1;
+        recording.next_start = block_start + pico_sped;
+
+        let session = &mut recording.session;
+        match frames {
+            Ok(frames) => {
+                let frames: Vec<(f64, VirtFrame)> = frames
+                    .into_iter()
+                    .map(|f| ((block_start + f.offset as u64) as f64 / sample_rate, f))
+                    .collect();
+
+                session.frame_count += frames.len() as u64;
+                if recording.sync_lost {
+                    recording.sync_lost = false;
+                    self.events.log(
+                        Some(&session.name),
+                        EventKind::SyncRegained,
+                        &format!(
+                            "Sync pulses found again {:.3}s into the session",
+                            block_start as f64 / sample_rate
+                        ),
+                    );
+                }
+                let samples: Vec<(f64, VirtSamples)> = frames
+                    .iter()
+                    .map(|(t, f)| (*t, f.samples.clone()))
+                    .collect();
+                if let Err(err) = recording.pyramid.append_block(sequence, samples) {
+                    tracing::error!(?err, "could not update summary pyramid");
+                }
+
+                // Pico channels with different layouts frame at different times, so each
+                // gets a table of its own
+                let mut tables: BTreeMap<PicoChannel, (Vec<f64>, Vec<VirtSamples>)> =
+                    BTreeMap::new();
+                for (time, frame) in frames.iter() {
+                    let table = tables.entry(frame.channel).or_default();
+                    table.0.push(*time);
+                    table.1.push(frame.samples.clone());
+                }
+                let filtered = filter_block(&self.live, sequence, frames);
+
+                for (channel, (timestamps, samples)) in tables {
+                    write_data(
+                        FrameTable::from_frames(&samples, timestamps).named(&session.electrodes),
+                        session,
+                        OutputFormat::Csv,
+                        Some(block_file(sequence, &channel.to_string())),
+                    );
+                }
+                write_filtered(filtered, sequence, session);
+            }
+            Err(err) => {
+                COUNTERS.dropped(pico_sped as f64 / sample_rate);
+                session.add_gap(
+                    block_start as f64 / sample_rate,
+                    pico_sped as f64 / sample_rate,
+                    err.kind(),
+                );
+                if let Err(err) = recording.pyramid.append_block(sequence, vec![]) {
+                    tracing::error!(?err, "could not update summary pyramid");
+                }
+                // Later blocks may have been waiting on this one
+                let filtered = filter_block(&self.live, sequence, vec![]);
+
+                let block_time = block_start as f64 / sample_rate;
+                self.events.log(
+                    Some(&session.name),
+                    EventKind::VirtChannelError,
+                    &format!("{} in block {} at {:.3}s", err, sequence, block_time),
+                );
+                if let VirtChannelError::NoSyncPulse { .. } = err {
+                    if !recording.sync_lost {
+                        recording.sync_lost = true;
+                        self.events.log(
+                            Some(&session.name),
+                            EventKind::SyncLost,
+                            &format!("Sync pulses lost {:.3}s into the session", block_time),
+                        );
+                    }
+                }
+                write_filtered(filtered, sequence, session);
+
+                tracing::warn!(%err, kind = err.kind(), "could not demultiplex block");
+            }
+        }
+    }
+}
+
+impl ActiveRecording {
+    /// Adds the sync stats of a recorded block to the live view, the session's totals
+    /// and its sync log
+    fn record_sync(&mut self, live: &LiveState, sequence: u64, time: f64, sync: Vec<ChannelSync>) {
+        for channel in sync.iter() {
+            self.session
+                .sync
+                .entry(channel.channel.clone())
+                .or_default()
+                .add(channel);
+        }
+        if let Err(err) = self.sync_log.append(sequence, time, &sync) {
+            tracing::error!(?err, "could not write sync log");
+        }
+
+        let mut view = live.sync.lock();
+        view.set_session(self.session.sync.clone());
+        view.push(BlockSync {
+            sequence: Some(sequence),
+            received: Local::now(),
+            channels: sync,
+        });
+    }
+
+    /// Blocks dropped between stages leave a hole the pyramid and filters would
+    /// otherwise wait on forever
+    fn skip_blocks(
+        &mut self,
+        live: &LiveState,
+        events: &EventLog,
+        sequences: Range<u64>,
+        samples: Range<u64>,
+    ) {
+        let start = samples.start as f64 / self.sample_rate;
+        let duration = samples.end.saturating_sub(samples.start) as f64 / self.sample_rate;
+        COUNTERS.dropped(duration);
+        self.session
+            .add_gap(start, duration, EventKind::StreamOverflow.as_str());
+
+        let mut filtered = vec![];
+        for sequence in sequences.clone() {
+            if let Err(err) = self.pyramid.append_block(sequence, vec![]) {
+                tracing::error!(?err, "could not update summary pyramid");
+            }
+            filtered.extend(filter_block(live, sequence, vec![]).unwrap_or_default());
+        }
+        tracing::warn!(?sequences, start, "blocks dropped before the sinks");
+        events.log(
+            Some(&self.session.name),
+            EventKind::StreamOverflow,
+            &format!(
+                "Dropped blocks {} to {} at {:.3}s, processing fell behind",
+                sequences.start,
+                sequences.end - 1,
+                start
+            ),
+        );
+
+        if !filtered.is_empty() {
+            write_filtered(Some(filtered), sequences.end - 1, &self.session);
+        }
+    }
+}
+
+/// Runs a block through the filter chains, returning the frames that are now ready if
+/// any channel is filtered. Every ready frame goes to the live views either way
+fn filter_block(
+    live: &LiveState,
+    sequence: u64,
+    frames: Vec<(f64, VirtFrame)>,
+) -> Option<Vec<FilteredFrame>> {
+    let mut filters = live.filters.lock();
+    let ready = filters.filter_block(sequence, frames);
+    let unfiltered = filters.is_empty();
+    drop(filters);
+
+    show_frames(live, &ready);
+
+    if unfiltered || ready.is_empty() {
+        None
+    } else {
+        Some(ready)
+    }
+}
+
+/// Hands frames fresh out of the filters to the live view, spectral analysis and quality
+/// checks
+fn show_frames(live: &LiveState, ready: &[FilteredFrame]) {
+    let mut recent_frames = live.recent_frames.lock();
+    recent_frames.extend(ready.iter().cloned());
+    let excess = recent_frames.len().saturating_sub(RECENT_FRAME_LIMIT);
+    recent_frames.drain(..excess);
+    drop(recent_frames);
+
+    live.spectrum.lock().push_frames(ready);
+    live.quality.lock().push_frames(ready);
+}
+
+/// Writes filtered frames next to the raw ones, a file per Pico channel, named after the
+/// block that made them ready
+fn write_filtered(frames: Option<Vec<FilteredFrame>>, sequence: u64, session: &SessionMetadata) {
+    let mut tables: BTreeMap<PicoChannel, (Vec<f64>, Vec<VirtSamples>)> = BTreeMap::new();
+    for frame in frames.unwrap_or_default() {
+        let table = tables.entry(frame.channel).or_default();
+        table.0.push(frame.time);
+        table.1.push(frame.filtered);
+    }
+
+    for (channel, (timestamps, samples)) in tables {
+        write_data(
+            FrameTable::from_frames(&samples, timestamps).named(&session.electrodes),
+            session,
+            OutputFormat::Csv,
+            Some(block_file(sequence, &format!("{}_filtered", channel))),
+        )
+    }
+}
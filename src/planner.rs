@@ -0,0 +1,139 @@
+use crate::{virt_channels::MuxLayout, ConstConfig};
+
+use serde::Serialize;
+
+/// Capture rates offered when streaming starts, in samples per second per channel
+pub const CAPTURE_RATES: [u32; 9] = [
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    5_000_000,
+    10_000_000,
+    20_000_000,
+    50_000_000,
+    100_000_000,
+];
+
+/// Fewest samples per slot the demultiplexer can work with at all, below this the
+/// estimated pulse width and averaging window collapse to nothing
+const MIN_USABLE_SAMPLES_PER_SLOT: f64 = 3.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Recommended,
+    Usable,
+    Refused,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RatePlan {
+    pub rate: u32,
+    /// Samples landing in each multiplexer slot, per channel
+    pub samples_per_slot: f64,
+    pub verdict: Verdict,
+    pub reason: String,
+}
+
+/// Works out which capture rates suit the multiplexer timing and the device
+pub struct CapturePlanner {
+    arduino_hz: usize,
+    /// Virtual channels in the widest frame of any enabled channel with sync pulses,
+    /// or none if no channel has them to find
+    widest_frame: Option<usize>,
+    enabled_channels: usize,
+    /// Samples per second the device can stream, shared across enabled channels
+    max_total_rate: u64,
+    min_samples_per_slot: usize,
+    max_samples_per_slot: usize,
+}
+
+impl CapturePlanner {
+    /// For the layouts of the enabled Pico channels. Every slot lasts as long whatever
+    /// the layout, so it's the widest frame that decides what's enough
+    pub fn new(layouts: &[MuxLayout]) -> Self {
+        let const_config = ConstConfig::get_config();
+
+        CapturePlanner {
+            arduino_hz: const_config.arduino_hz,
+            widest_frame: layouts
+                .iter()
+                .filter(|l| l.has_sync())
+                .map(|l| l.virt_channels())
+                .max(),
+            enabled_channels: layouts.len().max(1),
+            max_total_rate: const_config.max_streaming_rate,
+            min_samples_per_slot: const_config.min_samples_per_slot,
+            max_samples_per_slot: const_config.max_samples_per_slot,
+        }
+    }
+
+    pub fn assess(&self, rate: u32) -> RatePlan {
+        let samples_per_slot = rate as f64 / self.arduino_hz as f64;
+        let total_rate = rate as u64 * self.enabled_channels as u64;
+
+        let (verdict, reason) = match self.widest_frame {
+            Some(_) if samples_per_slot < MIN_USABLE_SAMPLES_PER_SLOT => (
+                Verdict::Refused,
+                format!(
+                    "{:.2} samples per slot, sync pulses can't be found below {}",
+                    samples_per_slot, MIN_USABLE_SAMPLES_PER_SLOT
+                ),
+            ),
+            _ if total_rate > self.max_total_rate => (
+                Verdict::Refused,
+                format!(
+                    "{} channels at this rate need {} S/s, over the device's {} S/s",
+                    self.enabled_channels, total_rate, self.max_total_rate
+                ),
+            ),
+            // Nothing to demultiplex, so only the device limits the rate
+            None => (
+                Verdict::Recommended,
+                "no channel is multiplexed, so any rate will do".to_string(),
+            ),
+            Some(_) if samples_per_slot < self.min_samples_per_slot as f64 => (
+                Verdict::Usable,
+                format!(
+                    "{:.1} samples per slot, virtual channels will be averaged over very few",
+                    samples_per_slot
+                ),
+            ),
+            Some(widest_frame) if samples_per_slot > self.max_samples_per_slot as f64 => (
+                Verdict::Usable,
+                format!(
+                    "{:.0} samples per slot, more than needed for {} virtual channels",
+                    samples_per_slot, widest_frame
+                ),
+            ),
+            Some(widest_frame) => (
+                Verdict::Recommended,
+                format!(
+                    "{:.0} samples per slot, {:.0} per frame",
+                    samples_per_slot,
+                    samples_per_slot * (widest_frame + 1) as f64
+                ),
+            ),
+        };
+
+        RatePlan {
+            rate,
+            samples_per_slot,
+            verdict,
+            reason,
+        }
+    }
+
+    /// Slowest recommended rate out of `rates`, as anything faster only costs memory
+    pub fn recommend(&self, rates: &[u32]) -> Option<u32> {
+        rates
+            .iter()
+            .copied()
+            .filter(|r| self.assess(*r).verdict == Verdict::Recommended)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,78 @@
+//! Verdicts either side of each limit, against the configured multiplexer and device
+
+use super::*;
+
+/// Capture rate putting `samples` in every multiplexer slot
+fn rate(samples: f64) -> u32 {
+    (samples * ConstConfig::get_config().arduino_hz as f64) as u32
+}
+
+fn verdict(layouts: &[MuxLayout], rate: u32) -> Verdict {
+    CapturePlanner::new(layouts).assess(rate).verdict
+}
+
+#[test]
+fn judges_the_samples_per_slot() {
+    let const_config = ConstConfig::get_config();
+    let (min, max) = (
+        const_config.min_samples_per_slot as f64,
+        const_config.max_samples_per_slot as f64,
+    );
+    let layouts = [MuxLayout::Slots(4)];
+    let cases = [
+        (rate(MIN_USABLE_SAMPLES_PER_SLOT) - 1, Verdict::Refused),
+        (rate(MIN_USABLE_SAMPLES_PER_SLOT), Verdict::Usable),
+        (rate(min) - 1, Verdict::Usable),
+        (rate(min), Verdict::Recommended),
+        (rate(max), Verdict::Recommended),
+        (rate(max) + 1, Verdict::Usable),
+    ];
+    for (rate, expected) in cases.iter() {
+        let plan = CapturePlanner::new(&layouts).assess(*rate);
+        assert_eq!(plan.verdict, *expected, "{} S/s: {}", rate, plan.reason);
+    }
+}
+
+#[test]
+fn shares_the_device_rate_across_channels() {
+    let max = ConstConfig::get_config().max_streaming_rate as u32;
+    let layouts = [MuxLayout::Slots(4); 4];
+    assert_eq!(verdict(&layouts[..2], max / 2), Verdict::Usable);
+    let plan = CapturePlanner::new(&layouts).assess(max / 2);
+    assert_eq!(plan.verdict, Verdict::Refused);
+    assert!(plan.reason.contains("over the device's"), "{}", plan.reason);
+}
+
+#[test]
+fn frames_by_the_widest_layout() {
+    let layouts = [MuxLayout::Slots(2), MuxLayout::None, MuxLayout::Slots(6)];
+    let plan = CapturePlanner::new(&layouts).assess(rate(20.0));
+    assert_eq!(plan.verdict, Verdict::Recommended);
+    assert_eq!(plan.reason, "20 samples per slot, 140 per frame");
+
+    // Sync pulses still have to be found on a channel with nothing else
+    let sync_only = [MuxLayout::SyncOnly];
+    assert_eq!(verdict(&sync_only, rate(1.0)), Verdict::Refused);
+}
+
+#[test]
+fn anything_goes_without_a_multiplexer() {
+    let layouts = [MuxLayout::None, MuxLayout::None];
+    assert_eq!(verdict(&layouts, 1), Verdict::Recommended);
+    let max = ConstConfig::get_config().max_streaming_rate as u32;
+    assert_eq!(verdict(&layouts, max), Verdict::Refused);
+}
+
+#[test]
+fn recommends_the_slowest_recommended_rate() {
+    let planner = CapturePlanner::new(&[MuxLayout::Slots(4)]);
+    let recommended = planner.recommend(&CAPTURE_RATES).unwrap();
+    assert_eq!(planner.assess(recommended).verdict, Verdict::Recommended);
+    assert!(CAPTURE_RATES
+        .iter()
+        .filter(|r| **r < recommended)
+        .all(|r| planner.assess(*r).verdict != Verdict::Recommended));
+
+    // Too slow for sync pulses at every rate offered
+    assert_eq!(planner.recommend(&CAPTURE_RATES[..2]), None);
+}
@@ -0,0 +1,277 @@
+use crate::{
+    planner::{CapturePlanner, Verdict},
+    quality::{ChannelQuality, QualityMetrics},
+    virt_channels::MuxLayout,
+    ConstConfig,
+};
+
+use chrono::{DateTime, Local};
+use pico_sdk::common::PicoChannel;
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreflightCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// Outcome of every check, with `status` being the worst of them
+#[derive(Clone, Debug, Serialize)]
+pub struct PreflightReport {
+    pub created: DateTime<Local>,
+    pub status: CheckStatus,
+    pub checks: Vec<PreflightCheck>,
+}
+
+/// Sync pulse timing measured on one Pico channel
+struct PulseTiming {
+    /// Median samples spent above the sync threshold, i.e. one multiplexer slot
+    width: f64,
+    /// Median samples from one pulse to the next
+    interval: f64,
+    /// Spread of the intervals relative to their median
+    jitter: f64,
+    count: usize,
+}
+
+fn median(values: &mut Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[values.len() / 2]
+}
+
+/// Finds runs above the sync threshold, ignoring the partial runs at either end
+fn pulse_timing(samples: &[f64]) -> Option<PulseTiming> {
+    let threshold = ConstConfig::get_config().sync_point_threshold;
+
+    let mut runs: Vec<(usize, usize)> = vec![];
+    let mut start = None;
+    for (index, value) in samples.iter().enumerate() {
+        match (start, *value > threshold) {
+            (None, true) => start = Some(index),
+            (Some(s), false) => {
+                if s > 0 {
+                    runs.push((s, index - s));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if runs.len() < 3 {
+        return None;
+    }
+
+    // Single samples over the threshold are noise, not pulses
+    let mut widths: Vec<f64> = runs.iter().map(|r| r.1 as f64).collect();
+    let width = median(&mut widths);
+    runs.retain(|r| r.1 as f64 >= width / 2.0);
+
+    let mut intervals: Vec<f64> = runs.windows(2).map(|w| (w[1].0 - w[0].0) as f64).collect();
+    if intervals.len() < 2 {
+        return None;
+    }
+    let interval = median(&mut intervals);
+    let mut deviations: Vec<f64> = intervals.iter().map(|i| (i - interval).abs()).collect();
+
+    Some(PulseTiming {
+        width,
+        interval,
+        jitter: median(&mut deviations) / interval,
+        count: runs.len(),
+    })
+}
+
+fn check(name: &str, status: CheckStatus, detail: String) -> PreflightCheck {
+    PreflightCheck {
+        name: name.to_string(),
+        status,
+        detail,
+    }
+}
+
+fn sync_checks(channel: PicoChannel, samples: &[f64], sample_rate: f64) -> Vec<PreflightCheck> {
+    let const_config = ConstConfig::get_config();
+    let layout = const_config.mux_layout(&channel.to_string());
+    // Wired straight in, so there are no pulses to look for
+    if !layout.has_sync() {
+        return vec![];
+    }
+    let timing = match pulse_timing(samples) {
+        Some(timing) => timing,
+        None => {
+            return vec![check(
+                &format!("Sync pulses on {}", channel),
+                CheckStatus::Fail,
+                format!(
+                    "No repeating pulses above {} in {:.2}s of signal",
+                    const_config.sync_point_threshold,
+                    samples.len() as f64 / sample_rate
+                ),
+            )]
+        }
+    };
+
+    let measured_hz = sample_rate / timing.width;
+    let hz_error = (measured_hz - const_config.arduino_hz as f64).abs() / const_config.arduino_hz as f64;
+    let measured_channels = ((timing.interval / timing.width).round() as usize).saturating_sub(1);
+
+    let mut checks = vec![
+        check(
+            &format!("Sync pulses on {}", channel),
+            if timing.jitter < 0.05 {
+                CheckStatus::Pass
+            } else if timing.jitter < 0.15 {
+                CheckStatus::Warn
+            } else {
+                CheckStatus::Fail
+            },
+            format!(
+                "{} pulses every {:.0} samples, {:.1}% jitter",
+                timing.count,
+                timing.interval,
+                timing.jitter * 100.0
+            ),
+        ),
+        check(
+            &format!("Multiplexer speed on {}", channel),
+            if hz_error < 0.1 {
+                CheckStatus::Pass
+            } else if hz_error < const_config.arduino_hz_tolerance as f64 {
+                CheckStatus::Warn
+            } else {
+                CheckStatus::Fail
+            },
+            format!(
+                "{:.0} Hz measured, {} Hz configured",
+                measured_hz, const_config.arduino_hz
+            ),
+        ),
+    ];
+    // Nothing says how many slots the pulses of a sync only channel are apart
+    if let MuxLayout::Slots(slots) = layout {
+        checks.push(check(
+            &format!("Virtual channels on {}", channel),
+            if measured_channels == slots {
+                CheckStatus::Pass
+            } else {
+                CheckStatus::Fail
+            },
+            format!("{} measured, {} configured", measured_channels, slots),
+        ));
+    }
+    checks
+}
+
+fn signal_check(quality: &ChannelQuality) -> PreflightCheck {
+    let const_config = ConstConfig::get_config();
+    let QualityMetrics {
+        rms_noise,
+        line_amplitude,
+        line_frequency,
+        clipped,
+        full_scale,
+        ..
+    } = quality.metrics;
+    let name = format!("Signal on {} channel {}", quality.kind, quality.channel);
+
+    if clipped > const_config.quality_clip_limit {
+        check(
+            &name,
+            CheckStatus::Fail,
+            format!("{:.2}% of samples are clipping", clipped * 100.0),
+        )
+    } else if rms_noise == 0.0 {
+        check(&name, CheckStatus::Fail, "Completely flat, nothing is connected".to_string())
+    } else if line_amplitude / full_scale > const_config.quality_line_limit {
+        // A floating input picks up mains far more than anything else
+        check(
+            &name,
+            if quality.metrics.score < 50.0 {
+                CheckStatus::Fail
+            } else {
+                CheckStatus::Warn
+            },
+            format!(
+                "{:.3} of {} Hz interference, the electrode may be floating",
+                line_amplitude, line_frequency
+            ),
+        )
+    } else {
+        check(
+            &name,
+            if quality.metrics.issues.is_empty() {
+                CheckStatus::Pass
+            } else {
+                CheckStatus::Warn
+            },
+            format!(
+                "Quality {:.0} ({}) {}",
+                quality.metrics.score,
+                quality.metrics.rating,
+                quality.metrics.issues.join(", ")
+            ),
+        )
+    }
+}
+
+/// Checks the latest raw signal is fit to record, before recording starts
+pub fn run(
+    raw: &HashMap<PicoChannel, Vec<f64>>,
+    sample_rate: f64,
+    quality: &[ChannelQuality],
+) -> PreflightReport {
+    let mut checks = vec![];
+
+    let const_config = ConstConfig::get_config();
+    let layouts: Vec<MuxLayout> = raw
+        .keys()
+        .map(|channel| const_config.mux_layout(&channel.to_string()))
+        .collect();
+    let plan = CapturePlanner::new(&layouts).assess(sample_rate as u32);
+    checks.push(check(
+        "Capture rate",
+        match plan.verdict {
+            Verdict::Recommended => CheckStatus::Pass,
+            Verdict::Usable => CheckStatus::Warn,
+            Verdict::Refused => CheckStatus::Fail,
+        },
+        plan.reason,
+    ));
+
+    if raw.is_empty() {
+        checks.push(check(
+            "Signal",
+            CheckStatus::Fail,
+            "No samples have been streamed yet".to_string(),
+        ));
+    }
+    let mut channels: Vec<&PicoChannel> = raw.keys().collect();
+    channels.sort();
+    for channel in channels {
+        checks.extend(sync_checks(*channel, &raw[channel], sample_rate));
+    }
+    checks.extend(quality.iter().map(signal_check));
+
+    PreflightReport {
+        created: Local::now(),
+        status: checks
+            .iter()
+            .map(|c| c.status)
+            .fold(CheckStatus::Pass, |a, b| if b > a { b } else { a }),
+        checks,
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,139 @@
+//! Checks of made up multiplexed signals, at 10 samples per slot
+
+use super::*;
+use crate::quality::measure;
+
+const SAMPLES_PER_SLOT: usize = 10;
+const SYNC_LEVEL: f64 = 4.5;
+
+fn sample_rate() -> f64 {
+    (SAMPLES_PER_SLOT * ConstConfig::get_config().arduino_hz) as f64
+}
+
+/// `frames` frames of `slots` slots each, starting on the first slot so the first pulse
+/// is whole
+fn mux_signal(frames: usize, slots: usize) -> Vec<f64> {
+    let mut samples = vec![];
+    for _ in 0..frames {
+        for slot in 0..slots {
+            samples.resize(samples.len() + SAMPLES_PER_SLOT, 0.1 * (slot + 1) as f64);
+        }
+        samples.resize(samples.len() + SAMPLES_PER_SLOT, SYNC_LEVEL);
+    }
+    samples
+}
+
+fn statuses(checks: &[PreflightCheck]) -> Vec<(&str, CheckStatus)> {
+    checks.iter().map(|c| (c.name.as_str(), c.status)).collect()
+}
+
+#[test]
+fn times_the_pulses() {
+    let mut samples = mux_signal(20, 4);
+    // Single samples over the threshold are noise
+    samples[3] = SYNC_LEVEL;
+    samples[27] = SYNC_LEVEL;
+
+    let timing = pulse_timing(&samples).unwrap();
+    assert_eq!(timing.width, SAMPLES_PER_SLOT as f64);
+    assert_eq!(timing.interval, (5 * SAMPLES_PER_SLOT) as f64);
+    assert_eq!(timing.jitter, 0.0);
+    // The last pulse runs off the end, so it isn't whole
+    assert_eq!(timing.count, 19);
+}
+
+#[test]
+fn needs_three_pulses() {
+    // The last of each runs off the end
+    assert!(pulse_timing(&mux_signal(3, 4)).is_none());
+    assert!(pulse_timing(&mux_signal(4, 4)).is_some());
+    assert!(pulse_timing(&[0.0; 1000]).is_none());
+}
+
+#[test]
+fn a_clean_signal_passes() {
+    let checks = sync_checks(PicoChannel::A, &mux_signal(100, 4), sample_rate());
+    assert_eq!(
+        statuses(&checks),
+        [
+            ("Sync pulses on A", CheckStatus::Pass),
+            ("Multiplexer speed on A", CheckStatus::Pass),
+            ("Virtual channels on A", CheckStatus::Pass),
+        ]
+    );
+}
+
+#[test]
+fn finds_the_wrong_layout_and_speed() {
+    // One slot too many, streamed at 8 samples per slot rather than 10
+    let checks = sync_checks(PicoChannel::A, &mux_signal(100, 5), sample_rate() * 0.8);
+    assert_eq!(
+        statuses(&checks),
+        [
+            ("Sync pulses on A", CheckStatus::Pass),
+            ("Multiplexer speed on A", CheckStatus::Warn),
+            ("Virtual channels on A", CheckStatus::Fail),
+        ]
+    );
+    assert_eq!(checks[2].detail, "5 measured, 4 configured");
+
+    let checks = sync_checks(PicoChannel::A, &[0.1; 1000], sample_rate());
+    assert_eq!(statuses(&checks), [("Sync pulses on A", CheckStatus::Fail)]);
+}
+
+#[test]
+fn judges_the_signal() {
+    let rate = 1000.0;
+    let quality = |samples: Vec<f64>| ChannelQuality {
+        kind: "raw",
+        channel: "A".to_string(),
+        metrics: measure(&samples, rate, 1.0),
+    };
+    let noise = |i: usize| 0.0005 * ((i * 7919) % 13) as f64 / 13.0;
+    let hum = |i: usize, amplitude: f64| {
+        amplitude * (2.0 * std::f64::consts::PI * 50.0 * i as f64 / rate).sin()
+    };
+
+    let clean: Vec<f64> = (0..2000).map(|i| 0.1 + noise(i)).collect();
+    let mut clipping = clean.clone();
+    for x in clipping.iter_mut().step_by(100) {
+        *x = 1.0;
+    }
+    let humming: Vec<f64> = (0..2000).map(|i| noise(i) + hum(i, 0.015)).collect();
+    // Wandering off as well, so it scores too low to record
+    let floating: Vec<f64> = (0..2000)
+        .map(|i| noise(i) + hum(i, 0.5) + 0.2 * i as f64 / rate)
+        .collect();
+
+    let cases = [
+        (clean, CheckStatus::Pass, "Quality"),
+        (clipping, CheckStatus::Fail, "clipping"),
+        (vec![0.1; 2000], CheckStatus::Fail, "Completely flat"),
+        (humming, CheckStatus::Warn, "50 Hz interference"),
+        (floating, CheckStatus::Fail, "may be floating"),
+    ];
+    for (samples, status, detail) in cases.iter() {
+        let check = signal_check(&quality(samples.clone()));
+        assert_eq!(check.status, *status, "{}", check.detail);
+        assert!(check.detail.contains(detail), "{}", check.detail);
+    }
+}
+
+#[test]
+fn reports_the_worst_check() {
+    let report = run(&HashMap::new(), sample_rate(), &[]);
+    assert_eq!(report.status, CheckStatus::Fail);
+    assert_eq!(
+        report.checks.last().unwrap().detail,
+        "No samples have been streamed yet"
+    );
+
+    let mut raw = HashMap::new();
+    raw.insert(PicoChannel::A, mux_signal(100, 4));
+    let report = run(&raw, sample_rate(), &[]);
+    assert_eq!(report.status, CheckStatus::Pass, "{:?}", report.checks);
+    assert_eq!(report.checks[0].name, "Capture rate");
+
+    let report = run(&raw, sample_rate() * 0.8, &[]);
+    assert_eq!(report.status, CheckStatus::Warn, "{:?}", report.checks);
+}
@@ -0,0 +1,87 @@
+use super::Bucket;
+
+/// Merges consecutive buckets into at most `target` buckets, keeping the extremes of
+/// every channel so no spike disappears when zoomed out
+pub fn min_max(buckets: &[Bucket], target: usize) -> Vec<Bucket> {
+    if target == 0 || buckets.len() <= target {
+        return buckets.to_vec();
+    }
+
+    let per_group = buckets.len() as f64 / target as f64;
+    (0..target)
+        .filter_map(|group| {
+            let from = (group as f64 * per_group) as usize;
+            let to = (((group + 1) as f64 * per_group) as usize).min(buckets.len());
+            let slice = &buckets[from..to];
+            let first = slice.first()?;
+
+            let mut merged = first.clone();
+            merged.end = slice.last()?.end;
+            for bucket in slice[1..].iter() {
+                for channel in 0..merged.min.len() {
+                    merged.min[channel] = merged.min[channel].min(bucket.min[channel]);
+                    merged.max[channel] = merged.max[channel].max(bucket.max[channel]);
+                }
+            }
+            merged.mean = (0..merged.mean.len())
+                .map(|channel| {
+                    let values: Vec<f64> = slice
+                        .iter()
+                        .map(|b| b.mean[channel])
+                        .filter(|v| !v.is_nan())
+                        .collect();
+                    values.iter().sum::<f64>() / values.len() as f64
+                })
+                .collect();
+
+            Some(merged)
+        })
+        .collect()
+}
+
+/// Largest-Triangle-Three-Buckets downsampling of `(time, value)` points to `threshold`
+/// points, which keeps the visual shape of a line far better than striding
+pub fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<(f64, f64)> {
+    let points: Vec<(f64, f64)> = points.iter().copied().filter(|p| !p.1.is_nan()).collect();
+    if threshold >= points.len() || threshold < 3 {
+        return points;
+    }
+
+    let mut sampled = Vec::with_capacity(threshold);
+    // The first and last points are always kept, the rest is split into equal buckets
+    let every = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let mut a = 0;
+    sampled.push(points[a]);
+
+    for i in 0..(threshold - 2) {
+        // Average of the next bucket, used as the third triangle point
+        let next_start = ((i + 1) as f64 * every) as usize + 1;
+        let next_end = (((i + 2) as f64 * every) as usize + 1).min(points.len());
+        let next = &points[next_start..next_end.max(next_start + 1).min(points.len())];
+        let avg_x = next.iter().map(|p| p.0).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.1).sum::<f64>() / next.len() as f64;
+
+        let range_start = (i as f64 * every) as usize + 1;
+        let range_end = (((i + 1) as f64 * every) as usize + 1).min(points.len() - 1);
+
+        let (ax, ay) = points[a];
+        let mut max_area = -1.0;
+        let mut chosen = range_start;
+        for (offset, (x, y)) in points[range_start..range_end].iter().enumerate() {
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+            if area > max_area {
+                max_area = area;
+                chosen = range_start + offset;
+            }
+        }
+
+        sampled.push(points[chosen]);
+        a = chosen;
+    }
+
+    sampled.push(points[points.len() - 1]);
+    sampled
+}
+
+#[cfg(test)]
+mod tests;
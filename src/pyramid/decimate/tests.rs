@@ -0,0 +1,111 @@
+use super::*;
+
+/// A level 0 bucket, one frame of two channels
+fn frame(time: f64, values: [f64; 2]) -> Bucket {
+    Bucket {
+        start: time,
+        end: time,
+        min: values.to_vec(),
+        max: values.to_vec(),
+        mean: values.to_vec(),
+    }
+}
+
+fn frames(count: usize) -> Vec<Bucket> {
+    (0..count)
+        .map(|i| frame(i as f64, [i as f64, -(i as f64)]))
+        .collect()
+}
+
+fn line(count: usize, value: impl Fn(f64) -> f64) -> Vec<(f64, f64)> {
+    (0..count).map(|i| (i as f64, value(i as f64))).collect()
+}
+
+#[test]
+fn min_max_leaves_few_enough_buckets_alone() {
+    let buckets = frames(5);
+    assert_eq!(min_max(&buckets, 5), buckets);
+    assert_eq!(min_max(&buckets, 8), buckets);
+    assert_eq!(min_max(&buckets, 0), buckets);
+}
+
+#[test]
+fn min_max_merges_into_the_target() {
+    let merged = min_max(&frames(10), 3);
+    assert_eq!(merged.len(), 3);
+
+    // Groups of 3, 3 and 4, covering every bucket once
+    let spans: Vec<(f64, f64)> = merged.iter().map(|b| (b.start, b.end)).collect();
+    assert_eq!(spans, [(0.0, 2.0), (3.0, 5.0), (6.0, 9.0)]);
+    assert_eq!(merged[2].min, [6.0, -9.0]);
+    assert_eq!(merged[2].max, [9.0, -6.0]);
+    assert_eq!(merged[2].mean, [7.5, -7.5]);
+}
+
+#[test]
+fn min_max_keeps_a_single_spike() {
+    let mut buckets = frames(100);
+    buckets[37] = frame(37.0, [500.0, -500.0]);
+
+    let merged = min_max(&buckets, 4);
+    let spiked: Vec<&Bucket> = merged.iter().filter(|b| b.max[0] == 500.0).collect();
+    assert_eq!(spiked.len(), 1);
+    assert!(spiked[0].start <= 37.0 && 37.0 <= spiked[0].end);
+    assert_eq!(spiked[0].min[1], -500.0);
+}
+
+#[test]
+fn min_max_means_skip_missing_frames() {
+    let mut buckets = frames(4);
+    buckets[1].mean[0] = f64::NAN;
+
+    let merged = min_max(&buckets, 2);
+    assert_eq!(merged[0].mean, [0.0, -0.5]);
+    assert_eq!(merged[1].mean, [2.5, -2.5]);
+}
+
+#[test]
+fn lttb_keeps_short_lines() {
+    let points = line(10, |x| x * x);
+    assert_eq!(lttb(&points, 10), points);
+    assert_eq!(lttb(&points, 50), points);
+    assert_eq!(lttb(&points, 2), points);
+}
+
+#[test]
+fn lttb_drops_missing_points() {
+    let mut points = line(10, |x| x);
+    points[4].1 = f64::NAN;
+
+    let sampled = lttb(&points, 20);
+    assert_eq!(sampled.len(), 9);
+    assert!(sampled.iter().all(|p| !p.1.is_nan()));
+}
+
+#[test]
+fn lttb_returns_the_threshold_in_time_order() {
+    let points = line(1000, |x| (x / 40.0).sin());
+    let sampled = lttb(&points, 50);
+
+    assert_eq!(sampled.len(), 50);
+    assert_eq!(sampled[0], points[0]);
+    assert_eq!(sampled[49], points[999]);
+    assert!(sampled.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(sampled.iter().all(|p| points.contains(p)));
+}
+
+#[test]
+fn lttb_keeps_the_shape() {
+    // A few cycles of a sine keep their peaks and troughs
+    let points = line(1000, |x| (x / 40.0).sin());
+    let sampled = lttb(&points, 50);
+    let highest = sampled.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+    let lowest = sampled.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+    assert!(highest > 0.98, "peaks flattened to {}", highest);
+    assert!(lowest < -0.98, "troughs flattened to {}", lowest);
+
+    // And a flat line keeps its one spike
+    let mut points = line(1000, |_| 0.0);
+    points[613].1 = 3.0;
+    assert!(lttb(&points, 20).contains(&(613.0, 3.0)));
+}
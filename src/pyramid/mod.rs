@@ -0,0 +1,466 @@
+pub mod decimate;
+
+use crate::{
+    metrics::{Output, COUNTERS},
+    virt_channels::{VirtChannel, VirtSamples},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Sub directory of the session holding the summary levels
+pub const PYRAMID_DIR: &str = "pyramid";
+const HEADER_FILE: &str = "pyramid.json";
+/// Each level summarises this many rows of the level below it
+const FANOUT: usize = 16;
+/// 16^7 frames is about two days at our frame rate, plenty
+const MAX_LEVELS: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PyramidHeader {
+    pub channels: Vec<VirtChannel>,
+    pub fanout: usize,
+}
+
+/// Summary of a stretch of frames. On level 0 this is a single frame,
+/// with `start == end` and `min == max == mean`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Bucket {
+    pub start: f64,
+    pub end: f64,
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+    pub mean: Vec<f64>,
+}
+
+fn level_path(directory: &Path, level: usize) -> PathBuf {
+    directory.join(format!("level_{}.bin", level))
+}
+
+/// Bytes per row: level 0 stores the time and one value per channel,
+/// higher levels store start, end and min/max/mean per channel
+fn row_len(level: usize, width: usize) -> usize {
+    if level == 0 {
+        8 * (1 + width)
+    } else {
+        8 * (2 + 3 * width)
+    }
+}
+
+/// Builds the next summary row while the rows below it come in
+#[derive(Clone)]
+struct Accumulator {
+    rows: usize,
+    start: f64,
+    end: f64,
+    min: Vec<f64>,
+    max: Vec<f64>,
+    sum: Vec<f64>,
+    /// Frames behind each channel's sum, ignoring missing (NaN) samples
+    weight: Vec<f64>,
+}
+
+impl Accumulator {
+    fn new(width: usize) -> Self {
+        Accumulator {
+            rows: 0,
+            start: 0.0,
+            end: 0.0,
+            min: vec![f64::NAN; width],
+            max: vec![f64::NAN; width],
+            sum: vec![0.0; width],
+            weight: vec![0.0; width],
+        }
+    }
+
+    fn add(&mut self, bucket: &Bucket, weight: &[f64]) {
+        if self.rows == 0 {
+            self.start = bucket.start;
+        }
+        self.end = bucket.end;
+        self.rows += 1;
+
+        for channel in 0..self.min.len() {
+            // f64::min/max ignore NaN, so missing samples drop out on their own
+            self.min[channel] = self.min[channel].min(bucket.min[channel]);
+            self.max[channel] = self.max[channel].max(bucket.max[channel]);
+            if !bucket.mean[channel].is_nan() {
+                self.sum[channel] += bucket.mean[channel] * weight[channel];
+                self.weight[channel] += weight[channel];
+            }
+        }
+    }
+
+    fn take(&mut self) -> (Bucket, Vec<f64>) {
+        let width = self.min.len();
+        let finished = std::mem::replace(self, Accumulator::new(width));
+        (
+            Bucket {
+                start: finished.start,
+                end: finished.end,
+                mean: finished
+                    .sum
+                    .iter()
+                    .zip(finished.weight.iter())
+                    .map(|(s, w)| if *w > 0.0 { s / w } else { f64::NAN })
+                    .collect(),
+                min: finished.min,
+                max: finished.max,
+            },
+            finished.weight,
+        )
+    }
+}
+
+/// Writes every frame of a session plus progressively coarser min/max/mean summaries,
+/// so a time range can be drawn at any zoom level by reading only a few thousand rows
+pub struct PyramidWriter {
+    directory: PathBuf,
+    header: PyramidHeader,
+    levels: Vec<BufWriter<File>>,
+    /// `accumulators[n]` builds the rows of level `n + 1`
+    accumulators: Vec<Accumulator>,
+    /// Demultiplexed blocks can finish out of order, so they're held until it's their turn
+    pending: BTreeMap<u64, Vec<(f64, VirtSamples)>>,
+    next_block: u64,
+}
+
+impl PyramidWriter {
+    pub fn create(session_directory: &Path, channels: Vec<VirtChannel>) -> Result<Self> {
+        let directory = session_directory.join(PYRAMID_DIR);
+        fs::create_dir_all(&directory)?;
+
+        let header = PyramidHeader {
+            channels,
+            fanout: FANOUT,
+        };
+        serde_json::to_writer_pretty(File::create(directory.join(HEADER_FILE))?, &header)?;
+
+        Ok(PyramidWriter {
+            levels: vec![BufWriter::new(File::create(level_path(&directory, 0))?)],
+            accumulators: vec![],
+            pending: BTreeMap::new(),
+            next_block: 0,
+            directory,
+            header,
+        })
+    }
+
+    /// Adds the `(timestamp, samples)` frames of one block. Blocks must be numbered
+    /// consecutively from 0, including blocks that produced no frames
+    pub fn append_block(&mut self, sequence: u64, frames: Vec<(f64, VirtSamples)>) -> Result<()> {
+        self.pending.insert(sequence, frames);
+
+        while let Some(mut frames) = self.pending.remove(&self.next_block) {
+            frames.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            for (timestamp, samples) in frames {
+                let values: Vec<f64> = self
+                    .header
+                    .channels
+                    .iter()
+                    .map(|c| samples.get(c).copied().unwrap_or(f64::NAN))
+                    .collect();
+                let weight: Vec<f64> = values
+                    .iter()
+                    .map(|v| if v.is_nan() { 0.0 } else { 1.0 })
+                    .collect();
+                self.push(
+                    0,
+                    Bucket {
+                        start: timestamp,
+                        end: timestamp,
+                        min: values.clone(),
+                        max: values.clone(),
+                        mean: values,
+                    },
+                    &weight,
+                )?;
+            }
+            self.next_block += 1;
+        }
+
+        // Make the new rows visible to queries while still recording
+        for level in self.levels.iter_mut() {
+            level.flush()?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, level: usize, bucket: Bucket, weight: &[f64]) -> Result<()> {
+        if level >= self.levels.len() {
+            self.levels.push(BufWriter::new(File::create(level_path(
+                &self.directory,
+                level,
+            ))?));
+        }
+
+        let writer = &mut self.levels[level];
+        COUNTERS.written(
+            Output::Pyramid,
+            row_len(level, self.header.channels.len()) as u64,
+        );
+        if level == 0 {
+            writer.write_all(&bucket.start.to_le_bytes())?;
+            for value in bucket.mean.iter() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        } else {
+            writer.write_all(&bucket.start.to_le_bytes())?;
+            writer.write_all(&bucket.end.to_le_bytes())?;
+            for value in bucket.min.iter().chain(bucket.max.iter()).chain(bucket.mean.iter()) {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        if level + 1 >= MAX_LEVELS {
+            return Ok(());
+        }
+        if level >= self.accumulators.len() {
+            self.accumulators
+                .push(Accumulator::new(self.header.channels.len()));
+        }
+
+        self.accumulators[level].add(&bucket, weight);
+        if self.accumulators[level].rows >= self.header.fanout {
+            let (summary, weight) = self.accumulators[level].take();
+            self.push(level + 1, summary, &weight)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out everything still held back, including partially filled summaries
+    pub fn finish(mut self) -> Result<()> {
+        // Skip over blocks that never arrived
+        for (sequence, frames) in std::mem::take(&mut self.pending) {
+            self.next_block = sequence;
+            self.append_block(sequence, frames)?;
+        }
+
+        let mut level = 0;
+        while level < self.accumulators.len() {
+            if self.accumulators[level].rows > 0 {
+                let (summary, weight) = self.accumulators[level].take();
+                self.push(level + 1, summary, &weight)?;
+            }
+            level += 1;
+        }
+
+        for level in self.levels.iter_mut() {
+            level.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecimationMode {
+    MinMax,
+    Lttb,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeQuery {
+    pub start: f64,
+    pub end: f64,
+    /// Pyramid level the data came from, 0 being every frame
+    pub level: usize,
+    pub mode: DecimationMode,
+    /// Per virtual channel: `[start, end, min, max]` buckets for min/max,
+    /// or `[time, value]` points for LTTB
+    pub channels: BTreeMap<VirtChannel, Vec<Vec<f64>>>,
+}
+
+/// Read side of a session's pyramid
+pub struct Pyramid {
+    directory: PathBuf,
+    header: PyramidHeader,
+}
+
+impl Pyramid {
+    pub fn open(session_directory: &Path) -> Result<Self> {
+        let directory = session_directory.join(PYRAMID_DIR);
+        let header = serde_json::from_reader(File::open(directory.join(HEADER_FILE))?)?;
+        Ok(Pyramid { directory, header })
+    }
+
+    fn level_count(&self) -> usize {
+        (0..MAX_LEVELS)
+            .take_while(|level| level_path(&self.directory, *level).is_file())
+            .count()
+    }
+
+    /// Time covered by the finest level
+    pub fn time_span(&self) -> Result<Option<(f64, f64)>> {
+        let mut level = LevelReader::open(&self.directory, 0, self.header.channels.len())?;
+        if level.rows == 0 {
+            return Ok(None);
+        }
+        let first = level.row(0)?;
+        let last = level.row(level.rows - 1)?;
+        Ok(Some((first.start, last.end)))
+    }
+
+    /// Returns the channels between `start` and `end` seconds, reduced to about `points`
+    /// values each, read from the coarsest level that still has enough detail
+    pub fn query(
+        &self,
+        start: f64,
+        end: f64,
+        points: usize,
+        mode: DecimationMode,
+    ) -> Result<RangeQuery> {
+        if end < start {
+            return Err(anyhow!("range ends before it starts"));
+        }
+        let width = self.header.channels.len();
+        let needed = match mode {
+            // Each bucket draws as two points
+            DecimationMode::MinMax => (points / 2).max(1),
+            DecimationMode::Lttb => points.max(3),
+        };
+
+        let mut chosen = LevelReader::open(&self.directory, 0, width)?;
+        for level in (1..self.level_count()).rev() {
+            let mut reader = LevelReader::open(&self.directory, level, width)?;
+            let (from, to) = reader.range(start, end)?;
+            if to - from >= needed as u64 {
+                chosen = reader;
+                break;
+            }
+        }
+
+        let (from, to) = chosen.range(start, end)?;
+        let buckets = chosen.rows_between(from, to)?;
+
+        let mut channels = BTreeMap::new();
+        match mode {
+            DecimationMode::MinMax => {
+                let reduced = decimate::min_max(&buckets, needed);
+                for (index, channel) in self.header.channels.iter().enumerate() {
+                    channels.insert(
+                        *channel,
+                        reduced
+                            .iter()
+                            .map(|b| vec![b.start, b.end, b.min[index], b.max[index]])
+                            .collect(),
+                    );
+                }
+            }
+            DecimationMode::Lttb => {
+                for (index, channel) in self.header.channels.iter().enumerate() {
+                    let line: Vec<(f64, f64)> = buckets
+                        .iter()
+                        .map(|b| ((b.start + b.end) / 2.0, b.mean[index]))
+                        .collect();
+                    channels.insert(
+                        *channel,
+                        decimate::lttb(&line, needed)
+                            .into_iter()
+                            .map(|(t, v)| vec![t, v])
+                            .collect(),
+                    );
+                }
+            }
+        }
+
+        Ok(RangeQuery {
+            start,
+            end,
+            level: chosen.level,
+            mode,
+            channels,
+        })
+    }
+}
+
+/// Random access to the fixed size rows of one level file
+struct LevelReader {
+    file: File,
+    level: usize,
+    width: usize,
+    rows: u64,
+}
+
+impl LevelReader {
+    fn open(directory: &Path, level: usize, width: usize) -> Result<Self> {
+        let file = File::open(level_path(directory, level))?;
+        // A row may be half written while recording, so only count whole ones
+        let rows = file.metadata()?.len() / row_len(level, width) as u64;
+        Ok(LevelReader {
+            file,
+            level,
+            width,
+            rows,
+        })
+    }
+
+    fn row(&mut self, index: u64) -> Result<Bucket> {
+        Ok(self.rows_between(index, index + 1)?.remove(0))
+    }
+
+    /// First row for which `before(row)` is false, rows being sorted by time
+    fn partition_point(&mut self, before: impl Fn(&Bucket) -> bool) -> Result<u64> {
+        let (mut low, mut high) = (0, self.rows);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if before(&self.row(middle)?) {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Rows overlapping `start..=end`, as a half open row range
+    fn range(&mut self, start: f64, end: f64) -> Result<(u64, u64)> {
+        let from = self.partition_point(|b| b.end < start)?;
+        let to = self.partition_point(|b| b.start <= end)?;
+        Ok((from, to.max(from)))
+    }
+
+    fn rows_between(&mut self, from: u64, to: u64) -> Result<Vec<Bucket>> {
+        let len = row_len(self.level, self.width);
+        let mut raw = vec![0u8; (to - from) as usize * len];
+        self.file.seek(SeekFrom::Start(from * len as u64))?;
+        self.file.read_exact(&mut raw)?;
+
+        let w = self.width;
+        Ok(raw
+            .chunks_exact(len)
+            .map(|row| {
+                let values: Vec<f64> = row
+                    .chunks_exact(8)
+                    .map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+                    .collect();
+
+                if self.level == 0 {
+                    let samples = values[1..].to_vec();
+                    Bucket {
+                        start: values[0],
+                        end: values[0],
+                        min: samples.clone(),
+                        max: samples.clone(),
+                        mean: samples,
+                    }
+                } else {
+                    Bucket {
+                        start: values[0],
+                        end: values[1],
+                        min: values[2..2 + w].to_vec(),
+                        max: values[2 + w..2 + 2 * w].to_vec(),
+                        mean: values[2 + 2 * w..2 + 3 * w].to_vec(),
+                    }
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,458 @@
+pub mod sync;
+
+use crate::{
+    electrodes::ChannelMap,
+    filters::FilteredFrame,
+    virt_channels::{VirtChannel, VirtSamples},
+    ConstConfig,
+};
+
+use pico_sdk::common::PicoChannel;
+use serde::Serialize;
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    f64::consts::PI,
+};
+
+/// Raw samples kept per channel at full rate, for noise, clipping and the preflight's
+/// demultiplexing, so fast capture rates don't hold seconds of data. At 1 MS/s that's
+/// only about 0.26 s of `quality_window`, which is why line noise and drift are
+/// measured on a `Decimated` window instead
+const MAX_RAW_SAMPLES: usize = 1 << 18;
+
+/// Mains frequencies checked for line interference
+const LINE_FREQUENCIES: [f64; 2] = [50.0, 60.0];
+
+/// Signal quality of one channel, amplitudes are in the channel's units
+#[derive(Clone, Debug, Serialize)]
+pub struct QualityMetrics {
+    /// Estimated from sample to sample differences, so slow signals don't count as noise
+    pub rms_noise: f64,
+    /// Whichever of 50 or 60 Hz is strongest
+    pub line_frequency: f64,
+    pub line_amplitude: f64,
+    /// Slope of the baseline in units per second
+    pub drift: f64,
+    /// Share of samples at the limit of the input range
+    pub clipped: f64,
+    /// Input range the limits were applied against
+    pub full_scale: f64,
+    /// 0 to 100, higher is better
+    pub score: f64,
+    pub rating: &'static str,
+    pub issues: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelQuality {
+    /// `raw` for a Pico channel, `virtual` for a demultiplexed one
+    pub kind: &'static str,
+    pub channel: String,
+    pub metrics: QualityMetrics,
+}
+
+#[derive(Clone)]
+struct Window {
+    samples: VecDeque<f64>,
+    capacity: usize,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Window {
+            samples: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn extend(&mut self, values: impl Iterator<Item = f64>) {
+        self.samples.extend(values);
+        let excess = self.samples.len().saturating_sub(self.capacity);
+        self.samples.drain(..excess);
+    }
+
+    fn to_vec(&self) -> Vec<f64> {
+        self.samples.iter().copied().collect()
+    }
+}
+
+/// Holds the last sample below `threshold` through any above it, so a multiplexer's
+/// sync pulses are masked out without moving the samples after them. Nothing comes out
+/// until there's a sample to hold
+fn hold_below(samples: &[f64], threshold: f64, held: &mut Option<f64>) -> Vec<f64> {
+    samples
+        .iter()
+        .filter_map(|x| {
+            if *x < threshold {
+                *held = Some(*x);
+            }
+            *held
+        })
+        .collect()
+}
+
+/// The whole quality window of a raw channel, averaged down so it fits in
+/// `MAX_RAW_SAMPLES`. A window of full rate samples can be too short to tell 50 from
+/// 60 Hz, or see drift
+#[derive(Clone)]
+struct Decimated {
+    window: Window,
+    /// Raw samples averaged into each one kept
+    factor: usize,
+    sum: f64,
+    count: usize,
+    /// Sync pulses are masked from multiplexed channels, above this
+    sync_threshold: Option<f64>,
+    held: Option<f64>,
+}
+
+impl Decimated {
+    fn new(sample_rate: f64, sync_threshold: Option<f64>) -> Self {
+        let window = ConstConfig::get_config().quality_window.as_secs_f64() * sample_rate;
+        let factor = ((window / MAX_RAW_SAMPLES as f64).ceil() as usize).max(1);
+        Decimated {
+            window: Window::new((window as usize / factor).max(1)),
+            factor,
+            sum: 0.0,
+            count: 0,
+            sync_threshold,
+            held: None,
+        }
+    }
+
+    fn extend(&mut self, samples: &[f64]) {
+        let masked;
+        let samples = match self.sync_threshold {
+            Some(threshold) => {
+                masked = hold_below(samples, threshold, &mut self.held);
+                &masked
+            }
+            None => samples,
+        };
+
+        let mut averaged = vec![];
+        for x in samples {
+            self.sum += x;
+            self.count += 1;
+            if self.count == self.factor {
+                averaged.push(self.sum / self.factor as f64);
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+        self.window.extend(averaged.into_iter());
+    }
+
+    fn sample_rate(&self, raw_rate: f64) -> f64 {
+        raw_rate / self.factor as f64
+    }
+}
+
+/// Amplitude of a single frequency, via the Goertzel algorithm
+fn tone_amplitude(samples: &[f64], frequency: f64, sample_rate: f64) -> f64 {
+    if samples.is_empty() || frequency >= sample_rate / 2.0 {
+        return 0.0;
+    }
+
+    let coefficient = 2.0 * (2.0 * PI * frequency / sample_rate).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for x in samples {
+        let s0 = x + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+    2.0 * power.max(0.0).sqrt() / samples.len() as f64
+}
+
+/// Least squares slope through the samples, per sample, and what's left once that line
+/// is removed
+fn detrend(samples: &[f64]) -> (f64, Vec<f64>) {
+    let n = samples.len() as f64;
+    let t_mean = (n - 1.0) / 2.0;
+    let mean = samples.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (i, x) in samples.iter().enumerate() {
+        covariance += (i as f64 - t_mean) * (x - mean);
+        variance += (i as f64 - t_mean).powi(2);
+    }
+    let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+    let detrended = samples
+        .iter()
+        .enumerate()
+        .map(|(i, x)| x - mean - slope * (i as f64 - t_mean))
+        .collect();
+    (slope, detrended)
+}
+
+/// Measures a window of samples against the configured limits, which are all given as
+/// fractions of `full_scale`
+pub fn measure(samples: &[f64], sample_rate: f64, full_scale: f64) -> QualityMetrics {
+    measure_decimated(samples, samples, sample_rate, full_scale, None)
+}
+
+/// As `measure`, but with line noise and drift measured on `decimated`, a longer window
+/// of the same signal at `decimated_rate`. A multiplexed channel's sync pulses are masked
+/// above `sync_threshold`, though they still count if they clip, and its noise isn't
+/// scored as it steps from slot to slot
+fn measure_decimated(
+    samples: &[f64],
+    decimated: &[f64],
+    decimated_rate: f64,
+    full_scale: f64,
+    sync_threshold: Option<f64>,
+) -> QualityMetrics {
+    let const_config = ConstConfig::get_config();
+    let n = samples.len() as f64;
+
+    // The line through the window is removed before looking for line noise
+    let (slope, detrended) = detrend(decimated);
+
+    let masked = match sync_threshold {
+        Some(threshold) => hold_below(samples, threshold, &mut None),
+        None => samples.to_vec(),
+    };
+    let rms_noise = if masked.len() > 1 {
+        let sum: f64 = masked.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+        (sum / (masked.len() as f64 - 1.0) / 2.0).sqrt()
+    } else {
+        0.0
+    };
+    let (line_frequency, line_amplitude) = LINE_FREQUENCIES
+        .iter()
+        .map(|f| (*f, tone_amplitude(&detrended, *f, decimated_rate)))
+        .fold((LINE_FREQUENCIES[0], 0.0), |a, b| if b.1 > a.1 { b } else { a });
+    let drift = slope * decimated_rate;
+    let clipped = samples
+        .iter()
+        .filter(|x| x.abs() >= full_scale * 0.99)
+        .count() as f64
+        / n;
+
+    let mut issues = vec![];
+    let mut score = 0.0;
+    let mut checks = vec![
+        (
+            "line interference",
+            line_amplitude / full_scale,
+            const_config.quality_line_limit,
+        ),
+        ("baseline drift", drift.abs() / full_scale, const_config.quality_drift_limit),
+        ("clipping", clipped, const_config.quality_clip_limit),
+    ];
+    if sync_threshold.is_none() {
+        checks.insert(
+            0,
+            (
+                "noise",
+                rms_noise / full_scale,
+                const_config.quality_noise_limit,
+            ),
+        );
+    }
+    for (name, value, limit) in checks.iter() {
+        let ratio = value / limit;
+        // Full marks up to half the limit, none at twice the limit
+        score += 100.0 / checks.len() as f64 * ((2.0 - ratio) / 1.5).max(0.0).min(1.0);
+        if ratio > 1.0 {
+            issues.push(format!("{} is {:.1}x the limit", name, ratio));
+        }
+    }
+    // Nothing at all moving, not even noise, means nothing is connected
+    if rms_noise == 0.0 {
+        score = 0.0;
+        issues.push("flat, nothing is connected".to_string());
+    }
+
+    QualityMetrics {
+        rms_noise,
+        line_frequency,
+        line_amplitude,
+        drift,
+        clipped,
+        full_scale,
+        score,
+        rating: if score >= 75.0 {
+            "good"
+        } else if score >= 50.0 {
+            "fair"
+        } else {
+            "poor"
+        },
+        issues,
+    }
+}
+
+/// Level above which a Pico channel's samples are sync pulses, if it's multiplexed
+fn sync_threshold(channel: PicoChannel) -> Option<f64> {
+    let const_config = ConstConfig::get_config();
+    if const_config.mux_layout(&channel.to_string()).has_sync() {
+        Some(const_config.sync_point_threshold)
+    } else {
+        None
+    }
+}
+
+/// Keeps the latest few seconds of every raw and virtual channel for quality checks
+#[derive(Clone)]
+pub struct QualityMonitor {
+    raw: HashMap<PicoChannel, Window>,
+    raw_rate: f64,
+    /// Of every Pico channel, sync pulses masked from the multiplexed ones
+    decimated: HashMap<PicoChannel, Decimated>,
+    virt: BTreeMap<VirtChannel, Window>,
+    /// Of every virtual channel, by key
+    frame_rates: BTreeMap<VirtChannel, f64>,
+    /// Input range of each Pico channel, in its units
+    full_scale: HashMap<PicoChannel, f64>,
+}
+
+impl QualityMonitor {
+    pub fn new() -> Self {
+        QualityMonitor {
+            raw: HashMap::new(),
+            raw_rate: 0.0,
+            decimated: HashMap::new(),
+            virt: BTreeMap::new(),
+            frame_rates: BTreeMap::new(),
+            full_scale: HashMap::new(),
+        }
+    }
+
+    pub fn set_frame_rates(&mut self, frame_rates: BTreeMap<VirtChannel, f64>) {
+        self.frame_rates = frame_rates;
+        self.virt.clear();
+    }
+
+    /// Of a virtual channel, or the default frame rate if it isn't mapped
+    fn frame_rate(&self, channel: VirtChannel) -> f64 {
+        self.frame_rates
+            .get(&channel)
+            .copied()
+            .unwrap_or_else(|| ConstConfig::get_config().frame_rate())
+    }
+
+    pub fn set_full_scale(&mut self, channel: PicoChannel, full_scale: f64) {
+        self.full_scale.insert(channel, full_scale);
+    }
+
+    pub fn push_raw(&mut self, channel: PicoChannel, samples: &[f64], sample_rate: f64) {
+        let capacity = ((ConstConfig::get_config().quality_window.as_secs_f64() * sample_rate)
+            as usize)
+            .min(MAX_RAW_SAMPLES);
+        self.raw_rate = sample_rate;
+        // Only the tail can stay in the window
+        let tail = &samples[samples.len().saturating_sub(capacity)..];
+        self.raw
+            .entry(channel)
+            .or_insert_with(|| Window::new(capacity))
+            .extend(tail.iter().copied());
+
+        self.decimated
+            .entry(channel)
+            .or_insert_with(|| Decimated::new(sample_rate, sync_threshold(channel)))
+            .extend(samples);
+    }
+
+    pub fn push_virtual<'a>(&mut self, frames: impl Iterator<Item = &'a VirtSamples>) {
+        let window = ConstConfig::get_config().quality_window.as_secs_f64();
+
+        for frame in frames {
+            for (channel, value) in frame.iter() {
+                if !value.is_nan() {
+                    let capacity = (window * self.frame_rate(*channel)) as usize;
+                    self.virt
+                        .entry(*channel)
+                        .or_insert_with(|| Window::new(capacity))
+                        .extend(std::iter::once(*value));
+                }
+            }
+        }
+    }
+
+    /// Raw side of frames that have been through the filter chains
+    pub fn push_frames(&mut self, frames: &[FilteredFrame]) {
+        self.push_virtual(frames.iter().map(|f| &f.raw));
+    }
+
+    /// Latest raw samples of every Pico channel, with the rate they were captured at
+    pub fn raw_samples(&self) -> (HashMap<PicoChannel, Vec<f64>>, f64) {
+        (
+            self.raw
+                .iter()
+                .map(|(channel, window)| (*channel, window.to_vec()))
+                .collect(),
+            self.raw_rate,
+        )
+    }
+
+    /// Quality of every Pico channel, and every virtual channel by its name in `map`
+    pub fn report(&self, map: &ChannelMap) -> Vec<ChannelQuality> {
+        // Montages don't come from any one Pico channel, so they're measured against the
+        // widest input range
+        let virt_full_scale = self
+            .full_scale
+            .values()
+            .copied()
+            .fold(0.0, f64::max)
+            .max(f64::MIN_POSITIVE);
+
+        let mut raw: Vec<(&PicoChannel, &Window, &Decimated)> = self
+            .raw
+            .iter()
+            .filter_map(|(channel, window)| Some((channel, window, self.decimated.get(channel)?)))
+            .collect();
+        raw.sort_by_key(|(channel, _, _)| **channel);
+
+        raw.into_iter()
+            .filter(|(_, window, decimated)| {
+                window.samples.len() > 1 && decimated.window.samples.len() > 1
+            })
+            .map(|(channel, window, decimated)| ChannelQuality {
+                kind: "raw",
+                channel: channel.to_string(),
+                metrics: measure_decimated(
+                    &window.to_vec(),
+                    &decimated.window.to_vec(),
+                    decimated.sample_rate(self.raw_rate),
+                    self.full_scale
+                        .get(channel)
+                        .copied()
+                        .unwrap_or(virt_full_scale),
+                    sync_threshold(*channel),
+                ),
+            })
+            .chain(
+                self.virt
+                    .iter()
+                    .filter(|(_, window)| window.samples.len() > 1)
+                    .map(|(key, window)| {
+                        let full_scale = map
+                            .channels
+                            .iter()
+                            .find(|c| c.key == *key)
+                            .and_then(|c| c.channel.as_ref())
+                            .and_then(|channel| {
+                                self.full_scale
+                                    .iter()
+                                    .find(|(pico, _)| &pico.to_string() == channel)
+                            })
+                            .map_or(virt_full_scale, |(_, full_scale)| *full_scale);
+                        ChannelQuality {
+                            kind: "virtual",
+                            channel: map.name(*key),
+                            metrics: measure(
+                                &window.to_vec(),
+                                self.frame_rate(*key),
+                                full_scale,
+                            ),
+                        }
+                    }),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests;
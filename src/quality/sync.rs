@@ -0,0 +1,233 @@
+//! How well the sync pulses were found, block by block for the live view and the
+//! session's `sync.csv`, and summed up per channel over a session
+
+use crate::virt_channels::{SyncStats, WIDTH_BINS};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    path::Path,
+};
+
+/// Name of the per block sync log written into every session directory
+pub const SYNC_LOG_FILE: &str = "sync.csv";
+
+/// Blocks kept for the live view
+const RECENT_BLOCKS: usize = 60;
+
+/// Sync pulses of one Pico channel in a block
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelSync {
+    pub channel: String,
+    pub stats: SyncStats,
+    /// Kind of `VirtChannelError` if the channel couldn't be demultiplexed
+    pub error: Option<&'static str>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BlockSync {
+    /// Place in the recording, or none for a block only demultiplexed to preview
+    pub sequence: Option<u64>,
+    pub received: DateTime<Local>,
+    pub channels: Vec<ChannelSync>,
+}
+
+/// Keeps the sync stats of the latest blocks, and the totals of the session being
+/// recorded
+pub struct SyncMonitor {
+    recent: VecDeque<BlockSync>,
+    session: BTreeMap<String, SyncSummary>,
+}
+
+impl SyncMonitor {
+    pub fn new() -> Self {
+        SyncMonitor {
+            recent: VecDeque::new(),
+            session: BTreeMap::new(),
+        }
+    }
+
+    /// Empty once the session stops
+    pub fn set_session(&mut self, totals: BTreeMap<String, SyncSummary>) {
+        self.session = totals;
+    }
+
+    pub fn view(&self) -> SyncView {
+        SyncView {
+            recent: self.recent(),
+            session: self
+                .session
+                .iter()
+                .map(|(channel, summary)| summary.report(channel))
+                .collect(),
+        }
+    }
+
+    pub fn push(&mut self, block: BlockSync) {
+        if self.recent.len() == RECENT_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(block);
+    }
+
+    /// Oldest first
+    pub fn recent(&self) -> Vec<BlockSync> {
+        self.recent.iter().cloned().collect()
+    }
+}
+
+/// Sync stats of one channel summed over a session, kept in its metadata
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SyncSummary {
+    pub blocks: u64,
+    /// Blocks the channel couldn't be demultiplexed in
+    pub failed: u64,
+    pub pulses: u64,
+    pub rejected: u64,
+    pub intervals: u64,
+    pub interval_sum: f64,
+    pub interval_sum_squares: f64,
+    pub width_histogram: Vec<u64>,
+    pub samples: u64,
+    pub covered_samples: f64,
+}
+
+impl SyncSummary {
+    pub fn add(&mut self, sync: &ChannelSync) {
+        let stats = &sync.stats;
+        self.blocks += 1;
+        if sync.error.is_some() {
+            self.failed += 1;
+        }
+        self.pulses += stats.pulses as u64;
+        self.rejected += stats.rejected as u64;
+
+        // Enough to get the mean and deviation of every interval back out
+        let intervals = stats.intervals as f64;
+        self.intervals += stats.intervals as u64;
+        self.interval_sum += intervals * stats.interval_mean;
+        self.interval_sum_squares +=
+            intervals * (stats.interval_std.powi(2) + stats.interval_mean.powi(2));
+
+        if self.width_histogram.is_empty() {
+            self.width_histogram = vec![0; WIDTH_BINS];
+        }
+        for (total, count) in self.width_histogram.iter_mut().zip(&stats.width_histogram) {
+            *total += count;
+        }
+
+        self.samples += stats.samples as u64;
+        self.covered_samples += stats.coverage * stats.samples as f64;
+    }
+
+    pub fn report(&self, channel: &str) -> SyncReport {
+        let (interval_mean, interval_std) = if self.intervals > 0 {
+            let n = self.intervals as f64;
+            let mean = self.interval_sum / n;
+            (
+                mean,
+                (self.interval_sum_squares / n - mean.powi(2))
+                    .max(0.0)
+                    .sqrt(),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        SyncReport {
+            channel: channel.to_string(),
+            blocks: self.blocks,
+            failed: self.failed,
+            pulses: self.pulses,
+            rejected: self.rejected,
+            interval_mean,
+            interval_std,
+            width_histogram: self.width_histogram.clone(),
+            coverage: if self.samples > 0 {
+                self.covered_samples / self.samples as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Latest blocks, and the totals of the session being recorded if any
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncView {
+    pub recent: Vec<BlockSync>,
+    pub session: Vec<SyncReport>,
+}
+
+/// Sync quality of one channel over a whole session
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncReport {
+    pub channel: String,
+    pub blocks: u64,
+    pub failed: u64,
+    pub pulses: u64,
+    pub rejected: u64,
+    /// In samples
+    pub interval_mean: f64,
+    pub interval_std: f64,
+    /// Bins of a tenth of a multiplexer slot, see `WIDTH_BINS`
+    pub width_histogram: Vec<u64>,
+    pub coverage: f64,
+}
+
+#[derive(Serialize)]
+struct SyncRow<'a> {
+    sequence: u64,
+    /// Seconds since the session started
+    time: f64,
+    channel: &'a str,
+    samples: usize,
+    pulses: usize,
+    rejected: usize,
+    interval_mean: f64,
+    interval_std: f64,
+    width_mean: f64,
+    width_std: f64,
+    coverage: f64,
+    error: &'a str,
+}
+
+/// Writes a row per channel of every recorded block to `sync.csv`
+pub struct SyncLog {
+    writer: csv::Writer<File>,
+}
+
+impl SyncLog {
+    pub fn create(directory: &Path) -> Result<Self> {
+        Ok(SyncLog {
+            writer: csv::Writer::from_path(directory.join(SYNC_LOG_FILE))?,
+        })
+    }
+
+    pub fn append(&mut self, sequence: u64, time: f64, channels: &[ChannelSync]) -> Result<()> {
+        for sync in channels {
+            let stats = &sync.stats;
+            self.writer.serialize(SyncRow {
+                sequence,
+                time,
+                channel: &sync.channel,
+                samples: stats.samples,
+                pulses: stats.pulses,
+                rejected: stats.rejected,
+                interval_mean: stats.interval_mean,
+                interval_std: stats.interval_std,
+                width_mean: stats.width_mean,
+                width_std: stats.width_std,
+                coverage: stats.coverage,
+                error: sync.error.unwrap_or(""),
+            })?;
+        }
+        // So it can be read while still recording
+        self.writer.flush()?;
+        Ok(())
+    }
+}
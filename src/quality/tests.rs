@@ -0,0 +1,151 @@
+//! Scoring of made up signals against the configured limits
+
+use super::*;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const SAMPLE_RATE: f64 = 1000.0;
+const FULL_SCALE: f64 = 1.0;
+
+/// Two seconds of noise well under the noise limit, around `offset`
+fn quiet(offset: f64) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(1);
+    (0..2000)
+        .map(|_| offset + rng.gen_range(-0.0005, 0.0005))
+        .collect()
+}
+
+fn tone(samples: &mut [f64], frequency: f64, amplitude: f64) {
+    for (i, x) in samples.iter_mut().enumerate() {
+        *x += amplitude * (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin();
+    }
+}
+
+#[test]
+fn a_quiet_signal_scores_full_marks() {
+    let metrics = measure(&quiet(0.1), SAMPLE_RATE, FULL_SCALE);
+    assert!(metrics.score > 99.0, "{:?}", metrics);
+    assert_eq!(metrics.rating, "good");
+    assert!(metrics.issues.is_empty());
+    assert_eq!(metrics.clipped, 0.0);
+}
+
+#[test]
+fn finds_clipping() {
+    let mut samples = quiet(0.1);
+    // One sample in a hundred at the edge of the range
+    for x in samples.iter_mut().step_by(100) {
+        *x = -FULL_SCALE;
+    }
+    let metrics = measure(&samples, SAMPLE_RATE, FULL_SCALE);
+    assert_eq!(metrics.clipped, 0.01);
+    assert!(metrics
+        .issues
+        .contains(&"clipping is 10.0x the limit".to_string()));
+    assert!(metrics.score < 80.0);
+}
+
+#[test]
+fn a_flat_signal_scores_nothing() {
+    let metrics = measure(&[0.2; 2000], SAMPLE_RATE, FULL_SCALE);
+    assert_eq!(metrics.rms_noise, 0.0);
+    assert_eq!(metrics.score, 0.0);
+    assert_eq!(metrics.rating, "poor");
+    assert!(metrics
+        .issues
+        .contains(&"flat, nothing is connected".to_string()));
+}
+
+#[test]
+fn finds_line_interference_at_either_frequency() {
+    for frequency in LINE_FREQUENCIES.iter() {
+        let mut samples = quiet(0.0);
+        tone(&mut samples, *frequency, 0.05);
+        let metrics = measure(&samples, SAMPLE_RATE, FULL_SCALE);
+        assert_eq!(metrics.line_frequency, *frequency);
+        assert!(
+            (metrics.line_amplitude - 0.05).abs() < 0.005,
+            "{} Hz: {}",
+            frequency,
+            metrics.line_amplitude
+        );
+        assert!(metrics
+            .issues
+            .iter()
+            .any(|issue| issue.starts_with("line interference is")));
+        assert!(metrics.score < 80.0);
+    }
+}
+
+#[test]
+fn finds_drift() {
+    let mut samples = quiet(0.0);
+    // A tenth of full scale a second
+    for (i, x) in samples.iter_mut().enumerate() {
+        *x += 0.1 * i as f64 / SAMPLE_RATE;
+    }
+    let metrics = measure(&samples, SAMPLE_RATE, FULL_SCALE);
+    assert!((metrics.drift - 0.1).abs() < 1e-3, "{}", metrics.drift);
+    assert!(metrics
+        .issues
+        .contains(&"baseline drift is 2.0x the limit".to_string()));
+}
+
+#[test]
+fn masks_sync_pulses() {
+    // The held sample stands in for a pulse, and nothing comes before the first to hold
+    let mut held = None;
+    assert_eq!(
+        hold_below(&[4.5, 0.1, 0.2, 4.5, 4.5, 0.3], 3.5, &mut held),
+        [0.1, 0.2, 0.2, 0.2, 0.3]
+    );
+    assert_eq!(hold_below(&[4.5, 0.4], 3.5, &mut held), [0.3, 0.4]);
+}
+
+#[test]
+fn scores_multiplexed_raw_channels() {
+    let const_config = ConstConfig::get_config();
+    let layout = const_config.mux_layout(&PicoChannel::A.to_string());
+    assert!(layout.has_sync());
+    let samples_per_slot = 10;
+    let sample_rate = (samples_per_slot * const_config.arduino_hz) as f64;
+
+    // A second of frames, each a sync pulse then a slot at every level
+    let signal = |levels: &[f64]| {
+        let mut samples = vec![];
+        for _ in 0..const_config.arduino_hz / (layout.virt_channels() + 1) {
+            let pulse = const_config.sync_point_threshold + 1.0;
+            for level in std::iter::once(pulse).chain(levels.iter().copied()) {
+                samples.resize(samples.len() + samples_per_slot, level);
+            }
+        }
+        samples
+    };
+    let levels: Vec<f64> = (0..layout.virt_channels())
+        .map(|i| 0.1 * (i + 1) as f64)
+        .collect();
+
+    let mut monitor = QualityMonitor::new();
+    monitor.set_full_scale(PicoChannel::A, 5.0);
+    monitor.push_raw(PicoChannel::A, &signal(&levels), sample_rate);
+    let report = monitor.report(&ChannelMap::default());
+    assert_eq!(report.len(), 1);
+    assert_eq!((report[0].kind, report[0].channel.as_str()), ("raw", "A"));
+    // Neither the pulses nor the steps between slots count against it
+    let metrics = &report[0].metrics;
+    assert!(metrics.issues.is_empty(), "{:?}", metrics.issues);
+    assert_eq!(metrics.rating, "good");
+
+    // One slot pinned to the edge of the range
+    let mut clipping = levels;
+    clipping[0] = 5.0;
+    let mut monitor = QualityMonitor::new();
+    monitor.set_full_scale(PicoChannel::A, 5.0);
+    monitor.push_raw(PicoChannel::A, &signal(&clipping), sample_rate);
+    let metrics = &monitor.report(&ChannelMap::default())[0].metrics;
+    assert!((metrics.clipped - 0.2).abs() < 0.01, "{}", metrics.clipped);
+    assert!(metrics
+        .issues
+        .iter()
+        .any(|issue| issue.starts_with("clipping is")));
+}
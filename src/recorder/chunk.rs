@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io::{self, Read},
+};
+
+/// Every chunk starts with this, followed by the chunk sequence number and 4 reserved bytes
+pub const CHUNK_MAGIC: &[u8; 8] = b"E2GCHNK1";
+pub const CHUNK_HEADER_LEN: u64 = 16;
+/// Payload length and CRC32 of the payload
+pub const RECORD_HEADER_LEN: u64 = 8;
+/// Anything larger than this is treated as a corrupt length field
+const MAX_RECORD_LEN: u32 = 256 * 1024 * 1024;
+
+/// One channel's worth of samples from a single streaming event
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawRecord {
+    pub channel: String,
+    /// Index of the first sample in this record, counted from the start of the session
+    pub first_sample: u64,
+    pub samples: Vec<f32>,
+}
+
+impl RawRecord {
+    /// Encodes the record with its header, ready to be appended to a chunk
+    ///
+    /// Payload layout: channel name length (u8), channel name, first sample (u64),
+    /// sample count (u32) then the samples as f32, all little endian
+    pub fn encode(&self) -> Vec<u8> {
+        let name = self.channel.as_bytes();
+        let mut payload = Vec::with_capacity(1 + name.len() + 12 + self.samples.len() * 4);
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name);
+        payload.extend_from_slice(&self.first_sample.to_le_bytes());
+        payload.extend_from_slice(&(self.samples.len() as u32).to_le_bytes());
+        for sample in self.samples.iter() {
+            payload.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend(payload);
+        record
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let name_len = *payload.first()? as usize;
+        let name = payload.get(1..1 + name_len)?;
+        let mut position = 1 + name_len;
+
+        let first_sample = u64::from_le_bytes(payload.get(position..position + 8)?.try_into().ok()?);
+        position += 8;
+        let count = u32::from_le_bytes(payload.get(position..position + 4)?.try_into().ok()?) as usize;
+        position += 4;
+
+        let data = payload.get(position..position + count * 4)?;
+        if position + count * 4 != payload.len() {
+            return None;
+        }
+
+        Some(RawRecord {
+            channel: String::from_utf8(name.to_vec()).ok()?,
+            first_sample,
+            samples: data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+    }
+}
+
+pub fn encode_header(sequence: u32) -> Vec<u8> {
+    let mut header = CHUNK_MAGIC.to_vec();
+    header.extend_from_slice(&sequence.to_le_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header
+}
+
+/// Range of samples a chunk holds for one channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SampleSpan {
+    pub first: u64,
+    pub count: u64,
+    /// Within the range, dropped before they could be recorded
+    #[serde(default)]
+    pub skipped: u64,
+}
+
+impl SampleSpan {
+    pub fn extend(&mut self, first_sample: u64, count: u64) {
+        if self.count == 0 {
+            self.first = first_sample;
+        } else {
+            self.skipped += first_sample.saturating_sub(self.first + self.count);
+        }
+        self.count = first_sample + count - self.first;
+    }
+
+    /// Samples the chunk actually holds
+    pub fn recorded(&self) -> u64 {
+        self.count - self.skipped
+    }
+}
+
+/// What was found while reading a chunk back
+#[derive(Clone, Debug, Default)]
+pub struct ChunkScan {
+    pub sequence: u32,
+    pub records: u64,
+    /// Bytes up to the end of the last intact record
+    pub valid_len: u64,
+    pub channels: BTreeMap<String, SampleSpan>,
+}
+
+/// Reads every intact record of a chunk, stopping quietly at the first truncated or
+/// corrupt one. Only errors if the chunk header itself is unreadable
+pub fn read_chunk<R: Read>(
+    mut reader: R,
+    mut visit: impl FnMut(RawRecord),
+) -> io::Result<ChunkScan> {
+    let mut header = [0u8; CHUNK_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if &header[0..8] != CHUNK_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a recording chunk",
+        ));
+    }
+
+    let mut scan = ChunkScan {
+        sequence: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+        valid_len: CHUNK_HEADER_LEN,
+        ..Default::default()
+    };
+
+    loop {
+        let mut record_header = [0u8; RECORD_HEADER_LEN as usize];
+        if read_full(&mut reader, &mut record_header)? < record_header.len() {
+            break;
+        }
+        let len = u32::from_le_bytes([
+            record_header[0],
+            record_header[1],
+            record_header[2],
+            record_header[3],
+        ]);
+        let crc = u32::from_le_bytes([
+            record_header[4],
+            record_header[5],
+            record_header[6],
+            record_header[7],
+        ]);
+        if len > MAX_RECORD_LEN {
+            break;
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        if read_full(&mut reader, &mut payload)? < payload.len() || crc32fast::hash(&payload) != crc
+        {
+            break;
+        }
+        let record = match RawRecord::decode(&payload) {
+            Some(record) => record,
+            None => break,
+        };
+
+        scan.records += 1;
+        scan.valid_len += RECORD_HEADER_LEN + len as u64;
+        scan.channels
+            .entry(record.channel.clone())
+            .or_default()
+            .extend(record.first_sample, record.samples.len() as u64);
+        visit(record);
+    }
+
+    Ok(scan)
+}
+
+/// Like `read_exact`, but returns how much was read instead of failing at end of file
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
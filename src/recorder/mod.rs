@@ -0,0 +1,280 @@
+pub mod chunk;
+
+use crate::{
+    metrics::{Output, COUNTERS},
+    ConstConfig,
+};
+use chunk::{encode_header, read_chunk, RawRecord, SampleSpan};
+
+use anyhow::Result;
+use pico_sdk::common::PicoChannel;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Name of the index file written into the session directory
+pub const INDEX_FILE: &str = "index.json";
+/// Sub directory of the session holding the raw chunks
+pub const CHUNK_DIR: &str = "raw";
+pub const CHUNK_EXTENSION: &str = "chunk";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    /// Path relative to the session directory
+    pub file: String,
+    pub sequence: u32,
+    pub bytes: u64,
+    pub records: u64,
+    pub channels: BTreeMap<String, SampleSpan>,
+    /// False while the chunk is being written, or if recovery had to cut it short
+    pub complete: bool,
+}
+
+/// Session index, listing every raw chunk in recording order
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecordingIndex {
+    pub chunks: Vec<ChunkEntry>,
+    /// Set when the index was rebuilt by `recover` rather than written by the recorder
+    pub recovered: bool,
+}
+
+impl RecordingIndex {
+    pub fn load(session_directory: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(
+            session_directory.join(INDEX_FILE),
+        )?))?)
+    }
+
+    /// Writes to a temporary file first, so a crash never leaves a half written index
+    pub fn save(&self, session_directory: &Path) -> Result<()> {
+        let temporary = session_directory.join(format!("{}.tmp", INDEX_FILE));
+        let mut file = File::create(&temporary)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.sync_all()?;
+        fs::rename(temporary, session_directory.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /// Total samples recorded per channel
+    pub fn sample_counts(&self) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for chunk in self.chunks.iter() {
+            for (channel, span) in chunk.channels.iter() {
+                *counts.entry(channel.clone()).or_insert(0) += span.recorded();
+            }
+        }
+        counts
+    }
+}
+
+/// Append-only recorder for the raw streaming data, so nothing held in memory is lost
+/// if the process dies. Rotates to a new chunk by size or age, and fsyncs on a schedule
+pub struct ChunkRecorder {
+    session_directory: PathBuf,
+    index: RecordingIndex,
+    writer: BufWriter<File>,
+    current: ChunkEntry,
+    opened: Instant,
+    last_sync: Instant,
+    next_sample: HashMap<PicoChannel, u64>,
+}
+
+impl ChunkRecorder {
+    pub fn create(session_directory: &Path) -> Result<Self> {
+        fs::create_dir_all(session_directory.join(CHUNK_DIR))?;
+        let (writer, current) = open_chunk(session_directory, 0)?;
+
+        let recorder = ChunkRecorder {
+            session_directory: session_directory.to_path_buf(),
+            index: RecordingIndex::default(),
+            writer,
+            current,
+            opened: Instant::now(),
+            last_sync: Instant::now(),
+            next_sample: HashMap::new(),
+        };
+        recorder.save_index()?;
+        Ok(recorder)
+    }
+
+    pub fn append(&mut self, channel: PicoChannel, samples: &[f64]) -> Result<()> {
+        let const_config = ConstConfig::get_config();
+
+        let first_sample = self.next_sample.entry(channel).or_insert(0);
+        let record = RawRecord {
+            channel: channel.to_string(),
+            first_sample: *first_sample,
+            samples: samples.iter().map(|s| *s as f32).collect(),
+        };
+        *first_sample += samples.len() as u64;
+
+        let encoded = record.encode();
+        self.writer.write_all(&encoded)?;
+        self.current.bytes += encoded.len() as u64;
+        COUNTERS.written(Output::Raw, encoded.len() as u64);
+        self.current.records += 1;
+        self.current
+            .channels
+            .entry(record.channel)
+            .or_default()
+            .extend(record.first_sample, samples.len() as u64);
+
+        if self.current.bytes >= const_config.chunk_max_bytes
+            || self.opened.elapsed() >= const_config.chunk_max_duration
+        {
+            self.rotate()?;
+        } else if self.last_sync.elapsed() >= const_config.chunk_fsync_interval {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a channel's next record past samples that were dropped before they reached
+    /// the recorder, so records keep their place in the session
+    pub fn skip(&mut self, channel: PicoChannel, count: u64) {
+        *self.next_sample.entry(channel).or_insert(0) += count;
+    }
+
+    /// Pushes everything written so far onto the disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Closes the current chunk and starts the next one
+    fn rotate(&mut self) -> Result<()> {
+        self.sync()?;
+
+        let (writer, next) = open_chunk(&self.session_directory, self.current.sequence + 1)?;
+        let mut finished = std::mem::replace(&mut self.current, next);
+        finished.complete = true;
+        tracing::debug!(
+            sequence = finished.sequence,
+            bytes = finished.bytes,
+            "raw chunk complete"
+        );
+        self.index.chunks.push(finished);
+        self.writer = writer;
+        self.opened = Instant::now();
+
+        self.save_index()
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let mut index = self.index.clone();
+        index.chunks.push(self.current.clone());
+        index.save(&self.session_directory)
+    }
+
+    /// Syncs and closes the last chunk, writing the final index
+    pub fn finish(mut self) -> Result<RecordingIndex> {
+        self.sync()?;
+        let mut last = self.current.clone();
+        last.complete = true;
+        self.index.chunks.push(last);
+        self.index.save(&self.session_directory)?;
+        Ok(self.index.clone())
+    }
+}
+
+fn open_chunk(session_directory: &Path, sequence: u32) -> Result<(BufWriter<File>, ChunkEntry)> {
+    let file = format!("{}/chunk_{:06}.{}", CHUNK_DIR, sequence, CHUNK_EXTENSION);
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(session_directory.join(&file))?,
+    );
+    let header = encode_header(sequence);
+    writer.write_all(&header)?;
+
+    Ok((
+        writer,
+        ChunkEntry {
+            file,
+            sequence,
+            bytes: header.len() as u64,
+            ..Default::default()
+        },
+    ))
+}
+
+/// Lists the chunk files of a session, in recording order
+pub fn chunk_files(session_directory: &Path) -> Result<Vec<PathBuf>> {
+    let directory = session_directory.join(CHUNK_DIR);
+    if !directory.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |e| e == CHUNK_EXTENSION))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    pub index: RecordingIndex,
+    /// Chunks that had a torn or corrupt tail, and how many bytes were cut off them
+    pub truncated: Vec<(String, u64)>,
+    /// Files that didn't even have a readable chunk header
+    pub unreadable: Vec<String>,
+}
+
+/// Rebuilds the index of a session from its chunks, cutting off any partially
+/// written record at the end of a chunk so the remaining data can be read normally
+pub fn recover(session_directory: &Path) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+    report.index.recovered = true;
+
+    for path in chunk_files(session_directory)? {
+        let file = format!(
+            "{}/{}",
+            CHUNK_DIR,
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let file_len = fs::metadata(&path)?.len();
+
+        let scan = match read_chunk(BufReader::new(File::open(&path)?), |_| {}) {
+            Ok(scan) => scan,
+            Err(_) => {
+                report.unreadable.push(file);
+                continue;
+            }
+        };
+
+        if scan.valid_len < file_len {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(scan.valid_len)?;
+            report.truncated.push((file.clone(), file_len - scan.valid_len));
+        }
+
+        report.index.chunks.push(ChunkEntry {
+            file,
+            sequence: scan.sequence,
+            bytes: scan.valid_len,
+            records: scan.records,
+            channels: scan.channels,
+            complete: scan.valid_len == file_len,
+        });
+    }
+
+    report.index.save(session_directory)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,190 @@
+//! Chunks written and read back, whole and cut short the ways a crash can leave them
+
+use super::*;
+use chunk::{ChunkScan, CHUNK_HEADER_LEN, RECORD_HEADER_LEN};
+
+use std::io::{self, Cursor};
+
+fn record(channel: &str, first_sample: u64, samples: &[f32]) -> RawRecord {
+    RawRecord {
+        channel: channel.to_string(),
+        first_sample,
+        samples: samples.to_vec(),
+    }
+}
+
+fn records() -> Vec<RawRecord> {
+    vec![
+        record("A", 0, &[0.5, -1.25, 3.0]),
+        record("B", 0, &[4.5, 4.5, 0.2]),
+        record("A", 3, &[0.75, f32::MAX]),
+        record("B", 3, &[]),
+    ]
+}
+
+fn chunk(sequence: u32, records: &[RawRecord]) -> Vec<u8> {
+    let mut chunk = encode_header(sequence);
+    for record in records {
+        chunk.extend(record.encode());
+    }
+    chunk
+}
+
+fn read(bytes: &[u8]) -> io::Result<(ChunkScan, Vec<RawRecord>)> {
+    let mut read = vec![];
+    let scan = read_chunk(Cursor::new(bytes), |r| read.push(r))?;
+    Ok((scan, read))
+}
+
+#[test]
+fn records_round_trip() {
+    let records = records();
+    let bytes = chunk(7, &records);
+    let (scan, read) = read(&bytes).unwrap();
+
+    assert_eq!(read, records);
+    assert_eq!(scan.sequence, 7);
+    assert_eq!(scan.records, 4);
+    assert_eq!(scan.valid_len, bytes.len() as u64);
+    assert_eq!(scan.channels["A"], SampleSpan { first: 0, count: 5, skipped: 0 });
+    assert_eq!(scan.channels["B"], SampleSpan { first: 0, count: 3, skipped: 0 });
+}
+
+#[test]
+fn an_empty_chunk_is_just_its_header() {
+    let (scan, read) = read(&chunk(0, &[])).unwrap();
+    assert!(read.is_empty());
+    assert_eq!(scan.valid_len, CHUNK_HEADER_LEN);
+}
+
+#[test]
+fn reading_stops_at_a_torn_record() {
+    let records = records();
+    let whole = chunk(0, &records[..2]);
+    let mut bytes = chunk(0, &records);
+
+    // Cut anywhere in the third record, header or payload
+    let third = records[2].encode().len();
+    for cut in [
+        1,
+        RECORD_HEADER_LEN as usize,
+        RECORD_HEADER_LEN as usize + 3,
+        third - 1,
+    ] {
+        bytes.truncate(whole.len() + cut);
+        let (scan, read) = read(&bytes).unwrap();
+        assert_eq!(read, records[..2], "cut {} bytes in", cut);
+        assert_eq!(scan.valid_len, whole.len() as u64, "cut {} bytes in", cut);
+        bytes = chunk(0, &records);
+    }
+}
+
+#[test]
+fn reading_stops_at_a_corrupt_record() {
+    let records = records();
+    let mut bytes = chunk(0, &records);
+    // A sample of the second record
+    let second = CHUNK_HEADER_LEN as usize + records[0].encode().len();
+    bytes[second + RECORD_HEADER_LEN as usize + 15] ^= 0x40;
+
+    let (scan, read) = read(&bytes).unwrap();
+    assert_eq!(read, records[..1]);
+    assert_eq!(scan.valid_len, second as u64);
+}
+
+#[test]
+fn reading_stops_at_an_impossible_length() {
+    let mut bytes = chunk(0, &records()[..1]);
+    let valid_len = bytes.len() as u64;
+    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+    bytes.extend_from_slice(&[0; 4]);
+
+    let (scan, read) = read(&bytes).unwrap();
+    assert_eq!(read.len(), 1);
+    assert_eq!(scan.valid_len, valid_len);
+}
+
+#[test]
+fn only_a_bad_header_is_an_error() {
+    let mut bytes = chunk(0, &records());
+    bytes[0] = b'X';
+    assert_eq!(read(&bytes).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        read(&bytes[..10]).unwrap_err().kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn the_recorder_indexes_what_it_writes() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut recorder = ChunkRecorder::create(directory.path()).unwrap();
+    recorder.append(PicoChannel::A, &[0.1, 0.2, 0.3]).unwrap();
+    recorder.append(PicoChannel::B, &[1.0, 2.0, 3.0]).unwrap();
+    recorder.append(PicoChannel::A, &[0.4]).unwrap();
+    let index = recorder.finish().unwrap();
+
+    let (a, b) = (PicoChannel::A.to_string(), PicoChannel::B.to_string());
+    assert_eq!(index.chunks.len(), 1);
+    assert!(index.chunks[0].complete);
+    assert_eq!(index.sample_counts()[&a], 4);
+    assert_eq!(index.sample_counts()[&b], 3);
+    assert_eq!(
+        RecordingIndex::load(directory.path()).unwrap().chunks[0].records,
+        3
+    );
+
+    let path = directory.path().join(&index.chunks[0].file);
+    let mut read = vec![];
+    read_chunk(File::open(path).unwrap(), |r| read.push(r)).unwrap();
+    assert_eq!(read[2], record(&a, 3, &[0.4]));
+}
+
+#[test]
+fn recover_cuts_off_a_torn_tail() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut recorder = ChunkRecorder::create(directory.path()).unwrap();
+    recorder.append(PicoChannel::A, &[0.1, 0.2, 0.3]).unwrap();
+    recorder.append(PicoChannel::A, &[0.4, 0.5]).unwrap();
+    let index = recorder.finish().unwrap();
+
+    // As if the process died part way through writing the second record
+    let path = directory.path().join(&index.chunks[0].file);
+    let whole = fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(whole - 5)
+        .unwrap();
+    // And left a chunk it had only just created
+    fs::write(
+        directory.path().join(CHUNK_DIR).join("chunk_000001.chunk"),
+        b"E2G",
+    )
+    .unwrap();
+
+    let report = recover(directory.path()).unwrap();
+    let first = record(&PicoChannel::A.to_string(), 0, &[0.1, 0.2, 0.3]);
+    let first_len = CHUNK_HEADER_LEN + first.encode().len() as u64;
+    assert_eq!(
+        report.truncated,
+        [(index.chunks[0].file.clone(), whole - 5 - first_len)]
+    );
+    assert_eq!(report.unreadable, ["raw/chunk_000001.chunk"]);
+
+    let recovered = &report.index.chunks[0];
+    assert!(!recovered.complete);
+    assert_eq!(recovered.records, 1);
+    assert_eq!(recovered.bytes, first_len);
+    assert_eq!(fs::metadata(&path).unwrap().len(), first_len);
+
+    let saved = RecordingIndex::load(directory.path()).unwrap();
+    assert!(saved.recovered);
+    assert_eq!(saved.sample_counts()[&PicoChannel::A.to_string()], 3);
+
+    // Whatever's left reads back whole, so recovering again changes nothing
+    let again = recover(directory.path()).unwrap();
+    assert!(again.truncated.is_empty());
+    assert!(again.index.chunks[0].complete);
+}
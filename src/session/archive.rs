@@ -0,0 +1,102 @@
+use super::{block_series, manifest::MANIFEST_FILE, session_files, SessionMetadata};
+use crate::export::{write_table, FrameTable, OutputFormat};
+
+use anyhow::Result;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, Cursor, Seek, Write},
+    path::Path,
+};
+
+/// Zips up a whole session directory. When `format` is given, the CSV blocks of each
+/// channel are joined into a single file in it on the way; frames already in another
+/// binary format are kept as is. The manifest only holds for the files as recorded, so
+/// converted archives go without it
+pub fn write_zip<W: Write + Seek>(
+    directory: &Path,
+    format: Option<OutputFormat>,
+    output: W,
+) -> Result<()> {
+    let metadata = SessionMetadata::load(directory)?;
+    let mut archive = ZipWriter::new(output);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let target = format.filter(|f| *f != OutputFormat::Csv);
+
+    for relative in session_files(directory)? {
+        let converted = block_series(&relative).is_some() || relative == MANIFEST_FILE;
+        if target.is_some() && converted {
+            continue;
+        }
+        archive.start_file(relative.as_str(), options)?;
+        io::copy(
+            &mut BufReader::new(File::open(directory.join(&relative))?),
+            &mut archive,
+        )?;
+    }
+
+    if let Some(target) = target {
+        for (series, blocks) in frame_series(directory)? {
+            let table = load_series(directory, &blocks, &metadata)?;
+            let mut converted = Cursor::new(vec![]);
+            write_table(&mut converted, target, &table, &metadata)?;
+
+            archive.start_file(format!("{}.{}", series, target.extension()), options)?;
+            archive.write_all(&converted.into_inner())?;
+        }
+    }
+
+    archive.finish()?;
+    Ok(())
+}
+
+/// Joins the CSV blocks staged while recording into one file per series in `format`,
+/// named after the series, then removes the blocks. A series that can't be joined keeps
+/// its blocks, and CSV sessions are left as recorded
+pub fn consolidate(directory: &Path, format: OutputFormat) -> Result<()> {
+    if format == OutputFormat::Csv {
+        return Ok(());
+    }
+    let metadata = SessionMetadata::load(directory)?;
+
+    for (series, blocks) in frame_series(directory)? {
+        let table = load_series(directory, &blocks, &metadata)?;
+        let mut file = File::create(directory.join(format!("{}.{}", series, format.extension())))?;
+        write_table(&mut file, format, &table, &metadata)?;
+        file.sync_all()?;
+
+        for block in blocks {
+            fs::remove_file(directory.join(block))?;
+        }
+    }
+    Ok(())
+}
+
+/// The staged block files of a session, by series, in recording order
+pub fn frame_series(directory: &Path) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut series: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for relative in session_files(directory)? {
+        if let Some(name) = block_series(&relative) {
+            series.entry(name.to_string()).or_default().push(relative);
+        }
+    }
+    Ok(series)
+}
+
+/// Reads the blocks of a series back into one table
+fn load_series(
+    directory: &Path,
+    blocks: &[String],
+    metadata: &SessionMetadata,
+) -> Result<FrameTable> {
+    let mut table = FrameTable::default();
+    for block in blocks {
+        table.append(FrameTable::from_csv(&directory.join(block), metadata)?);
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests;
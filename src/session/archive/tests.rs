@@ -0,0 +1,142 @@
+//! Sessions with a few staged blocks per series, joined on disk and in archives
+
+use super::*;
+use crate::{
+    app::state::DeviceInfo,
+    export::write_csv,
+    session::{block_file, SESSION_METADATA_FILE},
+};
+
+use tempfile::TempDir;
+
+use std::io::{Cursor, Read};
+
+/// A session directory holding `blocks` blocks of two frames on channel A, filtered and
+/// not, plus the sync log
+fn staged(blocks: u64) -> TempDir {
+    let directory = tempfile::tempdir().unwrap();
+    let metadata = SessionMetadata::new(
+        "archive-test".to_string(),
+        &DeviceInfo {
+            pico_scope_type: "test".to_string(),
+            channel_info: vec![],
+            refresh_rate: 147_000,
+        },
+    );
+    serde_json::to_writer_pretty(
+        File::create(directory.path().join(SESSION_METADATA_FILE)).unwrap(),
+        &metadata,
+    )
+    .unwrap();
+
+    for sequence in 0..blocks {
+        for series in ["A", "A_filtered"].iter() {
+            let start = sequence as f64 * 2.0;
+            let table = FrameTable {
+                channels: vec![0, 1],
+                names: vec![],
+                rows: vec![vec![start, 0.5], vec![start + 1.0, 0.5]],
+                timestamps: vec![start / 100.0, (start + 1.0) / 100.0],
+            };
+            let name = format!("{}.csv", block_file(sequence, series));
+            write_csv(
+                &mut File::create(directory.path().join(name)).unwrap(),
+                &table,
+            )
+            .unwrap();
+        }
+    }
+    fs::write(directory.path().join("sync.csv"), "sequence,time\n0,0\n").unwrap();
+    fs::write(directory.path().join(MANIFEST_FILE), "{}").unwrap();
+    directory
+}
+
+/// The `.npy` header of the frames in an `.npz`
+fn frames_header(npz: Vec<u8>) -> String {
+    let mut archive = zip::ZipArchive::new(Cursor::new(npz)).unwrap();
+    let mut frames = vec![];
+    archive
+        .by_name("frames.npy")
+        .unwrap()
+        .read_to_end(&mut frames)
+        .unwrap();
+    let length = u16::from_le_bytes([frames[8], frames[9]]) as usize;
+    String::from_utf8_lossy(&frames[10..10 + length]).to_string()
+}
+
+#[test]
+fn names_the_series_of_blocks() {
+    assert_eq!(block_series("block_00000012_A.csv"), Some("A"));
+    assert_eq!(
+        block_series("block_00000012_B_filtered.csv"),
+        Some("B_filtered")
+    );
+    assert_eq!(block_series("block_00000012_A.npz"), None);
+    assert_eq!(block_series("block_A.csv"), None);
+    assert_eq!(block_series("sync.csv"), None);
+    assert_eq!(block_series("raw/block_00000012_A.csv"), None);
+}
+
+#[test]
+fn joins_blocks_into_a_file_per_series() {
+    let directory = staged(3);
+    consolidate(directory.path(), OutputFormat::Npz).unwrap();
+
+    let files = session_files(directory.path()).unwrap();
+    assert_eq!(
+        files,
+        [
+            "A.npz",
+            "A_filtered.npz",
+            MANIFEST_FILE,
+            SESSION_METADATA_FILE,
+            "sync.csv"
+        ]
+    );
+    let header = frames_header(fs::read(directory.path().join("A.npz")).unwrap());
+    assert!(header.contains("'shape': (6, 2)"), "{}", header);
+}
+
+#[test]
+fn csv_sessions_keep_their_blocks() {
+    let directory = staged(2);
+    let before = session_files(directory.path()).unwrap();
+    consolidate(directory.path(), OutputFormat::Csv).unwrap();
+    assert_eq!(session_files(directory.path()).unwrap(), before);
+}
+
+#[test]
+fn converted_archives_join_blocks_and_leave_out_the_manifest() {
+    let directory = staged(2);
+    let mut output = Cursor::new(vec![]);
+    write_zip(directory.path(), Some(OutputFormat::Npz), &mut output).unwrap();
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(output.into_inner())).unwrap();
+    let mut names: Vec<String> = archive.file_names().map(|n| n.to_string()).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["A.npz", "A_filtered.npz", SESSION_METADATA_FILE, "sync.csv"]
+    );
+
+    let mut npz = vec![];
+    archive
+        .by_name("A.npz")
+        .unwrap()
+        .read_to_end(&mut npz)
+        .unwrap();
+    let header = frames_header(npz);
+    assert!(header.contains("'shape': (4, 2)"), "{}", header);
+}
+
+#[test]
+fn plain_archives_are_the_session_as_recorded() {
+    let directory = staged(2);
+    let mut output = Cursor::new(vec![]);
+    write_zip(directory.path(), None, &mut output).unwrap();
+
+    let archive = zip::ZipArchive::new(Cursor::new(output.into_inner())).unwrap();
+    let mut names: Vec<String> = archive.file_names().map(|n| n.to_string()).collect();
+    names.sort();
+    assert_eq!(names, session_files(directory.path()).unwrap());
+}
@@ -0,0 +1,141 @@
+use super::{session_files, SessionMetadata};
+use crate::{recorder::RecordingIndex, ConstConfig};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+/// Name of the manifest written into the session directory when recording stops
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the session directory, always with `/` separators
+    pub path: String,
+    pub sha256: String,
+    pub bytes: u64,
+    /// Samples held by the file, where it's a raw chunk
+    pub samples: Option<u64>,
+}
+
+/// Checksummed list of everything a session produced, to prove the data is unaltered
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub session: String,
+    pub created: DateTime<Local>,
+    pub files: Vec<ManifestEntry>,
+    /// Raw samples recorded per channel
+    pub sample_counts: BTreeMap<String, u64>,
+    pub frame_count: u64,
+    /// The configuration the session was recorded with
+    pub config: serde_json::Value,
+}
+
+impl Manifest {
+    /// Hashes every file in the session's directory and writes the manifest next to them
+    pub fn create(
+        directory: &Path,
+        session: &SessionMetadata,
+        index: &RecordingIndex,
+    ) -> Result<Self> {
+        let chunk_samples: BTreeMap<&str, u64> = index
+            .chunks
+            .iter()
+            .map(|c| (c.file.as_str(), c.channels.values().map(|s| s.recorded()).sum()))
+            .collect();
+
+        let mut files = vec![];
+        for path in session_files(directory)?
+            .into_iter()
+            .filter(|p| p != MANIFEST_FILE)
+        {
+            let (sha256, bytes) = hash_file(&directory.join(&path))?;
+            files.push(ManifestEntry {
+                samples: chunk_samples.get(path.as_str()).copied(),
+                path,
+                sha256,
+                bytes,
+            });
+        }
+
+        let manifest = Manifest {
+            session: session.name.clone(),
+            created: Local::now(),
+            files,
+            sample_counts: index.sample_counts(),
+            frame_count: session.frame_count,
+            config: serde_json::to_value(ConstConfig::get_config())?,
+        };
+        serde_json::to_writer_pretty(File::create(directory.join(MANIFEST_FILE))?, &manifest)?;
+
+        Ok(manifest)
+    }
+
+    pub fn load(session_directory: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(
+            session_directory.join(MANIFEST_FILE),
+        )?))?)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub verified: usize,
+    pub missing: Vec<String>,
+    /// Files whose size or hash no longer match the manifest
+    pub corrupt: Vec<String>,
+    /// Files in the session directory the manifest doesn't know about
+    pub extra: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Re-hashes a session directory against its manifest
+pub fn verify(session_directory: &Path) -> Result<VerifyReport> {
+    let manifest = Manifest::load(session_directory)?;
+    let mut report = VerifyReport::default();
+
+    for entry in manifest.files.iter() {
+        let path = session_directory.join(&entry.path);
+        if !path.is_file() {
+            report.missing.push(entry.path.clone());
+            continue;
+        }
+
+        let (sha256, bytes) = hash_file(&path)?;
+        if bytes != entry.bytes || sha256 != entry.sha256 {
+            report.corrupt.push(entry.path.clone());
+        } else {
+            report.verified += 1;
+        }
+    }
+
+    let known: BTreeSet<&str> = manifest.files.iter().map(|e| e.path.as_str()).collect();
+    report.extra = session_files(session_directory)?
+        .into_iter()
+        .filter(|path| path != MANIFEST_FILE && !known.contains(path.as_str()))
+        .collect();
+
+    Ok(report)
+}
+
+fn hash_file(path: &Path) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let bytes = io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok((hex::encode(hasher.finalize()), bytes))
+}
+
+#[cfg(test)]
+mod tests;
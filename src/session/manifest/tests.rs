@@ -0,0 +1,134 @@
+//! Manifests of small sessions, each recorded into a directory of its own
+
+use super::*;
+use crate::{app::state::DeviceInfo, recorder::ChunkRecorder, session::SESSION_METADATA_FILE};
+
+use pico_sdk::common::PicoChannel;
+use tempfile::TempDir;
+
+use std::fs;
+
+/// A recorded session, with the directory it's in
+struct TestSession {
+    directory: TempDir,
+    metadata: SessionMetadata,
+}
+
+impl TestSession {
+    fn directory(&self) -> &Path {
+        self.directory.path()
+    }
+
+    fn create_manifest(&self, index: &RecordingIndex) -> Manifest {
+        Manifest::create(self.directory(), &self.metadata, index).unwrap()
+    }
+}
+
+/// Metadata, a raw chunk with its index, and a nested frame file, as recording leaves them
+fn record() -> (TestSession, RecordingIndex) {
+    let mut metadata = SessionMetadata::new(
+        "manifest-test".to_string(),
+        &DeviceInfo {
+            pico_scope_type: "test".to_string(),
+            channel_info: vec![],
+            refresh_rate: 147_000,
+        },
+    );
+    metadata.frame_count = 2;
+    let session = TestSession {
+        directory: tempfile::tempdir().unwrap(),
+        metadata,
+    };
+    let directory = session.directory();
+    serde_json::to_writer_pretty(
+        File::create(directory.join(SESSION_METADATA_FILE)).unwrap(),
+        &session.metadata,
+    )
+    .unwrap();
+
+    let mut recorder = ChunkRecorder::create(directory).unwrap();
+    recorder.append(PicoChannel::A, &[0.1, 0.2, 0.3]).unwrap();
+    recorder.append(PicoChannel::B, &[0.4, 0.5, 0.6]).unwrap();
+    let index = recorder.finish().unwrap();
+
+    fs::create_dir_all(directory.join("frames")).unwrap();
+    fs::write(
+        directory.join("frames/block_00000000_A.csv"),
+        "time,0\n0,0.2\n",
+    )
+    .unwrap();
+    (session, index)
+}
+
+#[test]
+fn lists_and_hashes_every_file() {
+    let (session, index) = record();
+    let manifest = session.create_manifest(&index);
+
+    let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "frames/block_00000000_A.csv",
+            "index.json",
+            "raw/chunk_000000.chunk",
+            "session.json",
+        ]
+    );
+    for file in manifest.files.iter() {
+        let (sha256, bytes) = hash_file(&session.directory().join(&file.path)).unwrap();
+        assert_eq!(
+            (&file.sha256, file.bytes),
+            (&sha256, bytes),
+            "{}",
+            file.path
+        );
+        let samples = if file.path.starts_with("raw/") {
+            Some(6)
+        } else {
+            None
+        };
+        assert_eq!(file.samples, samples, "{}", file.path);
+    }
+    assert_eq!(manifest.sample_counts, index.sample_counts());
+    assert_eq!(manifest.frame_count, 2);
+}
+
+#[test]
+fn an_untouched_session_verifies() {
+    let (session, index) = record();
+    session.create_manifest(&index);
+
+    let report = verify(session.directory()).unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.verified, 4);
+}
+
+#[test]
+fn verify_finds_every_change() {
+    let (session, index) = record();
+    let directory = session.directory();
+    session.create_manifest(&index);
+
+    // The same size, so only the hash tells
+    let chunk = directory.join("raw/chunk_000000.chunk");
+    let mut bytes = fs::read(&chunk).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    fs::write(&chunk, bytes).unwrap();
+    fs::remove_file(directory.join("frames/block_00000000_A.csv")).unwrap();
+    fs::write(directory.join("frames/notes.txt"), "added later").unwrap();
+
+    let report = verify(directory).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.verified, 2);
+    assert_eq!(report.corrupt, ["raw/chunk_000000.chunk"]);
+    assert_eq!(report.missing, ["frames/block_00000000_A.csv"]);
+    assert_eq!(report.extra, ["frames/notes.txt"]);
+}
+
+#[test]
+fn verify_needs_a_manifest() {
+    let (session, _) = record();
+    assert!(verify(session.directory()).is_err());
+}
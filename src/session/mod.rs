@@ -0,0 +1,290 @@
+pub mod archive;
+pub mod manifest;
+
+use crate::{
+    app::state::DeviceInfo,
+    calibration::CalibrationProvenance,
+    crosstalk::CrosstalkProvenance,
+    electrodes::MappedChannel,
+    filters::ChannelFilters,
+    quality::sync::{SyncReport, SyncSummary},
+    virt_channels::{MuxLayout, VirtChannel},
+    ConstConfig,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+/// Name of the metadata file written into every session directory
+pub const SESSION_METADATA_FILE: &str = "session.json";
+
+/// Describes a single recording, from pressing start to pressing stop
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionMetadata {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub stopped: Option<DateTime<Local>>,
+    pub pico_scope_type: String,
+    pub channels: Vec<String>,
+    pub sample_rate: u32,
+    pub arduino_hz: usize,
+    pub virt_channel_count: usize,
+    /// How each Pico channel was multiplexed, sessions from before layouts having
+    /// `virt_channel_count` slots on every channel
+    #[serde(default)]
+    pub layouts: BTreeMap<String, MuxLayout>,
+    /// Frames demultiplexed and written out so far
+    #[serde(default)]
+    pub frame_count: u64,
+    /// Stretches of the recording that couldn't be demultiplexed
+    #[serde(default)]
+    pub gaps: Vec<SessionGap>,
+    /// Filters used for the `_filtered` frame files, as selected when recording started
+    #[serde(default)]
+    pub filters: Vec<ChannelFilters>,
+    /// How well the sync pulses were found, by Pico channel
+    #[serde(default)]
+    pub sync: BTreeMap<String, SyncSummary>,
+    /// Calibration applied to the virtual channels, if they were calibrated
+    #[serde(default)]
+    pub calibration: Option<CalibrationProvenance>,
+    /// Crosstalk taken out of the virtual channels, if it was compensated
+    #[serde(default)]
+    pub crosstalk: Option<CrosstalkProvenance>,
+    /// What each channel of the frames is, electrodes and montages
+    #[serde(default)]
+    pub electrodes: Vec<MappedChannel>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionGap {
+    /// Seconds since the session started
+    pub start: f64,
+    pub duration: f64,
+    pub reason: String,
+}
+
+impl SessionMetadata {
+    pub fn new(name: String, device_info: &DeviceInfo) -> Self {
+        let const_config = ConstConfig::get_config();
+
+        SessionMetadata {
+            name,
+            started: Local::now(),
+            stopped: None,
+            pico_scope_type: device_info.pico_scope_type.clone(),
+            channels: device_info
+                .channel_info
+                .iter()
+                .map(|c| c.channel.clone())
+                .collect(),
+            sample_rate: device_info.refresh_rate,
+            arduino_hz: const_config.arduino_hz,
+            virt_channel_count: const_config.virt_channel_count,
+            layouts: device_info
+                .channel_info
+                .iter()
+                .map(|c| (c.channel.clone(), c.layout))
+                .collect(),
+            frame_count: 0,
+            gaps: vec![],
+            filters: vec![],
+            sync: BTreeMap::new(),
+            calibration: None,
+            crosstalk: None,
+            electrodes: vec![],
+        }
+    }
+
+    /// Default session name, based off the current time
+    pub fn default_name() -> String {
+        format!("{}", Local::now().format("%F_%H-%M-%S"))
+    }
+
+    /// Directory all of this session's output files are written to
+    pub fn directory(&self) -> PathBuf {
+        Path::new(ConstConfig::get_config().output_dir).join(&self.name)
+    }
+
+    /// Frames per second of a channel, by key. Sessions from before channels could
+    /// differ have every channel at the one rate
+    pub fn frame_rate(&self, key: VirtChannel) -> f64 {
+        self.electrodes
+            .iter()
+            .find(|c| c.key == key && c.frame_rate > 0.0)
+            .map_or(
+                self.arduino_hz as f64 / (self.virt_channel_count + 1) as f64,
+                |c| c.frame_rate,
+            )
+    }
+
+    /// Records a stretch of missing frames, merging it into the previous gap if they touch
+    pub fn add_gap(&mut self, start: f64, duration: f64, reason: &str) {
+        if let Some(last) = self.gaps.last_mut() {
+            if last.reason == reason && (last.start + last.duration - start).abs() < 1e-6 {
+                last.duration += duration;
+                return;
+            }
+        }
+
+        self.gaps.push(SessionGap {
+            start,
+            duration,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Creates the session directory and writes the metadata into it
+    pub fn save(&self) -> Result<()> {
+        let directory = self.directory();
+        fs::create_dir_all(&directory)?;
+        serde_json::to_writer_pretty(
+            File::create(directory.join(SESSION_METADATA_FILE))?,
+            self,
+        )?;
+        Ok(())
+    }
+
+    /// Reads the metadata of a session directory
+    pub fn load(directory: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(File::open(
+            directory.join(SESSION_METADATA_FILE),
+        )?)?)
+    }
+}
+
+/// What the dashboard needs to know about a past recording
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionSummary {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub stopped: Option<DateTime<Local>>,
+    /// Seconds, or until now while still recording
+    pub duration: f64,
+    pub channels: Vec<String>,
+    pub frame_count: u64,
+    pub gaps: Vec<SessionGap>,
+    pub sync: Vec<SyncReport>,
+    /// Total size of the session directory in bytes
+    pub bytes: u64,
+    pub recording: bool,
+}
+
+impl SessionSummary {
+    pub fn new(metadata: SessionMetadata, recording: bool) -> Self {
+        let directory = metadata.directory();
+        let duration = (metadata.stopped.unwrap_or_else(Local::now) - metadata.started)
+            .num_milliseconds() as f64
+            / 1000.0;
+
+        SessionSummary {
+            bytes: directory_size(&directory).unwrap_or(0),
+            name: metadata.name,
+            started: metadata.started,
+            stopped: metadata.stopped,
+            duration,
+            channels: metadata.channels,
+            frame_count: metadata.frame_count,
+            gaps: metadata.gaps,
+            sync: metadata
+                .sync
+                .iter()
+                .map(|(channel, summary)| summary.report(channel))
+                .collect(),
+            recording,
+        }
+    }
+}
+
+/// Finds a session by name, refusing anything that could escape the output directory
+pub fn session_directory(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(|c| c == '/' || c == '\\') {
+        return Err(anyhow!("invalid session name {}", name));
+    }
+
+    let directory = Path::new(ConstConfig::get_config().output_dir).join(name);
+    if !directory.join(SESSION_METADATA_FILE).is_file() {
+        return Err(anyhow!("no session named {}", name));
+    }
+    Ok(directory)
+}
+
+/// Every session in the output directory, newest first
+pub fn list_sessions() -> Result<Vec<SessionMetadata>> {
+    let output_dir = Path::new(ConstConfig::get_config().output_dir);
+    if !output_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut sessions: Vec<SessionMetadata> = fs::read_dir(output_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| SessionMetadata::load(&entry.path()).ok())
+        .collect();
+    sessions.sort_by(|a, b| b.started.cmp(&a.started));
+    Ok(sessions)
+}
+
+/// Name of the file a block's frames are staged in while recording, without the
+/// extension. Named by place in the session so files never collide and sort in recording
+/// order
+pub fn block_file(sequence: u64, series: &str) -> String {
+    format!("block_{:08}_{}", sequence, series)
+}
+
+/// The series a staged block file belongs to, like `A` or `A_filtered`, if it is one
+pub fn block_series(relative: &str) -> Option<&str> {
+    let name = relative.strip_suffix(".csv")?.strip_prefix("block_")?;
+    let (sequence, series) = name.split_at(name.find('_')?);
+    if sequence.is_empty() || !sequence.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(&series[1..])
+}
+
+/// Every file in a session directory, relative to it with `/` separators, sorted
+pub fn session_files(directory: &Path) -> Result<Vec<String>> {
+    let mut files = vec![];
+    let mut pending: Vec<PathBuf> = vec![directory.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let relative = path
+                .strip_prefix(directory)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join("/");
+            files.push(relative);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn directory_size(directory: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        total += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(total)
+}
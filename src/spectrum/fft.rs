@@ -0,0 +1,46 @@
+use std::f64::consts::PI;
+
+/// In-place iterative radix-2 FFT. Both slices must be the same power of two long
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        let (w_re, w_im) = (angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let (mut t_re, mut t_im) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let u_re = re[b] * t_re - im[b] * t_im;
+                let u_im = re[b] * t_im + im[b] * t_re;
+                re[b] = re[a] - u_re;
+                im[b] = im[a] - u_im;
+                re[a] += u_re;
+                im[a] += u_im;
+
+                let next = t_re * w_re - t_im * w_im;
+                t_im = t_re * w_im + t_im * w_re;
+                t_re = next;
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,248 @@
+pub mod fft;
+pub mod welch;
+
+use crate::{filters::FilteredFrame, virt_channels::VirtChannel, ConstConfig};
+use welch::{welch, Psd};
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, VecDeque};
+
+/// Spectrogram tiles kept for the dashboard, 2 hours at the default interval
+pub const SPECTROGRAM_TILE_LIMIT: usize = 480;
+
+/// A named frequency band, in cycles per minute as slow waves are usually described
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Band {
+    pub name: &'static str,
+    pub low_cpm: f64,
+    pub high_cpm: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BandPower {
+    pub name: &'static str,
+    pub low_cpm: f64,
+    pub high_cpm: f64,
+    pub power: f64,
+    /// Share of the channel's total power, excluding DC
+    pub fraction: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DominantFrequency {
+    pub frequency: f64,
+    pub cpm: f64,
+    pub power: f64,
+}
+
+/// Spectral summary of one channel over the analysis window
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelSpectrum {
+    pub channel: VirtChannel,
+    pub psd: Psd,
+    /// Strongest frequency within the configured bands
+    pub dominant: Option<DominantFrequency>,
+    pub bands: Vec<BandPower>,
+}
+
+/// PSD of every channel at one point in time
+#[derive(Clone, Debug, Serialize)]
+pub struct SpectrogramTile {
+    /// Seconds since the session or preview started, of the newest sample in the window
+    pub time: f64,
+    pub power: BTreeMap<VirtChannel, Vec<f64>>,
+}
+
+/// Averages frames down to the analysis rate, one channel at a time. Frames are binned
+/// by their time, so channels framing at different rates come out sampled alike
+#[derive(Clone)]
+struct Decimator {
+    sum: f64,
+    count: usize,
+    /// Analysis sample the frames are going towards, counted from the session start
+    bin: i64,
+}
+
+/// Rolling Welch PSD of every virtual channel, fed the filtered frames as they're ready
+#[derive(Clone)]
+pub struct SpectralAnalyser {
+    sample_rate: f64,
+    window_len: usize,
+    segment_len: usize,
+    /// New samples between two spectrogram tiles
+    tile_every: usize,
+    bands: &'static [Band],
+    decimators: BTreeMap<VirtChannel, Decimator>,
+    windows: BTreeMap<VirtChannel, VecDeque<f64>>,
+    latest_time: f64,
+    /// Newest analysis sample any channel has reached
+    latest_bin: Option<i64>,
+    since_tile: usize,
+    tiles: VecDeque<SpectrogramTile>,
+}
+
+impl SpectralAnalyser {
+    pub fn new() -> Self {
+        let const_config = ConstConfig::get_config();
+        let sample_rate = const_config.spectral_rate;
+
+        SpectralAnalyser {
+            sample_rate,
+            window_len: (const_config.spectral_window.as_secs_f64() * sample_rate) as usize,
+            segment_len: const_config.spectral_segment,
+            tile_every: ((const_config.spectrogram_interval.as_secs_f64() * sample_rate) as usize)
+                .max(1),
+            bands: const_config.spectral_bands,
+            decimators: BTreeMap::new(),
+            windows: BTreeMap::new(),
+            latest_time: 0.0,
+            latest_bin: None,
+            since_tile: 0,
+            tiles: VecDeque::new(),
+        }
+    }
+
+    /// Forgets everything, ready for a new recording or preview
+    pub fn reset(&mut self) {
+        self.decimators.clear();
+        self.windows.clear();
+        self.latest_time = 0.0;
+        self.latest_bin = None;
+        self.since_tile = 0;
+        self.tiles.clear();
+    }
+
+    /// Adds frames in time order, adding a spectrogram tile every interval. A channel's
+    /// sample is taken once its frames reach the next one
+    pub fn push_frames(&mut self, frames: &[FilteredFrame]) {
+        for frame in frames {
+            let bin = (frame.time * self.sample_rate).floor() as i64;
+            for (channel, value) in frame.filtered.iter() {
+                let decimator = self.decimators.entry(*channel).or_insert(Decimator {
+                    sum: 0.0,
+                    count: 0,
+                    bin,
+                });
+
+                if bin != decimator.bin {
+                    // Hold the last value through fully missing stretches, a NaN would
+                    // spread across the whole window
+                    let window = self.windows.entry(*channel).or_insert_with(VecDeque::new);
+                    let sample = if decimator.count > 0 {
+                        decimator.sum / decimator.count as f64
+                    } else {
+                        window.back().copied().unwrap_or(0.0)
+                    };
+                    *decimator = Decimator {
+                        sum: 0.0,
+                        count: 0,
+                        bin,
+                    };
+
+                    window.push_back(sample);
+                    if window.len() > self.window_len {
+                        window.pop_front();
+                    }
+                }
+                if !value.is_nan() {
+                    decimator.sum += value;
+                    decimator.count += 1;
+                }
+            }
+
+            self.latest_time = frame.time;
+            // None orders before any bin
+            if self.latest_bin < Some(bin) {
+                if self.latest_bin.is_some() {
+                    self.since_tile += 1;
+                    if self.since_tile >= self.tile_every {
+                        self.since_tile = 0;
+                        self.add_tile();
+                    }
+                }
+                self.latest_bin = Some(bin);
+            }
+        }
+    }
+
+    fn psd(&self, channel: VirtChannel) -> Option<Psd> {
+        let window: Vec<f64> = self.windows.get(&channel)?.iter().copied().collect();
+        welch(&window, self.sample_rate, self.segment_len)
+    }
+
+    fn add_tile(&mut self) {
+        let power: BTreeMap<VirtChannel, Vec<f64>> = self
+            .windows
+            .keys()
+            .filter_map(|channel| Some((*channel, self.psd(*channel)?.power)))
+            .collect();
+        if power.is_empty() {
+            return;
+        }
+
+        self.tiles.push_back(SpectrogramTile {
+            time: self.latest_time,
+            power,
+        });
+        if self.tiles.len() > SPECTROGRAM_TILE_LIMIT {
+            self.tiles.pop_front();
+        }
+    }
+
+    /// Frequencies of every spectrogram row, empty until the first tile
+    pub fn frequencies(&self) -> Vec<f64> {
+        self.windows
+            .keys()
+            .find_map(|channel| self.psd(*channel))
+            .map(|psd| psd.frequencies)
+            .unwrap_or_default()
+    }
+
+    /// Tiles newer than `since` seconds into the session
+    pub fn tiles_since(&self, since: f64) -> Vec<&SpectrogramTile> {
+        self.tiles.iter().filter(|t| t.time > since).collect()
+    }
+
+    /// Current spectrum of every channel with a full segment of data
+    pub fn spectra(&self) -> Vec<ChannelSpectrum> {
+        let (low, high) = self.bands.iter().fold((f64::INFINITY, 0f64), |(l, h), b| {
+            (l.min(b.low_cpm / 60.0), h.max(b.high_cpm / 60.0))
+        });
+
+        self.windows
+            .keys()
+            .filter_map(|channel| {
+                let psd = self.psd(*channel)?;
+                let total = psd.total_power();
+
+                Some(ChannelSpectrum {
+                    channel: *channel,
+                    dominant: psd.peak(low, high).map(|(frequency, power)| DominantFrequency {
+                        frequency,
+                        cpm: frequency * 60.0,
+                        power,
+                    }),
+                    bands: self
+                        .bands
+                        .iter()
+                        .map(|band| {
+                            let power = psd.band_power(band.low_cpm / 60.0, band.high_cpm / 60.0);
+                            BandPower {
+                                name: band.name,
+                                low_cpm: band.low_cpm,
+                                high_cpm: band.high_cpm,
+                                power,
+                                fraction: if total > 0.0 { power / total } else { 0.0 },
+                            }
+                        })
+                        .collect(),
+                    psd,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests;
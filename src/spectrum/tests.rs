@@ -0,0 +1,99 @@
+use super::{fft::fft, welch::welch};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use std::f64::consts::PI;
+
+const SAMPLE_RATE: f64 = 256.0;
+
+fn sine(amplitude: f64, frequency: f64, seconds: f64) -> Vec<f64> {
+    (0..(seconds * SAMPLE_RATE) as usize)
+        .map(|i| amplitude * (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin())
+        .collect()
+}
+
+fn assert_near(actual: f64, expected: f64, tolerance: f64, what: &str) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "{}: {} isn't within {} of {}",
+        what,
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+#[test]
+fn fft_matches_the_dft() {
+    let mut rng = StdRng::seed_from_u64(33);
+    let input: Vec<f64> = (0..32).map(|_| rng.gen_range(-1.0, 1.0)).collect();
+    let (mut re, mut im) = (input.clone(), vec![0.0; 32]);
+    fft(&mut re, &mut im);
+
+    for k in 0..32 {
+        let (dft_re, dft_im) = input.iter().enumerate().fold((0.0, 0.0), |(r, i), (n, x)| {
+            let angle = -2.0 * PI * (k * n) as f64 / 32.0;
+            (r + x * angle.cos(), i + x * angle.sin())
+        });
+        assert_near(re[k], dft_re, 1e-9, &format!("bin {} real", k));
+        assert_near(im[k], dft_im, 1e-9, &format!("bin {} imaginary", k));
+    }
+}
+
+#[test]
+fn welch_finds_a_sine() {
+    let psd = welch(&sine(2.0, 10.0, 8.0), SAMPLE_RATE, 256).unwrap();
+
+    assert_eq!(psd.frequencies.len(), 129);
+    assert_eq!(psd.frequencies[10], 10.0);
+    assert_eq!(psd.frequencies[128], SAMPLE_RATE / 2.0);
+
+    let (frequency, _) = psd.peak(1.0, 100.0).unwrap();
+    assert_eq!(frequency, 10.0);
+    // All of a sine's power, A² / 2, within the bins the window spreads it over
+    assert_near(psd.total_power(), 2.0, 1e-9, "total power");
+    assert_near(psd.band_power(8.0, 13.0), 2.0, 1e-9, "alpha band power");
+    assert!(psd.band_power(13.0, 30.0) < 1e-9);
+}
+
+#[test]
+fn welch_ignores_an_offset() {
+    let with_offset: Vec<f64> = sine(1.0, 20.0, 4.0).iter().map(|v| v + 3.0).collect();
+    let psd = welch(&with_offset, SAMPLE_RATE, 128).unwrap();
+    assert!(psd.power[0] < 1e-12, "DC bin holds {}", psd.power[0]);
+    assert_near(psd.total_power(), 0.5, 1e-9, "total power");
+}
+
+#[test]
+fn welch_of_white_noise_is_flat() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let noise: Vec<f64> = (0..SAMPLE_RATE as usize * 120)
+        .map(|_| rng.gen_range(-1.0, 1.0))
+        .collect();
+    let psd = welch(&noise, SAMPLE_RATE, 256).unwrap();
+
+    // Uniform on ±1 has a variance of 1/3, spread evenly up to Nyquist
+    let density = 1.0 / 3.0 / (SAMPLE_RATE / 2.0);
+    assert_near(psd.total_power(), 1.0 / 3.0, 0.01, "total power");
+    for (frequency, power) in psd
+        .frequencies
+        .iter()
+        .zip(psd.power.iter())
+        .skip(1)
+        .take(127)
+    {
+        assert_near(*power, density, density * 0.3, &format!("{} Hz", frequency));
+    }
+}
+
+#[test]
+fn welch_needs_a_whole_segment() {
+    let samples = sine(1.0, 10.0, 1.0);
+    assert!(welch(&samples, SAMPLE_RATE, 512).is_none());
+    assert!(welch(&samples, SAMPLE_RATE, 1).is_none());
+    assert!(welch(&samples[..255], SAMPLE_RATE, 256).is_none());
+
+    // Rounded down to a power of two
+    let psd = welch(&samples, SAMPLE_RATE, 300).unwrap();
+    assert_eq!(psd.frequencies.len(), 129);
+}
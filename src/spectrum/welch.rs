@@ -0,0 +1,95 @@
+use super::fft::fft;
+
+use serde::Serialize;
+
+use std::f64::consts::PI;
+
+/// One-sided power spectral density
+#[derive(Clone, Debug, Serialize)]
+pub struct Psd {
+    /// Hz, from 0 up to Nyquist
+    pub frequencies: Vec<f64>,
+    /// Units² / Hz
+    pub power: Vec<f64>,
+}
+
+/// Welch's method: averages the periodograms of Hann windowed, half overlapping
+/// segments. `segment_len` is rounded down to a power of two, and `None` is returned
+/// until there's at least one full segment
+pub fn welch(samples: &[f64], sample_rate: f64, segment_len: usize) -> Option<Psd> {
+    if segment_len < 2 {
+        return None;
+    }
+    let n = if segment_len.is_power_of_two() {
+        segment_len
+    } else {
+        segment_len.next_power_of_two() / 2
+    };
+    if samples.len() < n {
+        return None;
+    }
+
+    let window: Vec<f64> = (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos())
+        .collect();
+    let scale = sample_rate * window.iter().map(|w| w * w).sum::<f64>();
+
+    let bins = n / 2 + 1;
+    let mut power = vec![0.0; bins];
+    let mut segments = 0;
+    let (mut re, mut im) = (vec![0.0; n], vec![0.0; n]);
+
+    for start in (0..=samples.len() - n).step_by(n / 2) {
+        let segment = &samples[start..start + n];
+        let mean = segment.iter().sum::<f64>() / n as f64;
+        for i in 0..n {
+            re[i] = (segment[i] - mean) * window[i];
+            im[i] = 0.0;
+        }
+        fft(&mut re, &mut im);
+
+        for (k, p) in power.iter_mut().enumerate() {
+            // Negative frequencies fold onto positive ones, except DC and Nyquist
+            let fold = if k == 0 || k == n / 2 { 1.0 } else { 2.0 };
+            *p += fold * (re[k] * re[k] + im[k] * im[k]) / scale;
+        }
+        segments += 1;
+    }
+
+    Some(Psd {
+        frequencies: (0..bins).map(|k| k as f64 * sample_rate / n as f64).collect(),
+        power: power.into_iter().map(|p| p / segments as f64).collect(),
+    })
+}
+
+impl Psd {
+    fn resolution(&self) -> f64 {
+        self.frequencies.get(1).copied().unwrap_or(0.0)
+    }
+
+    /// Integrated power between `low` (inclusive) and `high` (exclusive) Hz
+    pub fn band_power(&self, low: f64, high: f64) -> f64 {
+        self.frequencies
+            .iter()
+            .zip(self.power.iter())
+            .filter(|(f, _)| **f >= low && **f < high)
+            .map(|(_, p)| p)
+            .sum::<f64>()
+            * self.resolution()
+    }
+
+    /// Power of every bin except DC
+    pub fn total_power(&self) -> f64 {
+        self.power.iter().skip(1).sum::<f64>() * self.resolution()
+    }
+
+    /// `(frequency, power)` of the strongest bin between `low` and `high` Hz
+    pub fn peak(&self, low: f64, high: f64) -> Option<(f64, f64)> {
+        self.frequencies
+            .iter()
+            .copied()
+            .zip(self.power.iter().copied())
+            .filter(|(f, _)| *f >= low && *f < high)
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+    }
+}
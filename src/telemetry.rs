@@ -0,0 +1,113 @@
+//! Diagnostics through `tracing`, to any of the console, a JSON lines file and rolling
+//! log files, as configured
+
+use crate::ConstConfig;
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    EnvFilter, Registry,
+};
+
+use std::fs::OpenOptions;
+
+/// How often a rolling log starts a new file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl LogRotation {
+    fn rotation(&self) -> Rotation {
+        match self {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Plain text logs, a new file each `rotation` named `prefix` and the date
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RollingLog {
+    pub directory: &'static str,
+    pub prefix: &'static str,
+    pub rotation: LogRotation,
+}
+
+/// Sets up every configured output. `trace` logs everything, with the console also
+/// showing when spans are entered and left. Files are written on a background thread,
+/// so the returned guards must be held until exit to flush them
+pub fn init(trace: bool) -> Result<Vec<WorkerGuard>> {
+    let const_config = ConstConfig::get_config();
+    let mut guards = vec![];
+
+    let filter = EnvFilter::try_new(if trace {
+        "trace"
+    } else {
+        const_config.log_filter
+    })?;
+
+    // Stderr, so it stays out of the way of the menus on stdout
+    let console = if const_config.log_console {
+        Some(
+            fmt::layer()
+                .pretty()
+                .with_writer(std::io::stderr)
+                .with_span_events(if trace {
+                    FmtSpan::ACTIVE
+                } else {
+                    FmtSpan::NONE
+                }),
+        )
+    } else {
+        None
+    };
+
+    let json = match const_config.log_json_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            guards.push(guard);
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(writer),
+            )
+        }
+        None => None,
+    };
+
+    let rolling = match const_config.log_rolling {
+        Some(log) => {
+            let appender =
+                RollingFileAppender::new(log.rotation.rotation(), log.directory, log.prefix);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            guards.push(guard);
+            Some(fmt::layer().with_ansi(false).with_writer(writer))
+        }
+        None => None,
+    };
+
+    tracing::subscriber::set_global_default(
+        Registry::default()
+            .with(filter)
+            .with(console)
+            .with(json)
+            .with(rolling),
+    )?;
+    Ok(guards)
+}
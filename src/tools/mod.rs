@@ -0,0 +1,177 @@
+use crate::{
+    app::state::DeviceInfo,
+    export::{write_table, FrameTable, OutputFormat},
+    recorder,
+    session::{manifest, SessionMetadata},
+};
+
+use anyhow::{anyhow, Result};
+use console::style;
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+/// Runs an offline tool if one was requested on the command line.
+/// Returns `None` when the arguments should start the normal capture interface instead
+pub fn run(args: &[String]) -> Option<Result<()>> {
+    match args.get(1).map(|a| a.as_str()) {
+        Some("export") => Some(export(&args[2..])),
+        Some("recover") => Some(recover(&args[2..])),
+        Some("verify") => Some(verify(&args[2..])),
+        _ => None,
+    }
+}
+
+/// `export <csv file | session directory> <csv|npz|mat>`
+///
+/// Converts recorded CSV frames into another output format, next to the original files
+fn export(args: &[String]) -> Result<()> {
+    let (input, format) = match args {
+        [input, format, ..] => (Path::new(input), format),
+        _ => {
+            return Err(anyhow!(
+                "usage: export <csv file | session directory> <csv|npz|mat>"
+            ))
+        }
+    };
+    let format = OutputFormat::from_extension(format)
+        .ok_or_else(|| anyhow!("unknown output format {}", format))?;
+
+    let (directory, inputs) = if input.is_dir() {
+        (input.to_path_buf(), csv_files(input)?)
+    } else {
+        (
+            input.parent().unwrap_or_else(|| Path::new(".")).to_path_buf(),
+            vec![input.to_path_buf()],
+        )
+    };
+
+    let metadata = SessionMetadata::load(&directory).unwrap_or_else(|_| {
+        let name = directory
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        SessionMetadata::new(
+            name,
+            &DeviceInfo {
+                pico_scope_type: "".to_string(),
+                channel_info: vec![],
+                refresh_rate: 0,
+            },
+        )
+    });
+
+    for path in inputs {
+        let table = FrameTable::from_csv(&path, &metadata)?;
+        let output = path.with_extension(format.extension());
+        if output == path {
+            continue;
+        }
+
+        write_table(&mut File::create(&output)?, format, &table, &metadata)?;
+        println!(
+            "{} {} -> {}",
+            style("✔").bold().green(),
+            path.display(),
+            style(output.display()).bold()
+        );
+    }
+
+    Ok(())
+}
+
+/// `recover <session directory>`
+///
+/// Rebuilds the raw chunk index of a session that didn't shut down cleanly
+fn recover(args: &[String]) -> Result<()> {
+    let directory = match args.first() {
+        Some(directory) => Path::new(directory),
+        None => return Err(anyhow!("usage: recover <session directory>")),
+    };
+
+    let report = recorder::recover(directory)?;
+    for (file, bytes) in report.truncated.iter() {
+        println!(
+            "{} {} had a torn tail, dropped {} bytes",
+            style("!").bold().yellow(),
+            file,
+            bytes
+        );
+    }
+    for file in report.unreadable.iter() {
+        println!(
+            "{} {} {}",
+            style("✘").bold().red(),
+            file,
+            style("is not a readable chunk").red()
+        );
+    }
+    for (channel, samples) in report.index.sample_counts() {
+        println!("Channel {} -> {} samples", channel, samples);
+    }
+    println!(
+        "{} Rebuilt index from {} chunks",
+        style("✔").bold().green(),
+        report.index.chunks.len()
+    );
+
+    Ok(())
+}
+
+/// `verify <session directory>`
+///
+/// Checks every file of a session against the checksums in its manifest
+fn verify(args: &[String]) -> Result<()> {
+    let directory = match args.first() {
+        Some(directory) => Path::new(directory),
+        None => return Err(anyhow!("usage: verify <session directory>")),
+    };
+
+    let report = manifest::verify(directory)?;
+    for file in report.missing.iter() {
+        println!("{} {} {}", style("✘").bold().red(), file, style("is missing").red());
+    }
+    for file in report.corrupt.iter() {
+        println!(
+            "{} {} {}",
+            style("✘").bold().red(),
+            file,
+            style("does not match its checksum").red()
+        );
+    }
+    for file in report.extra.iter() {
+        println!(
+            "{} {} {}",
+            style("!").bold().yellow(),
+            file,
+            style("is not in the manifest").yellow()
+        );
+    }
+
+    if report.is_ok() {
+        println!(
+            "{} All {} files verified",
+            style("✔").bold().green(),
+            report.verified
+        );
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} missing, {} corrupt, {} extra files",
+            report.missing.len(),
+            report.corrupt.len(),
+            report.extra.len()
+        ))
+    }
+}
+
+fn csv_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |e| e == "csv"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
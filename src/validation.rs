@@ -0,0 +1,565 @@
+use crate::{
+    app::state::DeviceInfo,
+    electrodes::Montage,
+    planner::{CapturePlanner, Verdict},
+    recorder::chunk::CHUNK_HEADER_LEN,
+    virt_channels::MuxLayout,
+    ConstConfig,
+};
+
+use serde::Serialize;
+
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The pipeline would panic or produce nothing
+    Error,
+    /// Works, but probably not as intended
+    Warning,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfigProblem {
+    pub field: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// What to change to fix it
+    pub hint: String,
+}
+
+#[derive(Default)]
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn error(&mut self, field: &'static str, message: String, hint: &str) {
+        self.push(field, Severity::Error, message, hint);
+    }
+
+    fn warning(&mut self, field: &'static str, message: String, hint: &str) {
+        self.push(field, Severity::Warning, message, hint);
+    }
+
+    fn push(&mut self, field: &'static str, severity: Severity, message: String, hint: &str) {
+        self.0.push(ConfigProblem {
+            field,
+            severity,
+            message,
+            hint: hint.to_string(),
+        });
+    }
+}
+
+/// True if any problem stops the pipeline from working
+pub fn has_errors(problems: &[ConfigProblem]) -> bool {
+    problems.iter().any(|p| p.severity == Severity::Error)
+}
+
+/// Checks every setting, and how they combine with the device once it's configured.
+/// Returns every problem found rather than stopping at the first
+pub fn validate(config: &ConstConfig, device: Option<&DeviceInfo>) -> Vec<ConfigProblem> {
+    let mut problems = Problems::default();
+
+    check_demux(config, &mut problems);
+    check_storage(config, &mut problems);
+    check_analysis(config, &mut problems);
+    check_memory(config, &mut problems);
+    check_pipeline(config, &mut problems);
+    check_layouts(config, &mut problems);
+    check_electrodes(config, &mut problems);
+    if let Some(device) = device {
+        check_device(config, device, &mut problems);
+    }
+
+    problems.0
+}
+
+fn check_demux(config: &ConstConfig, problems: &mut Problems) {
+    if config.arduino_hz == 0 {
+        problems.error(
+            "arduino_hz",
+            "is 0, so every slot would be infinitely wide".to_string(),
+            "Set it to the multiplexer's switching frequency",
+        );
+    }
+    if config.virt_channel_count == 0 {
+        problems.error(
+            "virt_channel_count",
+            "is 0, so no virtual channels would be produced".to_string(),
+            "Set it to the number of multiplexed inputs",
+        );
+    }
+    if config.arduino_hz_tolerance <= 0.0 || config.arduino_hz_tolerance >= 1.0 {
+        problems.error(
+            "arduino_hz_tolerance",
+            format!(
+                "is {}, which makes the narrowest accepted sync pulse {} samples wide",
+                config.arduino_hz_tolerance,
+                if config.arduino_hz_tolerance >= 1.0 { "0" } else { "wider than the widest" }
+            ),
+            "Use a value between 0 and 1, such as 0.2",
+        );
+    } else if config.arduino_hz_tolerance > 0.5 {
+        problems.warning(
+            "arduino_hz_tolerance",
+            format!(
+                "is {}, so noise as short as {:.0}% of a slot is taken for a sync pulse",
+                config.arduino_hz_tolerance,
+                (1.0 - config.arduino_hz_tolerance) * 100.0
+            ),
+            "Lower it unless the multiplexer's timing really is that loose",
+        );
+    }
+    if config.sync_point_threshold <= 0.0 {
+        problems.error(
+            "sync_point_threshold",
+            format!("is {}, so every sample looks like a sync pulse", config.sync_point_threshold),
+            "Set it between the highest signal level and the sync pulse level",
+        );
+    }
+    if config.virt_channel_noise_threshold < 0.0 {
+        problems.error(
+            "virt_channel_noise_threshold",
+            format!("is {}, so every slot is taken as noisy", config.virt_channel_noise_threshold),
+            "Use a positive value",
+        );
+    }
+    if config.slowest_frame_rate() < 3.0 {
+        problems.error(
+            "arduino_hz",
+            format!(
+                "gives {:.2} frames per second, fewer than the 3 sync pulses each block needs",
+                config.slowest_frame_rate()
+            ),
+            "Raise arduino_hz or use fewer slots",
+        );
+    }
+}
+
+fn check_storage(config: &ConstConfig, problems: &mut Problems) {
+    if config.output_dir.trim().is_empty() {
+        problems.error(
+            "output_dir",
+            "is empty".to_string(),
+            "Name the directory sessions are written to",
+        );
+    }
+    if config.chunk_max_bytes <= CHUNK_HEADER_LEN {
+        problems.error(
+            "chunk_max_bytes",
+            format!("is {}, so no record fits in a chunk", config.chunk_max_bytes),
+            "Use a few MiB or more",
+        );
+    }
+    if config.chunk_max_duration.as_secs_f64() <= 0.0 {
+        problems.error(
+            "chunk_max_duration",
+            "is 0, so a new chunk would be started for every record".to_string(),
+            "Use a few minutes",
+        );
+    }
+    if config.chunk_fsync_interval > config.chunk_max_duration {
+        problems.warning(
+            "chunk_fsync_interval",
+            "is longer than chunk_max_duration, so chunks are only synced when rotated"
+                .to_string(),
+            "Make it shorter than chunk_max_duration",
+        );
+    }
+    let port = config
+        .web_interface_bind
+        .rsplit(':')
+        .next()
+        .and_then(|p| p.parse::<u16>().ok());
+    if !config.web_interface_bind.contains(':') || port.is_none() {
+        problems.error(
+            "web_interface_bind",
+            format!("\"{}\" has no valid port", config.web_interface_bind),
+            "Use host:port, such as localhost:8000",
+        );
+    }
+}
+
+fn check_analysis(config: &ConstConfig, problems: &mut Problems) {
+    // Every channel is analysed at the same rate, so the slowest has to keep up
+    let frame_rate = config.slowest_frame_rate();
+
+    if config.spectral_rate <= 0.0 || config.spectral_rate > frame_rate {
+        problems.error(
+            "spectral_rate",
+            format!(
+                "is {} Hz, it must be above 0 and at most the slowest frame rate, {:.1} Hz",
+                config.spectral_rate, frame_rate
+            ),
+            "A few Hz is plenty for slow waves",
+        );
+    } else {
+        let window = (config.spectral_window.as_secs_f64() * config.spectral_rate) as usize;
+        if window < config.spectral_segment {
+            problems.error(
+                "spectral_window",
+                format!(
+                    "holds {} samples, fewer than one {} sample segment, so no spectrum is ever produced",
+                    window, config.spectral_segment
+                ),
+                "Lengthen spectral_window or shorten spectral_segment",
+            );
+        }
+        for band in config.spectral_bands.iter() {
+            if band.low_cpm >= band.high_cpm {
+                problems.error(
+                    "spectral_bands",
+                    format!("{} runs from {} to {} cpm", band.name, band.low_cpm, band.high_cpm),
+                    "Put the lower edge first",
+                );
+            }
+            if band.high_cpm / 60.0 > config.spectral_rate / 2.0 {
+                problems.warning(
+                    "spectral_bands",
+                    format!(
+                        "{} reaches {} cpm, above the {:.0} cpm that {} Hz can resolve",
+                        band.name,
+                        band.high_cpm,
+                        config.spectral_rate * 30.0,
+                        config.spectral_rate
+                    ),
+                    "Raise spectral_rate or narrow the band",
+                );
+            }
+        }
+    }
+    if config.spectral_segment < 8 {
+        problems.error(
+            "spectral_segment",
+            format!("is {}, too short for a useful spectrum", config.spectral_segment),
+            "Use a power of two such as 512",
+        );
+    } else if !config.spectral_segment.is_power_of_two() {
+        problems.warning(
+            "spectral_segment",
+            format!(
+                "is {}, and will be rounded down to {}",
+                config.spectral_segment,
+                config.spectral_segment.next_power_of_two() / 2
+            ),
+            "Use a power of two",
+        );
+    }
+    if config.spectrogram_interval.as_secs_f64() <= 0.0 {
+        problems.error(
+            "spectrogram_interval",
+            "is 0".to_string(),
+            "Use a few seconds",
+        );
+    }
+
+    let limits = [
+        ("quality_noise_limit", config.quality_noise_limit),
+        ("quality_line_limit", config.quality_line_limit),
+        ("quality_drift_limit", config.quality_drift_limit),
+        ("quality_clip_limit", config.quality_clip_limit),
+    ];
+    for (field, limit) in limits.iter() {
+        if *limit <= 0.0 {
+            problems.error(
+                field,
+                format!("is {}, so every channel fails it", limit),
+                "Use a small positive fraction of the input range",
+            );
+        }
+    }
+    if config.quality_window.as_secs_f64() * 50.0 < 1.0 {
+        problems.error(
+            "quality_window",
+            "is shorter than one cycle of mains".to_string(),
+            "Use a few seconds",
+        );
+    }
+    if config.min_samples_per_slot < 3 || config.min_samples_per_slot > config.max_samples_per_slot
+    {
+        problems.error(
+            "min_samples_per_slot",
+            format!(
+                "is {}, it must be at least 3 and at most max_samples_per_slot ({})",
+                config.min_samples_per_slot, config.max_samples_per_slot
+            ),
+            "10 gives a clean average of each slot",
+        );
+    }
+}
+
+fn check_memory(config: &ConstConfig, problems: &mut Problems) {
+    // Blocks are a second of samples, and the measured rate can run a little over
+    if config.stream_buffer_duration.as_secs_f64() < 2.0 {
+        problems.error(
+            "stream_buffer_duration",
+            format!(
+                "is {:.1}s, so the buffer may never hold a whole one second block",
+                config.stream_buffer_duration.as_secs_f64()
+            ),
+            "Use 2 seconds or more",
+        );
+    }
+    if config.memory_queue_duration.as_secs_f64() <= 0.0 {
+        problems.warning(
+            "memory_queue_duration",
+            "is 0, so clients can't collect any raw samples".to_string(),
+            "Use a few seconds unless nothing reads the memory queue",
+        );
+    }
+    if config.memory_warning_bytes >= config.memory_critical_bytes {
+        problems.warning(
+            "memory_warning_bytes",
+            format!(
+                "is {}, not below memory_critical_bytes of {}, so there's no early warning",
+                config.memory_warning_bytes, config.memory_critical_bytes
+            ),
+            "Set it below the critical limit",
+        );
+    }
+}
+
+fn check_pipeline(config: &ConstConfig, problems: &mut Problems) {
+    // A queue with no room is a rendezvous, which the dropping event queue never gets through
+    for (field, depth) in vec![
+        ("pipeline_event_queue_depth", config.pipeline_event_queue_depth),
+        ("pipeline_block_queue_depth", config.pipeline_block_queue_depth),
+    ] {
+        if depth == 0 {
+            problems.error(
+                field,
+                "is 0, so no data gets through the processing pipeline".to_string(),
+                "Use 1 or more",
+            );
+        }
+    }
+    if config.demux_threads == 0 {
+        problems.error(
+            "demux_threads",
+            "is 0, so nothing gets demultiplexed".to_string(),
+            "Use 1 or more, up to about one per core",
+        );
+    }
+}
+
+fn check_layouts(config: &ConstConfig, problems: &mut Problems) {
+    let mut channels = HashSet::new();
+    for layout in config.channel_layouts.iter() {
+        if !["A", "B", "C", "D", "E", "F", "G", "H"].contains(&layout.channel) {
+            problems.error(
+                "channel_layouts",
+                format!("lays out channel {:?}, which isn't one", layout.channel),
+                "Use the channel's letter, A to H",
+            );
+        }
+        if !channels.insert(layout.channel) {
+            problems.error(
+                "channel_layouts",
+                format!("lays out channel {} more than once", layout.channel),
+                "Give each channel one layout",
+            );
+        }
+        if layout.layout == MuxLayout::Slots(0) {
+            problems.error(
+                "channel_layouts",
+                format!("gives channel {} 0 slots", layout.channel),
+                "Use MuxLayout::SyncOnly for a channel with only sync pulses",
+            );
+        }
+    }
+}
+
+fn check_electrodes(config: &ConstConfig, problems: &mut Problems) {
+    let mut names = HashSet::new();
+    let mut slots = HashSet::new();
+    for electrode in config.electrodes.iter() {
+        if !["A", "B", "C", "D", "E", "F", "G", "H"].contains(&electrode.channel) {
+            problems.error(
+                "electrodes",
+                format!(
+                    "puts {} on channel {:?}, which isn't one",
+                    electrode.name, electrode.channel
+                ),
+                "Use the channel's letter, A to H",
+            );
+        }
+        let slot_count = config.mux_layout(electrode.channel).virt_channels();
+        if electrode.virt_channel >= slot_count {
+            problems.error(
+                "electrodes",
+                format!(
+                    "puts {} on slot {}, but channel {} only has {} virtual channels",
+                    electrode.name, electrode.virt_channel, electrode.channel, slot_count
+                ),
+                "Slots count from 0, and a channel without a multiplexer only has slot 0",
+            );
+        }
+        if !slots.insert((electrode.channel, electrode.virt_channel)) {
+            problems.error(
+                "electrodes",
+                format!(
+                    "has more than one electrode on slot {} of channel {}",
+                    electrode.virt_channel, electrode.channel
+                ),
+                "Give each slot one electrode",
+            );
+        }
+        if !names.insert(electrode.name) {
+            problems.error(
+                "electrodes",
+                format!("names more than one electrode {}", electrode.name),
+                "Give each electrode its own name",
+            );
+        }
+    }
+
+    // Frames are demultiplexed per Pico channel, so only electrodes on one can be combined
+    let channel_of = |name: &str| {
+        config
+            .electrodes
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| e.channel)
+    };
+    for montage in config.montages.iter() {
+        let inputs: Vec<&str> = match *montage {
+            Montage::Bipolar {
+                name,
+                positive,
+                negative,
+            } => {
+                if positive == negative {
+                    problems.error(
+                        "montages",
+                        format!("derives {} from {} minus itself", name, positive),
+                        "Pick two different electrodes",
+                    );
+                }
+                vec![positive, negative]
+            }
+            Montage::CommonAverage { electrodes, .. } => electrodes.to_vec(),
+        };
+        let mut channels = BTreeSet::new();
+        for input in inputs {
+            match channel_of(input) {
+                Some(channel) => {
+                    channels.insert(channel);
+                }
+                None => problems.error(
+                    "montages",
+                    format!("uses {}, which isn't a configured electrode", input),
+                    "Add it to electrodes or fix the name",
+                ),
+            }
+        }
+        if channels.len() > 1 {
+            problems.error(
+                "montages",
+                format!(
+                    "combines electrodes on channels {:?}, which frame at their own times",
+                    channels
+                ),
+                "Only combine electrodes multiplexed onto the same channel",
+            );
+        }
+    }
+}
+
+fn check_device(config: &ConstConfig, device: &DeviceInfo, problems: &mut Problems) {
+    let layouts: Vec<MuxLayout> = device.channel_info.iter().map(|c| c.layout).collect();
+    let plan = CapturePlanner::new(&layouts).assess(device.refresh_rate);
+    if plan.verdict == Verdict::Refused {
+        problems.error(
+            "arduino_hz",
+            format!("at {} S/s capture, {}", device.refresh_rate, plan.reason),
+            "Capture faster or lower arduino_hz",
+        );
+    }
+
+    // Slot widths only matter where there are sync pulses to find and slots to average
+    let multiplexed = device.channel_info.iter().any(|c| c.layout.has_sync());
+    if config.arduino_hz > 0 && multiplexed {
+        let est_sample_width = device.refresh_rate as usize / config.arduino_hz;
+        let lower_sample_width =
+            ((est_sample_width as f32) * (1f32 - config.arduino_hz_tolerance)).round() as usize;
+        if est_sample_width > 0 && lower_sample_width == 0 {
+            problems.error(
+                "arduino_hz_tolerance",
+                format!(
+                    "accepts sync pulses 0 samples wide with {} samples per slot",
+                    est_sample_width
+                ),
+                "Lower the tolerance or capture faster",
+            );
+        }
+        // The average of each slot is taken over a third of it either side of its middle
+        if est_sample_width > 0 && est_sample_width / 3 == 0 {
+            problems.error(
+                "arduino_hz",
+                format!(
+                    "leaves {} samples per slot, so each virtual channel is averaged over nothing",
+                    est_sample_width
+                ),
+                "Capture at least 3 samples per slot",
+            );
+        }
+    }
+
+    // Both buffers full on every channel, as f64 samples
+    let buffered = (config.memory_queue_duration + config.stream_buffer_duration).as_secs_f64()
+        * device.refresh_rate as f64
+        * device.channel_info.len() as f64
+        * 8.0;
+    if buffered >= config.memory_warning_bytes as f64 {
+        problems.warning(
+            "memory_queue_duration",
+            format!(
+                "and the stream buffer hold up to {:.0} MiB at {} S/s on {} channels, past the warning limit",
+                buffered / (1024.0 * 1024.0),
+                device.refresh_rate,
+                device.channel_info.len()
+            ),
+            "Shorten the memory queue or capture slower",
+        );
+    }
+
+    if !device.channel_info.is_empty()
+        && device
+            .channel_info
+            .iter()
+            .all(|c| c.layout.virt_channels() == 0)
+    {
+        problems.warning(
+            "channel_layouts",
+            "leaves only sync pulses on every enabled channel, so nothing is recorded".to_string(),
+            "Enable a channel with electrodes on it",
+        );
+    }
+    for channel in device.channel_info.iter() {
+        let full_scale = channel.voltage_range as f64;
+        if channel.layout.has_sync() && config.sync_point_threshold >= full_scale {
+            problems.error(
+                "sync_point_threshold",
+                format!(
+                    "is {}, but channel {} only reaches {}, so sync pulses are never seen",
+                    config.sync_point_threshold, channel.channel, full_scale
+                ),
+                "Pick a larger range for the channel or lower the threshold",
+            );
+        }
+        if config.virt_channel_noise_threshold > full_scale {
+            problems.warning(
+                "virt_channel_noise_threshold",
+                format!(
+                    "is {}, above channel {}'s {} range, so slots are never taken as noisy",
+                    config.virt_channel_noise_threshold, channel.channel, full_scale
+                ),
+                "Lower it to a fraction of the range",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
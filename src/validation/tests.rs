@@ -0,0 +1,94 @@
+//! Settings either side of the limits they're checked against, starting from the
+//! shipped configuration
+
+use super::*;
+use crate::{app::state::ChannelInfo, virt_channels::ChannelLayout};
+
+/// Problems with one field, by severity
+fn problems(config: &ConstConfig, device: Option<&DeviceInfo>, field: &str) -> Vec<Severity> {
+    validate(config, device)
+        .into_iter()
+        .filter(|p| p.field == field)
+        .map(|p| p.severity)
+        .collect()
+}
+
+fn device(layout: MuxLayout, refresh_rate: u32) -> DeviceInfo {
+    DeviceInfo {
+        pico_scope_type: "test".to_string(),
+        channel_info: vec![ChannelInfo {
+            channel: "A".to_string(),
+            virt_channels: layout.virt_channels() as u32,
+            layout,
+            voltage_range: 5.0,
+        }],
+        refresh_rate,
+    }
+}
+
+#[test]
+fn the_shipped_configuration_works() {
+    let problems = validate(&ConstConfig::get_config(), None);
+    assert!(!has_errors(&problems), "{:?}", problems);
+}
+
+#[test]
+fn tolerance_has_to_leave_a_pulse_to_find() {
+    let cases = [
+        (0.0, vec![Severity::Error]),
+        (0.2, vec![]),
+        (0.5, vec![]),
+        (0.6, vec![Severity::Warning]),
+        (0.99, vec![Severity::Warning]),
+        (1.0, vec![Severity::Error]),
+        (1.5, vec![Severity::Error]),
+    ];
+    for (tolerance, expected) in cases.iter() {
+        let mut config = ConstConfig::get_config();
+        config.arduino_hz_tolerance = *tolerance;
+        assert_eq!(
+            &problems(&config, None, "arduino_hz_tolerance"),
+            expected,
+            "tolerance {}",
+            tolerance
+        );
+    }
+}
+
+#[test]
+fn every_block_needs_three_sync_pulses() {
+    // The default layout frames every 5 slots
+    let mut config = ConstConfig::get_config();
+    config.arduino_hz = 15;
+    assert_eq!(problems(&config, None, "arduino_hz"), []);
+    config.arduino_hz = 14;
+    assert_eq!(problems(&config, None, "arduino_hz"), [Severity::Error]);
+
+    // Even if it's only the one channel with a wider frame
+    let mut config = ConstConfig::get_config();
+    config.arduino_hz = 25;
+    assert_eq!(problems(&config, None, "arduino_hz"), []);
+    config.channel_layouts = &[ChannelLayout {
+        channel: "B",
+        layout: MuxLayout::Slots(9),
+    }];
+    assert_eq!(problems(&config, None, "arduino_hz"), [Severity::Error]);
+}
+
+#[test]
+fn the_capture_rate_has_to_suit_the_multiplexer() {
+    let config = ConstConfig::get_config();
+    let slot_rate = config.arduino_hz as u32;
+    let muxed = |samples_per_slot| device(MuxLayout::Slots(4), samples_per_slot * slot_rate);
+    assert_eq!(problems(&config, Some(&muxed(10)), "arduino_hz"), []);
+    assert_eq!(problems(&config, Some(&muxed(3)), "arduino_hz"), []);
+    // Refused by the planner, and too narrow to average
+    assert_eq!(
+        problems(&config, Some(&muxed(2)), "arduino_hz"),
+        [Severity::Error, Severity::Error]
+    );
+
+    // Nothing to demultiplex, so nothing is too slow
+    let direct = device(MuxLayout::None, slot_rate);
+    assert_eq!(problems(&config, Some(&direct), "arduino_hz"), []);
+}
@@ -1,82 +1,379 @@
 use crate::ConstConfig;
 
-use std::{collections::HashMap, ops::Add};
+use std::{cmp::Ordering, collections::HashMap, fmt, ops::Add};
 
 use pico_sdk::prelude::PicoChannel;
+use serde::{Deserialize, Serialize};
 
 pub type VirtChannel = usize;
 pub type VirtSamples = HashMap<VirtChannel, f64>;
 
-use console::{style, Term};
-use chrono::prelude::Local;
+/// What one Pico channel carries, and so how it's demultiplexed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "slots", rename_all = "snake_case")]
+pub enum MuxLayout {
+    /// A signal wired straight in, averaged down to the frame rate as one virtual channel
+    None,
+    /// A sync pulse followed by this many multiplexed inputs
+    Slots(usize),
+    /// Just the sync pulses, only checked for timing
+    SyncOnly,
+}
+
+impl MuxLayout {
+    /// Virtual channels the layout produces in every frame
+    pub fn virt_channels(&self) -> usize {
+        match self {
+            MuxLayout::None => 1,
+            MuxLayout::Slots(slots) => *slots,
+            MuxLayout::SyncOnly => 0,
+        }
+    }
+
+    pub fn has_sync(&self) -> bool {
+        *self != MuxLayout::None
+    }
+}
+
+impl fmt::Display for MuxLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuxLayout::None => write!(f, "direct"),
+            MuxLayout::Slots(slots) => write!(f, "{} slots", slots),
+            MuxLayout::SyncOnly => write!(f, "sync only"),
+        }
+    }
+}
 
+/// Layout of one Pico channel, as configured
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ChannelLayout {
+    /// Pico channel, by letter
+    pub channel: &'static str,
+    pub layout: MuxLayout,
+}
 
-use std::fs::File;
-use std::io::Write;
+/// One round of the multiplexer, starting at the sync pulse `offset` samples into the block
+#[derive(Clone, Debug)]
+pub struct VirtFrame {
+    /// Pico channel it was demultiplexed from
+    pub channel: PicoChannel,
+    pub offset: usize,
+    pub samples: VirtSamples,
+}
 
-#[derive(Debug)]
+/// Why a block couldn't be demultiplexed. Offsets are in samples from the start of the block
+#[derive(Clone, Debug, PartialEq)]
 pub enum VirtChannelError {
-    NotEnoughData,
-    NoSyncPulse,
+    /// Too few sync pulses to bound a single frame
+    NotEnoughData { channel: PicoChannel, pulses: usize },
+    NoSyncPulse { channel: PicoChannel },
+    /// Fewer than one sample per multiplexer slot, so pulse widths can't be estimated
+    SampleRateTooLow { sample_rate: u64, arduino_hz: usize },
+    /// NaN or infinite sample in the input
+    NanInput { channel: PicoChannel, offset: usize },
+    /// Two sync pulses too close together to fit every virtual channel between them
+    BadFrame {
+        channel: PicoChannel,
+        offset: usize,
+        width: usize,
+        pulses: usize,
+    },
+    /// The averaging window of a virtual channel runs off the end of the block
+    EdgeOfBuffer {
+        channel: PicoChannel,
+        offset: usize,
+        len: usize,
+    },
 }
 
-// fn generate_virtal_sample_layout() -> VirtSamples {
-//     let virt_channels: VirtSamples = HashMap::new();
-//     for i in 0..ConstConfig::get_config().virt_channel_count {
-//         virt_channels.insert(i, None);
-//     }
-//     return virt_channels;
-// }
+impl VirtChannelError {
+    /// Every `kind`, in declaration order
+    pub const KINDS: [&'static str; 6] = [
+        "NotEnoughData",
+        "NoSyncPulse",
+        "SampleRateTooLow",
+        "NanInput",
+        "BadFrame",
+        "EdgeOfBuffer",
+    ];
+
+    /// Name of the variant without its context, so errors of a kind can be grouped
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VirtChannelError::NotEnoughData { .. } => "NotEnoughData",
+            VirtChannelError::NoSyncPulse { .. } => "NoSyncPulse",
+            VirtChannelError::SampleRateTooLow { .. } => "SampleRateTooLow",
+            VirtChannelError::NanInput { .. } => "NanInput",
+            VirtChannelError::BadFrame { .. } => "BadFrame",
+            VirtChannelError::EdgeOfBuffer { .. } => "EdgeOfBuffer",
+        }
+    }
+}
 
+impl fmt::Display for VirtChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtChannelError::NotEnoughData { channel, pulses } => write!(
+                f,
+                "only {} sync pulses on channel {}, at least 3 are needed",
+                pulses, channel
+            ),
+            VirtChannelError::NoSyncPulse { channel } => {
+                write!(f, "can't find a synchronization pulse on channel {}", channel)
+            }
+            VirtChannelError::SampleRateTooLow {
+                sample_rate,
+                arduino_hz,
+            } => write!(
+                f,
+                "{} S/s is too slow to see a {} Hz multiplexer",
+                sample_rate, arduino_hz
+            ),
+            VirtChannelError::NanInput { channel, offset } => {
+                write!(f, "invalid sample on channel {} at {}", channel, offset)
+            }
+            VirtChannelError::BadFrame {
+                channel,
+                offset,
+                width,
+                pulses,
+            } => write!(
+                f,
+                "frame on channel {} at {} is only {} samples wide ({} pulses in block)",
+                channel, offset, width, pulses
+            ),
+            VirtChannelError::EdgeOfBuffer {
+                channel,
+                offset,
+                len,
+            } => write!(
+                f,
+                "frame on channel {} at {} runs past the end of the {} sample block",
+                channel, offset, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VirtChannelError {}
+
+/// Pulse widths are binned in tenths of the expected width, the last bin taking
+/// everything from 1.9 times the expected width up
+pub const WIDTH_BINS: usize = 20;
+
+/// How the sync pulses of one channel looked in a block. Widths and intervals are in
+/// samples
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncStats {
+    pub samples: usize,
+    /// Width of a multiplexer slot at the capture rate, which a pulse should be
+    pub expected_width: usize,
+    pub pulses: usize,
+    /// Runs above the sync threshold too narrow to be a pulse
+    pub rejected: usize,
+    /// Between consecutive pulses, which should be one slot per virtual channel plus one
+    pub intervals: usize,
+    pub interval_mean: f64,
+    pub interval_std: f64,
+    /// Of the pulses found
+    pub width_mean: f64,
+    pub width_std: f64,
+    /// Of every run above the threshold, pulse or not, see `WIDTH_BINS`
+    pub width_histogram: Vec<u64>,
+    /// Share of the block inside frames that were demultiplexed
+    pub coverage: f64,
+}
+
+impl SyncStats {
+    fn add_width(&mut self, width: usize, expected_width: usize) {
+        if self.width_histogram.is_empty() {
+            self.width_histogram = vec![0; WIDTH_BINS];
+        }
+        let bin = (width * 10 / expected_width.max(1)).min(WIDTH_BINS - 1);
+        self.width_histogram[bin] += 1;
+    }
+
+    fn add_pulses(&mut self, pulses: &[usize], widths: &[usize]) {
+        self.pulses = pulses.len();
+        let widths: Vec<f64> = widths.iter().map(|w| *w as f64).collect();
+        let (mean, std) = mean_std(&widths);
+        self.width_mean = mean;
+        self.width_std = std;
+
+        let intervals: Vec<f64> = pulses.windows(2).map(|w| (w[1] - w[0]) as f64).collect();
+        let (mean, std) = mean_std(&intervals);
+        self.intervals = intervals.len();
+        self.interval_mean = mean;
+        self.interval_std = std;
+    }
+}
+
+/// Mean and population standard deviation, both 0 for no values
+fn mean_std(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+/// Demultiplexes every Pico channel of a block in turn, each by its layout, returning
+/// their frames in channel order
 pub fn split_into_virt_channels(
     raw_data: &HashMap<PicoChannel, Vec<f64>>,
     picoscope_sample_speed: u64,
-) -> Result<Vec<VirtSamples>, VirtChannelError> {
-    let const_config = ConstConfig::get_config();
-    let mut final_virtual_samples: Vec<VirtSamples> = vec![];
+) -> Result<Vec<VirtFrame>, VirtChannelError> {
+    let mut channels: Vec<_> = raw_data.iter().collect();
+    channels.sort_by_key(|(channel, _)| **channel);
 
-    // Estimate samples per arudino switch
-    let est_sample_width: usize = (picoscope_sample_speed as usize) / const_config.arduino_hz;
+    let mut final_virtual_samples: Vec<VirtFrame> = vec![];
+    for (channel, data) in channels {
+        final_virtual_samples
+            .extend(demultiplex_channel(*channel, data, picoscope_sample_speed).frames?);
+    }
+    Ok(final_virtual_samples)
+}
 
-    // Find High points in data (indicateding a sync pulse)
-    let mut sync_pulses: HashMap<PicoChannel, Vec<usize>> = HashMap::new();
-    for (channel, data) in raw_data {
+/// Frames of one Pico channel in a block, and how its sync pulses looked
+pub struct ChannelDemux {
+    pub frames: Result<Vec<VirtFrame>, VirtChannelError>,
+    pub sync: SyncStats,
+}
+
+/// Demultiplexes one Pico channel by its configured layout. Channels don't depend on
+/// each other, so they can be done in parallel
+pub fn demultiplex_channel(
+    channel: PicoChannel,
+    data: &Vec<f64>,
+    picoscope_sample_speed: u64,
+) -> ChannelDemux {
+    let layout = ConstConfig::get_config().mux_layout(&channel.to_string());
+    demultiplex_layout(channel, layout, data, picoscope_sample_speed)
+}
+
+/// Finds the sync pulses of one Pico channel and averages every virtual channel's slot
+/// between them. A channel without a multiplexer is averaged down to the frame rate
+/// instead, and one with only sync pulses gives no frames
+#[tracing::instrument(level = "debug", skip(data), fields(samples = data.len()))]
+pub fn demultiplex_layout(
+    channel: PicoChannel,
+    layout: MuxLayout,
+    data: &Vec<f64>,
+    picoscope_sample_speed: u64,
+) -> ChannelDemux {
+    let mut sync = SyncStats {
+        samples: data.len(),
+        ..SyncStats::default()
+    };
+    if layout == MuxLayout::None {
+        let frames = average_direct(channel, data, picoscope_sample_speed);
+        if let Ok(frames) = frames.as_ref() {
+            sync.coverage = frames.len() as f64 * frame_width(picoscope_sample_speed) as f64
+                / data.len().max(1) as f64;
+        }
+        return ChannelDemux { frames, sync };
+    }
 
-        match find_sync_pulse(&data, est_sample_width) {
-            Ok(pulses) => sync_pulses.insert(*channel, pulses),
-            Err(err) => return Err(err),
+    let frames = detect_sync(channel, data, picoscope_sample_speed, &mut sync).and_then(|pulses| {
+        let frames = match layout {
+            MuxLayout::SyncOnly => vec![],
+            _ => determine_virt_channel_samples(channel, &pulses, layout.virt_channels(), data)?,
         };
+        // Frames run from each pulse to the next, bar the last pair
+        sync.coverage = (pulses[pulses.len() - 2] - pulses[0]) as f64 / data.len() as f64;
+        Ok(frames)
+    });
+    ChannelDemux { frames, sync }
+}
+
+/// Samples in one frame at the configured frame rate
+fn frame_width(picoscope_sample_speed: u64) -> usize {
+    (picoscope_sample_speed as f64 / ConstConfig::get_config().frame_rate()) as usize
+}
+
+/// Means of consecutive frame-wide windows of a channel without a multiplexer, so its
+/// frames come at the same rate as multiplexed ones. A window cut by the end of the
+/// block is left out
+fn average_direct(
+    channel: PicoChannel,
+    data: &[f64],
+    picoscope_sample_speed: u64,
+) -> Result<Vec<VirtFrame>, VirtChannelError> {
+    let width = frame_width(picoscope_sample_speed);
+    if width == 0 {
+        return Err(VirtChannelError::SampleRateTooLow {
+            sample_rate: picoscope_sample_speed,
+            arduino_hz: ConstConfig::get_config().arduino_hz,
+        });
     }
-    for (_, data) in sync_pulses.clone() {
-        if data.len() < 2 {
-            return Err(VirtChannelError::NotEnoughData);
-        }
+    if let Some(offset) = data.iter().position(|v| !v.is_finite()) {
+        return Err(VirtChannelError::NanInput { channel, offset });
     }
 
-    // Determine points to extract from data, and extracts an averaged result
-    let mut virt_channel_samples: HashMap<PicoChannel, Vec<VirtSamples>> = HashMap::new();
-    for (channel, data) in sync_pulses {
-        virt_channel_samples.insert(
-            channel,
-            determine_virt_channel_samples(
-                &data,
-                const_config.virt_channel_count,
-                &raw_data[&channel],
-            ),
-        );
+    Ok(data
+        .chunks_exact(width)
+        .enumerate()
+        .map(|(i, window)| {
+            let mut samples = HashMap::new();
+            samples.insert(0, window.iter().sum::<f64>() / width as f64);
+            VirtFrame {
+                channel,
+                offset: i * width,
+                samples,
+            }
+        })
+        .collect())
+}
+
+/// Finds the sync pulses of one channel, making sure there are enough to bound a frame
+#[tracing::instrument(level = "trace", skip(data, stats))]
+fn detect_sync(
+    channel: PicoChannel,
+    data: &Vec<f64>,
+    picoscope_sample_speed: u64,
+    stats: &mut SyncStats,
+) -> Result<Vec<usize>, VirtChannelError> {
+    let const_config = ConstConfig::get_config();
+
+    // Estimate samples per arudino switch
+    let est_sample_width: usize = (picoscope_sample_speed as usize)
+        .checked_div(const_config.arduino_hz)
+        .unwrap_or(0);
+    if est_sample_width == 0 {
+        return Err(VirtChannelError::SampleRateTooLow {
+            sample_rate: picoscope_sample_speed,
+            arduino_hz: const_config.arduino_hz,
+        });
     }
-    // Flatten Pico channels into Virt Channels
-    for (_, data) in virt_channel_samples {
-        final_virtual_samples.extend(data);
+    stats.expected_width = est_sample_width;
+
+    if let Some(offset) = data.iter().position(|v| !v.is_finite()) {
+        return Err(VirtChannelError::NanInput { channel, offset });
     }
 
-    return Ok(final_virtual_samples);
+    // Find High points in data (indicateding a sync pulse)
+    let pulses = find_sync_pulse(channel, data, est_sample_width, stats)?;
+    tracing::trace!(pulses = pulses.len(), est_sample_width, "found sync pulses");
+    // Each frame is bounded by two pulses, and the last pair is left for the next block
+    if pulses.len() < 3 {
+        return Err(VirtChannelError::NotEnoughData {
+            channel,
+            pulses: pulses.len(),
+        });
+    }
+    Ok(pulses)
 }
 
-/// Takes Picoscope data and determines the center of all the sync pulses
+/// Takes Picoscope data and determines the center of all the sync pulses, noting in
+/// `stats` how they look and how many were rejected
 fn find_sync_pulse(
+    channel: PicoChannel,
     input: &Vec<f64>,
     est_sample_width: usize,
+    stats: &mut SyncStats,
 ) -> Result<Vec<usize>, VirtChannelError> {
     let const_config = ConstConfig::get_config();
 
@@ -89,16 +386,16 @@ fn find_sync_pulse(
             elevated_points.push(index)
         }
         if *data_point > 5f64 {
-            println!("WHAT {}",*data_point);
+            tracing::trace!(index, value = *data_point, "sample past the 5 V sync level");
         }
     }
-    println!(
-        "\nElevated Points {} vs points {}",
-        elevated_points.len(),
-        input.len()
+    tracing::trace!(
+        elevated = elevated_points.len(),
+        samples = input.len(),
+        "elevated points"
     );
     if elevated_points.len() == 0 {
-        return Err(VirtChannelError::NoSyncPulse);
+        return Err(VirtChannelError::NoSyncPulse { channel });
     }
 
     // Classify points into blocks
@@ -109,8 +406,11 @@ fn find_sync_pulse(
     for point in elevated_points.iter() {
         if point - current_block.0 > upper_sample_width {
             if current_block.1 < current_block.0 {
-                println!("What...");
-                println!("block 1 {}, block 0 {}", current_block.1, current_block.0)
+                tracing::debug!(
+                    start = current_block.0,
+                    end = current_block.1,
+                    "pulse ends before it starts"
+                );
             }
             raw_block_points.push(current_block);
             current_block = (point.clone(), point.clone());
@@ -120,99 +420,119 @@ fn find_sync_pulse(
     }
     // Scan and find midpoints of good blocks
     // Algorithm is jank so it either creates an empty block at start or an empty one, so we remove it
+    // Every elevated point fitting in the first block leaves nothing to remove
+    if raw_block_points.is_empty() {
+        return Ok(final_sync_points);
+    }
     raw_block_points.remove(0);
     let lower_sample_width =
         ((est_sample_width as f32) * (1f32 - const_config.arduino_hz_tolerance)).round() as usize;
+    let mut pulse_widths = vec![];
     for block in raw_block_points.clone() {
-        if block.1 - block.0 > lower_sample_width {
-            let mid_point = block.0 + ((block.1 - block.0) / 2);
+        let width = block.1.saturating_sub(block.0);
+        stats.add_width(width, est_sample_width);
+        if width > lower_sample_width {
+            let mid_point = block.0 + (width / 2);
             final_sync_points.push(mid_point);
+            pulse_widths.push(width);
         } else {
-            println!("Found bad block from noise {} ", est_sample_width)
+            stats.rejected += 1;
+            tracing::debug!(
+                start = block.0,
+                width,
+                est_sample_width,
+                "pulse too narrow, taken as noise"
+            )
         }
     }
+    stats.add_pulses(&final_sync_points, &pulse_widths);
     return Ok(final_sync_points);
 }
 
 /// Calculates the mid-point of all the virtual channels.
 fn determine_virt_channel_samples(
+    channel: PicoChannel,
     sync_points: &Vec<usize>,
     virt_channel_count: usize,
     full_data: &Vec<f64>,
-) -> Vec<VirtSamples> {
-    let mut virt_channel_samples: Vec<VirtSamples> = vec![];
-    // let mut cumulative_diff: usize = 0;
+) -> Result<Vec<VirtFrame>, VirtChannelError> {
+    let mut virt_channel_samples: Vec<VirtFrame> = vec![];
 
     // Find spacing for data points in between sync points
     // round = Index of the current element
     // pulse_index = Index of the sync point in the full data set
-    for (round, pulse_index) in (&sync_points[0..(sync_points.len() - 2)])
+    for (round, pulse_index) in (&sync_points[0..sync_points.len().saturating_sub(2)])
         .iter()
         .enumerate()
     {
-        let samples: Vec<f64> = full_data[*pulse_index..sync_points[round + 1]].to_vec();
-        dump_data(samples);
+        let next_pulse = sync_points[round + 1];
         // The spacing between the two sync points
-        let diff = sync_points[round + 1] - pulse_index;
+        let diff = next_pulse.saturating_sub(*pulse_index);
         // There is the virt channel count + 1 in one diff
         let spacing = diff / (virt_channel_count+1);
-        virt_channel_samples.push(HashMap::new());
+        if spacing == 0 {
+            return Err(VirtChannelError::BadFrame {
+                channel,
+                offset: *pulse_index,
+                width: diff,
+                pulses: sync_points.len(),
+            });
+        }
+        virt_channel_samples.push(VirtFrame {
+            channel,
+            offset: *pulse_index,
+            samples: HashMap::new(),
+        });
         // loop through virt channels
         for i in 0..virt_channel_count {
             let virt_channel_index = pulse_index + spacing * (i + 1);
-            virt_channel_samples.last_mut().unwrap().insert(
-                i,
-                get_average_sample(&virt_channel_index, &full_data, &spacing),
-            );
+            let sample = get_average_sample(&virt_channel_index, &full_data, &spacing).ok_or(
+                VirtChannelError::EdgeOfBuffer {
+                    channel,
+                    offset: *pulse_index,
+                    len: full_data.len(),
+                },
+            )?;
+            virt_channel_samples
+                .last_mut()
+                .unwrap()
+                .samples
+                .insert(i, sample);
         }
     }
 
-    // Find averages to
-    // let average_diff = cumulative_diff / (sync_points.len() - 2);
-    // let average_spacing = average_diff / virt_channel_count;
-
-    return virt_channel_samples;
+    return Ok(virt_channel_samples);
 }
 
-/// Takes a fancy average of the sampled data
-fn get_average_sample(index: &usize, full_dataset: &Vec<f64>, width_of_channel: &usize) -> f64 {
+/// Takes a fancy average of the sampled data, or `None` if the window runs off the data
+fn get_average_sample(
+    index: &usize,
+    full_dataset: &Vec<f64>,
+    width_of_channel: &usize,
+) -> Option<f64> {
     let const_config = ConstConfig::get_config();
 
     let width_of_average: usize = width_of_channel / 3;
-    let mut samples: Vec<f64> =
-        full_dataset[(index - width_of_average)..(index + width_of_average + 1)].to_vec();
+    let mut samples: Vec<f64> = full_dataset
+        .get(index.checked_sub(width_of_average)?..(index + width_of_average + 1))?
+        .to_vec();
     // Can't use default .sort() because rust small brain with floats
-    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
 
     // Haven't done statistics properly yet, so here goes...
     // If the range is too high (EG: Error), we use median, otherwise a mean would be more accurate
-    if samples.last().unwrap() - samples.first().unwrap()
-        < const_config.virt_channel_noise_threshold
-    {
-        // Sensitive to skews, insensitive to noise
-        samples.iter().fold(0.0, Add::add) as f64 / samples.len() as f64
-    } else {
-        // Sensitive to noise, insensitive to skews
-        samples[samples.len() / 2]
-
-    }
+    Some(
+        if samples.last()? - samples.first()? < const_config.virt_channel_noise_threshold {
+            // Sensitive to skews, insensitive to noise
+            samples.iter().fold(0.0, Add::add) as f64 / samples.len() as f64
+        } else {
+            // Sensitive to noise, insensitive to skews
+            samples[samples.len() / 2]
+        },
+    )
 }
 
-fn dump_data(data: Vec<f64>) {
-    let cwd = std::env::current_dir().unwrap();
-
-    let save_path = cwd.join(format!("data_output/_Test_{}.csv", Local::now().format("%+")));
-    let mut file: File = File::create(save_path).unwrap();
-    let mut writer = csv::Writer::from_writer(vec![]);
-    for value in &data {
-        
-        writer.write_record( std::iter::once(value.clone().to_string())).unwrap();
-       
-
-    }
-    let csv_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
-    file.write_all(csv_data.as_bytes()).unwrap();
-    let mut samples = data.clone();
-    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    // println!("{:?}",samples.iter().fold(0.0, Add::add) as f64 / samples.len() as f64);
-}
\ No newline at end of file
+#[cfg(test)]
+pub(crate) mod testing;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,25 @@
+//! Frames for the tests of what's made of them, sized by the configured layouts
+
+use super::{VirtChannel, VirtFrame};
+use crate::ConstConfig;
+
+use pico_sdk::prelude::PicoChannel;
+
+/// Virtual channels of a Pico channel, from its configured layout
+pub fn slots(channel: PicoChannel) -> usize {
+    ConstConfig::get_config()
+        .mux_layout(&channel.to_string())
+        .virt_channels()
+}
+
+/// A frame of `channel` at the start of its block
+pub fn frame(
+    channel: PicoChannel,
+    samples: impl IntoIterator<Item = (VirtChannel, f64)>,
+) -> VirtFrame {
+    VirtFrame {
+        channel,
+        offset: 0,
+        samples: samples.into_iter().collect(),
+    }
+}
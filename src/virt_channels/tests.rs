@@ -0,0 +1,738 @@
+//! Regression tests for the demultiplexer. They rely on the values in
+//! `ConstConfig::get_config()`: a 14.7 kHz multiplexer with 4 virtual channels, sync
+//! pulses above 3.5 and a 0.8 width tolerance.
+//!
+//! The golden fixtures in `tests/fixtures/virt_channels` are one waveform per `.csv`
+//! with the frames it should produce in the matching `.json`. After an intended change
+//! to the demultiplexer, rerun with `UPDATE_GOLDEN=1` to rewrite the expected frames,
+//! and review the diff.
+//!
+//! Waveforms recorded from the device go in `recorded`, again one per `.csv`, with a
+//! `.json` giving the capture rate, `{"sample_rate": 147000}`. `UPDATE_GOLDEN=1` adds the
+//! frames they should produce to it.
+
+use super::*;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const SYNC_LEVEL: f64 = 4.5;
+const VIRT_CHANNELS: usize = 4;
+const LEVELS: [f64; VIRT_CHANNELS] = [0.2, 0.4, 0.6, 0.8];
+
+/// Capture rate that puts `samples_per_slot` samples in each multiplexer slot
+fn rate_for(samples_per_slot: usize) -> u64 {
+    (samples_per_slot * ConstConfig::get_config().arduino_hz) as u64
+}
+
+/// Builds multiplexed test waveforms: a sync pulse slot followed by one slot per
+/// virtual channel, repeated
+#[derive(Clone)]
+struct MuxSignal {
+    samples_per_slot: usize,
+    levels: Vec<f64>,
+    /// Samples into the first frame the waveform starts at
+    phase: usize,
+    frames: usize,
+    /// Standard deviation of Gaussian noise added to every sample
+    noise: f64,
+    /// Change in slot width over the whole waveform, as a fraction of `samples_per_slot`
+    drift: f64,
+    /// Frames whose sync pulse is left out
+    missing_pulses: Vec<usize>,
+    seed: u64,
+}
+
+impl MuxSignal {
+    fn clean(samples_per_slot: usize) -> Self {
+        MuxSignal {
+            samples_per_slot,
+            levels: LEVELS.to_vec(),
+            phase: samples_per_slot * 3 / 2,
+            frames: 30,
+            noise: 0.0,
+            drift: 0.0,
+            missing_pulses: vec![],
+            seed: 0,
+        }
+    }
+
+    /// Returns the samples, and where each full sync pulse starts
+    fn generate(&self) -> (Vec<f64>, Vec<usize>) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut samples = vec![];
+        let mut pulse_starts = vec![];
+
+        for frame in 0..self.frames {
+            let width = (self.samples_per_slot as f64
+                * (1.0 + self.drift * frame as f64 / self.frames as f64))
+                .round() as usize;
+            for slot in 0..=self.levels.len() {
+                if slot == 0 && !self.missing_pulses.contains(&frame) {
+                    pulse_starts.push(samples.len());
+                }
+                let level = match slot {
+                    0 if self.missing_pulses.contains(&frame) => 0.0,
+                    0 => SYNC_LEVEL,
+                    _ => self.levels[slot - 1],
+                };
+                for _ in 0..width {
+                    samples.push(level + gaussian(&mut rng) * self.noise);
+                }
+            }
+        }
+
+        let samples = samples.split_off(self.phase.min(samples.len()));
+        let pulse_starts = pulse_starts
+            .into_iter()
+            .filter(|s| *s >= self.phase)
+            .map(|s| s - self.phase)
+            .collect();
+        (samples, pulse_starts)
+    }
+
+    fn block(&self) -> HashMap<PicoChannel, Vec<f64>> {
+        let mut block = HashMap::new();
+        block.insert(PicoChannel::A, self.generate().0);
+        block
+    }
+}
+
+/// Box-Muller, so the tests don't need another dependency for normal noise
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON, 1.0);
+    let u2: f64 = rng.gen_range(0.0, 1.0);
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn values(frame: &VirtFrame) -> Vec<f64> {
+    (0..VIRT_CHANNELS).map(|c| frame.samples[&c]).collect()
+}
+
+fn assert_close(actual: &[f64], expected: &[f64], tolerance: f64, context: &str) {
+    assert_eq!(actual.len(), expected.len(), "{}", context);
+    for (a, e) in actual.iter().zip(expected.iter()) {
+        assert!(
+            (a - e).abs() <= tolerance,
+            "{}: got {:?}, expected {:?} within {}",
+            context,
+            actual,
+            expected,
+            tolerance
+        );
+    }
+}
+
+#[test]
+fn finds_the_middle_of_every_full_pulse() {
+    let signal = MuxSignal::clean(10);
+    let (samples, starts) = signal.generate();
+
+    let pulses = find_sync_pulse(PicoChannel::A, &samples, 10, &mut SyncStats::default()).unwrap();
+
+    // The pulse still open at the end of the block is left for the next one
+    let expected: Vec<usize> = starts[..starts.len() - 1].iter().map(|s| s + 4).collect();
+    assert_eq!(pulses, expected);
+}
+
+#[test]
+fn no_pulses_in_a_flat_signal() {
+    assert_eq!(
+        find_sync_pulse(
+            PicoChannel::B,
+            &vec![0.5; 500],
+            10,
+            &mut SyncStats::default()
+        ),
+        Err(VirtChannelError::NoSyncPulse {
+            channel: PicoChannel::B
+        })
+    );
+}
+
+#[test]
+fn short_noise_spikes_are_not_pulses() {
+    let (mut samples, starts) = MuxSignal::clean(10).generate();
+    // Single sample spikes in data slots. They have to be further than the widest
+    // accepted pulse from the next pulse, or the two get grouped together
+    samples[starts[3] + 25] = SYNC_LEVEL;
+    samples[starts[7] + 30] = SYNC_LEVEL;
+
+    let pulses = find_sync_pulse(PicoChannel::A, &samples, 10, &mut SyncStats::default()).unwrap();
+    let expected: Vec<usize> = starts[..starts.len() - 1].iter().map(|s| s + 4).collect();
+    assert_eq!(pulses, expected);
+}
+
+#[test]
+fn samples_the_middle_of_every_slot() {
+    let (samples, starts) = MuxSignal::clean(10).generate();
+    let pulses: Vec<usize> = starts.iter().map(|s| s + 4).collect();
+
+    let frames =
+        determine_virt_channel_samples(PicoChannel::A, &pulses, VIRT_CHANNELS, &samples).unwrap();
+
+    assert_eq!(frames.len(), pulses.len() - 2);
+    for (frame, pulse) in frames.iter().zip(pulses.iter()) {
+        assert_eq!(frame.offset, *pulse);
+        assert_close(&values(frame), &LEVELS, 1e-12, "clean frame");
+    }
+}
+
+#[test]
+fn frames_too_narrow_for_every_channel_are_bad() {
+    let samples = vec![0.0; 100];
+    assert_eq!(
+        determine_virt_channel_samples(PicoChannel::A, &vec![10, 13, 16], VIRT_CHANNELS, &samples)
+            .unwrap_err(),
+        VirtChannelError::BadFrame {
+            channel: PicoChannel::A,
+            offset: 10,
+            width: 3,
+            pulses: 3,
+        }
+    );
+}
+
+#[test]
+fn frames_past_the_end_of_the_block_are_an_error() {
+    // The last slot of the frame at 10 is averaged from 47 to 53
+    let samples = vec![0.0; 50];
+    assert_eq!(
+        determine_virt_channel_samples(PicoChannel::A, &vec![10, 60, 110], VIRT_CHANNELS, &samples)
+            .unwrap_err(),
+        VirtChannelError::EdgeOfBuffer {
+            channel: PicoChannel::A,
+            offset: 10,
+            len: 50,
+        }
+    );
+}
+
+#[test]
+fn averages_quiet_slots_and_takes_the_median_of_noisy_ones() {
+    // Quiet, so the mean of the 7 samples around index 5
+    let quiet = get_average_sample(&5, &vec![0.0, 0.0, 1.0, 1.1, 1.2, 1.3, 1.35, 1.4, 1.45], &9);
+    assert_close(&[quiet.unwrap()], &[8.8 / 7.0], 1e-12, "quiet slot");
+    // A glitch past the noise threshold, so the median
+    assert_eq!(
+        get_average_sample(&5, &vec![0.0, 0.0, 1.0, 1.1, 1.2, 1.3, 9.0, 1.4, 1.45], &9),
+        Some(1.3)
+    );
+    assert_eq!(get_average_sample(&1, &vec![1.0; 10], &9), None);
+    assert_eq!(get_average_sample(&8, &vec![1.0; 10], &9), None);
+}
+
+#[test]
+fn clean_block() {
+    let frames = split_into_virt_channels(&MuxSignal::clean(10).block(), rate_for(10)).unwrap();
+
+    // 29 full pulses, less the one still open at the end, bound 28 frames. The last
+    // of those is left for the next block too
+    assert_eq!(frames.len(), 26);
+    for frame in frames.iter() {
+        assert_close(&values(frame), &LEVELS, 1e-12, "clean block");
+    }
+}
+
+#[test]
+fn channels_come_out_in_order() {
+    // Different levels and phases per channel, so their frames can be told apart
+    let signals: Vec<(PicoChannel, MuxSignal)> = vec![
+        (
+            PicoChannel::C,
+            MuxSignal {
+                levels: vec![0.3; 4],
+                ..MuxSignal::clean(10)
+            },
+        ),
+        (
+            PicoChannel::A,
+            MuxSignal {
+                phase: 5,
+                ..MuxSignal::clean(10)
+            },
+        ),
+        (
+            PicoChannel::B,
+            MuxSignal {
+                levels: vec![0.1; 4],
+                ..MuxSignal::clean(10)
+            },
+        ),
+    ];
+    let samples: HashMap<_, _> = signals
+        .iter()
+        .map(|(channel, signal)| (*channel, signal.generate().0))
+        .collect();
+
+    let mut expected = vec![];
+    for channel in [PicoChannel::A, PicoChannel::B, PicoChannel::C].iter() {
+        expected.extend(
+            demultiplex_channel(*channel, &samples[channel], rate_for(10))
+                .frames
+                .unwrap(),
+        );
+    }
+    // Each map is seeded differently, so iterates its channels in a different order
+    for _ in 0..5 {
+        let block: HashMap<_, _> = samples.clone().into_iter().collect();
+        let frames = split_into_virt_channels(&block, rate_for(10)).unwrap();
+        assert_eq!(frames.len(), expected.len());
+        for (frame, expected) in frames.iter().zip(expected.iter()) {
+            assert_eq!(frame.offset, expected.offset);
+            assert_close(&values(frame), &values(expected), 0.0, "channel order");
+        }
+    }
+}
+
+#[test]
+fn sync_stats_of_a_clean_block() {
+    let (samples, starts) = MuxSignal::clean(10).generate();
+
+    let sync = demultiplex_channel(PicoChannel::A, &samples, rate_for(10)).sync;
+
+    assert_eq!(sync.samples, samples.len());
+    assert_eq!(sync.expected_width, 10);
+    assert_eq!(sync.pulses, starts.len() - 1);
+    assert_eq!(sync.rejected, 0);
+    // One slot for the pulse and one for each virtual channel
+    assert_eq!(sync.interval_mean, 50.0);
+    assert_eq!(sync.interval_std, 0.0);
+    // Each full pulse is 10 samples, but measured from its first to last sample
+    assert_eq!(sync.width_mean, 9.0);
+    assert_eq!(sync.width_std, 0.0);
+    assert_eq!(sync.width_histogram[9], sync.pulses as u64);
+    let covered = (starts[starts.len() - 3] - starts[0]) as f64 / samples.len() as f64;
+    assert!((sync.coverage - covered).abs() < 1e-12);
+}
+
+#[test]
+fn sync_stats_count_noise_spikes() {
+    let (mut samples, starts) = MuxSignal::clean(10).generate();
+    samples[starts[3] + 25] = SYNC_LEVEL;
+
+    let sync = demultiplex_channel(PicoChannel::A, &samples, rate_for(10)).sync;
+
+    assert_eq!(sync.rejected, 1);
+    assert_eq!(sync.width_histogram[0], 1);
+    assert_eq!(sync.interval_std, 0.0);
+}
+
+#[test]
+fn sync_stats_of_a_failed_block() {
+    let demux = demultiplex_channel(PicoChannel::B, &vec![0.5; 500], rate_for(10));
+
+    assert!(demux.frames.is_err());
+    assert_eq!(demux.sync.pulses, 0);
+    assert_eq!(demux.sync.coverage, 0.0);
+}
+
+#[test]
+fn slots_follow_the_layout() {
+    // Starts inside a pulse. A full pulse too close to the start of the block is taken
+    // as part of the partial one, and dropped with it
+    let signal = MuxSignal {
+        levels: vec![0.3, 0.9],
+        phase: 5,
+        ..MuxSignal::clean(10)
+    };
+    let (samples, _) = signal.generate();
+
+    let demux = demultiplex_layout(PicoChannel::B, MuxLayout::Slots(2), &samples, rate_for(10));
+
+    let frames = demux.frames.unwrap();
+    assert!(!frames.is_empty());
+    for frame in frames.iter() {
+        assert_eq!(frame.samples.len(), 2);
+        assert_close(
+            &[frame.samples[&0], frame.samples[&1]],
+            &[0.3, 0.9],
+            1e-12,
+            "2 slots",
+        );
+    }
+    assert_eq!(demux.sync.interval_mean, 30.0);
+}
+
+#[test]
+fn a_direct_channel_is_averaged_to_the_frame_rate() {
+    // Alternating samples, so only whole windows average to the middle
+    let samples: Vec<f64> = (0..1010)
+        .map(|i| if i % 2 == 0 { 0.5 } else { 1.5 })
+        .collect();
+
+    let demux = demultiplex_layout(PicoChannel::B, MuxLayout::None, &samples, rate_for(10));
+
+    // 5 slots of 10 samples a frame, and the last 10 samples aren't a whole frame
+    let frames = demux.frames.unwrap();
+    assert_eq!(frames.len(), 20);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.offset, i * 50);
+        assert_close(&[frame.samples[&0]], &[1.0], 1e-12, "direct channel");
+    }
+    assert_eq!(demux.sync.pulses, 0);
+    assert!((demux.sync.coverage - 1000.0 / 1010.0).abs() < 1e-12);
+}
+
+#[test]
+fn a_sync_only_channel_has_no_frames() {
+    let (samples, starts) = MuxSignal::clean(10).generate();
+
+    let demux = demultiplex_layout(PicoChannel::C, MuxLayout::SyncOnly, &samples, rate_for(10));
+
+    assert!(demux.frames.unwrap().is_empty());
+    assert_eq!(demux.sync.pulses, starts.len() - 1);
+    assert_eq!(demux.sync.interval_mean, 50.0);
+}
+
+#[test]
+fn noisy_block() {
+    let signal = MuxSignal {
+        noise: 0.02,
+        seed: 7,
+        ..MuxSignal::clean(20)
+    };
+    let frames = split_into_virt_channels(&signal.block(), rate_for(20)).unwrap();
+
+    assert_eq!(frames.len(), 26);
+    for frame in frames.iter() {
+        // Averaging 13 samples shrinks the noise to well under 0.02
+        assert_close(&values(frame), &LEVELS, 0.03, "noisy block");
+    }
+}
+
+#[test]
+fn drifting_multiplexer_frequency() {
+    let signal = MuxSignal {
+        drift: 0.3,
+        ..MuxSignal::clean(10)
+    };
+    let (_, starts) = signal.generate();
+    let frames = split_into_virt_channels(&signal.block(), rate_for(10)).unwrap();
+
+    assert_eq!(frames.len(), starts.len() - 3);
+    for frame in frames.iter() {
+        assert_close(&values(frame), &LEVELS, 1e-12, "drifting block");
+    }
+}
+
+#[test]
+fn missing_pulses_merge_two_frames() {
+    let signal = MuxSignal {
+        missing_pulses: vec![10],
+        ..MuxSignal::clean(10)
+    };
+    let frames = split_into_virt_channels(&signal.block(), rate_for(10)).unwrap();
+
+    // One pulse fewer means one frame fewer, and a frame twice as wide
+    assert_eq!(frames.len(), 25);
+    let widths: Vec<usize> = frames
+        .windows(2)
+        .map(|w| w[1].offset - w[0].offset)
+        .collect();
+    assert_eq!(widths.iter().filter(|w| **w == 100).count(), 1);
+    assert!(widths.iter().all(|w| *w == 50 || *w == 100));
+
+    // Only the merged frame is sampled in the wrong places
+    let merged = widths.iter().position(|w| *w == 100).unwrap();
+    for (index, frame) in frames.iter().enumerate() {
+        if index != merged {
+            assert_close(
+                &values(frame),
+                &LEVELS,
+                1e-12,
+                "frame next to a missing pulse",
+            );
+        }
+    }
+}
+
+#[test]
+fn pulses_cut_by_the_block_edges_are_skipped() {
+    let signal = MuxSignal {
+        // Starts 3 samples into a sync pulse
+        phase: 50 * 2 + 3,
+        ..MuxSignal::clean(10)
+    };
+    let (mut samples, starts) = signal.generate();
+    // Ends 4 samples into one
+    let last = *starts.last().unwrap();
+    samples.truncate(last + 4);
+    let mut block = HashMap::new();
+    block.insert(PicoChannel::A, samples);
+
+    let frames = split_into_virt_channels(&block, rate_for(10)).unwrap();
+
+    assert_eq!(frames[0].offset, starts[0] + 4);
+    assert!(frames.iter().all(|f| f.offset + 50 <= last));
+    for frame in frames.iter() {
+        assert_close(&values(frame), &LEVELS, 1e-12, "block cut mid-pulse");
+    }
+}
+
+#[test]
+fn reports_why_a_block_fails() {
+    let too_slow = split_into_virt_channels(&MuxSignal::clean(10).block(), 1_000);
+    assert_eq!(
+        too_slow.unwrap_err(),
+        VirtChannelError::SampleRateTooLow {
+            sample_rate: 1_000,
+            arduino_hz: 14700,
+        }
+    );
+
+    let mut block = MuxSignal::clean(10).block();
+    block.get_mut(&PicoChannel::A).unwrap()[42] = f64::NAN;
+    assert_eq!(
+        split_into_virt_channels(&block, rate_for(10)).unwrap_err(),
+        VirtChannelError::NanInput {
+            channel: PicoChannel::A,
+            offset: 42,
+        }
+    );
+
+    let short = MuxSignal {
+        frames: 3,
+        ..MuxSignal::clean(10)
+    };
+    assert_eq!(
+        split_into_virt_channels(&short.block(), rate_for(10)).unwrap_err(),
+        VirtChannelError::NotEnoughData {
+            channel: PicoChannel::A,
+            pulses: 1,
+        }
+    );
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoldenFrame {
+    offset: usize,
+    values: Vec<f64>,
+}
+
+fn fixture_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/virt_channels")
+}
+
+/// Waveforms the golden fixtures were generated from, with the capture rate to use
+fn golden_signals() -> Vec<(&'static str, MuxSignal)> {
+    vec![
+        ("clean", MuxSignal::clean(10)),
+        (
+            "noisy",
+            MuxSignal {
+                noise: 0.05,
+                seed: 1,
+                ..MuxSignal::clean(12)
+            },
+        ),
+        (
+            "drifting",
+            MuxSignal {
+                drift: -0.25,
+                noise: 0.01,
+                seed: 2,
+                ..MuxSignal::clean(16)
+            },
+        ),
+        (
+            "missing_pulses",
+            MuxSignal {
+                missing_pulses: vec![5, 6, 20],
+                noise: 0.01,
+                seed: 3,
+                ..MuxSignal::clean(10)
+            },
+        ),
+        (
+            "edge_pulses",
+            MuxSignal {
+                phase: 5,
+                frames: 25,
+                noise: 0.01,
+                seed: 4,
+                ..MuxSignal::clean(10)
+            },
+        ),
+    ]
+}
+
+/// One waveform per line, in volts
+fn read_samples(path: &Path) -> Vec<f64> {
+    fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+        .lines()
+        .map(|l| l.parse().unwrap())
+        .collect()
+}
+
+fn golden_frames(name: &str, samples: Vec<f64>, sample_rate: u64) -> Vec<GoldenFrame> {
+    let mut block = HashMap::new();
+    block.insert(PicoChannel::A, samples);
+    split_into_virt_channels(&block, sample_rate)
+        .unwrap_or_else(|e| panic!("{}: {}", name, e))
+        .iter()
+        .map(|f| GoldenFrame {
+            offset: f.offset,
+            values: values(f),
+        })
+        .collect()
+}
+
+fn assert_golden(name: &str, frames: &[GoldenFrame], expected: &[GoldenFrame]) {
+    assert_eq!(frames.len(), expected.len(), "{}: frame count", name);
+    for (actual, expected) in frames.iter().zip(expected.iter()) {
+        let context = format!("{} frame at {}", name, expected.offset);
+        assert_eq!(actual.offset, expected.offset, "{}", context);
+        assert_close(&actual.values, &expected.values, 1e-9, &context);
+    }
+}
+
+#[test]
+fn golden_fixtures() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    for (name, signal) in golden_signals() {
+        let csv_path = fixture_dir().join(format!("{}.csv", name));
+        let json_path = fixture_dir().join(format!("{}.json", name));
+
+        if update {
+            fs::create_dir_all(fixture_dir()).unwrap();
+            let lines: Vec<String> = signal.generate().0.iter().map(|v| v.to_string()).collect();
+            fs::write(&csv_path, lines.join("\n") + "\n").unwrap();
+        }
+
+        let samples = read_samples(&csv_path);
+        let frames = golden_frames(name, samples, rate_for(signal.samples_per_slot));
+
+        if update {
+            fs::write(&json_path, serde_json::to_string_pretty(&frames).unwrap()).unwrap();
+        }
+
+        let expected: Vec<GoldenFrame> =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_golden(name, &frames, &expected);
+    }
+}
+
+/// A waveform recorded from the device, with the capture rate it was recorded at
+#[derive(Serialize, Deserialize)]
+struct RecordedFixture {
+    sample_rate: u64,
+    /// Left out of a new capture, until `UPDATE_GOLDEN=1` fills them in
+    #[serde(default)]
+    frames: Vec<GoldenFrame>,
+}
+
+#[test]
+fn recorded_fixtures() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    let directory = fixture_dir().join("recorded");
+    let mut captures: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|e| panic!("{}: {}", directory.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("csv".as_ref()))
+        .collect();
+    captures.sort();
+
+    for csv_path in captures {
+        let name = csv_path.file_stem().unwrap().to_string_lossy().to_string();
+        let json_path = csv_path.with_extension("json");
+        let mut fixture: RecordedFixture = fs::read_to_string(&json_path)
+            .map(|json| serde_json::from_str(&json).unwrap())
+            .unwrap_or_else(|e| panic!("{} needs its capture rate: {}", json_path.display(), e));
+
+        let frames = golden_frames(&name, read_samples(&csv_path), fixture.sample_rate);
+        if update {
+            fixture.frames = frames;
+            fs::write(&json_path, serde_json::to_string_pretty(&fixture).unwrap()).unwrap();
+            continue;
+        }
+
+        assert!(
+            !fixture.frames.is_empty(),
+            "{}: no expected frames, rerun with UPDATE_GOLDEN=1",
+            name
+        );
+        assert_golden(&name, &frames, &fixture.frames);
+    }
+}
+
+#[test]
+fn random_mux_signals_demultiplex_to_their_levels() {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+
+    for case in 0..200 {
+        // From the fewest samples per slot the config accepts
+        let samples_per_slot = rng.gen_range(ConstConfig::get_config().min_samples_per_slot, 40);
+        let signal = MuxSignal {
+            samples_per_slot,
+            levels: (0..VIRT_CHANNELS)
+                .map(|_| rng.gen_range(-3.0, 3.0))
+                .collect(),
+            phase: rng.gen_range(0, samples_per_slot * 5),
+            frames: rng.gen_range(8, 40),
+            noise: rng.gen_range(0.0, 0.01),
+            drift: rng.gen_range(-0.1, 0.1),
+            missing_pulses: vec![],
+            seed: case,
+        };
+        let context = format!(
+            "case {} ({} per slot, levels {:?})",
+            case, samples_per_slot, signal.levels
+        );
+
+        let frames = split_into_virt_channels(&signal.block(), rate_for(samples_per_slot))
+            .unwrap_or_else(|e| panic!("{}: {}", context, e));
+
+        // Lost at most: the partial first frame, a first pulse close enough to the start
+        // of the block to be grouped with it, the pulse still open at the end, and the
+        // two frames left for the next block
+        assert!(
+            frames.len() + 5 >= signal.frames,
+            "{}: too few frames",
+            context
+        );
+        for pair in frames.windows(2) {
+            assert!(
+                pair[0].offset < pair[1].offset,
+                "{}: offsets out of order",
+                context
+            );
+        }
+        for frame in frames.iter() {
+            assert_eq!(frame.samples.len(), VIRT_CHANNELS, "{}", context);
+            assert_close(&values(frame), &signal.levels, 0.05, &context);
+        }
+    }
+}
+
+#[test]
+fn random_garbage_never_panics() {
+    let mut rng = StdRng::seed_from_u64(0xbad);
+
+    for _ in 0..500 {
+        let len = rng.gen_range(0, 2000);
+        let spike_chance = rng.gen_range(0.0, 0.5);
+        let samples: Vec<f64> = (0..len)
+            .map(|_| {
+                if rng.gen_bool(spike_chance) {
+                    SYNC_LEVEL
+                } else {
+                    rng.gen_range(-5.0, 3.0)
+                }
+            })
+            .collect();
+        let mut block = HashMap::new();
+        block.insert(PicoChannel::A, samples);
+
+        // Either outcome is fine, as long as it's not a panic
+        let _ = split_into_virt_channels(&block, rng.gen_range(0, 1_000_000));
+    }
+}
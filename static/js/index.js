@@ -15,6 +15,12 @@ let deviceConfig = {};
 
 let chart_initalised = false;
 
+let electrode_graph;
+let electrode_points = {};
+let last_frame_time;
+let empty_frame_requests = 0;
+let stale_frame_time;
+
 // Absolutely needed
 const isEven = (a) => a % 2 == 0;
 const zeroPad = (num, places) => String(num).padStart(places, "0");
@@ -121,6 +127,17 @@ $(() => {
     setInterval(async () => {
         getDeviceInfo();
     }, 1000);
+    getSessions();
+    setInterval(async () => {
+        getSessions();
+    }, 5000);
+    setInterval(async () => {
+        getSync();
+    }, 1000);
+    getElectrodes();
+    setInterval(async () => {
+        getElectrodeFrames();
+    }, 400);
     
     
 });
@@ -234,6 +251,18 @@ function testRefreshRate() {
 
 }
 
+/// How a Pico channel is multiplexed, as the server's layout
+const formatLayout = (layout) => {
+    switch (layout["kind"]) {
+        case "slots":
+            return layout["slots"] + " slots";
+        case "sync_only":
+            return "sync only";
+        default:
+            return "direct";
+    }
+};
+
 function getDeviceInfo() {
     $.ajax({
         type: "get",
@@ -254,11 +283,18 @@ function getDeviceInfo() {
                 ")"
             );
             $("#info-virtual-channel-count").html(virtualChannelCount);
+            $("#info-mux-layout").html(
+                data["channel_info"]
+                    .map((e) => e["channel"] + ": " + formatLayout(e["layout"]))
+                    .join(" | ")
+            );
 
             $("#info-target-refresh-rate").html(
                 data["refresh_rate"] +
                 " / " +
-                (data["refresh_rate"] * ChannelCount) / virtualChannelCount
+                (virtualChannelCount > 0
+                    ? (data["refresh_rate"] * ChannelCount) / virtualChannelCount
+                    : "-")
             );
             $("#info-recived-refresh-rate").html(testRefreshRate());
             $("#info-voltage-range").html(
@@ -282,3 +318,226 @@ function getDeviceInfo() {
         },
     });
 }
+
+const formatDuration = (seconds) => {
+    let hours = Math.floor(seconds / 3600);
+    let minutes = Math.floor((seconds % 3600) / 60);
+    return zeroPad(hours, 2) + ":" + zeroPad(minutes, 2) + ":" + zeroPad(Math.floor(seconds % 60), 2);
+};
+
+const formatBytes = (bytes) => {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let unit = 0;
+    while (bytes >= 1000 && unit < units.length - 1) {
+        bytes /= 1000;
+        unit++;
+    }
+    return bytes.toFixed(1) + " " + units[unit];
+};
+
+function getSessions() {
+    $.ajax({
+        type: "get",
+        url: "/api/sessions",
+        success: (sessions, text) => {
+            let rows = sessions.map((session) => {
+                let name = encodeURIComponent(session["name"]);
+                let gap_seconds = session["gaps"]
+                    .map((g) => g["duration"])
+                    .reduce((a, b) => a + b, 0);
+                let row = $("<tr>");
+                row.append($("<td>").text(session["name"] + (session["recording"] ? " (recording)" : "")));
+                row.append($("<td>").text(new Date(session["started"]).toLocaleString()));
+                row.append($("<td>").text(formatDuration(session["duration"])));
+                row.append($("<td>").text(session["channels"].join(" | ")));
+                row.append($("<td>").text(session["frame_count"]));
+                row.append($("<td>").text(session["gaps"].length + " (" + gap_seconds.toFixed(1) + "s)"));
+                row.append($("<td>").text(
+                    session["sync"]
+                        .map((s) => s["channel"] + ": " + formatPercent(s["coverage"]) + ", " + s["rejected"] + " rejected")
+                        .join(" | ")
+                ));
+                row.append($("<td>").text(formatBytes(session["bytes"])));
+
+                let actions = $("<td>");
+                for (let format of ["zip", "csv", "npz", "mat"]) {
+                    actions.append(
+                        $("<a>")
+                            .addClass("btn btn-outline-light btn-sm me-1")
+                            .attr("href", `/api/sessions/${name}/download?format=${format}`)
+                            .text(format)
+                    );
+                }
+                if (!session["recording"]) {
+                    actions.append(
+                        $("<button>")
+                            .addClass("btn btn-outline-danger btn-sm")
+                            .text("delete")
+                            .on("click", () => deleteSession(session["name"]))
+                    );
+                }
+                row.append(actions);
+                return row;
+            });
+            $("#sessions-table").empty().append(rows);
+        },
+        error: (request, status, error) => {
+            console.log("Error retrieving sessions.");
+            console.table({
+                error: error,
+                status: status,
+            });
+        },
+    });
+}
+
+const formatPercent = (fraction) => (fraction * 100).toFixed(1) + "%";
+
+const formatSpread = (mean, std) => mean.toFixed(1) + " ± " + std.toFixed(1);
+
+/// Shows the sync pulses of the latest block next to the totals of the session
+function getSync() {
+    $.ajax({
+        type: "get",
+        url: "/api/sync",
+        success: (sync, text) => {
+            let latest = sync["recent"].length > 0 ? sync["recent"][sync["recent"].length - 1]["channels"] : [];
+            let rows = latest.map((channel) => {
+                let stats = channel["stats"];
+                let session = sync["session"].find((s) => s["channel"] === channel["channel"]);
+                let row = $("<tr>");
+                row.append($("<td>").text(channel["channel"]));
+                row.append($("<td>").text(stats["pulses"]));
+                row.append($("<td>").text(stats["rejected"]));
+                row.append($("<td>").text(formatSpread(stats["interval_mean"], stats["interval_std"])));
+                row.append($("<td>").text(formatSpread(stats["width_mean"], stats["width_std"])));
+                row.append($("<td>").text(channel["error"] === null ? formatPercent(stats["coverage"]) : channel["error"]));
+                if (session !== undefined) {
+                    row.append($("<td>").text(session["rejected"]));
+                    row.append($("<td>").text(formatSpread(session["interval_mean"], session["interval_std"])));
+                    row.append($("<td>").text(formatPercent(session["coverage"])));
+                } else {
+                    row.append($("<td colspan=3>").text("-"));
+                }
+                return row;
+            });
+            $("#sync-table").empty().append(rows);
+        },
+        error: (request, status, error) => {
+            console.log("Error retrieving sync quality.");
+        },
+    });
+}
+
+/// Charts every electrode and montage, labelled and coloured from the channel map
+function getElectrodes() {
+    $.ajax({
+        type: "get",
+        url: "/api/electrodes",
+        success: (electrodes, text) => {
+            let datasets = electrodes.map((electrode) => ({
+                label: electrode["name"],
+                borderColor: electrode["colour"],
+                backgroundColor: "rgba(0, 0, 0, 0)",
+                borderDash: electrode["derived"] === null ? [] : [4, 4],
+                pointRadius: 0,
+                data: [],
+            }));
+            electrode_graph = new Chart($("#electrode-graph"), {
+                type: "line",
+                data: { datasets: datasets },
+                options: {
+                    scales: {
+                        yAxes: [{
+                            scaleLabel: {
+                                display: true,
+                                labelString: "filtered",
+                            },
+                        }],
+                        xAxes: [{
+                            type: "realtime",
+                            realtime: {
+                                duration: 10000,
+                                refresh: 400,
+                                onRefresh: (chart) => {
+                                    chart.data.datasets.forEach((dataset) => {
+                                        let points = electrode_points[dataset["label"]];
+                                        if (points !== undefined) {
+                                            dataset.data.push(...points);
+                                            electrode_points[dataset["label"]] = [];
+                                        }
+                                    });
+                                },
+                            },
+                        }],
+                    },
+                },
+            });
+        },
+        error: (request, status, error) => {
+            console.log("Error retrieving electrodes.");
+        },
+    });
+}
+
+/// Queues the filtered frames since the last request, by electrode name. Frames are
+/// timed from the start of the session, so they're placed relative to the newest.
+/// Nothing new for a few seconds could be a new session, whose frames start from 0 again
+function getElectrodeFrames() {
+    if (electrode_graph === undefined) {
+        return;
+    }
+    let since = last_frame_time === undefined ? "" : `&since=${last_frame_time}`;
+    $.ajax({
+        type: "get",
+        url: `/api/frames?stream=filtered${since}`,
+        success: (frames, text) => {
+            if (frames.length === 0) {
+                empty_frame_requests += 1;
+                if (empty_frame_requests > 5 && last_frame_time !== undefined) {
+                    stale_frame_time = last_frame_time;
+                    last_frame_time = undefined;
+                }
+                return;
+            }
+            let newest = frames[frames.length - 1][0];
+            if (newest === stale_frame_time) {
+                last_frame_time = newest;
+                return;
+            }
+            let now = Date.now();
+            // Only the latest after starting over, not the backlog of the last session
+            let oldest = last_frame_time === undefined ? newest - 0.4 : last_frame_time;
+            empty_frame_requests = 0;
+            for (let [time, samples] of frames.filter(([time]) => time > oldest)) {
+                for (let name of Object.keys(samples)) {
+                    if (electrode_points[name] === undefined) {
+                        electrode_points[name] = [];
+                    }
+                    electrode_points[name].push({
+                        x: now - (newest - time) * 1000,
+                        y: samples[name],
+                    });
+                }
+            }
+            last_frame_time = newest;
+        },
+        error: (request, status, error) => {
+            console.log("Error retrieving frames.");
+        },
+    });
+}
+
+function deleteSession(name) {
+    if (!confirm(`Delete session ${name}? This can't be undone.`)) {
+        return;
+    }
+    $.ajax({
+        type: "delete",
+        url: `/api/sessions/${encodeURIComponent(name)}`,
+        success: () => getSessions(),
+        error: (request, status, error) => {
+            alert(`Could not delete ${name}: ${request.responseText}`);
+        },
+    });
+}